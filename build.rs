@@ -0,0 +1,170 @@
+//! Generates the export-forwarding table for the selected proxy DLL identity.
+//!
+//! The `dinput8` identity is implemented by hand in `src/dinput8.rs`. Every other identity
+//! forwards each export listed in `proxy/<name>.exports` to the system DLL of the same name,
+//! through a small assembly trampoline that jumps via a table filled in at load time.
+//!
+//! An export line may give, after the name, the value to return if the system DLL turns out
+//! not to have that export (0 if omitted). It's a 32-bit integer, in decimal or `0x` hex.
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// Proxy identities, by cargo feature.
+const IDENTITIES: &[(&str, &str)] = &[
+    ("CARGO_FEATURE_PROXY_DINPUT8", "dinput8"),
+    ("CARGO_FEATURE_PROXY_VERSION", "version"),
+    ("CARGO_FEATURE_PROXY_WINMM", "winmm"),
+    ("CARGO_FEATURE_PROXY_DSOUND", "dsound"),
+];
+
+fn main() {
+    let selected = IDENTITIES
+        .iter()
+        .filter(|(feature, _)| env::var_os(feature).is_some())
+        .map(|&(_, name)| name)
+        .collect::<Vec<_>>();
+
+    let identity = match selected.as_slice() {
+        [identity] => *identity,
+        [] => panic!("no proxy identity selected; enable exactly one `proxy-*` feature"),
+        _ => panic!("multiple proxy identities selected ({}); enable exactly one `proxy-*` feature", selected.join(", ")),
+    };
+
+    let (exports, failures): (Vec<String>, Vec<i32>) = if identity == "dinput8" {
+        (Vec::new(), Vec::new())
+    } else {
+        let list = format!("proxy/{identity}.exports");
+        println!("cargo:rerun-if-changed={list}");
+
+        fs::read_to_string(&list)
+            .unwrap_or_else(|e| panic!("failed to read {list}: {e}"))
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| parse_export(l).unwrap_or_else(|| panic!("{list}: malformed export line `{l}`")))
+            .unzip()
+    };
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+    let x86 = env::var("CARGO_CFG_TARGET_ARCH").unwrap() == "x86";
+
+    fs::write(out_dir.join("proxy_exports.rs"), generate_table(identity, &exports, &failures, x86)).unwrap();
+
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() == "windows" && !exports.is_empty() {
+        if env::var("CARGO_CFG_TARGET_ENV").unwrap() == "msvc" {
+            for export in &exports {
+                println!("cargo:rustc-cdylib-link-arg=/EXPORT:{export}={}", trampoline_symbol(export, x86));
+            }
+        } else {
+            // GNU ld takes a module-definition file as a regular input.
+            let mut def = String::from("EXPORTS\n");
+            for export in &exports {
+                writeln!(def, "    {export}={}", trampoline_symbol(export, x86)).unwrap();
+            }
+
+            let def_path = out_dir.join("proxy_exports.def");
+            fs::write(&def_path, def).unwrap();
+            println!("cargo:rustc-cdylib-link-arg={}", def_path.display());
+        }
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// Split an export line into the export's name and its failure value.
+fn parse_export(line: &str) -> Option<(String, i32)> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next()?.to_owned();
+    let failure = match fields.next() {
+        None => 0,
+        Some(hex) if hex.starts_with("0x") => u32::from_str_radix(&hex[2..], 16).ok()? as i32,
+        Some(decimal) => decimal.parse().ok()?,
+    };
+
+    match fields.next() {
+        None => Some((name, failure)),
+        Some(_) => None,
+    }
+}
+
+fn trampoline_symbol(export: &str, x86: bool) -> String {
+    // 32-bit COFF prefixes C symbols with an underscore.
+    match x86 {
+        true => format!("_renx_proxy_{export}"),
+        false => format!("renx_proxy_{export}"),
+    }
+}
+
+fn generate_table(identity: &str, exports: &[String], failures: &[i32], x86: bool) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "/// File name of the DLL this build stands in for.").unwrap();
+    writeln!(out, "pub const PROXY_DLL: &str = \"{identity}.dll\";").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "/// Exports forwarded to the system DLL, in trampoline table order.").unwrap();
+    writeln!(out, "pub const PROXY_EXPORTS: &[&str] = &[").unwrap();
+    for export in exports {
+        writeln!(out, "    \"{export}\",").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "/// Addresses of the forwarded exports in the system DLL. Filled in by `init`.").unwrap();
+    match exports.len() {
+        0 => writeln!(out, "static PROXY_TABLE: [std::sync::atomic::AtomicUsize; 0] = [];").unwrap(),
+        len => writeln!(
+            out,
+            "static PROXY_TABLE: [std::sync::atomic::AtomicUsize; {len}] = [const {{ std::sync::atomic::AtomicUsize::new(0) }}; {len}];"
+        )
+        .unwrap(),
+    }
+
+    if exports.is_empty() {
+        return out;
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "/// What each forwarded export returns if the system DLL doesn't have it.").unwrap();
+    writeln!(out, "const PROXY_FAILURES: &[i32] = &{failures:?};").unwrap();
+
+    // Each trampoline tail-jumps through its table entry, leaving the arguments untouched. An
+    // entry left empty (the system DLL lacks the export) goes to `missing_export` instead, with
+    // the export's index as its argument.
+    let mut asm = String::from(".text\n");
+    for (i, export) in exports.iter().enumerate() {
+        let symbol = trampoline_symbol(export, x86);
+        writeln!(asm, ".globl {symbol}").unwrap();
+        writeln!(asm, "{symbol}:").unwrap();
+        match x86 {
+            true => {
+                writeln!(asm, "    mov eax, dword ptr [{{table}} + {}]", i * 4).unwrap();
+                writeln!(asm, "    test eax, eax").unwrap();
+                writeln!(asm, "    jz {symbol}_missing").unwrap();
+                writeln!(asm, "    jmp eax").unwrap();
+                writeln!(asm, "{symbol}_missing:").unwrap();
+                writeln!(asm, "    push {i}").unwrap();
+                writeln!(asm, "    call {{missing}}").unwrap();
+            }
+            false => {
+                writeln!(asm, "    mov rax, qword ptr [rip + {{table}} + {}]", i * 8).unwrap();
+                writeln!(asm, "    test rax, rax").unwrap();
+                writeln!(asm, "    jz {symbol}_missing").unwrap();
+                writeln!(asm, "    jmp rax").unwrap();
+                writeln!(asm, "{symbol}_missing:").unwrap();
+                writeln!(asm, "    mov ecx, {i}").unwrap();
+                writeln!(asm, "    jmp {{missing}}").unwrap();
+            }
+        }
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "std::arch::global_asm!(").unwrap();
+    writeln!(out, "    {:?},", asm).unwrap();
+    writeln!(out, "    table = sym PROXY_TABLE,").unwrap();
+    writeln!(out, "    missing = sym missing_export,").unwrap();
+    writeln!(out, ");").unwrap();
+
+    out
+}
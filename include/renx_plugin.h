@@ -0,0 +1,67 @@
+/*
+ * Plugin ABI for the Renegade X UDK Extensions.
+ *
+ * Plugins are DLLs placed in the `Plugins` folder next to UDK.exe. They are loaded in file name
+ * order on the game thread, once the engine is up and the extensions have installed their own hooks.
+ *
+ * Every function in the context takes the context as its first argument and returns 0 on
+ * success. Failures are written to the UDK log on the plugin's behalf.
+ */
+#ifndef RENX_PLUGIN_H
+#define RENX_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RENX_PLUGIN_ABI_VERSION 1
+
+#define RENX_PLUGIN_LOG_INFO 0
+#define RENX_PLUGIN_LOG_WARNING 1
+#define RENX_PLUGIN_LOG_ERROR 2
+
+typedef struct RenXPluginContext RenXPluginContext;
+
+struct RenXPluginContext {
+    /* RENX_PLUGIN_ABI_VERSION. */
+    uint32_t abi_version;
+    /* sizeof(RenXPluginContext). Fields may be appended without bumping the ABI version. */
+    uint32_t size;
+    /* File name of the plugin. */
+    const char *plugin_name;
+    /* SHA-256 of the UDK's .text section, 32 bytes. */
+    const uint8_t *udk_hash;
+    /* Base address and size of UDK.exe in memory. */
+    const uint8_t *udk_base;
+    size_t udk_size;
+
+    /* Write a UTF-8 message to the UDK log. */
+    void (*log)(const RenXPluginContext *ctx, uint32_t level, const char *message);
+
+    /* Detour the function at `target` to `replacement`, and enable it. If `original` is not
+     * NULL, it receives the address to call the original function through. Hook names are
+     * scoped to the plugin. */
+    int32_t (*hook_detour)(const RenXPluginContext *ctx, const char *name, uintptr_t target,
+                           uintptr_t replacement, uintptr_t *original);
+    /* Overwrite the function pointer at `slot` with `replacement`, and enable it. If `original`
+     * is not NULL, it receives the pointer that was replaced. */
+    int32_t (*hook_pointer)(const RenXPluginContext *ctx, const char *name, uintptr_t slot,
+                            uintptr_t replacement, uintptr_t *original);
+    /* Enable or disable one of the plugin's hooks, by name. */
+    int32_t (*hook_enable)(const RenXPluginContext *ctx, const char *name);
+    int32_t (*hook_disable)(const RenXPluginContext *ctx, const char *name);
+    /* Overwrite slot `index` of the vtable at `vtable` with `replacement`, and enable it. If
+     * `original` is not NULL, it receives the method that was replaced. Check `size` before
+     * calling this; it was appended after the first release of the ABI. */
+    int32_t (*hook_vtable)(const RenXPluginContext *ctx, const char *name, uintptr_t vtable,
+                           uintptr_t index, uintptr_t replacement, uintptr_t *original);
+};
+
+/* Exported by every plugin. Must return RENX_PLUGIN_ABI_VERSION. */
+__declspec(dllexport) uint32_t RenXPlugin_AbiVersion(void);
+/* Exported by every plugin. The context stays valid while the plugin is loaded. Return 0 on
+ * success; on failure the plugin's hooks are removed and it is unloaded. */
+__declspec(dllexport) int32_t RenXPlugin_Init(const RenXPluginContext *ctx);
+/* Optional. Called before the extensions unload from a running game. */
+__declspec(dllexport) void RenXPlugin_Shutdown(void);
+
+#endif /* RENX_PLUGIN_H */
@@ -0,0 +1,361 @@
+//! This module keeps statistics of XAudio's performance over an audio session, from the engine's
+//! creation to its release, so reports of stuttering audio come with numbers.
+//!
+//! XAudio's performance data is read once a frame. Its cycle counts cover the time since the
+//! previous read, so every reader in a frame shares that one read through [`sample`]. Glitches
+//! are warned about as they happen, and a summary is logged when the session ends.
+//! `renx stats audio` logs the statistics so far.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use windows::Win32::Media::Audio::XAudio2::XAUDIO2_PERFORMANCE_DATA;
+
+#[cfg(windows)]
+use crate::console_commands::{self, no_args};
+use crate::udk_log::{log, LogType};
+#[cfg(windows)]
+use crate::udk_log::log_ratelimited;
+#[cfg(windows)]
+use crate::{udk_tick, xaudio27};
+
+/// The period the audio load is averaged over for its peak.
+const LOAD_PERIOD: Duration = Duration::from_secs(1);
+
+/// XAudio's performance data at one read.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PerfSample {
+    /// CPU cycles spent processing audio since the previous read.
+    pub audio_cycles: u64,
+    /// CPU cycles elapsed since the previous read.
+    pub total_cycles: u64,
+    pub active_source_voices: u32,
+    pub total_source_voices: u32,
+    pub active_submix_voices: u32,
+    /// Glitches since the engine started.
+    pub glitches: u32,
+    pub latency_samples: u32,
+    pub memory_bytes: u32,
+}
+
+impl PerfSample {
+    fn new(perf: &XAUDIO2_PERFORMANCE_DATA) -> Self {
+        // The struct is packed, so copy the fields out rather than borrow them.
+        Self {
+            audio_cycles: { perf.AudioCyclesSinceLastQuery },
+            total_cycles: { perf.TotalCyclesSinceLastQuery },
+            active_source_voices: { perf.ActiveSourceVoiceCount },
+            total_source_voices: { perf.TotalSourceVoiceCount },
+            active_submix_voices: { perf.ActiveSubmixVoiceCount },
+            glitches: { perf.GlitchesSinceEngineStarted },
+            latency_samples: { perf.CurrentLatencyInSamples },
+            memory_bytes: { perf.MemoryUsageInBytes },
+        }
+    }
+
+    /// The share of the time since the previous read spent processing audio, from 0 to 1.
+    pub fn load(&self) -> f64 {
+        match self.total_cycles {
+            0 => 0.0,
+            total => self.audio_cycles as f64 / total as f64,
+        }
+    }
+}
+
+/// Statistics for an audio session.
+pub struct AudioStats {
+    started: Instant,
+    samples: u64,
+    glitches: u64,
+    last_glitches: u32,
+    audio_cycles: u64,
+    total_cycles: u64,
+    /// Cycles in the current [`LOAD_PERIOD`], and when it started.
+    period: (Instant, u64, u64),
+    peak_load: f64,
+    peak_active_voices: u32,
+    peak_total_voices: u32,
+    peak_latency_samples: u32,
+    peak_memory_bytes: u32,
+}
+
+impl AudioStats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            samples: 0,
+            glitches: 0,
+            last_glitches: 0,
+            audio_cycles: 0,
+            total_cycles: 0,
+            period: (now, 0, 0),
+            peak_load: 0.0,
+            peak_active_voices: 0,
+            peak_total_voices: 0,
+            peak_latency_samples: 0,
+            peak_memory_bytes: 0,
+        }
+    }
+
+    /// Record a read taken at `now`, returning the number of new glitches.
+    pub fn record(&mut self, now: Instant, sample: &PerfSample) -> u32 {
+        self.samples += 1;
+
+        // A counter that went backwards was reset, and everything it counts is new.
+        let glitches = sample.glitches.checked_sub(self.last_glitches).unwrap_or(sample.glitches);
+        self.last_glitches = sample.glitches;
+        self.glitches += u64::from(glitches);
+
+        self.audio_cycles += sample.audio_cycles;
+        self.total_cycles += sample.total_cycles;
+
+        let (start, audio, total) = &mut self.period;
+        *audio += sample.audio_cycles;
+        *total += sample.total_cycles;
+        if now.duration_since(*start) >= LOAD_PERIOD {
+            if *total > 0 {
+                self.peak_load = self.peak_load.max(*audio as f64 / *total as f64);
+            }
+            self.period = (now, 0, 0);
+        }
+
+        self.peak_active_voices = self.peak_active_voices.max(sample.active_source_voices);
+        self.peak_total_voices = self.peak_total_voices.max(sample.total_source_voices);
+        self.peak_latency_samples = self.peak_latency_samples.max(sample.latency_samples);
+        self.peak_memory_bytes = self.peak_memory_bytes.max(sample.memory_bytes);
+
+        glitches
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn glitches(&self) -> u64 {
+        self.glitches
+    }
+
+    /// Describe the session up to `now`, one line each.
+    pub fn summary(&self, now: Instant) -> Vec<String> {
+        if self.samples == 0 {
+            return vec!["No audio data".to_string()];
+        }
+
+        let load = |audio: u64, total: u64| match total {
+            0 => 0.0,
+            total => audio as f64 / total as f64,
+        };
+        let mean_load = load(self.audio_cycles, self.total_cycles);
+        // Count the period in progress too, so a session ending mid-spike still shows it.
+        let (_, audio, total) = self.period;
+        let peak_load = self.peak_load.max(load(audio, total));
+
+        vec![
+            format!(
+                "Audio session of {} s: {} glitches",
+                now.duration_since(self.started).as_secs(),
+                self.glitches
+            ),
+            format!(
+                "Audio load: mean {:.1}%, peak {:.1}% over {} s",
+                mean_load * 100.0,
+                peak_load * 100.0,
+                LOAD_PERIOD.as_secs()
+            ),
+            format!(
+                "Audio peaks: {} active source voices ({} total), {} samples latency, {} KiB",
+                self.peak_active_voices,
+                self.peak_total_voices,
+                self.peak_latency_samples,
+                self.peak_memory_bytes / 1024
+            ),
+        ]
+    }
+}
+
+struct State {
+    stats: AudioStats,
+    /// This frame's read, if there was one.
+    last: Option<(u64, Option<PerfSample>)>,
+}
+
+impl State {
+    fn new(now: Instant) -> Self {
+        Self {
+            stats: AudioStats::new(now),
+            last: None,
+        }
+    }
+
+    /// The read taken during `frame`, if there was one. `Some(None)` if there was no engine.
+    fn read_in(&self, frame: u64) -> Option<Option<PerfSample>> {
+        self.last.filter(|&(last_frame, _)| last_frame == frame).map(|(_, sample)| sample)
+    }
+
+    /// Record the read taken during `frame` at `now`, returning the number of new glitches
+    /// and the session's total.
+    fn record(&mut self, frame: u64, now: Instant, sample: Option<PerfSample>) -> (u32, u64) {
+        self.last = Some((frame, sample));
+        let new_glitches = sample.map_or(0, |s| self.stats.record(now, &s));
+        (new_glitches, self.stats.glitches())
+    }
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn with_state<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    f(state.get_or_insert_with(|| State::new(Instant::now())))
+}
+
+/// Read XAudio's performance data for this frame, or return this frame's read if there
+/// already was one. `None` if there's no engine.
+#[cfg(windows)]
+pub fn sample() -> Option<PerfSample> {
+    let frame = udk_tick::frame();
+    if let Some(sample) = with_state(|state| state.read_in(frame)) {
+        return sample;
+    }
+
+    let sample = xaudio27::performance_data().map(|perf| PerfSample::new(&perf));
+    let (new_glitches, glitches) = with_state(|state| state.record(frame, Instant::now(), sample));
+
+    if let (Some(sample), 1..) = (sample, new_glitches) {
+        log_ratelimited!(
+            LogType::Warning,
+            "XAudio glitched {} time(s), {} this session ({} active source voices, {:.1}% load, {} samples latency)",
+            new_glitches,
+            glitches,
+            sample.active_source_voices,
+            sample.load() * 100.0,
+            sample.latency_samples
+        );
+    }
+
+    sample
+}
+
+/// Start a new session. Called when the UDK creates an audio engine.
+pub fn start_session() {
+    with_state(|state| *state = State::new(Instant::now()));
+}
+
+/// Describe the session so far, one line each.
+pub fn dump() -> Vec<String> {
+    with_state(|state| state.stats.summary(Instant::now()))
+}
+
+/// Log a summary of the session, if it was sampled at all. Called when the UDK releases its
+/// audio engine.
+pub fn log_summary() {
+    let lines = with_state(|state| (state.stats.samples() > 0).then(|| state.stats.summary(Instant::now())));
+    for line in lines.into_iter().flatten() {
+        log(LogType::Log, &line);
+    }
+}
+
+/// Start sampling every frame.
+#[cfg(windows)]
+pub fn init() -> anyhow::Result<()> {
+    udk_tick::subscribe(|_| {
+        sample();
+    });
+
+    console_commands::register("stats audio", "", "show XAudio performance statistics", |args| {
+        no_args(args)?;
+        Ok(dump())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(100);
+
+    fn sample(audio_cycles: u64, glitches: u32) -> PerfSample {
+        PerfSample {
+            audio_cycles,
+            total_cycles: 1000,
+            active_source_voices: 4,
+            total_source_voices: 8,
+            glitches,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_new_glitches() {
+        let start = Instant::now();
+        let mut stats = AudioStats::new(start);
+
+        assert_eq!(stats.record(start, &sample(100, 0)), 0);
+        assert_eq!(stats.record(start + FRAME, &sample(100, 3)), 3);
+        assert_eq!(stats.record(start + FRAME * 2, &sample(100, 3)), 0);
+        assert_eq!(stats.record(start + FRAME * 3, &sample(100, 4)), 1);
+        assert_eq!(stats.glitches(), 4);
+    }
+
+    #[test]
+    fn counts_glitches_across_counter_resets() {
+        let start = Instant::now();
+        let mut stats = AudioStats::new(start);
+
+        stats.record(start, &sample(100, 10));
+        // The engine restarted and glitched twice since.
+        assert_eq!(stats.record(start + FRAME, &sample(100, 2)), 2);
+        assert_eq!(stats.record(start + FRAME * 2, &sample(100, 0)), 0);
+        assert_eq!(stats.record(start + FRAME * 3, &sample(100, 1)), 1);
+        assert_eq!(stats.glitches(), 13);
+        assert_eq!(stats.samples(), 4);
+    }
+
+    #[test]
+    fn peak_load_is_averaged_over_a_period() {
+        let start = Instant::now();
+        let mut stats = AudioStats::new(start);
+
+        // One busy frame in a quiet second doesn't make a peak on its own.
+        stats.record(start + FRAME, &sample(900, 0));
+        for i in 2..=10 {
+            stats.record(start + FRAME * i, &sample(100, 0));
+        }
+        assert!((stats.peak_load - 0.18).abs() < 1e-9, "{}", stats.peak_load);
+
+        // A busy second does.
+        for i in 11..=20 {
+            stats.record(start + FRAME * i, &sample(500, 0));
+        }
+        assert!((stats.peak_load - 0.5).abs() < 1e-9, "{}", stats.peak_load);
+        assert_eq!((stats.peak_active_voices, stats.peak_total_voices), (4, 8));
+    }
+
+    #[test]
+    fn summary_includes_the_period_in_progress() {
+        let start = Instant::now();
+        let mut stats = AudioStats::new(start);
+        assert_eq!(stats.summary(start), ["No audio data"]);
+
+        stats.record(start + FRAME, &sample(700, 1));
+        let summary = stats.summary(start + Duration::from_secs(2));
+        assert_eq!(summary[0], "Audio session of 2 s: 1 glitches");
+        assert_eq!(summary[1], "Audio load: mean 70.0%, peak 70.0% over 1 s");
+    }
+
+    #[test]
+    fn shares_one_read_per_frame() {
+        let start = Instant::now();
+        let mut state = State::new(start);
+        assert!(state.read_in(1).is_none());
+
+        assert_eq!(state.record(1, start, Some(sample(100, 2))), (2, 2));
+        assert_eq!(state.read_in(1).flatten().map(|s| s.glitches), Some(2));
+        assert!(state.read_in(2).is_none());
+
+        // No engine this frame: the read is remembered as such, and the counts stand.
+        assert_eq!(state.record(2, start + FRAME, None), (0, 2));
+        assert!(matches!(state.read_in(2), Some(None)));
+
+        assert_eq!(state.record(3, start + FRAME * 2, Some(sample(100, 5))), (3, 5));
+        assert_eq!(state.stats.samples(), 2);
+    }
+}
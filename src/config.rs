@@ -0,0 +1,759 @@
+//! This module contains the configuration for the extensions.
+//!
+//! Settings are read, in increasing order of precedence, from:
+//!  * the `[TotemArts.Extensions]` section of the UDK's own `Game` ini, as `Section.Key=Value`,
+//!  * `RenXExtensions.ini` next to UDK.exe,
+//!  * UDK command line arguments of the form `-RenX.Section.Key=Value`.
+//!
+//! Section and key names are matched case-insensitively. Anything that doesn't parse is
+//! reported and left at its default.
+//!
+//! The file is watched, and [`reload`] re-applies changes to subscribers while the game runs.
+//! Settings that are only read at startup are reported as requiring a restart.
+//!
+//! ```ini
+//! [XAudio]
+//! Enabled=true
+//! TraceLevel=Warnings
+//! MasterVolume=1.0
+//! LimiterRelease=6
+//! LimiterLoudness=1000
+//! VoiceBudget=64
+//! VoiceStealing=Quietest
+//!
+//! [DirectInput]
+//! ChainDll=dinput8_chain.dll
+//!
+//! [Input]
+//! Remap=CapsLock=LeftControl, Insert=None
+//! DenyDevices=*RGB*, 1234:bead
+//!
+//! [Plugins]
+//! Enabled=true
+//!
+//! [Crash]
+//! Enabled=true
+//! ```
+//!
+//! Parsing is kept free of any UDK or Windows dependency.
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::bail;
+
+use crate::device_filter::DeviceFilter;
+use crate::input_remap::Rules;
+use crate::udk_ini::UdkIni;
+use crate::udk_log::{log, LogType};
+use crate::voice_budget::Stealing;
+
+/// Name of the configuration file, next to UDK.exe.
+pub const CONFIG_FILE_NAME: &str = "RenXExtensions.ini";
+
+/// Prefix of command line overrides.
+const COMMAND_LINE_PREFIX: &str = "-RenX.";
+
+/// Section of the UDK ini files our settings are read from.
+pub const UDK_INI_SECTION: &str = "TotemArts.Extensions";
+
+/// How much XAudio's own debug output is traced.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum XAudioTraceLevel {
+    None,
+    Errors,
+    Warnings,
+    Info,
+    Detail,
+}
+
+impl XAudioTraceLevel {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "none" => Self::None,
+            "errors" => Self::Errors,
+            "warnings" => Self::Warnings,
+            "info" => Self::Info,
+            "detail" => Self::Detail,
+            _ => bail!("expected one of None, Errors, Warnings, Info, Detail"),
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct XAudioConfig {
+    /// Install the XAudio 2.7 -> 2.9 hooks.
+    pub enabled: bool,
+    pub trace_level: XAudioTraceLevel,
+    /// Scale applied on top of the volume the UDK sets on its mastering voice.
+    pub master_volume: f32,
+    /// Overrides for the UDK's mastering limiter. `None` keeps the UDK's own setting.
+    pub limiter_release: Option<u32>,
+    pub limiter_loudness: Option<u32>,
+    /// Most source voices playing at once. `None` for no limit.
+    pub voice_budget: Option<u32>,
+    /// Which voices to virtualize beyond the budget.
+    pub voice_stealing: Stealing,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DirectInputConfig {
+    /// DLL next to UDK.exe that `DirectInput8Create` is forwarded to, if present. Empty disables chaining.
+    pub chain_dll: String,
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct InputConfig {
+    pub remap: Rules,
+    pub devices: DeviceFilter,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PluginsConfig {
+    pub enabled: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CrashConfig {
+    /// Install the crash handler.
+    pub enabled: bool,
+}
+
+/// The complete configuration.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Config {
+    pub xaudio: XAudioConfig,
+    pub direct_input: DirectInputConfig,
+    pub input: InputConfig,
+    pub plugins: PluginsConfig,
+    pub crash: CrashConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            xaudio: XAudioConfig {
+                enabled: true,
+                trace_level: XAudioTraceLevel::Warnings,
+                master_volume: 1.0,
+                limiter_release: None,
+                limiter_loudness: None,
+                voice_budget: None,
+                voice_stealing: Stealing::Quietest,
+            },
+            direct_input: DirectInputConfig {
+                chain_dll: "dinput8_chain.dll".to_string(),
+            },
+            input: InputConfig::default(),
+            plugins: PluginsConfig { enabled: true },
+            crash: CrashConfig { enabled: true },
+        }
+    }
+}
+
+/// Why a setting could not be applied.
+#[derive(Debug)]
+pub enum SetError {
+    UnknownSection,
+    UnknownKey,
+    Invalid(anyhow::Error),
+}
+
+fn parse_bool(s: &str) -> anyhow::Result<bool> {
+    Ok(match s.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => true,
+        "false" | "no" | "off" | "0" => false,
+        _ => bail!("expected true or false"),
+    })
+}
+
+fn parse_f32_in(s: &str, range: std::ops::RangeInclusive<f32>) -> anyhow::Result<f32> {
+    match s.parse::<f32>() {
+        Ok(v) if range.contains(&v) => Ok(v),
+        _ => bail!("expected a number from {} to {}", range.start(), range.end()),
+    }
+}
+
+/// Parse an optional integer setting, where an empty value means "not set".
+fn parse_optional_u32_in(s: &str, range: std::ops::RangeInclusive<u32>) -> anyhow::Result<Option<u32>> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+
+    match s.parse::<u32>() {
+        Ok(v) if range.contains(&v) => Ok(Some(v)),
+        _ => bail!("expected a whole number from {} to {}, or nothing", range.start(), range.end()),
+    }
+}
+
+impl Config {
+    /// Whether a setting takes a list, so that several values (from UDK ini array operators)
+    /// are joined rather than the last one winning.
+    pub fn is_list(section: &str, key: &str) -> bool {
+        section.eq_ignore_ascii_case("Input")
+            && ["Remap", "AllowDevices", "DenyDevices"]
+                .iter()
+                .any(|k| k.eq_ignore_ascii_case(key))
+    }
+
+    /// Apply a single setting.
+    pub fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), SetError> {
+        let section = section.to_ascii_lowercase();
+        let key = key.to_ascii_lowercase();
+
+        let result = match (section.as_str(), key.as_str()) {
+            ("xaudio", "enabled") => parse_bool(value).map(|v| self.xaudio.enabled = v),
+            ("xaudio", "tracelevel") => XAudioTraceLevel::parse(value).map(|v| self.xaudio.trace_level = v),
+            ("xaudio", "mastervolume") => parse_f32_in(value, 0.0..=4.0).map(|v| self.xaudio.master_volume = v),
+            // Ranges are those of `FXMASTERINGLIMITER_PARAMETERS`.
+            ("xaudio", "limiterrelease") => parse_optional_u32_in(value, 1..=20).map(|v| self.xaudio.limiter_release = v),
+            ("xaudio", "limiterloudness") => {
+                parse_optional_u32_in(value, 1..=1800).map(|v| self.xaudio.limiter_loudness = v)
+            }
+            ("xaudio", "voicebudget") => parse_optional_u32_in(value, 1..=1024).map(|v| self.xaudio.voice_budget = v),
+            ("xaudio", "voicestealing") => Stealing::parse(value).map(|v| self.xaudio.voice_stealing = v),
+            ("xaudio", _) => return Err(SetError::UnknownKey),
+
+            ("directinput", "chaindll") => {
+                self.direct_input.chain_dll = value.to_string();
+                Ok(())
+            }
+            ("directinput", _) => return Err(SetError::UnknownKey),
+
+            ("input", "remap") => Rules::parse(value).map(|v| self.input.remap = v),
+            ("input", "allowdevices") => DeviceFilter::parse_list(value).map(|v| self.input.devices.allow = v),
+            ("input", "denydevices") => DeviceFilter::parse_list(value).map(|v| self.input.devices.deny = v),
+            ("input", _) => return Err(SetError::UnknownKey),
+
+            ("plugins", "enabled") => parse_bool(value).map(|v| self.plugins.enabled = v),
+            ("plugins", _) => return Err(SetError::UnknownKey),
+
+            ("crash", "enabled") => parse_bool(value).map(|v| self.crash.enabled = v),
+            ("crash", _) => return Err(SetError::UnknownKey),
+
+            _ => return Err(SetError::UnknownSection),
+        };
+
+        result.map_err(SetError::Invalid)
+    }
+
+    /// Apply settings in order, returning a warning for each one that couldn't be applied.
+    pub fn apply(&mut self, entries: &[Entry]) -> Vec<String> {
+        entries
+            .iter()
+            .filter_map(|e| {
+                let problem = match self.set(&e.section, &e.key, &e.value) {
+                    Ok(()) => return None,
+                    Err(SetError::UnknownSection) => format!("unknown section [{}]", e.section),
+                    Err(SetError::UnknownKey) => format!("unknown key {} in [{}]", e.key, e.section),
+                    Err(SetError::Invalid(err)) => {
+                        format!("invalid value for {}.{} ({:#}); using the default", e.section, e.key, err)
+                    }
+                };
+
+                Some(format!("{}: {problem}", e.origin))
+            })
+            .collect()
+    }
+}
+
+/// Where a setting came from, for diagnostics.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Origin {
+    File { name: String, line: usize },
+    /// The merged `[TotemArts.Extensions]` section of a UDK ini hierarchy.
+    UdkIni { name: String },
+    CommandLine,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File { name, line } => write!(f, "{name}:{line}"),
+            Origin::UdkIni { name } => write!(f, "{name} [{UDK_INI_SECTION}]"),
+            Origin::CommandLine => f.write_str("command line"),
+        }
+    }
+}
+
+/// A single `Section.Key=Value` setting.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Entry {
+    pub section: String,
+    pub key: String,
+    pub value: String,
+    pub origin: Origin,
+}
+
+/// Strip one pair of surrounding double quotes.
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(s)
+}
+
+/// Parse an INI file into settings. Returns the settings, and a warning for each malformed line.
+///
+/// Lines starting with `;` or `#` are comments. Everything after the first `=` is the value,
+/// so values may contain `=` themselves.
+pub fn parse_ini(text: &str, name: &str) -> (Vec<Entry>, Vec<String>) {
+    let mut entries = Vec::new();
+    let mut warnings = Vec::new();
+    let mut section = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let origin = Origin::File {
+            name: name.to_string(),
+            line: i + 1,
+        };
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            match header.strip_suffix(']') {
+                Some(name) => section = Some(name.trim().to_string()),
+                None => {
+                    warnings.push(format!("{origin}: malformed section header `{line}`"));
+                    section = None;
+                }
+            }
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            warnings.push(format!("{origin}: expected `Key=Value`, found `{line}`"));
+            continue;
+        };
+        let Some(section) = &section else {
+            warnings.push(format!("{origin}: `{}` is outside of any section", key.trim()));
+            continue;
+        };
+
+        entries.push(Entry {
+            section: section.clone(),
+            key: key.trim().to_string(),
+            value: unquote(value.trim()).to_string(),
+            origin,
+        });
+    }
+
+    (entries, warnings)
+}
+
+/// Pick our settings out of a merged UDK ini hierarchy. Keys are of the form `Section.Key`.
+pub fn udk_ini_entries(ini: &UdkIni, name: &str) -> (Vec<Entry>, Vec<String>) {
+    let mut entries = Vec::new();
+    let mut warnings = Vec::new();
+    let origin = Origin::UdkIni { name: name.to_string() };
+
+    for (name, values) in ini.section(UDK_INI_SECTION) {
+        let Some((section, key)) = name.split_once('.').filter(|(s, k)| !s.is_empty() && !k.is_empty()) else {
+            warnings.push(format!("{origin}: expected `Section.Key=Value`, found `{name}`"));
+            continue;
+        };
+
+        let value = match Config::is_list(section, key) {
+            true => values.join(", "),
+            false => values.last().cloned().unwrap_or_default(),
+        };
+
+        entries.push(Entry {
+            section: section.to_string(),
+            key: key.to_string(),
+            value,
+            origin: origin.clone(),
+        });
+    }
+
+    (entries, warnings)
+}
+
+/// Pick the `-RenX.Section.Key=Value` overrides out of a command line. Other arguments are ignored.
+pub fn parse_command_line<I: IntoIterator<Item = S>, S: AsRef<str>>(args: I) -> (Vec<Entry>, Vec<String>) {
+    let mut entries = Vec::new();
+    let mut warnings = Vec::new();
+
+    for arg in args {
+        let arg = arg.as_ref();
+        let Some(setting) = arg
+            .get(..COMMAND_LINE_PREFIX.len())
+            .filter(|p| p.eq_ignore_ascii_case(COMMAND_LINE_PREFIX))
+            .map(|_| &arg[COMMAND_LINE_PREFIX.len()..])
+        else {
+            continue;
+        };
+
+        let parsed = setting
+            .split_once('=')
+            .and_then(|(name, value)| name.split_once('.').map(|(section, key)| (section, key, value)));
+
+        match parsed {
+            Some((section, key, value)) if !section.is_empty() && !key.is_empty() => entries.push(Entry {
+                section: section.to_string(),
+                key: key.to_string(),
+                value: unquote(value.trim()).to_string(),
+                origin: Origin::CommandLine,
+            }),
+            _ => warnings.push(format!("command line: expected `-RenX.Section.Key=Value`, found `{arg}`")),
+        }
+    }
+
+    (entries, warnings)
+}
+
+/// Build the configuration from a UDK ini hierarchy, an INI file's contents and a command line,
+/// each given with its name. Returns the configuration and every warning encountered along the way.
+pub fn build<I: IntoIterator<Item = S>, S: AsRef<str>>(
+    udk_ini: Option<(&UdkIni, &str)>,
+    ini: Option<(&str, &str)>,
+    args: I,
+) -> (Config, Vec<String>) {
+    let mut config = Config::default();
+    let mut warnings = Vec::new();
+
+    if let Some((udk_ini, name)) = udk_ini {
+        let (entries, mut malformed) = udk_ini_entries(udk_ini, name);
+        warnings.append(&mut malformed);
+        warnings.append(&mut config.apply(&entries));
+    }
+
+    if let Some((text, name)) = ini {
+        let (entries, mut malformed) = parse_ini(text, name);
+        warnings.append(&mut malformed);
+        warnings.append(&mut config.apply(&entries));
+    }
+
+    let (entries, mut malformed) = parse_command_line(args);
+    warnings.append(&mut malformed);
+    warnings.append(&mut config.apply(&entries));
+
+    (config, warnings)
+}
+
+/// Load the UDK's `Game` ini hierarchy: the player's `UDKGame.ini` if it has been generated,
+/// otherwise `DefaultGame.ini`. UDK.exe lives in `Binaries/<platform>`, next to `UDKGame`.
+fn load_udk_ini(exe: &Path) -> Option<(UdkIni, String, Vec<String>)> {
+    let game_dir = exe.parent()?.parent()?.parent()?.join("UDKGame");
+    let config_dir = game_dir.join("Config");
+
+    let path = ["UDKGame.ini", "DefaultGame.ini"]
+        .iter()
+        .map(|name| config_dir.join(name))
+        .find(|path| path.exists())?;
+    let name = path.file_name()?.to_string_lossy().into_owned();
+
+    match UdkIni::load(&path, &game_dir, |p| std::fs::read_to_string(p)) {
+        Ok((ini, warnings)) => Some((ini, name, warnings)),
+        Err(e) => {
+            log(LogType::Warning, &format!("Config: failed to load {name}: {:#}", e));
+            None
+        }
+    }
+}
+
+/// Read the configuration from every source, logging anything that doesn't parse.
+fn read() -> Config {
+    let exe = std::env::current_exe().ok();
+    let path = exe.as_ref().map(|exe| exe.with_file_name(CONFIG_FILE_NAME));
+    let udk_ini = exe.as_deref().and_then(load_udk_ini);
+
+    let text = path.as_ref().and_then(|p| match std::fs::read_to_string(p) {
+        Ok(text) => Some(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            log(LogType::Warning, &format!("Failed to read {}: {}", p.display(), e));
+            None
+        }
+    });
+
+    let (config, mut warnings) = build(
+        udk_ini.as_ref().map(|(ini, name, _)| (ini, name.as_str())),
+        text.as_deref().map(|t| (t, CONFIG_FILE_NAME)),
+        std::env::args().skip(1),
+    );
+    if let Some((_, _, malformed)) = udk_ini {
+        warnings.splice(0..0, malformed);
+    }
+
+    for warning in warnings {
+        log(LogType::Warning, &format!("Config: {warning}"));
+    }
+
+    match text {
+        Some(_) => log(LogType::Init, &format!("Loaded configuration from {CONFIG_FILE_NAME}")),
+        None => log(LogType::Init, &format!("No {CONFIG_FILE_NAME} found, using the default configuration")),
+    }
+
+    config
+}
+
+/// The current configuration. `None` until [`load`] is called.
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+/// Functions to call with the new configuration after a reload.
+static SUBSCRIBERS: Mutex<Vec<fn(&Config)>> = Mutex::new(Vec::new());
+
+/// Load the configuration. Must be called once the UDK has been verified, since problems are
+/// reported through the UDK log.
+pub fn load() {
+    *CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(read()));
+}
+
+/// Return the current configuration, or the defaults if it hasn't been loaded.
+pub fn get() -> Arc<Config> {
+    CONFIG
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+/// Call `f` with the new configuration whenever it is reloaded.
+pub fn subscribe(f: fn(&Config)) {
+    SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).push(f);
+}
+
+/// Names of the settings that differ between `old` and `new` but are only read at startup.
+pub fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    [
+        ("XAudio.Enabled", old.xaudio.enabled != new.xaudio.enabled),
+        ("XAudio.TraceLevel", old.xaudio.trace_level != new.xaudio.trace_level),
+        ("DirectInput.ChainDll", old.direct_input.chain_dll != new.direct_input.chain_dll),
+        ("Plugins.Enabled", old.plugins.enabled != new.plugins.enabled),
+        ("Crash.Enabled", old.crash.enabled != new.crash.enabled),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect()
+}
+
+/// Re-read the configuration and apply it to every subscriber. Settings that only take effect
+/// at startup are reported as requiring a restart.
+pub fn reload() {
+    let new = Arc::new(read());
+    let old = CONFIG.write().unwrap_or_else(|e| e.into_inner()).replace(new.clone());
+
+    if let Some(old) = old {
+        for name in restart_required(&old, &new) {
+            log(LogType::Warning, &format!("Config: {name} changed, but requires a restart to take effect"));
+        }
+    }
+
+    // Copy the list, so a subscriber may subscribe (or reload) without deadlocking.
+    let subscribers = SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    for subscriber in subscribers {
+        subscriber(&new);
+    }
+}
+
+/// How often the configuration file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Whether the watcher should keep running, and whether it is.
+struct WatchState {
+    stop: bool,
+    running: bool,
+}
+
+static WATCH: Mutex<WatchState> = Mutex::new(WatchState { stop: false, running: false });
+static WATCH_SIGNAL: Condvar = Condvar::new();
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Start watching `RenXExtensions.ini`, reloading the configuration whenever it changes.
+pub fn watch() {
+    let Ok(path) = std::env::current_exe().map(|exe| exe.with_file_name(CONFIG_FILE_NAME)) else {
+        return;
+    };
+
+    {
+        let mut state = WATCH.lock().unwrap_or_else(|e| e.into_inner());
+        if state.running {
+            return;
+        }
+        *state = WatchState { stop: false, running: true };
+    }
+
+    let spawned = std::thread::Builder::new()
+        .name("RenX config watcher".to_string())
+        .spawn(move || {
+            let mut last = modified(&path);
+            let mut state = WATCH.lock().unwrap_or_else(|e| e.into_inner());
+
+            while !state.stop {
+                state = WATCH_SIGNAL
+                    .wait_timeout(state, WATCH_INTERVAL)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+                if state.stop {
+                    break;
+                }
+
+                let current = modified(&path);
+                if current != last {
+                    last = current;
+
+                    // Reload without holding the lock, so `stop_watching` isn't held up.
+                    drop(state);
+                    log(LogType::Init, &format!("{CONFIG_FILE_NAME} changed, reloading"));
+                    reload();
+                    state = WATCH.lock().unwrap_or_else(|e| e.into_inner());
+                }
+            }
+
+            state.running = false;
+            WATCH_SIGNAL.notify_all();
+        });
+
+    if let Err(e) = spawned {
+        WATCH.lock().unwrap_or_else(|e| e.into_inner()).running = false;
+        log(LogType::Warning, &format!("Config: failed to start watching {CONFIG_FILE_NAME}: {e}"));
+    }
+}
+
+/// Stop the watcher, and wait until it's no longer doing anything.
+pub fn stop_watching() {
+    let mut state = WATCH.lock().unwrap_or_else(|e| e.into_inner());
+    state.stop = true;
+    WATCH_SIGNAL.notify_all();
+
+    while state.running {
+        state = WATCH_SIGNAL.wait(state).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = r#"
+; Comments and blank lines are skipped.
+# So are these.
+
+[XAudio]
+Enabled = false
+TraceLevel=errors
+MasterVolume=0.5
+LimiterRelease=
+VoiceBudget=64
+VoiceStealing=Oldest
+
+[DirectInput]
+ChainDll="my chain.dll"
+
+[Input]
+Remap=CapsLock=LeftControl, Insert=None
+"#;
+
+    #[test]
+    fn parses_ini_fixture() {
+        let (entries, warnings) = parse_ini(FIXTURE, "RenXExtensions.ini");
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(entries.len(), 8);
+
+        let remap = entries.last().unwrap();
+        assert_eq!((remap.section.as_str(), remap.key.as_str()), ("Input", "Remap"));
+        assert_eq!(remap.value, "CapsLock=LeftControl, Insert=None");
+        assert_eq!(remap.origin.to_string(), "RenXExtensions.ini:17");
+
+        let chain = entries.iter().find(|e| e.key == "ChainDll").unwrap();
+        assert_eq!(chain.value, "my chain.dll");
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let text = "Orphan=1\n[XAudio\nEnabled=true\n[Plugins]\nEnabled\n";
+        let (entries, warnings) = parse_ini(text, "test.ini");
+        assert!(entries.is_empty());
+        assert_eq!(
+            warnings,
+            [
+                "test.ini:1: `Orphan` is outside of any section",
+                "test.ini:2: malformed section header `[XAudio`",
+                "test.ini:3: `Enabled` is outside of any section",
+                "test.ini:5: expected `Key=Value`, found `Enabled`",
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_settings() {
+        let (config, warnings) = build(None, Some((FIXTURE, "RenXExtensions.ini")), Vec::<String>::new());
+        assert!(warnings.is_empty(), "{warnings:?}");
+
+        assert!(!config.xaudio.enabled);
+        assert_eq!(config.xaudio.trace_level, XAudioTraceLevel::Errors);
+        assert_eq!(config.xaudio.master_volume, 0.5);
+        assert_eq!(config.xaudio.limiter_release, None);
+        assert_eq!(config.xaudio.voice_budget, Some(64));
+        assert_eq!(config.xaudio.voice_stealing, Stealing::Oldest);
+        assert_eq!(config.direct_input.chain_dll, "my chain.dll");
+        assert_eq!(config.input.remap.map_key(0x3A), Some(0x1D));
+        assert!(config.plugins.enabled);
+    }
+
+    #[test]
+    fn keeps_defaults_for_bad_values() {
+        let text = "[XAudio]\nMasterVolume=5\nVoiceBudget=0\nBogus=1\n[Nowhere]\nKey=1\n";
+        let (config, warnings) = build(None, Some((text, "test.ini")), Vec::<String>::new());
+
+        assert_eq!(config, Config::default());
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].starts_with("test.ini:2: invalid value for XAudio.MasterVolume"));
+        assert!(warnings[1].starts_with("test.ini:3: invalid value for XAudio.VoiceBudget"));
+        assert_eq!(warnings[2], "test.ini:4: unknown key Bogus in [XAudio]");
+        assert_eq!(warnings[3], "test.ini:6: unknown section [Nowhere]");
+    }
+
+    #[test]
+    fn parses_command_line_overrides() {
+        let args = ["UDK.exe", "-renx.xaudio.enabled=false", "-RenX.Input.Remap=\"A=B\"", "-RenX.Broken", "-nomovie"];
+        let (entries, warnings) = parse_command_line(args);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].section.as_str(), entries[0].key.as_str()), ("xaudio", "enabled"));
+        assert_eq!(entries[1].value, "A=B");
+        assert!(entries.iter().all(|e| e.origin == Origin::CommandLine));
+        assert_eq!(warnings, ["command line: expected `-RenX.Section.Key=Value`, found `-RenX.Broken`"]);
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let udk = "[TotemArts.Extensions]\nXAudio.MasterVolume=2\nXAudio.TraceLevel=Info\nPlugins.Enabled=false\n";
+        let (udk_ini, _) = UdkIni::load(Path::new("Game.ini"), Path::new(""), |_| Ok(udk.to_string())).unwrap();
+        let file = "[XAudio]\nMasterVolume=3\nTraceLevel=Detail\n";
+        let args = ["-RenX.XAudio.MasterVolume=4"];
+
+        let (config, warnings) = build(Some((&udk_ini, "Game.ini")), Some((file, "test.ini")), args);
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(config.xaudio.master_volume, 4.0);
+        assert_eq!(config.xaudio.trace_level, XAudioTraceLevel::Detail);
+        assert!(!config.plugins.enabled);
+    }
+
+    #[test]
+    fn joins_udk_ini_lists() {
+        let udk = "[TotemArts.Extensions]\n+Input.Remap=A=B\n+Input.Remap=C=D\nXAudio.VoiceBudget=8\nXAudio.VoiceBudget=16\nNoDot=1\n";
+        let (udk_ini, _) = UdkIni::load(Path::new("Game.ini"), Path::new(""), |_| Ok(udk.to_string())).unwrap();
+
+        let (entries, warnings) = udk_ini_entries(&udk_ini, "Game.ini");
+        assert_eq!(warnings, ["Game.ini [TotemArts.Extensions]: expected `Section.Key=Value`, found `NoDot`"]);
+        assert_eq!(entries[0].value, "A=B, C=D");
+        assert_eq!(entries[1].value, "16");
+    }
+
+    #[test]
+    fn reports_settings_requiring_a_restart() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.xaudio.master_volume = 2.0;
+        new.xaudio.enabled = false;
+        new.crash.enabled = false;
+
+        assert_eq!(restart_required(&old, &new), ["XAudio.Enabled", "Crash.Enabled"]);
+    }
+}
@@ -0,0 +1,487 @@
+//! This module routes `renx ...` console commands to Rust handlers.
+//!
+//! Every console command, typed or run by script, ends up in the native `Actor.ConsoleCommand`
+//! of the player's controller, which we bind (see `udk_native.rs`). Lines starting with
+//! [`PREFIX`] are ours: the following words pick a command (`renx audio dump`), and the rest are
+//! its arguments. Output goes to the UDK log, which the console shows. Other lines go on to the
+//! engine. Tokenizing and routing work on plain strings.
+use std::sync::RwLock;
+#[cfg(windows)]
+use std::sync::Mutex;
+
+use anyhow::{bail, Context};
+
+use crate::udk_log::{log, LogType};
+#[cfg(windows)]
+use crate::udk_events::{self, Action, HandlerId};
+#[cfg(windows)]
+use crate::udk_native::{self, FFrame, NativeFunction, NativeParam, NativeReturn};
+#[cfg(windows)]
+use crate::udk_object::{CPF_OUT_PARM, CPF_RETURN_PARM};
+#[cfg(windows)]
+use crate::{config, hooks, udk_call, udk_fname, udk_object, xaudio27};
+
+/// The first word of every command of ours.
+pub const PREFIX: &str = "renx";
+
+/// `native function string ConsoleCommand(string Command, optional bool bWriteToLog = true)`.
+#[cfg(windows)]
+static CONSOLE_COMMAND: NativeFunction = NativeFunction::new(
+    "Function Engine.Actor.ConsoleCommand",
+    console_command,
+    &[<String as NativeParam>::TYPE, <bool as NativeParam>::TYPE],
+    <String as NativeReturn>::TYPE,
+);
+
+/// Functions being traced, with their `ProcessEvent` handlers.
+#[cfg(windows)]
+static TRACES: Mutex<Vec<(String, HandlerId)>> = Mutex::new(Vec::new());
+
+/// Runs a command with its arguments, returning its output lines.
+pub type Handler = fn(&[String]) -> anyhow::Result<Vec<String>>;
+
+pub struct Command {
+    /// The words naming the command, lowercase.
+    pub path: Vec<String>,
+    /// Arguments, for help: `<name> [value]`.
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+impl Command {
+    fn name(&self) -> String {
+        format!("{PREFIX} {}", self.path.join(" "))
+    }
+}
+
+/// A set of commands.
+#[derive(Default)]
+pub struct Commands {
+    commands: Vec<Command>,
+}
+
+impl Commands {
+    pub const fn new() -> Self {
+        Self { commands: Vec::new() }
+    }
+
+    /// Add the command named by the words of `path` (`"audio dump"`).
+    pub fn register(&mut self, path: &str, usage: &'static str, help: &'static str, handler: Handler) -> anyhow::Result<()> {
+        let path = path.split_whitespace().map(str::to_lowercase).collect::<Vec<_>>();
+        if path.is_empty() {
+            bail!("empty command name");
+        }
+        if self.commands.iter().any(|c| c.path == path) {
+            bail!("`{PREFIX} {}` is already registered", path.join(" "));
+        }
+
+        self.commands.push(Command {
+            path,
+            usage,
+            help,
+            handler,
+        });
+        Ok(())
+    }
+
+    /// Find the command named by the leading words of `words`, returning it with the remaining
+    /// words as its arguments. The longest matching name wins; names ignore case.
+    pub fn route<'a>(&self, words: &'a [String]) -> anyhow::Result<(&Command, &'a [String])> {
+        let matches = |c: &&Command| {
+            c.path.len() <= words.len() && c.path.iter().zip(words).all(|(p, w)| p.eq_ignore_ascii_case(w))
+        };
+
+        if let Some(command) = self.commands.iter().filter(matches).max_by_key(|c| c.path.len()) {
+            return Ok((command, &words[command.path.len()..]));
+        }
+
+        // Maybe the words are the start of some commands' names.
+        let typed = words.iter().map(|w| w.to_lowercase()).collect::<Vec<_>>();
+        let mut next = self
+            .commands
+            .iter()
+            .filter(|c| c.path.len() > typed.len() && c.path.starts_with(&typed))
+            .map(|c| c.path[typed.len()].as_str())
+            .collect::<Vec<_>>();
+        next.sort_unstable();
+        next.dedup();
+
+        let typed = std::iter::once(PREFIX).chain(words.iter().map(String::as_str)).collect::<Vec<_>>().join(" ");
+        match next.is_empty() {
+            true => bail!("unknown command `{typed}`; try `{PREFIX} help`"),
+            false => bail!("`{typed}` expects one of: {}", next.join(", ")),
+        }
+    }
+
+    /// One line per command, sorted by name.
+    pub fn help(&self) -> Vec<String> {
+        let mut commands = self.commands.iter().collect::<Vec<_>>();
+        commands.sort_by(|a, b| a.path.cmp(&b.path));
+
+        commands
+            .iter()
+            .map(|c| match c.usage {
+                "" => format!("{} - {}", c.name(), c.help),
+                usage => format!("{} {usage} - {}", c.name(), c.help),
+            })
+            .collect()
+    }
+}
+
+/// Split a command line into words. Double quotes group words, and a backslash escapes the
+/// next character inside them.
+pub fn tokenize(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(words);
+        };
+
+        let mut word = String::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => word.push(chars.next().context("unterminated escape")?),
+                    Some(c) => word.push(c),
+                    None => bail!("unterminated quote"),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+/// If `line` is one of our commands, return the part after [`PREFIX`].
+pub fn strip_prefix(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+
+    line[..end].eq_ignore_ascii_case(PREFIX).then(|| &line[end..])
+}
+
+/// Fail if a command that takes no arguments was given some.
+pub fn no_args(args: &[String]) -> anyhow::Result<()> {
+    match args {
+        [] => Ok(()),
+        _ => bail!("unexpected arguments: {}", args.join(" ")),
+    }
+}
+
+static COMMANDS: RwLock<Commands> = RwLock::new(Commands::new());
+
+/// Add a command. See [`Commands::register`].
+pub fn register(path: &str, usage: &'static str, help: &'static str, handler: Handler) -> anyhow::Result<()> {
+    COMMANDS.write().unwrap_or_else(|e| e.into_inner()).register(path, usage, help, handler)
+}
+
+/// Run a command line (without the prefix), logging its output or error.
+pub fn execute(line: &str) {
+    let result = tokenize(line).and_then(|words| {
+        let commands = COMMANDS.read().unwrap_or_else(|e| e.into_inner());
+        let (command, args) = commands.route(&words)?;
+        let handler = command.handler;
+        let name = command.name();
+        drop(commands);
+
+        handler(args).with_context(|| name)
+    });
+
+    match result {
+        Ok(lines) => lines.iter().for_each(|l| log(LogType::Log, l)),
+        Err(error) => log(LogType::Warning, &format!("{error:#}")),
+    }
+}
+
+/// Register the built-in commands and start listening to the console.
+#[cfg(windows)]
+pub fn init() -> anyhow::Result<()> {
+    register("help", "", "list commands", |args| {
+        no_args(args)?;
+        Ok(COMMANDS.read().unwrap_or_else(|e| e.into_inner()).help())
+    })?;
+    register("hooks list", "", "list installed hooks", |args| {
+        no_args(args)?;
+        Ok(hooks::list().iter().map(ToString::to_string).collect())
+    })?;
+    register("hooks enable", "[name]", "enable a hook, or every hook", |args| {
+        match args {
+            [] => hooks::enable_all()?,
+            [name] => hooks::enable(name)?,
+            _ => bail!("expected at most one hook name"),
+        }
+        Ok(vec!["Enabled".to_string()])
+    })?;
+    register("hooks disable", "[name]", "disable a hook, or every hook", |args| {
+        match args {
+            [] => hooks::disable_all()?,
+            [name] => hooks::disable(name)?,
+            _ => bail!("expected at most one hook name"),
+        }
+        Ok(vec!["Disabled".to_string()])
+    })?;
+    register("config reload", "", "reload the configuration", |args| {
+        no_args(args)?;
+        config::reload();
+        Ok(vec!["Configuration reloaded".to_string()])
+    })?;
+    register("audio dump", "", "show XAudio engines and voices", |args| {
+        no_args(args)?;
+        Ok(xaudio27::dump_state())
+    })?;
+    register("name", "<name|index>", "look up an entry of the name table", |args| {
+        let [arg] = args else {
+            bail!("expected a name or a name index");
+        };
+
+        let names = udk_fname::names()?;
+        let mut lines = Vec::new();
+        let index = match arg.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => {
+                let name = names.find(arg)?.with_context(|| format!("`{arg}` is not in the name table"))?;
+                if name.number != 0 {
+                    lines.push(format!("{arg} is name {} with instance number {}", name.index, name.number));
+                }
+                name.index as usize
+            }
+        };
+
+        let entry = names.entry(index)?.with_context(|| format!("name {index} is empty"))?;
+        let encoding = if entry.wide { "UTF-16" } else { "Latin-1" };
+        lines.push(format!(
+            "{}: {} ({encoding}, entry at {:#x}, next in hash bucket {:#x})",
+            entry.index, entry.name, entry.address, entry.hash_next
+        ));
+        Ok(lines)
+    })?;
+    register("get", "<object> <property>", "show a property of an object, given by full name", |args| {
+        let [object, property] = args else {
+            bail!("expected an object and a property");
+        };
+
+        let reflection = udk_object::reflection()?;
+        let object = reflection.find(object)?.with_context(|| format!("{object} not found"))?;
+        let value = reflection.get(object, property)?;
+        Ok(vec![format!("{property} = {}", reflection.describe(&value)?)])
+    })?;
+    register("set", "<object> <property> <value>", "change a property of an object, given by full name", |args| {
+        let [object, property, value] = args else {
+            bail!("expected an object, a property and a value");
+        };
+
+        let reflection = udk_object::reflection()?;
+        let object = reflection.find(object)?.with_context(|| format!("{object} not found"))?;
+        let class = reflection.class(object)?.context("object has no class")?;
+        let value = reflection.parse_value(&reflection.property(class, property)?, value)?;
+        reflection.set(object, property, &value)?;
+        Ok(vec![format!("{property} = {}", reflection.describe(&value)?)])
+    })?;
+    let usage = "<object> <function> [<param>=<value>...]";
+    register("call", usage, "call a function on an object, given by full name", |args| {
+        let [object, function, params @ ..] = args else {
+            bail!("expected an object and a function");
+        };
+
+        let reflection = udk_object::reflection()?;
+        let object = reflection.find(object)?.with_context(|| format!("{object} not found"))?;
+        let function = udk_call::find_function(object, function)?;
+        let mut values = Vec::new();
+        for param in params {
+            let (name, text) = param
+                .split_once('=')
+                .with_context(|| format!("expected <param>=<value>, got `{param}`"))?;
+            values.push((name, reflection.parse_value(&reflection.property(function, name)?, text)?));
+        }
+
+        let path = reflection.path_name(function)?;
+        let outcome = udk_call::call(object, &reflection.full_name(function)?, values)?;
+        let mut lines = Vec::new();
+        for param in reflection.properties(function)?.iter().filter(|p| p.flags & CPF_OUT_PARM != 0) {
+            lines.push(format!("{} = {}", param.name, reflection.describe(&outcome.get(&param.name)?)?));
+        }
+        match outcome.return_value()? {
+            Some(value) => lines.push(format!("{path} returned {}", reflection.describe(&value)?)),
+            None if lines.is_empty() => lines.push(format!("Called {path}")),
+            None => {}
+        }
+        Ok(lines)
+    })?;
+    register("trace", "<function>", "log native calls of a function, or stop logging them", |args| {
+        let [function] = args else {
+            bail!("expected a function's full name");
+        };
+
+        let reflection = udk_object::reflection()?;
+        let found = reflection.find(function)?.with_context(|| format!("{function} not found"))?;
+        let function = reflection.full_name(found)?;
+
+        let mut traces = TRACES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = traces.iter().position(|(f, _)| *f == function) {
+            udk_events::remove(traces.remove(i).1);
+            return Ok(vec![format!("Stopped tracing {function}")]);
+        }
+
+        let id = udk_events::on(&function, |event| {
+            let reflection = event.reflection();
+            let describe = |name: &str| event.get(name).and_then(|v| reflection.describe(&v));
+            let params = event
+                .params()
+                .unwrap_or_default()
+                .iter()
+                .filter(|p| p.flags & CPF_RETURN_PARM == 0)
+                .map(|p| format!("{}={}", p.name, describe(&p.name).unwrap_or_else(|e| format!("<{e}>"))))
+                .collect::<Vec<_>>();
+            let object = reflection.full_name(event.object).unwrap_or_else(|_| format!("{:#x}", event.object.address()));
+            let function = reflection.path_name(event.function).unwrap_or_default();
+
+            log(LogType::Log, &format!("{object}: {function}({})", params.join(", ")));
+            Action::Continue
+        });
+        traces.push((function.clone(), id));
+        Ok(vec![format!("Tracing {function}")])
+    })?;
+
+    udk_native::register(&CONSOLE_COMMAND);
+    Ok(())
+}
+
+/// `CONSOLE_COMMAND`'s thunk: run our commands, and pass the rest on.
+#[cfg(windows)]
+unsafe extern "C" fn console_command(this: usize, stack: *mut FFrame, result: usize) {
+    let frame = &mut *stack;
+    let Some(line) = frame.peek::<String>() else {
+        return CONSOLE_COMMAND.call_original(this, stack, result);
+    };
+    let Some(rest) = strip_prefix(&line) else {
+        return CONSOLE_COMMAND.call_original(this, stack, result);
+    };
+
+    // The return value is left empty.
+    let read = |frame: &mut FFrame| (frame.get::<String>(), frame.get::<bool>());
+    udk_native::invoke(CONSOLE_COMMAND.path, this, stack, result, read, |_, _| {
+        execute(rest);
+        String::new()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    fn commands() -> Commands {
+        let mut commands = Commands::new();
+        commands.register("audio dump", "", "show voices", |_| Ok(vec!["dump".to_string()])).unwrap();
+        commands.register("audio", "<on|off>", "toggle audio", |_| Ok(vec!["audio".to_string()])).unwrap();
+        commands.register("Hooks  List", "", "list hooks", |_| Ok(vec!["hooks".to_string()])).unwrap();
+        commands.register("hooks disable", "<name>", "disable a hook", |_| Ok(Vec::new())).unwrap();
+        commands
+    }
+
+    fn route(commands: &Commands, line: &str) -> anyhow::Result<(String, Vec<String>)> {
+        let words = words(line);
+        let (command, args) = commands.route(&words)?;
+        Ok((command.name(), args.to_vec()))
+    }
+
+    #[test]
+    fn tokenizes_words() {
+        assert_eq!(words("  audio   dump "), ["audio", "dump"]);
+        assert_eq!(words(""), Vec::<String>::new());
+        assert_eq!(words(" \t "), Vec::<String>::new());
+        assert_eq!(words("get\tPawn"), ["get", "Pawn"]);
+    }
+
+    #[test]
+    fn tokenizes_quotes() {
+        assert_eq!(words(r#"get "Pawn Map.Pawn_3" Health"#), ["get", "Pawn Map.Pawn_3", "Health"]);
+        assert_eq!(words(r#"say "a \"b\" \\ c""#), ["say", r#"a "b" \ c"#]);
+        assert_eq!(words(r#"say """#), ["say", ""]);
+        // Quotes only group at the start of a word.
+        assert_eq!(words(r#"a"b c"#), [r#"a"b"#, "c"]);
+
+        assert_eq!(tokenize(r#"say "open"#).unwrap_err().to_string(), "unterminated quote");
+        assert_eq!(tokenize(r#"say "open\"#).unwrap_err().to_string(), "unterminated escape");
+    }
+
+    #[test]
+    fn strips_the_prefix() {
+        assert_eq!(strip_prefix("renx audio dump"), Some(" audio dump"));
+        assert_eq!(strip_prefix("  RenX help"), Some(" help"));
+        assert_eq!(strip_prefix("renx"), Some(""));
+        assert_eq!(strip_prefix("renx\taudio"), Some("\taudio"));
+        assert_eq!(strip_prefix("renxaudio"), None);
+        assert_eq!(strip_prefix("say renx help"), None);
+        assert_eq!(strip_prefix(""), None);
+    }
+
+    #[test]
+    fn routes_to_the_longest_name() {
+        let commands = commands();
+
+        assert_eq!(route(&commands, "audio dump").unwrap(), ("renx audio dump".to_string(), vec![]));
+        assert_eq!(route(&commands, "AUDIO Dump x").unwrap(), ("renx audio dump".to_string(), vec!["x".to_string()]));
+        assert_eq!(route(&commands, "audio on").unwrap(), ("renx audio".to_string(), vec!["on".to_string()]));
+        assert_eq!(route(&commands, "hooks list").unwrap(), ("renx hooks list".to_string(), vec![]));
+    }
+
+    #[test]
+    fn suggests_completions() {
+        let commands = commands();
+
+        assert_eq!(
+            route(&commands, "hooks").unwrap_err().to_string(),
+            "`renx hooks` expects one of: disable, list"
+        );
+        assert_eq!(
+            route(&commands, "hooks enable").unwrap_err().to_string(),
+            "unknown command `renx hooks enable`; try `renx help`"
+        );
+        assert_eq!(
+            route(&commands, "").unwrap_err().to_string(),
+            "`renx` expects one of: audio, hooks"
+        );
+        assert_eq!(
+            route(&Commands::new(), "").unwrap_err().to_string(),
+            "unknown command `renx`; try `renx help`"
+        );
+    }
+
+    #[test]
+    fn registers_each_name_once() {
+        let mut commands = commands();
+
+        let error = commands.register("hooks LIST", "", "again", |_| Ok(Vec::new())).unwrap_err();
+        assert_eq!(error.to_string(), "`renx hooks list` is already registered");
+        assert!(commands.register(" ", "", "nameless", |_| Ok(Vec::new())).is_err());
+
+        assert_eq!(
+            commands.help(),
+            [
+                "renx audio <on|off> - toggle audio",
+                "renx audio dump - show voices",
+                "renx hooks disable <name> - disable a hook",
+                "renx hooks list - list hooks",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_for_arguments() {
+        assert!(no_args(&[]).is_ok());
+        assert_eq!(no_args(&words("a b")).unwrap_err().to_string(), "unexpected arguments: a b");
+    }
+}
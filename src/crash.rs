@@ -0,0 +1,286 @@
+//! This module contains the crash handler, which writes a minidump and an extension-aware
+//! crash report when the UDK dies of an unhandled exception.
+use std::fmt::Write as _;
+use std::fs::File;
+use std::ops::Range;
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use windows::Win32::Foundation::{BOOL, HANDLE};
+use windows::Win32::System::Diagnostics::Debug::{
+    MiniDumpWithIndirectlyReferencedMemory, MiniDumpWriteDump, SetUnhandledExceptionFilter,
+    EXCEPTION_POINTERS, LPTOP_LEVEL_EXCEPTION_FILTER, MINIDUMP_EXCEPTION_INFORMATION,
+};
+use windows::Win32::System::Threading::{
+    CreateEventW, GetCurrentProcess, GetCurrentProcessId, GetCurrentThreadId, SetEvent, WaitForSingleObject, INFINITE,
+};
+
+use crate::dll::{udk_build_hash, DLL_RANGE, UDK_RANGE};
+use crate::hooks;
+
+/// Returned from the filter to let the next handler (usually the OS) deal with the exception.
+const EXCEPTION_CONTINUE_SEARCH: i32 = 0;
+
+/// How long the crashing thread waits for the report before giving up on it.
+const REPORT_TIMEOUT_MS: u32 = 30_000;
+
+/// The exception filter that was installed before ours, if any.
+static PREVIOUS_FILTER: OnceLock<LPTOP_LEVEL_EXCEPTION_FILTER> = OnceLock::new();
+
+/// Set once a crash is being handled (or the handler is uninstalled), so a fault inside the
+/// handler doesn't recurse.
+static HANDLING_CRASH: AtomicBool = AtomicBool::new(false);
+
+/// The thread that writes the report, and the events it's handed crashes through. The
+/// crashing thread may hold any lock (including the heap's), so it doesn't do the work itself.
+struct Reporter {
+    /// Signaled by the crashing thread once `CRASH_INFO` and `CRASH_THREAD` are set.
+    crashed: HANDLE,
+    /// Signaled by the reporter once it's done with the crash.
+    done: HANDLE,
+    /// Where reports go, worked out up front.
+    dir: PathBuf,
+}
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+/// The `EXCEPTION_POINTERS` of the crash being reported.
+static CRASH_INFO: AtomicUsize = AtomicUsize::new(0);
+/// The ID of the thread that crashed.
+static CRASH_THREAD: AtomicU32 = AtomicU32::new(0);
+
+/// Where the faulting instruction lives.
+enum FaultModule {
+    Extensions(usize),
+    Udk(usize),
+    Other,
+}
+
+impl FaultModule {
+    fn classify(address: usize) -> Self {
+        let offset_in = |range: Option<&Range<usize>>| {
+            range
+                .filter(|r| r.contains(&address))
+                .map(|r| address - r.start)
+        };
+
+        if let Some(offset) = offset_in(DLL_RANGE.get()) {
+            FaultModule::Extensions(offset)
+        } else if let Some(offset) = offset_in(UDK_RANGE.get()) {
+            FaultModule::Udk(offset)
+        } else {
+            FaultModule::Other
+        }
+    }
+}
+
+/// Start the reporter thread and install the crash handler. This chains to whichever filter
+/// was installed before us.
+pub fn install() -> anyhow::Result<()> {
+    let dir = std::env::current_exe()?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let crashed = unsafe { CreateEventW(None, false, false, None) }?;
+    let done = unsafe { CreateEventW(None, false, false, None) }?;
+    if REPORTER.set(Reporter { crashed, done, dir }).is_err() {
+        return Ok(());
+    }
+
+    std::thread::Builder::new()
+        .name("crash reporter".to_owned())
+        .spawn(report_crashes)
+        .context("failed to start the crash reporter")?;
+
+    let previous = unsafe { SetUnhandledExceptionFilter(Some(exception_filter)) };
+    let _ = PREVIOUS_FILTER.set(previous);
+    Ok(())
+}
+
+/// Put back the filter that was installed before ours. The reporter thread stays parked; it's
+/// never woken once the filter is gone, so this is safe to call under the loader lock.
+pub fn uninstall() {
+    if let Some(&previous) = PREVIOUS_FILTER.get() {
+        HANDLING_CRASH.store(true, Ordering::SeqCst);
+
+        // The current filter can only be read by replacing it. If it isn't ours anymore, someone
+        // installed theirs on top of it, so put theirs back rather than unhooking them too.
+        let ours: LPTOP_LEVEL_EXCEPTION_FILTER = Some(exception_filter);
+        let current = unsafe { SetUnhandledExceptionFilter(previous) };
+        if current.map(|f| f as usize) != ours.map(|f| f as usize) {
+            unsafe { SetUnhandledExceptionFilter(current) };
+        }
+    }
+}
+
+unsafe extern "system" fn exception_filter(info: *const EXCEPTION_POINTERS) -> i32 {
+    // No allocation, formatting or I/O on this thread; just hand the crash over and wait.
+    if let Some(reporter) = REPORTER.get() {
+        if !HANDLING_CRASH.swap(true, Ordering::SeqCst) {
+            CRASH_INFO.store(info as usize, Ordering::SeqCst);
+            CRASH_THREAD.store(GetCurrentThreadId(), Ordering::SeqCst);
+            if SetEvent(reporter.crashed).is_ok() {
+                WaitForSingleObject(reporter.done, REPORT_TIMEOUT_MS);
+            }
+        }
+    }
+
+    match PREVIOUS_FILTER.get().copied().flatten() {
+        Some(previous) => previous(info),
+        None => EXCEPTION_CONTINUE_SEARCH,
+    }
+}
+
+/// Body of the reporter thread: wait for a crash, report it, and let the crashing thread go on.
+fn report_crashes() {
+    let Some(reporter) = REPORTER.get() else {
+        return;
+    };
+
+    unsafe {
+        WaitForSingleObject(reporter.crashed, INFINITE);
+
+        let info = CRASH_INFO.load(Ordering::SeqCst) as *const EXCEPTION_POINTERS;
+        // Nothing useful can be done with an error here; the process is going down regardless.
+        let _ = write_crash_report(&reporter.dir, info, CRASH_THREAD.load(Ordering::SeqCst));
+        let _ = SetEvent(reporter.done);
+    }
+}
+
+/// Write `<stem>.dmp` and `<stem>.json` into `dir`, for a crash on thread `thread_id`.
+unsafe fn write_crash_report(dir: &Path, info: *const EXCEPTION_POINTERS, thread_id: u32) -> anyhow::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let stem = format!("RenXExtensions-Crash-{timestamp}");
+
+    let dump_path = dir.join(format!("{stem}.dmp"));
+    let dump_result = write_minidump(&dump_path, info, thread_id);
+
+    let record = info.as_ref().and_then(|info| info.ExceptionRecord.as_ref());
+    let code = record.map_or(0, |r| r.ExceptionCode.0 as u32);
+    let address = record.map_or(0, |r| r.ExceptionAddress as usize);
+
+    let mut report = String::new();
+    writeln!(report, "{{")?;
+    writeln!(report, "  \"extension_version\": {},", json_str(env!("CARGO_PKG_VERSION")))?;
+    writeln!(report, "  \"udk_hash\": {},", json_str(&hex(udk_build_hash())))?;
+    writeln!(report, "  \"timestamp\": {timestamp},")?;
+    writeln!(report, "  \"thread_id\": {thread_id},")?;
+    writeln!(report, "  \"exception_code\": \"0x{code:08X}\",")?;
+    writeln!(report, "  \"fault_address\": \"0x{address:X}\",")?;
+    match FaultModule::classify(address) {
+        FaultModule::Extensions(offset) => {
+            writeln!(report, "  \"fault_module\": \"extensions\",")?;
+            writeln!(report, "  \"fault_offset\": \"0x{offset:X}\",")?;
+        }
+        FaultModule::Udk(offset) => {
+            writeln!(report, "  \"fault_module\": \"udk\",")?;
+            writeln!(report, "  \"fault_offset\": \"0x{offset:X}\",")?;
+        }
+        FaultModule::Other => {
+            writeln!(report, "  \"fault_module\": \"other\",")?;
+            writeln!(report, "  \"fault_offset\": null,")?;
+        }
+    }
+    // Don't block on the hook registry; the crash may have happened while it was locked.
+    match hooks::try_list() {
+        Some(list) => {
+            let active = list
+                .iter()
+                .filter(|h| h.enabled)
+                .map(|h| json_str(h.name))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(report, "  \"active_hooks\": [{active}],")?;
+        }
+        None => writeln!(report, "  \"active_hooks\": null,")?,
+    }
+    match &dump_result {
+        Ok(()) => writeln!(report, "  \"minidump\": {}", json_str(&dump_path.to_string_lossy()))?,
+        Err(e) => {
+            writeln!(report, "  \"minidump\": null,")?;
+            writeln!(report, "  \"minidump_error\": {}", json_str(&e.to_string()))?;
+        }
+    }
+    writeln!(report, "}}")?;
+
+    std::fs::write(dir.join(format!("{stem}.json")), report)?;
+    Ok(())
+}
+
+unsafe fn write_minidump(path: &Path, info: *const EXCEPTION_POINTERS, thread_id: u32) -> anyhow::Result<()> {
+    let file = File::create(path)?;
+
+    let exception = MINIDUMP_EXCEPTION_INFORMATION {
+        ThreadId: thread_id,
+        ExceptionPointers: info as *mut _,
+        ClientPointers: BOOL(0),
+    };
+
+    MiniDumpWriteDump(
+        GetCurrentProcess(),
+        GetCurrentProcessId(),
+        HANDLE(file.as_raw_handle() as isize),
+        MiniDumpWithIndirectlyReferencedMemory,
+        Some(&exception),
+        None,
+        None,
+    )?;
+
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Quote and escape a string for inclusion in the JSON report.
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_str_escapes_quotes_and_backslashes() {
+        assert_eq!(json_str(""), r#""""#);
+        assert_eq!(json_str(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_str(r"C:\UDK\UDK.exe"), r#""C:\\UDK\\UDK.exe""#);
+    }
+
+    #[test]
+    fn json_str_escapes_control_characters() {
+        assert_eq!(json_str("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(json_str("\0\u{1}\u{1f}"), r#""\u0000\u0001\u001f""#);
+        // DEL isn't a control character as far as JSON is concerned.
+        assert_eq!(json_str("\u{7f}"), "\"\u{7f}\"");
+    }
+
+    #[test]
+    fn json_str_keeps_non_ascii() {
+        assert_eq!(json_str("Mañana — 日本 🎮"), "\"Mañana — 日本 🎮\"");
+    }
+}
@@ -0,0 +1,319 @@
+//! This module decides which DirectInput devices the UDK gets to see.
+//!
+//! Some HID devices (RGB controllers, wheel bases, virtual joysticks) crash the UDK or steal
+//! its joystick bindings. A [`DeviceFilter`] hides them by product GUID, VID/PID or name.
+//! Like `input_remap.rs`, this is plain data; `dinput_hooks.rs` applies it.
+use std::fmt;
+
+use anyhow::{bail, Context};
+use windows::core::GUID;
+
+/// `DI8DEVTYPE_MOUSE` and `DI8DEVTYPE_KEYBOARD`, in the low byte of `dwDevType`.
+const DI8DEVTYPE_MOUSE: u32 = 0x12;
+const DI8DEVTYPE_KEYBOARD: u32 = 0x13;
+
+/// What the filter knows about an enumerated device.
+pub struct DeviceInfo {
+    pub instance: GUID,
+    pub product: GUID,
+    /// `dwDevType`, the device type and subtype.
+    pub dev_type: u32,
+    pub instance_name: String,
+    pub product_name: String,
+}
+
+impl DeviceInfo {
+    /// USB vendor and product ID, for HID devices. DirectInput builds HID product GUIDs as
+    /// `{PPPPVVVV-0000-0000-0000-504944564944}`, the last part spelling "PIDVID".
+    pub fn vid_pid(&self) -> Option<(u16, u16)> {
+        let p = &self.product;
+        if p.data2 != 0 || p.data3 != 0 || p.data4 != *b"\0\0PIDVID" {
+            return None;
+        }
+
+        Some((p.data1 as u16, (p.data1 >> 16) as u16))
+    }
+
+    /// Keyboards and mice are never filtered, so a bad rule can't lock the player out of the game.
+    fn is_exempt(&self) -> bool {
+        matches!(self.dev_type & 0xFF, DI8DEVTYPE_MOUSE | DI8DEVTYPE_KEYBOARD)
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.product_name)?;
+        if self.instance_name != self.product_name {
+            write!(f, " (\"{}\")", self.instance_name)?;
+        }
+        if let Some((vid, pid)) = self.vid_pid() {
+            write!(f, ", VID/PID {vid:04x}:{pid:04x}")?;
+        }
+        write!(f, ", product {:?}, instance {:?}", self.product, self.instance)
+    }
+}
+
+/// A pattern matching a set of devices.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DeviceMatch {
+    /// `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}`: a product GUID.
+    Product(GUID),
+    /// `vvvv:pppp`: a USB vendor and product ID, in hex.
+    VidPid(u16, u16),
+    /// Anything else: a case-insensitive glob over the device's product and instance names,
+    /// where `*` matches any run of characters and `?` a single one.
+    Name(String),
+}
+
+impl DeviceMatch {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            bail!("empty device pattern");
+        }
+
+        if s.starts_with('{') {
+            return parse_guid(s).map(DeviceMatch::Product);
+        }
+
+        if let Some((vid, pid)) = s.split_once(':') {
+            if vid.len() == 4 && pid.len() == 4 {
+                if let (Ok(vid), Ok(pid)) = (u16::from_str_radix(vid, 16), u16::from_str_radix(pid, 16)) {
+                    return Ok(DeviceMatch::VidPid(vid, pid));
+                }
+            }
+        }
+
+        Ok(DeviceMatch::Name(s.to_string()))
+    }
+
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            DeviceMatch::Product(guid) => device.product == *guid,
+            DeviceMatch::VidPid(vid, pid) => device.vid_pid() == Some((*vid, *pid)),
+            DeviceMatch::Name(pattern) => {
+                glob_match(pattern, &device.product_name) || glob_match(pattern, &device.instance_name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for DeviceMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceMatch::Product(guid) => write!(f, "{{{guid:?}}}"),
+            DeviceMatch::VidPid(vid, pid) => write!(f, "{vid:04x}:{pid:04x}"),
+            DeviceMatch::Name(pattern) => f.write_str(pattern),
+        }
+    }
+}
+
+/// Parse a GUID in registry format, `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}`.
+fn parse_guid(s: &str) -> anyhow::Result<GUID> {
+    let f = || -> Option<GUID> {
+        let inner = s.strip_prefix('{')?.strip_suffix('}')?;
+        let groups = inner.split('-').map(str::len).collect::<Vec<_>>();
+        if groups != [8, 4, 4, 4, 12] {
+            return None;
+        }
+
+        let hex = inner.replace('-', "");
+        u128::from_str_radix(&hex, 16).ok().map(GUID::from_u128)
+    };
+
+    f().with_context(|| format!("`{s}` is not a GUID of the form {{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}}"))
+}
+
+/// Case-insensitive glob match supporting `*` and `?`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*`, and the text position it's currently matched up to.
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character and try again.
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Allow and deny lists for DirectInput devices.
+///
+/// A device is hidden if it matches any deny pattern, or if there are allow patterns and it
+/// matches none of them. Keyboards and mice are always visible.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct DeviceFilter {
+    pub allow: Vec<DeviceMatch>,
+    pub deny: Vec<DeviceMatch>,
+}
+
+impl DeviceFilter {
+    pub const fn new() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+
+    /// Parse a comma- or semicolon-separated list of patterns.
+    pub fn parse_list(s: &str) -> anyhow::Result<Vec<DeviceMatch>> {
+        s.split([',', ';'])
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(DeviceMatch::parse)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether the UDK should see `device`.
+    pub fn allows(&self, device: &DeviceInfo) -> bool {
+        if device.is_exempt() {
+            return true;
+        }
+        if self.deny.iter().any(|m| m.matches(device)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|m| m.matches(device))
+    }
+}
+
+impl fmt::Display for DeviceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |list: &[DeviceMatch]| list.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+        write!(f, "allow [{}], deny [{}]", join(&self.allow), join(&self.deny))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FANATEC: GUID = GUID::from_u128(0x0e8f_0eb7_0000_0000_0000_5049_4456_4944);
+
+    fn device(name: &str, product: GUID, dev_type: u32) -> DeviceInfo {
+        DeviceInfo {
+            instance: GUID::from_u128(0x1234),
+            product,
+            dev_type,
+            instance_name: format!("{name} #1"),
+            product_name: name.to_string(),
+        }
+    }
+
+    fn joystick(name: &str) -> DeviceInfo {
+        device(name, FANATEC, 0x0114)
+    }
+
+    fn filter(allow: &str, deny: &str) -> DeviceFilter {
+        DeviceFilter {
+            allow: DeviceFilter::parse_list(allow).unwrap(),
+            deny: DeviceFilter::parse_list(deny).unwrap(),
+        }
+    }
+
+    #[test]
+    fn parses_guids() {
+        let parsed = DeviceMatch::parse("{0E8F0EB7-0000-0000-0000-504944564944}").unwrap();
+        assert_eq!(parsed, DeviceMatch::Product(FANATEC));
+        assert_eq!(parse_guid("{0e8f0eb7-0000-0000-0000-504944564944}").unwrap(), FANATEC);
+        assert_eq!(parsed.to_string(), "{0E8F0EB7-0000-0000-0000-504944564944}");
+
+        // Braces are required, and the groups must have the registry layout.
+        assert!(parse_guid("0E8F0EB7-0000-0000-0000-504944564944").is_err());
+        assert!(parse_guid("{0E8F0EB7-0000-0000-0000-504944564944").is_err());
+        assert!(parse_guid("{0E8F0EB70000-0000-0000-0000-504944564944}").is_err());
+        assert!(parse_guid("{0E8F0EB7-0000-0000-0000-50494456494G}").is_err());
+        assert!(DeviceMatch::parse("{not a guid}").is_err());
+
+        // Without braces, it's just a name.
+        assert!(matches!(
+            DeviceMatch::parse("0E8F0EB7-0000-0000-0000-504944564944").unwrap(),
+            DeviceMatch::Name(_)
+        ));
+    }
+
+    #[test]
+    fn parses_vid_pid_in_either_case() {
+        assert_eq!(DeviceMatch::parse("0eb7:0e8f").unwrap(), DeviceMatch::VidPid(0x0EB7, 0x0E8F));
+        assert_eq!(DeviceMatch::parse(" 0EB7:0E8F ").unwrap(), DeviceMatch::VidPid(0x0EB7, 0x0E8F));
+        assert_eq!(DeviceMatch::VidPid(0x0EB7, 0x0E8F).to_string(), "0eb7:0e8f");
+
+        // Anything that isn't exactly two groups of four hex digits is a name.
+        for name in ["eb7:0e8f", "0eb7:0e8fa", "0eb7:0e8g", "Pad: Pro"] {
+            assert_eq!(DeviceMatch::parse(name).unwrap(), DeviceMatch::Name(name.to_string()));
+        }
+        assert!(DeviceMatch::parse("  ").is_err());
+    }
+
+    #[test]
+    fn reads_vid_pid_from_hid_product_guids() {
+        assert_eq!(joystick("Wheel").vid_pid(), Some((0x0EB7, 0x0E8F)));
+        assert_eq!(device("Virtual", GUID::from_u128(0x0e8f_0eb7 << 96), 0x0114).vid_pid(), None);
+        assert!(DeviceMatch::parse("0eb7:0e8f").unwrap().matches(&joystick("Wheel")));
+    }
+
+    #[test]
+    fn globs_names() {
+        assert!(glob_match("*wheel*", "Fanatec Wheel Base"));
+        assert!(glob_match("FANATEC*", "fanatec wheel base"));
+        assert!(glob_match("vJoy ?evice", "vJoy Device"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        // The first `*` has to give back characters it swallowed for the rest to match.
+        assert!(glob_match("*ab", "aaab"));
+        assert!(glob_match("*a?c", "abcabc"));
+
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("*wheel", "Fanatec Wheel Base"));
+        assert!(!glob_match("a*b*c", "a-b-b-"));
+        assert!(!glob_match("vJoy?Device", "vJoyDevice"));
+    }
+
+    #[test]
+    fn matches_instance_names_too() {
+        let pattern = DeviceMatch::parse("*#1").unwrap();
+        assert!(pattern.matches(&joystick("Wheel")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let wheel = joystick("Fanatec Wheel");
+        assert!(!filter("Fanatec*", "0eb7:0e8f").allows(&wheel));
+        assert!(filter("Fanatec*", "vJoy*").allows(&wheel));
+        assert!(!filter("vJoy*", "").allows(&wheel));
+        assert!(DeviceFilter::new().allows(&wheel));
+    }
+
+    #[test]
+    fn never_hides_keyboards_or_mice() {
+        let deny_all = filter("nothing", "*");
+        assert!(deny_all.allows(&device("Keyboard", GUID::zeroed(), 0x0413)));
+        assert!(deny_all.allows(&device("Mouse", GUID::zeroed(), 0x0112)));
+        assert!(!deny_all.allows(&joystick("Gamepad")));
+    }
+}
@@ -1,15 +1,115 @@
 //! This module handles functionality related to the original dinput8.dll
+use std::ffi::c_void;
+use std::sync::OnceLock;
+
+use anyhow::bail;
+use libloading::Library;
 use windows::{
-    core::{IUnknown, ComInterface, GUID, HRESULT},
+    core::{IUnknown, ComInterface, Interface, GUID, HRESULT},
     Win32::{
-        Devices::HumanInterfaceDevice::{CLSID_DirectInput8, IDirectInput8A, IDirectInput8W},
-        Foundation::{E_NOINTERFACE, HINSTANCE, S_OK},
+        Devices::HumanInterfaceDevice::{
+            CLSID_DirectInput8, IDirectInput8A, IDirectInput8W, DIDATAFORMAT,
+        },
+        Foundation::{E_FAIL, E_NOINTERFACE, HINSTANCE, S_OK},
         System::Com::{CoCreateInstance, CLSCTX_INPROC_SERVER},
     },
 };
 
-/// This function simply redirects the one and only DirectInput8Create call to the real dinput8 DLL.
-#[no_mangle]
+use crate::config;
+use crate::dinput_hooks;
+use crate::dll::{load_system_library, DLL_RANGE};
+use crate::udk_log::{self, LogType};
+
+type DirectInput8CreateFn = unsafe extern "system" fn(
+    hinst: HINSTANCE,
+    dwversion: u32,
+    riid: *const GUID,
+    out: *mut *mut c_void,
+    outer: *mut c_void,
+) -> HRESULT;
+
+/// A chain-loaded dinput8 replacement. The library is kept loaded for the lifetime of the process.
+struct ChainDll {
+    _library: Library,
+    create: DirectInput8CreateFn,
+}
+
+/// The chain-loaded DLL, or `None` if we're using the system DirectInput.
+static CHAIN_DLL: OnceLock<Option<ChainDll>> = OnceLock::new();
+
+/// Load the chain DLL, if one is configured and present. Logs which path was taken.
+///
+/// The chain DLL lets players keep a third-party dinput8.dll (remappers, overlays) by renaming it.
+fn load_chain_dll() -> Option<ChainDll> {
+    let config = config::get();
+    let name = &config.direct_input.chain_dll;
+    if name.is_empty() {
+        udk_log::log(LogType::Init, "DirectInput: chaining is disabled, using the system DirectInput");
+        return None;
+    }
+
+    // The chain DLL must sit next to UDK.exe; anything else could load us again, or something
+    // the player never put there.
+    if name.contains(['/', '\\']) || name.contains("..") {
+        udk_log::log(
+            LogType::Warning,
+            &format!("DirectInput: ChainDll must be a plain file name, not {name}; using the system DirectInput"),
+        );
+        return None;
+    }
+
+    let path = std::env::current_exe().ok()?.with_file_name(name);
+    if !path.exists() {
+        udk_log::log(
+            LogType::Init,
+            &format!("DirectInput: no {name} found, using the system DirectInput"),
+        );
+        return None;
+    }
+
+    let f = || -> anyhow::Result<ChainDll> {
+        // SAFETY: Loading a DLL runs its initialization code; the player put it there on purpose.
+        let library = unsafe { libloading::os::windows::Library::new(&path)? };
+
+        // Chaining to ourselves would forward DirectInput8Create to itself forever.
+        let base = library.into_raw();
+        let library = Library::from(unsafe { libloading::os::windows::Library::from_raw(base) });
+        if DLL_RANGE.get().is_some_and(|range| range.contains(&(base as usize))) {
+            bail!("it is this DLL");
+        }
+
+        let create = unsafe { *library.get::<DirectInput8CreateFn>(b"DirectInput8Create\0")? };
+
+        Ok(ChainDll {
+            _library: library,
+            create,
+        })
+    };
+
+    match f() {
+        Ok(chain) => {
+            udk_log::log(
+                LogType::Init,
+                &format!("DirectInput: forwarding DirectInput8Create to {}", path.display()),
+            );
+            Some(chain)
+        }
+        Err(e) => {
+            udk_log::log(
+                LogType::Warning,
+                &format!(
+                    "DirectInput: failed to load {}, falling back to the system DirectInput: {:#}",
+                    path.display(),
+                    e
+                ),
+            );
+            None
+        }
+    }
+}
+
+/// This function redirects the one and only DirectInput8Create call, either to a chain-loaded
+/// dinput8 replacement or to the real dinput8 DLL.
 #[export_name = "DirectInput8Create"]
 pub unsafe extern "C" fn directinput8_create(
     hinst: HINSTANCE,
@@ -18,6 +118,21 @@ pub unsafe extern "C" fn directinput8_create(
     out: *mut Option<IUnknown>,
     outer: Option<IUnknown>,
 ) -> HRESULT {
+    if let Some(chain) = CHAIN_DLL.get_or_init(load_chain_dll) {
+        let hr = (chain.create)(
+            hinst,
+            dwversion,
+            riid,
+            out as *mut *mut c_void,
+            outer.as_ref().map_or(std::ptr::null_mut(), Interface::as_raw),
+        );
+
+        if hr.is_ok() && outer.is_none() {
+            intercept(riid, out);
+        }
+        return hr;
+    }
+
     // Instead of trying to load the original dinput8.dll and calling the original `DirectInput8Create`,
     // we can simply load the dinput8 interface via COM and return it up to our caller. This is basically
     // what DirectInput8Create does noawadays anyway.
@@ -50,7 +165,7 @@ pub unsafe extern "C" fn directinput8_create(
                 }
                 Ok(dinput.cast()?)
             }
-            _ => return Err(E_NOINTERFACE.into()),
+            _ => Err(E_NOINTERFACE.into()),
         }
     };
 
@@ -59,5 +174,94 @@ pub unsafe extern "C" fn directinput8_create(
         Err(e) => return e.code(),
     };
 
+    if outer.is_none() {
+        intercept(riid, out);
+    }
     S_OK
 }
+
+/// Intercept the DirectInput object we're about to hand to the caller. Aggregated objects are
+/// left alone, since their vtable belongs to the outer object.
+unsafe fn intercept(riid: *const GUID, out: *mut Option<IUnknown>) {
+    let wide = match riid.as_ref() {
+        Some(&IDirectInput8A::IID) => false,
+        Some(&IDirectInput8W::IID) => true,
+        _ => return,
+    };
+
+    if let Some(dinput) = (*out).as_ref() {
+        if let Err(e) = dinput_hooks::intercept_dinput(dinput.as_raw(), wide) {
+            udk_log::log(LogType::Error, &format!("DirectInput: failed to intercept DirectInput: {:#}", e));
+        }
+    }
+}
+
+/// The real dinput8.dll, loaded by full path from the system directory. It must never be
+/// resolved through the normal search order, which would find us in the game directory.
+static SYSTEM_DINPUT8: OnceLock<Option<Library>> = OnceLock::new();
+
+fn load_system_dinput8() -> Option<Library> {
+    match load_system_library("dinput8.dll") {
+        Ok(library) => Some(library),
+        Err(e) => {
+            udk_log::log(LogType::Error, &format!("DirectInput: {:#}", e));
+            None
+        }
+    }
+}
+
+/// Look up an export of the system dinput8.dll.
+unsafe fn system_export<T: Copy>(name: &[u8]) -> Option<T> {
+    let library = SYSTEM_DINPUT8.get_or_init(load_system_dinput8).as_ref()?;
+    library.get::<T>(name).ok().map(|f| *f)
+}
+
+// The remaining dinput8.dll exports. These are rarely called by anything but tools and scanners
+// that inspect the loaded module, so they're simply forwarded to the system DLL.
+
+#[no_mangle]
+pub unsafe extern "system" fn DllCanUnloadNow() -> HRESULT {
+    match system_export::<unsafe extern "system" fn() -> HRESULT>(b"DllCanUnloadNow\0") {
+        Some(f) => f(),
+        None => E_FAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn DllGetClassObject(
+    rclsid: *const GUID,
+    riid: *const GUID,
+    ppv: *mut *mut c_void,
+) -> HRESULT {
+    type DllGetClassObjectFn =
+        unsafe extern "system" fn(*const GUID, *const GUID, *mut *mut c_void) -> HRESULT;
+
+    match system_export::<DllGetClassObjectFn>(b"DllGetClassObject\0") {
+        Some(f) => f(rclsid, riid, ppv),
+        None => E_FAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn DllRegisterServer() -> HRESULT {
+    match system_export::<unsafe extern "system" fn() -> HRESULT>(b"DllRegisterServer\0") {
+        Some(f) => f(),
+        None => E_FAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn DllUnregisterServer() -> HRESULT {
+    match system_export::<unsafe extern "system" fn() -> HRESULT>(b"DllUnregisterServer\0") {
+        Some(f) => f(),
+        None => E_FAIL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn GetdfDIJoystick() -> *const DIDATAFORMAT {
+    match system_export::<unsafe extern "system" fn() -> *const DIDATAFORMAT>(b"GetdfDIJoystick\0") {
+        Some(f) => f(),
+        None => std::ptr::null(),
+    }
+}
@@ -0,0 +1,361 @@
+//! This module intercepts the DirectInput objects handed to the UDK.
+//!
+//! The `IDirectInput8` returned by `DirectInput8Create` has its `CreateDevice` hooked, and the
+//! system keyboard and mouse it creates are intercepted in turn:
+//!  * Keyboard data, immediate and buffered, goes through the remapping rules in `input_remap.rs`.
+//!  * Mouse data passes through untouched, but its raw deltas are accumulated for
+//!    `renx input mouse`.
+//!  * Devices hidden by the device filter are skipped by `EnumDevices` and fail to create,
+//!    as if they had been unplugged.
+use std::collections::BTreeSet;
+use std::ffi::c_void;
+use std::mem::{offset_of, size_of};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use windows::{
+    core::{GUID, HRESULT},
+    Win32::{
+        Devices::HumanInterfaceDevice::{
+            IDirectInput8W_Vtbl, IDirectInputDevice8W_Vtbl, DIDEVICEINSTANCEA, DIDEVICEINSTANCEW,
+            DIDEVICEOBJECTDATA, DIENUM_CONTINUE, DIERR_DEVICENOTREG, DIGDD_PEEK, GUID_SysKeyboard,
+            GUID_SysMouse,
+        },
+        Foundation::BOOL,
+    },
+};
+
+use crate::console_commands::{self, no_args};
+use crate::device_filter::{DeviceFilter, DeviceInfo};
+use crate::input_remap::Rules;
+use crate::udk_log::{log, LogType};
+use crate::vtable;
+
+/// Number of entries in a vtable described by a `windows` vtable struct.
+const fn vtable_len<T>() -> usize {
+    size_of::<T>() / size_of::<usize>()
+}
+
+// The ANSI and wide interfaces share their layout; only the structs some methods take differ.
+const DINPUT_VTABLE_LEN: usize = vtable_len::<IDirectInput8W_Vtbl>();
+const CREATE_DEVICE: usize = offset_of!(IDirectInput8W_Vtbl, CreateDevice) / size_of::<usize>();
+const ENUM_DEVICES: usize = offset_of!(IDirectInput8W_Vtbl, EnumDevices) / size_of::<usize>();
+
+const DEVICE_VTABLE_LEN: usize = vtable_len::<IDirectInputDevice8W_Vtbl>();
+const GET_DEVICE_STATE: usize = offset_of!(IDirectInputDevice8W_Vtbl, GetDeviceState) / size_of::<usize>();
+const GET_DEVICE_DATA: usize = offset_of!(IDirectInputDevice8W_Vtbl, GetDeviceData) / size_of::<usize>();
+
+type CreateDeviceFn =
+    unsafe extern "system" fn(*mut c_void, *const GUID, *mut *mut c_void, *mut c_void) -> HRESULT;
+/// `EnumDevices`, with the callback and its device instance left untyped, since they differ
+/// between the ANSI and wide interfaces.
+type EnumDevicesFn = unsafe extern "system" fn(*mut c_void, u32, Option<EnumCallbackFn>, *mut c_void, u32) -> HRESULT;
+type EnumCallbackFn = unsafe extern "system" fn(*const c_void, *mut c_void) -> BOOL;
+type GetDeviceStateFn = unsafe extern "system" fn(*mut c_void, u32, *mut c_void) -> HRESULT;
+type GetDeviceDataFn =
+    unsafe extern "system" fn(*mut c_void, u32, *mut DIDEVICEOBJECTDATA, *mut u32, u32) -> HRESULT;
+
+/// Size of `DIKEYBOARDSTATE`, the keyboard's `c_dfDIKeyboard` data format.
+const KEYBOARD_STATE_LEN: usize = 256;
+/// Byte offsets of the axes in `DIMOUSESTATE`/`DIMOUSESTATE2` (`DIMOFS_X`, `DIMOFS_Y`, `DIMOFS_Z`).
+const MOUSE_AXES: [u32; 3] = [0, 4, 8];
+
+/// Settings for the DirectInput interception.
+#[derive(Clone, Default)]
+pub struct InputSettings {
+    /// Key remapping rules applied to the system keyboard.
+    pub remap: Rules,
+    /// Devices hidden from the UDK.
+    pub devices: DeviceFilter,
+}
+
+static SETTINGS: RwLock<InputSettings> = RwLock::new(InputSettings {
+    remap: Rules::new(),
+    devices: DeviceFilter::new(),
+});
+
+/// Replace the interception settings. Takes effect on the next read from any device.
+pub fn configure(settings: InputSettings) {
+    if !settings.remap.is_empty() {
+        log(LogType::Init, &format!("DirectInput: remapping keys: {}", settings.remap));
+    }
+    if !settings.devices.is_empty() {
+        log(LogType::Init, &format!("DirectInput: filtering devices: {}", settings.devices));
+    }
+
+    *SETTINGS.write().unwrap_or_else(|e| e.into_inner()) = settings;
+}
+
+fn settings() -> std::sync::RwLockReadGuard<'static, InputSettings> {
+    SETTINGS.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Mouse motion reported to the UDK since the last [`take_mouse_delta`], per axis.
+static MOUSE_DELTA: [AtomicI64; 3] = [AtomicI64::new(0), AtomicI64::new(0), AtomicI64::new(0)];
+
+/// Return and reset the raw mouse motion (X, Y, wheel) the UDK has read since the last call.
+/// These are the device's own counts, before any of the UDK's sensitivity or smoothing.
+pub fn take_mouse_delta() -> [i64; 3] {
+    MOUSE_DELTA.each_ref().map(|axis| axis.swap(0, Ordering::Relaxed))
+}
+
+/// Register the input console commands.
+pub fn init() -> anyhow::Result<()> {
+    console_commands::register("input mouse", "", "show raw mouse motion since the last call", |args| {
+        no_args(args)?;
+        let [x, y, wheel] = take_mouse_delta();
+        Ok(vec![format!("Raw mouse motion: X {x}, Y {y}, wheel {wheel}")])
+    })
+}
+
+/// Instance GUIDs of every device enumerated so far, so each is only logged once.
+static SEEN_DEVICES: Mutex<BTreeSet<u128>> = Mutex::new(BTreeSet::new());
+/// Instance GUIDs of the devices hidden from enumeration.
+static HIDDEN_DEVICES: Mutex<BTreeSet<u128>> = Mutex::new(BTreeSet::new());
+
+/// A `DIDEVICEINSTANCE`, in either flavor.
+trait DeviceInstance {
+    fn info(&self) -> DeviceInfo;
+}
+
+impl DeviceInstance for DIDEVICEINSTANCEA {
+    fn info(&self) -> DeviceInfo {
+        let string = |s: &[u8]| {
+            let len = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+            String::from_utf8_lossy(&s[..len]).into_owned()
+        };
+
+        DeviceInfo {
+            instance: self.guidInstance,
+            product: self.guidProduct,
+            dev_type: self.dwDevType,
+            instance_name: string(&self.tszInstanceName),
+            product_name: string(&self.tszProductName),
+        }
+    }
+}
+
+impl DeviceInstance for DIDEVICEINSTANCEW {
+    fn info(&self) -> DeviceInfo {
+        let string = |s: &[u16]| {
+            let len = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+            String::from_utf16_lossy(&s[..len])
+        };
+
+        DeviceInfo {
+            instance: self.guidInstance,
+            product: self.guidProduct,
+            dev_type: self.dwDevType,
+            instance_name: string(&self.tszInstanceName),
+            product_name: string(&self.tszProductName),
+        }
+    }
+}
+
+/// The caller's `EnumDevices` callback, passed to [`enum_devices_callback`] in its context pointer.
+struct EnumContext {
+    callback: EnumCallbackFn,
+    context: *mut c_void,
+}
+
+unsafe extern "system" fn enum_devices_callback<D: DeviceInstance>(instance: *const c_void, context: *mut c_void) -> BOOL {
+    let ctx = &*(context as *const EnumContext);
+    let Some(device) = (instance as *const D).as_ref().map(D::info) else {
+        return (ctx.callback)(instance, ctx.context);
+    };
+
+    let allowed = settings().devices.allows(&device);
+    let key = device.instance.to_u128();
+
+    if SEEN_DEVICES.lock().unwrap_or_else(|e| e.into_inner()).insert(key) {
+        let verdict = if allowed { "" } else { " (hidden)" };
+        log(LogType::Init, &format!("DirectInput: found device {device}{verdict}"));
+    }
+
+    let mut hidden = HIDDEN_DEVICES.lock().unwrap_or_else(|e| e.into_inner());
+    if !allowed {
+        hidden.insert(key);
+        return BOOL(DIENUM_CONTINUE as i32);
+    }
+    hidden.remove(&key);
+    drop(hidden);
+
+    (ctx.callback)(instance, ctx.context)
+}
+
+/// Start intercepting an `IDirectInput8A` (`wide == false`) or `IDirectInput8W` (`wide == true`) object.
+///
+/// SAFETY: `dinput` must be a live `IDirectInput8A` or `IDirectInput8W`, as given by `wide`.
+pub unsafe fn intercept_dinput(dinput: *mut c_void, wide: bool) -> anyhow::Result<()> {
+    let iface = vtable::intercept(dinput, DINPUT_VTABLE_LEN)?;
+
+    let filter: EnumCallbackFn = match wide {
+        true => enum_devices_callback::<DIDEVICEINSTANCEW>,
+        false => enum_devices_callback::<DIDEVICEINSTANCEA>,
+    };
+
+    iface.hook::<ENUM_DEVICES, EnumDevicesFn>(Box::new(
+        move |original: EnumDevicesFn,
+              this: *mut c_void,
+              dev_type: u32,
+              callback: Option<EnumCallbackFn>,
+              context: *mut c_void,
+              flags: u32| {
+            let Some(callback) = callback else {
+                return original(this, dev_type, callback, context, flags);
+            };
+
+            let ctx = EnumContext { callback, context };
+            original(this, dev_type, Some(filter), &ctx as *const EnumContext as *mut c_void, flags)
+        },
+    ))?;
+
+    iface.hook::<CREATE_DEVICE, CreateDeviceFn>(Box::new(
+        |original: CreateDeviceFn, this: *mut c_void, guid: *const GUID, out: *mut *mut c_void, outer: *mut c_void| {
+            let guid_value = *guid;
+            if HIDDEN_DEVICES.lock().unwrap_or_else(|e| e.into_inner()).contains(&guid_value.to_u128()) {
+                log(LogType::Warning, &format!("DirectInput: refusing to create hidden device {guid_value:?}"));
+                return HRESULT(DIERR_DEVICENOTREG);
+            }
+
+            let hr = original(this, guid, out, outer);
+            if hr.is_ok() && outer.is_null() {
+                if let Err(e) = intercept_device(*out, &guid_value) {
+                    log(LogType::Error, &format!("DirectInput: failed to intercept device: {:#}", e));
+                }
+            }
+
+            hr
+        },
+    ))?;
+
+    Ok(())
+}
+
+/// Intercept a freshly created device, if it's one we have an interest in.
+unsafe fn intercept_device(device: *mut c_void, guid: &GUID) -> anyhow::Result<()> {
+    if *guid == GUID_SysKeyboard {
+        let iface = vtable::intercept(device, DEVICE_VTABLE_LEN)?;
+        iface.hook::<GET_DEVICE_STATE, GetDeviceStateFn>(Box::new(keyboard_get_device_state))?;
+        iface.hook::<GET_DEVICE_DATA, GetDeviceDataFn>(Box::new(keyboard_get_device_data))?;
+    } else if *guid == GUID_SysMouse {
+        let iface = vtable::intercept(device, DEVICE_VTABLE_LEN)?;
+        iface.hook::<GET_DEVICE_STATE, GetDeviceStateFn>(Box::new(mouse_get_device_state))?;
+        iface.hook::<GET_DEVICE_DATA, GetDeviceDataFn>(Box::new(mouse_get_device_data))?;
+    }
+
+    Ok(())
+}
+
+fn keyboard_get_device_state(original: GetDeviceStateFn, this: *mut c_void, len: u32, data: *mut c_void) -> HRESULT {
+    let hr = unsafe { original(this, len, data) };
+
+    // Anything but the standard keyboard format is passed through as-is.
+    if hr.is_ok() && len as usize == KEYBOARD_STATE_LEN && !data.is_null() {
+        let state = unsafe { &mut *(data as *mut [u8; KEYBOARD_STATE_LEN]) };
+        settings().remap.apply_state(state);
+    }
+
+    hr
+}
+
+fn keyboard_get_device_data(
+    original: GetDeviceDataFn,
+    this: *mut c_void,
+    stride: u32,
+    data: *mut DIDEVICEOBJECTDATA,
+    count: *mut u32,
+    flags: u32,
+) -> HRESULT {
+    let hr = unsafe { original(this, stride, data, count, flags) };
+
+    // A null buffer only asks for (or flushes) the number of pending events.
+    if hr.is_err() || data.is_null() || count.is_null() || (stride as usize) < size_of::<u32>() {
+        return hr;
+    }
+
+    let settings = settings();
+    if settings.remap.is_empty() {
+        return hr;
+    }
+
+    // Events are `stride` bytes apart, and each starts with its key's scan code (`dwOfs`).
+    // Rewrite the scan codes, and compact away events for disabled keys.
+    let base = data as *mut u8;
+    let stride = stride as usize;
+    let mut kept = 0;
+
+    unsafe {
+        for i in 0..*count as usize {
+            let event = base.add(i * stride);
+            let offset = *(event as *const u32);
+
+            // Offsets past the scan code range aren't keys; leave them alone.
+            let offset = match u8::try_from(offset) {
+                Ok(key) => match settings.remap.map_key(key) {
+                    Some(key) => key as u32,
+                    None => continue,
+                },
+                Err(_) => offset,
+            };
+
+            let dest = base.add(kept * stride);
+            if dest != event {
+                std::ptr::copy_nonoverlapping(event, dest, stride);
+            }
+            *(dest as *mut u32) = offset;
+            kept += 1;
+        }
+
+        *count = kept as u32;
+    }
+
+    hr
+}
+
+fn mouse_get_device_state(original: GetDeviceStateFn, this: *mut c_void, len: u32, data: *mut c_void) -> HRESULT {
+    let hr = unsafe { original(this, len, data) };
+
+    // `DIMOUSESTATE` and `DIMOUSESTATE2` both start with the three axes.
+    if hr.is_ok() && len as usize >= 3 * size_of::<i32>() && !data.is_null() {
+        let axes = unsafe { *(data as *const [i32; 3]) };
+        for (total, delta) in MOUSE_DELTA.iter().zip(axes) {
+            total.fetch_add(delta as i64, Ordering::Relaxed);
+        }
+    }
+
+    hr
+}
+
+fn mouse_get_device_data(
+    original: GetDeviceDataFn,
+    this: *mut c_void,
+    stride: u32,
+    data: *mut DIDEVICEOBJECTDATA,
+    count: *mut u32,
+    flags: u32,
+) -> HRESULT {
+    let hr = unsafe { original(this, stride, data, count, flags) };
+
+    // Peeked events will be read again, so only count them once they're consumed.
+    if hr.is_err() || data.is_null() || count.is_null() || flags & DIGDD_PEEK != 0 {
+        return hr;
+    }
+    if (stride as usize) < 2 * size_of::<u32>() {
+        return hr;
+    }
+
+    unsafe {
+        for i in 0..*count as usize {
+            // `dwOfs` and `dwData`, the first two fields of every event.
+            let event = (data as *const u8).add(i * stride as usize) as *const [u32; 2];
+            let [offset, value] = *event;
+
+            if let Some(axis) = MOUSE_AXES.iter().position(|&o| o == offset) {
+                MOUSE_DELTA[axis].fetch_add(value as i32 as i64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    hr
+}
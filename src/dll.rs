@@ -1,35 +1,61 @@
+#[cfg(windows)]
 use std::os::windows::fs::FileExt;
-use std::{ops::Range, fs::File};
+#[cfg(windows)]
+use std::{fs::File, path::PathBuf};
+use std::ops::Range;
 use std::sync::OnceLock;
 
-use crate::{post_udk_init, udk_log};
+#[cfg(windows)]
+use anyhow::Context;
+#[cfg(windows)]
+use libloading::Library;
+
+#[cfg(windows)]
+use crate::{config, crash, post_udk_init, pre_unload, proxy, shutdown, udk_log};
+#[cfg(windows)]
 use sha2::{Digest, Sha256};
 
+#[cfg(windows)]
 use windows::{
     Win32::{
         Foundation::{HANDLE, HINSTANCE},
         System::{
             LibraryLoader::GetModuleHandleA,
             ProcessStatus::{K32GetModuleInformation, MODULEINFO},
+            SystemInformation::GetSystemDirectoryW,
             SystemServices::{
                 DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH, DLL_THREAD_ATTACH, DLL_THREAD_DETACH
             },
-            Threading::GetCurrentProcess,
+            Threading::{GetCurrentProcess, GetCurrentThreadId},
         },
     },
     core::Error,
 };
 
+#[cfg(windows)]
 #[no_mangle]
-pub extern "stdcall" fn DllMain(_hinst_dll: HINSTANCE, fdw_reason: u32, _lpv_reserved: usize) -> i32 {
+pub extern "system" fn DllMain(hinst_dll: HINSTANCE, fdw_reason: u32, lpv_reserved: usize) -> i32 {
     match fdw_reason {
         DLL_PROCESS_ATTACH => {
-            dll_attach();
+            // Forwarded exports have to work even if the rest of the library fails to initialize.
+            let proxy_result = proxy::init();
+
+            dll_attach(hinst_dll);
+            if let Err(error) = proxy_result {
+                udk_log::log(udk_log::LogType::Error, &format!("An error occurred forwarding {}: {:#}", proxy::PROXY_DLL, error))
+            }
             if let Err(error) = post_udk_init() {
                 udk_log::log(udk_log::LogType::Error, &format!("An error occurred initializing the library: {}", error))
             }
         }
-        DLL_PROCESS_DETACH => {}
+        DLL_PROCESS_DETACH => {
+            // A non-null lpvReserved means the process is terminating rather than unloading us.
+            // Every other thread is already gone and the heap may be in any state, so the
+            // right thing to do is nothing at all and let the OS tear everything down.
+            if lpv_reserved == 0 {
+                dll_detach();
+            }
+        }
 
         DLL_THREAD_ATTACH => {}
         DLL_THREAD_DETACH => {}
@@ -40,10 +66,32 @@ pub extern "stdcall" fn DllMain(_hinst_dll: HINSTANCE, fdw_reason: u32, _lpv_res
     1
 }
 
+/// Undo everything the extensions set up, so the library can be unloaded from a running game.
+/// Whoever unloads us must call this first, outside of `DllMain`.
+#[cfg(windows)]
+#[no_mangle]
+pub extern "system" fn RenX_Shutdown() {
+    // Nothing was set up if the UDK didn't match our known hash.
+    if UDK_RANGE.get().is_some() {
+        shutdown();
+    }
+}
+
 /// Called upon DLL attach. This function verifies the UDK and initializes
 /// hooks if the UDK matches our known hash.
-fn dll_attach() {
+#[cfg(windows)]
+fn dll_attach(hinst_dll: HINSTANCE) {
     let process = unsafe { GetCurrentProcess() };
+
+    // The UDK loads us from its main thread, which goes on to run the game.
+    GAME_THREAD_ID.set(unsafe { GetCurrentThreadId() }).unwrap();
+
+    let dll_information = get_module_information(process, hinst_dll).expect("Failed to get module information for our DLL");
+    DLL_RANGE.set(Range {
+        start: dll_information.lpBaseOfDll as usize,
+        end: dll_information.lpBaseOfDll as usize + dll_information.SizeOfImage as usize,
+    }).unwrap();
+
     let module: windows::Win32::Foundation::HMODULE = unsafe { GetModuleHandleA(None) }.expect("Couldn't get Module Handle for the UDK process");
 
     let exe_information = get_module_information(process, module.into()).expect("Failed to get module information for UDK");
@@ -78,6 +126,28 @@ fn dll_attach() {
 
     // Cache the UDK slice.
     UDK_RANGE.set(udk_range).unwrap();
+
+    // Logging works from here on, so problems with the configuration can be reported.
+    config::load();
+
+    // From here on, crashes are reported against a known UDK build.
+    if config::get().crash.enabled {
+        if let Err(error) = crash::install() {
+            udk_log::log(udk_log::LogType::Error, &format!("Failed to install the crash handler: {:#}", error));
+        }
+    }
+}
+
+/// Called upon DLL detach when we are being unloaded from a live process. This runs under the
+/// loader lock, so the real teardown is left to `RenX_Shutdown`.
+#[cfg(windows)]
+fn dll_detach() {
+    // If the UDK didn't match our known hash, nothing was installed (and logging isn't available).
+    if UDK_RANGE.get().is_none() {
+        return;
+    }
+
+    pre_unload();
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -95,6 +165,46 @@ const UDK_KNOWN_HASH: [u8; 32] = [
 /// Cached memory range for UDK.exe
 pub static UDK_RANGE: OnceLock<Range<usize>> = OnceLock::new();
 
+/// Cached memory range for our own DLL
+pub static DLL_RANGE: OnceLock<Range<usize>> = OnceLock::new();
+
+/// ID of the UDK's main thread, the only one allowed to touch UObjects.
+#[cfg(windows)]
+static GAME_THREAD_ID: OnceLock<u32> = OnceLock::new();
+
+/// Return whether we're running on the UDK's game thread.
+#[cfg(windows)]
+pub fn is_game_thread() -> bool {
+    GAME_THREAD_ID.get() == Some(&unsafe { GetCurrentThreadId() })
+}
+
+/// Return the hash of the UDK build we matched against.
+pub fn udk_build_hash() -> &'static [u8; 32] {
+    &UDK_KNOWN_HASH
+}
+
+/// Return `len` bytes of UDK.exe as it is on disk, starting at `offset` from the image base.
+/// The on-disk `.text` is what we hashed, so this is the code before anyone patched it.
+#[cfg(windows)]
+pub fn udk_file_bytes(offset: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+    let filemap = pelite::FileMap::open(&std::env::current_exe()?).context("failed to map UDK.exe")?;
+    let pefile = pelite::PeFile::from_bytes(&filemap).context("failed to parse UDK.exe")?;
+
+    let rva = u32::try_from(offset).context("offset lies outside of UDK.exe")?;
+    let section = pefile
+        .section_headers()
+        .by_rva(rva)
+        .with_context(|| format!("offset {offset:#X} is not in any section of UDK.exe"))?;
+
+    let start = (section.file_range().start + (rva - section.VirtualAddress)) as usize;
+    let end = start + len;
+    if end > section.file_range().end as usize {
+        anyhow::bail!("{len} bytes at offset {offset:#X} run past the end of {}", section.name().unwrap_or("?"));
+    }
+
+    Ok(filemap.as_ref()[start..end].to_vec())
+}
+
 /// Return the base pointer for UDK.exe
 pub fn get_udk_ptr() -> *const u8 {
     let range = UDK_RANGE.get().unwrap();
@@ -104,6 +214,7 @@ pub fn get_udk_ptr() -> *const u8 {
 }
 
 /// Wrapped version of the Win32 GetModuleInformation.
+#[cfg(windows)]
 fn get_module_information(process: HANDLE, module: HINSTANCE) -> windows::core::Result<MODULEINFO> {
     let mut module_info = MODULEINFO {
         ..Default::default()
@@ -121,4 +232,20 @@ fn get_module_information(process: HANDLE, module: HINSTANCE) -> windows::core::
         true => Ok(module_info),
         false => Err(Error::from_win32()),
     }
-}
\ No newline at end of file
+}
+
+/// Load a DLL by full path from the system directory. This never goes through the normal
+/// search order, which would find our own proxy DLL in the game directory first.
+#[cfg(windows)]
+pub fn load_system_library(name: &str) -> anyhow::Result<Library> {
+    let mut buf = [0u16; 260];
+    let len = unsafe { GetSystemDirectoryW(Some(&mut buf)) } as usize;
+    if len == 0 || len > buf.len() {
+        return Err(Error::from_win32()).context("failed to locate the system directory");
+    }
+
+    let path = PathBuf::from(String::from_utf16_lossy(&buf[..len])).join(name);
+
+    // SAFETY: This is a system DLL, loaded by full path.
+    unsafe { Library::new(&path) }.with_context(|| format!("failed to load {}", path.display()))
+}
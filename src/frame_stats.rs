@@ -0,0 +1,414 @@
+//! This module measures frame times from the engine tick, and correlates hitches with the audio
+//! engine's load.
+//!
+//! Frame times are the wall-clock time between ticks, which includes everything the main loop
+//! does, not just the engine's `DeltaSeconds` (which can be clamped or smoothed). Level loads
+//! count as hitches too. Each frame also queries XAudio's performance data for the share of
+//! time its processing thread was busy and for new glitches. If hitches come with a higher
+//! audio load than the rest of the session, or with glitches, they're likely audio-related.
+//!
+//! `renx stats frames` logs the statistics, and `renx stats reset` starts over.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(windows)]
+use crate::console_commands::{self, no_args};
+#[cfg(windows)]
+use crate::udk_log::{log_ratelimited, LogType};
+#[cfg(windows)]
+use crate::{audio_stats, udk_tick};
+
+/// Frames taking longer than this are hitches.
+pub const HITCH_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// Histogram resolution, and the longest frame it tells apart.
+const BUCKET_WIDTH: Duration = Duration::from_micros(100);
+const BUCKETS: usize = 10_000;
+
+/// A histogram of frame times.
+pub struct Histogram {
+    /// Frames per [`BUCKET_WIDTH`]. The last bucket also counts anything longer.
+    buckets: Box<[u32]>,
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS].into_boxed_slice(),
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, frame_time: Duration) {
+        let bucket = (frame_time.as_nanos() / BUCKET_WIDTH.as_nanos()) as usize;
+        let bucket = &mut self.buckets[bucket.min(BUCKETS - 1)];
+        *bucket = bucket.saturating_add(1);
+
+        self.count += 1;
+        self.total += frame_time;
+        self.max = self.max.max(frame_time);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.total.div_f64(count as f64),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The frame time `p` (0 to 1) of frames are at or below, to the histogram's resolution.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let rank = ((p.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (i, &frames) in self.buckets.iter().enumerate() {
+            seen += u64::from(frames);
+            if seen >= rank {
+                // Report the bucket's upper edge, but never more than what was actually seen.
+                // The last bucket has no upper edge, so it reports the longest frame.
+                if i == BUCKETS - 1 {
+                    return self.max;
+                }
+                return (BUCKET_WIDTH * (i as u32 + 1)).min(self.max);
+            }
+        }
+
+        self.max
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the audio engine did during a frame.
+#[derive(Clone, Copy, Debug)]
+pub struct AudioSample {
+    /// The share of the frame XAudio spent processing audio, from 0 to 1.
+    pub load: f64,
+    /// Glitches since the previous frame.
+    pub glitches: u32,
+}
+
+/// The sum and count of audio loads, for averaging.
+#[derive(Default)]
+struct LoadSum {
+    frames: u64,
+    sum: f64,
+}
+
+impl LoadSum {
+    fn add(&mut self, load: f64) {
+        self.frames += 1;
+        self.sum += load;
+    }
+
+    fn mean(&self) -> Option<f64> {
+        (self.frames > 0).then(|| self.sum / self.frames as f64)
+    }
+}
+
+/// Frame statistics for a session.
+pub struct FrameStats {
+    histogram: Histogram,
+    hitch_threshold: Duration,
+    hitches: u64,
+    /// Hitches during which the audio engine glitched.
+    glitching_hitches: u64,
+    /// Audio load over frames that weren't hitches, and over those that were.
+    load: LoadSum,
+    hitch_load: LoadSum,
+}
+
+impl FrameStats {
+    pub fn new(hitch_threshold: Duration) -> Self {
+        Self {
+            histogram: Histogram::new(),
+            hitch_threshold,
+            hitches: 0,
+            glitching_hitches: 0,
+            load: LoadSum::default(),
+            hitch_load: LoadSum::default(),
+        }
+    }
+
+    /// Record a frame, returning whether it was a hitch.
+    pub fn record(&mut self, frame_time: Duration, audio: Option<AudioSample>) -> bool {
+        self.histogram.record(frame_time);
+
+        let hitch = frame_time > self.hitch_threshold;
+        if hitch {
+            self.hitches += 1;
+        }
+
+        if let Some(audio) = audio {
+            match hitch {
+                true => self.hitch_load.add(audio.load),
+                false => self.load.add(audio.load),
+            }
+            if hitch && audio.glitches > 0 {
+                self.glitching_hitches += 1;
+            }
+        }
+
+        hitch
+    }
+
+    /// Describe the statistics, one line each.
+    pub fn summary(&self) -> Vec<String> {
+        let h = &self.histogram;
+        if h.count() == 0 {
+            return vec!["No frames recorded".to_string()];
+        }
+
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let mut lines = vec![
+            format!(
+                "{} frames: mean {:.1} ms, p50 {:.1} ms, p95 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+                h.count(),
+                ms(h.mean()),
+                ms(h.percentile(0.50)),
+                ms(h.percentile(0.95)),
+                ms(h.percentile(0.99)),
+                ms(h.max()),
+            ),
+            format!(
+                "{} hitches over {} ms ({:.2}% of frames)",
+                self.hitches,
+                self.hitch_threshold.as_millis(),
+                self.hitches as f64 * 100.0 / h.count() as f64,
+            ),
+        ];
+
+        match (self.load.mean(), self.hitch_load.mean()) {
+            (None, None) => lines.push("No audio data".to_string()),
+            (load, hitch_load) => {
+                let percent = |l: Option<f64>| l.map_or("-".to_string(), |l| format!("{:.1}%", l * 100.0));
+                lines.push(format!(
+                    "Audio load: {} in normal frames, {} in hitches; audio glitched during {} hitches",
+                    percent(load),
+                    percent(hitch_load),
+                    self.glitching_hitches,
+                ));
+            }
+        }
+
+        lines
+    }
+}
+
+struct State {
+    stats: FrameStats,
+    last_tick: Option<Instant>,
+    last_glitches: Option<u32>,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            stats: FrameStats::new(HITCH_THRESHOLD),
+            last_tick: None,
+            last_glitches: None,
+        }
+    }
+
+    /// Account for a tick at `now`. `audio` is XAudio's load over the frame and its glitch count
+    /// since the engine started, if it's running. Returns the frame time if the frame was a
+    /// hitch, and what the audio engine did during the frame.
+    fn tick(&mut self, now: Instant, audio: Option<(f64, u32)>) -> (Option<Duration>, Option<AudioSample>) {
+        let frame_time = self.last_tick.replace(now).map(|last| now - last);
+
+        let audio = audio.map(|(load, glitches)| {
+            // A counter that went backwards was reset, and everything it counts is new.
+            let previous = self.last_glitches.replace(glitches).unwrap_or(glitches);
+            AudioSample {
+                load,
+                glitches: glitches.checked_sub(previous).unwrap_or(glitches),
+            }
+        });
+
+        let hitch = frame_time.is_some_and(|t| self.stats.record(t, audio));
+        (frame_time.filter(|_| hitch), audio)
+    }
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn with_state<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    f(state.get_or_insert_with(State::new))
+}
+
+/// Describe the session's frame statistics, one line each.
+pub fn dump() -> Vec<String> {
+    with_state(|state| state.stats.summary())
+}
+
+/// Forget the statistics so far.
+pub fn reset() {
+    with_state(|state| state.stats = FrameStats::new(HITCH_THRESHOLD));
+}
+
+/// Start measuring frames.
+#[cfg(windows)]
+pub fn init() -> anyhow::Result<()> {
+    udk_tick::subscribe(tick);
+
+    console_commands::register("stats frames", "", "show frame time statistics", |args| {
+        no_args(args)?;
+        Ok(dump())
+    })?;
+    console_commands::register("stats reset", "", "reset frame time statistics", |args| {
+        no_args(args)?;
+        reset();
+        Ok(vec!["Frame statistics reset".to_string()])
+    })?;
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn tick(_delta_seconds: f32) {
+    let now = Instant::now();
+    let perf = audio_stats::sample().map(|perf| (perf.load(), perf.glitches));
+    let (hitch, audio) = with_state(|state| state.tick(now, perf));
+
+    if let Some(frame_time) = hitch {
+        log_ratelimited!(
+            LogType::Warning,
+            "Hitch: frame {} took {:.1} ms{}",
+            udk_tick::frame(),
+            frame_time.as_secs_f64() * 1000.0,
+            audio.map_or(String::new(), |a| format!(
+                " (audio load {:.1}%, {} glitches)",
+                a.load * 100.0,
+                a.glitches
+            ))
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn histogram(frame_times: &[Duration]) -> Histogram {
+        let mut h = Histogram::new();
+        frame_times.iter().for_each(|&t| h.record(t));
+        h
+    }
+
+    #[test]
+    fn percentiles_of_a_single_frame() {
+        let h = histogram(&[Duration::from_micros(16_650)]);
+        // The bucket's upper edge would be 16.7 ms, more than the frame ever took.
+        for p in [0.0, 0.5, 0.99, 1.0] {
+            assert_eq!(h.percentile(p), Duration::from_micros(16_650), "p{p}");
+        }
+        assert_eq!(h.mean(), Duration::from_micros(16_650));
+    }
+
+    #[test]
+    fn percentiles_of_equal_frames() {
+        let h = histogram(&[Duration::from_micros(16_650); 100]);
+        assert_eq!(h.percentile(0.01), h.percentile(1.0));
+        assert_eq!(h.percentile(0.5), Duration::from_micros(16_650));
+        assert_eq!(h.mean(), Duration::from_micros(16_650));
+    }
+
+    #[test]
+    fn percentiles_round_up_to_the_bucket() {
+        // 90 frames of 10 ms, 10 frames of 40 ms.
+        let mut frames = vec![10 * MS; 90];
+        frames.extend([40 * MS; 10]);
+        let h = histogram(&frames);
+
+        assert_eq!(h.percentile(0.5), 10 * MS + BUCKET_WIDTH);
+        assert_eq!(h.percentile(0.9), 10 * MS + BUCKET_WIDTH);
+        assert_eq!(h.percentile(0.91), 40 * MS);
+        assert_eq!(h.max(), 40 * MS);
+        assert_eq!(h.mean(), 13 * MS);
+    }
+
+    #[test]
+    fn empty_histogram() {
+        let h = Histogram::new();
+        assert_eq!(h.count(), 0);
+        assert_eq!(h.mean(), Duration::ZERO);
+        assert_eq!(h.percentile(0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn long_frames_land_in_the_last_bucket() {
+        let longest = BUCKET_WIDTH * BUCKETS as u32;
+        let h = histogram(&[MS, longest, 10 * longest]);
+        assert_eq!(h.buckets[BUCKETS - 1], 2);
+        assert_eq!(h.percentile(0.3), MS + BUCKET_WIDTH);
+        assert_eq!(h.percentile(0.5), 10 * longest);
+        assert_eq!(h.percentile(1.0), 10 * longest);
+    }
+
+    #[test]
+    fn counts_hitches_over_the_threshold() {
+        let mut stats = FrameStats::new(50 * MS);
+        let quiet = AudioSample { load: 0.1, glitches: 0 };
+        let glitch = AudioSample { load: 0.5, glitches: 2 };
+
+        assert!(!stats.record(16 * MS, Some(quiet)));
+        assert!(!stats.record(50 * MS, Some(quiet)));
+        assert!(stats.record(51 * MS, Some(glitch)));
+        assert!(stats.record(200 * MS, Some(quiet)));
+        assert!(stats.record(200 * MS, None));
+
+        assert_eq!((stats.hitches, stats.glitching_hitches), (3, 1));
+        assert_eq!(stats.load.mean(), Some(0.1));
+        assert_eq!(stats.hitch_load.mean(), Some(0.3));
+        assert_eq!(stats.histogram.count(), 5);
+    }
+
+    #[test]
+    fn tick_measures_between_ticks_and_counts_new_glitches() {
+        let mut state = State::new();
+        let start = Instant::now();
+
+        // The first tick only starts the clock.
+        assert!(matches!(state.tick(start, Some((0.1, 5))), (None, Some(AudioSample { glitches: 0, .. }))));
+        assert_eq!(state.stats.histogram.count(), 0);
+
+        let (hitch, audio) = state.tick(start + 16 * MS, Some((0.1, 7)));
+        assert_eq!(hitch, None);
+        assert_eq!(audio.unwrap().glitches, 2);
+
+        // Frames without audio don't lose track of the glitch count.
+        state.tick(start + 32 * MS, None);
+        let (hitch, audio) = state.tick(start + 132 * MS, Some((0.6, 8)));
+        assert_eq!(hitch, Some(100 * MS));
+        assert_eq!(audio.unwrap().glitches, 1);
+
+        // A restarted engine starts counting from zero, and glitched once since.
+        let (_, audio) = state.tick(start + 148 * MS, Some((0.1, 1)));
+        assert_eq!(audio.unwrap().glitches, 1);
+        let (_, audio) = state.tick(start + 164 * MS, Some((0.1, 3)));
+        assert_eq!(audio.unwrap().glitches, 2);
+
+        assert_eq!(state.stats.histogram.count(), 5);
+        assert_eq!((state.stats.hitches, state.stats.glitching_hitches), (1, 1));
+    }
+}
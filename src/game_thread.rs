@@ -0,0 +1,193 @@
+//! This module runs work on the game thread on behalf of other threads.
+//!
+//! UObjects may only be touched on the game thread: XAudio's callbacks, the configuration
+//! watcher and other threads of ours would race the engine and its garbage collector. Instead
+//! they [`post`] closures here, which run at the start of a frame from the engine tick.
+//!
+//! Each frame spends at most [`FRAME_BUDGET`] on queued work, so a burst of tasks is spread
+//! over several frames instead of causing a hitch. At least one task runs every frame.
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+
+#[cfg(windows)]
+pub use crate::dll::is_game_thread;
+use crate::udk_log::{log_ratelimited, LogType};
+#[cfg(windows)]
+use crate::udk_tick;
+
+/// Time each frame may spend on queued work.
+pub const FRAME_BUDGET: Duration = Duration::from_millis(2);
+
+/// Warn when this many tasks are waiting, since something is posting faster than we run.
+const BACKLOG_WARNING: usize = 1000;
+
+type Task = Box<dyn FnOnce() + Send>;
+type Queue = Mutex<VecDeque<(&'static str, Task)>>;
+
+static QUEUE: Queue = Mutex::new(VecDeque::new());
+
+/// Run `task` on the game thread at the start of a coming frame. `name` identifies it in the log.
+///
+/// Tasks run in the order they were posted. Tasks posted before the engine is up wait for it.
+pub fn post(name: &'static str, task: impl FnOnce() + Send + 'static) {
+    let backlog = {
+        let mut queue = QUEUE.lock().unwrap_or_else(|e| e.into_inner());
+        queue.push_back((name, Box::new(task)));
+        queue.len()
+    };
+
+    if backlog >= BACKLOG_WARNING {
+        log_ratelimited!(LogType::Warning, "{} game thread tasks are waiting", backlog);
+    }
+}
+
+/// Run `task` now if this is the game thread, and [`post`] it otherwise.
+#[cfg(windows)]
+pub fn run(name: &'static str, task: impl FnOnce() + Send + 'static) {
+    match is_game_thread() {
+        true => run_task(name, Box::new(task), Instant::now),
+        false => post(name, task),
+    }
+}
+
+/// Fail unless this is the game thread.
+#[cfg(windows)]
+pub fn require_game_thread() -> anyhow::Result<()> {
+    match is_game_thread() {
+        true => Ok(()),
+        false => bail!("only allowed on the game thread"),
+    }
+}
+
+/// Start running queued tasks.
+#[cfg(windows)]
+pub fn init() {
+    udk_tick::subscribe(drain);
+}
+
+/// Run queued tasks until the frame's budget is spent.
+#[cfg(windows)]
+fn drain(_delta_seconds: f32) {
+    drain_queue(&QUEUE, Instant::now);
+}
+
+/// Run tasks from `queue` until the frame's budget is spent, by `clock`.
+fn drain_queue(queue: &Queue, clock: impl Fn() -> Instant + Copy) {
+    let start = clock();
+
+    while clock() - start < FRAME_BUDGET {
+        // Not held while the task runs, since it may post more.
+        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+        let Some((name, task)) = next else {
+            return;
+        };
+
+        run_task(name, task, clock);
+    }
+}
+
+fn run_task(name: &'static str, task: Task, clock: impl Fn() -> Instant) {
+    let start = clock();
+    if catch_unwind(AssertUnwindSafe(task)).is_err() {
+        log_ratelimited!(LogType::Error, "Game thread task {} panicked", name);
+    }
+
+    let elapsed = clock() - start;
+    if elapsed > FRAME_BUDGET {
+        log_ratelimited!(
+            LogType::Warning,
+            "Game thread task {} took {:.1} ms, over the {} ms frame budget",
+            name,
+            elapsed.as_secs_f64() * 1000.0,
+            FRAME_BUDGET.as_millis()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// A task queue, and a clock that only moves when a task says it took a while.
+    struct Frame {
+        queue: Queue,
+        start: Instant,
+        elapsed_ms: Arc<AtomicU64>,
+        ran: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Frame {
+        fn new() -> Self {
+            Self {
+                queue: Mutex::new(VecDeque::new()),
+                start: Instant::now(),
+                elapsed_ms: Arc::default(),
+                ran: Arc::default(),
+            }
+        }
+
+        /// Queue a task named `name` that takes `ms` milliseconds.
+        fn post(&self, name: &'static str, ms: u64) {
+            let (elapsed_ms, ran) = (self.elapsed_ms.clone(), self.ran.clone());
+            let task: Task = Box::new(move || {
+                elapsed_ms.fetch_add(ms, Ordering::SeqCst);
+                ran.lock().unwrap().push(name);
+            });
+            self.queue.lock().unwrap().push_back((name, task));
+        }
+
+        /// Run a frame's worth of tasks, returning the ones that ran.
+        fn drain(&self) -> Vec<&'static str> {
+            let (start, elapsed_ms) = (self.start, &*self.elapsed_ms);
+            drain_queue(&self.queue, || start + Duration::from_millis(elapsed_ms.load(Ordering::SeqCst)));
+            std::mem::take(&mut *self.ran.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn runs_tasks_in_order_within_the_budget() {
+        let frame = Frame::new();
+        for name in ["a", "b", "c", "d", "e"] {
+            frame.post(name, 1);
+        }
+
+        // A 2 ms budget fits two 1 ms tasks a frame.
+        assert_eq!(frame.drain(), ["a", "b"]);
+        assert_eq!(frame.drain(), ["c", "d"]);
+        assert_eq!(frame.drain(), ["e"]);
+        assert!(frame.drain().is_empty());
+    }
+
+    #[test]
+    fn defers_the_rest_after_a_task_over_budget() {
+        let frame = Frame::new();
+        frame.post("slow", 5);
+        frame.post("a", 0);
+        frame.post("b", 0);
+
+        // The slow task still runs, since at least one does every frame.
+        assert_eq!(frame.drain(), ["slow"]);
+        assert_eq!(frame.drain(), ["a", "b"]);
+    }
+
+    #[test]
+    fn queues_tasks_posted_by_tasks_behind_the_rest() {
+        let frame = Arc::new(Frame::new());
+        frame.post("a", 0);
+        let inner = frame.clone();
+        frame.queue.lock().unwrap().push_back(("poster", Box::new(move || inner.post("posted", 0))));
+        frame.post("b", 0);
+        frame.post("slow", 3);
+        frame.post("c", 0);
+
+        // Whatever a task posts goes to the back of the queue.
+        assert_eq!(frame.drain(), ["a", "b", "slow"]);
+        assert_eq!(frame.drain(), ["c", "posted"]);
+    }
+}
@@ -0,0 +1,518 @@
+//! This module contains the hook manager, a central registry of every detour and patch we
+//! install into the UDK.
+//!
+//! Each hook is declared with a name, a target, and how it patches that target. Hooks are
+//! registered once and can then be enabled and disabled by name or all at once.
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Context};
+use retour::{Function, StaticDetour};
+
+use crate::dll::UDK_RANGE;
+use crate::iat;
+
+/// The kind of patch a hook applies.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    /// Inline detour of a function's prologue.
+    Detour,
+    /// Overwritten function pointer, such as an import slot.
+    Pointer,
+    /// Overwritten vtable slot.
+    Vtable,
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HookKind::Detour => "detour",
+            HookKind::Pointer => "pointer",
+            HookKind::Vtable => "vtable",
+        })
+    }
+}
+
+/// Where a hook's target lives.
+#[derive(Clone, Copy)]
+pub enum Target {
+    /// Offset from the beginning of UDK.exe.
+    UdkOffset(usize),
+    /// Absolute address.
+    Address(usize),
+    /// Slot `index` of the vtable at `vtable`.
+    VtableSlot { vtable: usize, index: usize },
+    /// The UDK's IAT slot for `function` imported from `dll`.
+    Import {
+        dll: &'static str,
+        function: &'static str,
+    },
+}
+
+impl Target {
+    /// Resolve the target to an absolute address.
+    pub fn resolve(&self) -> anyhow::Result<usize> {
+        match *self {
+            Target::UdkOffset(offset) => {
+                let udk = UDK_RANGE.get().context("UDK range is not known yet")?;
+                let address = udk.start + offset;
+                if !udk.contains(&address) {
+                    bail!("offset {offset:#X} lies outside of UDK.exe");
+                }
+                Ok(address)
+            }
+            Target::Address(address) => Ok(address),
+            Target::VtableSlot { vtable, index } => {
+                Ok(vtable + index * std::mem::size_of::<usize>())
+            }
+            Target::Import { dll, function } => iat::udk_slot(dll, function),
+        }
+    }
+}
+
+/// Type-erased view of a `retour` detour.
+pub trait Detour: Sync {
+    unsafe fn enable(&self) -> retour::Result<()>;
+    unsafe fn disable(&self) -> retour::Result<()>;
+
+    /// Address of the trampoline to the original function, for detours that aren't called
+    /// through a typed static.
+    fn trampoline(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<T: Function> Detour for StaticDetour<T> {
+    unsafe fn enable(&self) -> retour::Result<()> {
+        StaticDetour::enable(self)
+    }
+
+    unsafe fn disable(&self) -> retour::Result<()> {
+        StaticDetour::disable(self)
+    }
+}
+
+/// A detour whose signature is only known at runtime, such as one requested by a plugin.
+pub struct RawDetour(retour::RawDetour);
+
+// SAFETY: `enable` and `disable` are only ever called with the hook registry locked.
+unsafe impl Sync for RawDetour {}
+
+impl Detour for RawDetour {
+    unsafe fn enable(&self) -> retour::Result<()> {
+        self.0.enable()
+    }
+
+    unsafe fn disable(&self) -> retour::Result<()> {
+        self.0.disable()
+    }
+
+    fn trampoline(&self) -> Option<usize> {
+        Some(self.0.trampoline() as *const () as usize)
+    }
+}
+
+type DetourInit = Box<dyn FnOnce(usize) -> retour::Result<&'static dyn Detour> + Send>;
+
+enum Patch {
+    /// A detour that is initialized against the resolved target on registration.
+    Detour {
+        init: Option<DetourInit>,
+        detour: Option<&'static dyn Detour>,
+    },
+    /// A pointer-sized slot that is overwritten with `replacement`.
+    Slot {
+        replacement: usize,
+        original: Option<usize>,
+    },
+}
+
+/// A hook declaration. Hand it to [`register`] or [`install`] to make it live.
+pub struct Hook {
+    name: &'static str,
+    kind: HookKind,
+    target: Target,
+    address: usize,
+    expected: Option<Cow<'static, [u8]>>,
+    patch: Patch,
+    enabled: bool,
+}
+
+impl Hook {
+    /// Declare an inline detour. `init` is handed the resolved target address and must
+    /// initialize (but not enable) the detour.
+    pub fn detour<D: Detour + 'static>(
+        name: &'static str,
+        target: Target,
+        init: impl FnOnce(usize) -> retour::Result<&'static D> + Send + 'static,
+    ) -> Self {
+        let init: DetourInit = Box::new(move |address| init(address).map(|d| d as &'static dyn Detour));
+        Self::new(name, HookKind::Detour, target, Patch::Detour { init: Some(init), detour: None })
+    }
+
+    /// Declare an inline detour of the function at `target` to `replacement`, whose signature
+    /// the caller vouches for. The trampoline to the original is available from [`original`].
+    pub fn raw_detour(name: &'static str, target: Target, replacement: usize) -> Self {
+        Self::detour(name, target, move |address| {
+            let detour = unsafe { retour::RawDetour::new(address as *const (), replacement as *const ())? };
+            Ok(&*Box::leak(Box::new(RawDetour(detour))))
+        })
+    }
+
+    /// Declare a patch of a pointer-sized slot (for example, an import or function pointer).
+    pub fn pointer(name: &'static str, target: Target, replacement: usize) -> Self {
+        Self::new(name, HookKind::Pointer, target, Patch::Slot { replacement, original: None })
+    }
+
+    /// Declare a patch of the UDK's IAT slot for `function` imported from `dll`.
+    /// The original import is saved and can be retrieved with [`original`].
+    pub fn import(
+        name: &'static str,
+        dll: &'static str,
+        function: &'static str,
+        replacement: usize,
+    ) -> Self {
+        Self::pointer(name, Target::Import { dll, function }, replacement)
+    }
+
+    /// Declare a patch of a single vtable slot.
+    pub fn vtable(name: &'static str, vtable: usize, index: usize, replacement: usize) -> Self {
+        Self::new(
+            name,
+            HookKind::Vtable,
+            Target::VtableSlot { vtable, index },
+            Patch::Slot { replacement, original: None },
+        )
+    }
+
+    fn new(name: &'static str, kind: HookKind, target: Target, patch: Patch) -> Self {
+        Self {
+            name,
+            kind,
+            target,
+            address: 0,
+            expected: None,
+            patch,
+            enabled: false,
+        }
+    }
+
+    /// Refuse to enable the hook unless the target starts with these bytes.
+    pub fn with_expected_bytes(mut self, expected: impl Into<Cow<'static, [u8]>>) -> Self {
+        self.expected = Some(expected.into());
+        self
+    }
+
+    /// The original value of a patched slot, if this hook is a pointer or vtable patch
+    /// and is currently enabled, or the trampoline of a raw detour.
+    pub fn original(&self) -> Option<usize> {
+        match self.patch {
+            Patch::Slot { original, .. } => original,
+            Patch::Detour { detour, .. } => detour.and_then(|d| d.trampoline()),
+        }
+    }
+
+    fn resolve(&mut self) -> anyhow::Result<()> {
+        self.address = self.target.resolve()?;
+
+        if let Patch::Detour { init, detour } = &mut self.patch {
+            let init = init.take().context("detour was already initialized")?;
+            *detour = Some(init(self.address)?);
+        }
+
+        Ok(())
+    }
+
+    fn verify(&self) -> anyhow::Result<()> {
+        let Some(expected) = self.expected.as_deref() else {
+            return Ok(());
+        };
+
+        // SAFETY: The target was resolved against a known UDK build.
+        let actual = unsafe { std::slice::from_raw_parts(self.address as *const u8, expected.len()) };
+        if actual != expected {
+            bail!(
+                "unexpected bytes at {:#X}: expected {:02X?}, found {:02X?}",
+                self.address,
+                expected,
+                actual
+            );
+        }
+
+        Ok(())
+    }
+
+    unsafe fn enable(&mut self) -> anyhow::Result<()> {
+        if self.enabled {
+            return Ok(());
+        }
+
+        self.verify()?;
+
+        match &mut self.patch {
+            Patch::Detour { detour, .. } => {
+                detour.context("detour was never initialized")?.enable()?;
+            }
+            Patch::Slot { replacement, original } => {
+                *original = Some(swap_slot(self.address, None, *replacement)?);
+            }
+        }
+
+        self.enabled = true;
+        Ok(())
+    }
+
+    unsafe fn disable(&mut self) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        match &mut self.patch {
+            Patch::Detour { detour, .. } => {
+                detour.context("detour was never initialized")?.disable()?;
+            }
+            Patch::Slot { replacement, original } => {
+                let saved = original.context("no original value was saved")?;
+
+                // Only restore the slot if it still points at us, so we don't clobber someone else's patch.
+                swap_slot(self.address, Some(*replacement), saved)?;
+                *original = None;
+            }
+        }
+
+        self.enabled = false;
+        Ok(())
+    }
+}
+
+/// Atomically replace the pointer-sized slot at `address` with `new`, returning the old value.
+/// If `current` is provided, the swap only happens if the slot still holds that value.
+unsafe fn swap_slot(address: usize, current: Option<usize>, new: usize) -> anyhow::Result<usize> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let _guard = region::protect_with_handle(
+        address as *const usize,
+        std::mem::size_of::<usize>(),
+        region::Protection::READ_WRITE,
+    )
+    .context("failed to adjust memory protection")?;
+
+    let slot = &*(address as *const AtomicUsize);
+    match current {
+        Some(current) => slot
+            .compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|found| anyhow!("slot at {address:#X} was modified (now {found:#X})")),
+        None => Ok(slot.swap(new, Ordering::SeqCst)),
+    }
+}
+
+/// A snapshot of a registered hook.
+pub struct HookInfo {
+    pub name: &'static str,
+    pub kind: HookKind,
+    pub address: usize,
+    pub enabled: bool,
+}
+
+impl fmt::Display for HookInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} @ {:#X}, {})",
+            self.name,
+            self.kind,
+            self.address,
+            if self.enabled { "enabled" } else { "disabled" }
+        )
+    }
+}
+
+/// Every hook that has been registered, in registration order.
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+fn hooks() -> std::sync::MutexGuard<'static, Vec<Hook>> {
+    HOOKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn with_hook<R>(name: &str, f: impl FnOnce(&mut Hook) -> anyhow::Result<R>) -> anyhow::Result<R> {
+    let mut hooks = hooks();
+    let hook = hooks
+        .iter_mut()
+        .find(|h| h.name == name)
+        .with_context(|| format!("no hook named {name}"))?;
+    f(hook)
+}
+
+/// Register a hook without enabling it. The target is resolved (and detours initialized) here.
+pub fn register(mut hook: Hook) -> anyhow::Result<()> {
+    let mut hooks = hooks();
+    if hooks.iter().any(|h| h.name == hook.name) {
+        bail!("a hook named {} is already registered", hook.name);
+    }
+
+    hook.resolve()
+        .with_context(|| format!("failed to resolve hook {}", hook.name))?;
+    hooks.push(hook);
+    Ok(())
+}
+
+/// Register a hook and enable it immediately.
+pub fn install(hook: Hook) -> anyhow::Result<()> {
+    let name = hook.name;
+    register(hook)?;
+    enable(name)
+}
+
+/// Enable a registered hook.
+pub fn enable(name: &str) -> anyhow::Result<()> {
+    with_hook(name, |hook| unsafe { hook.enable() })
+        .with_context(|| format!("failed to enable hook {name}"))
+}
+
+/// Disable a registered hook, restoring the original code or pointer.
+pub fn disable(name: &str) -> anyhow::Result<()> {
+    with_hook(name, |hook| unsafe { hook.disable() })
+        .with_context(|| format!("failed to disable hook {name}"))
+}
+
+/// Disable a registered hook and drop it from the registry. A hook that fails to disable stays
+/// registered, since it's still live.
+pub fn unregister(name: &str) -> anyhow::Result<()> {
+    let mut hooks = hooks();
+    let index = hooks
+        .iter()
+        .position(|h| h.name == name)
+        .with_context(|| format!("no hook named {name}"))?;
+
+    unsafe { hooks[index].disable() }.with_context(|| format!("failed to disable hook {name}"))?;
+    hooks.remove(index);
+    Ok(())
+}
+
+/// Return the original value of a patched slot, if the named hook is an enabled pointer or vtable patch,
+/// or the trampoline of a raw detour.
+pub fn original(name: &str) -> Option<usize> {
+    with_hook(name, |hook| Ok(hook.original())).ok().flatten()
+}
+
+/// Enable every registered hook. Keeps going past failures and reports all of them.
+pub fn enable_all() -> anyhow::Result<()> {
+    let mut hooks = hooks();
+    let errors = hooks
+        .iter_mut()
+        .filter_map(|hook| {
+            unsafe { hook.enable() }
+                .err()
+                .map(|e| format!("{}: {e:#}", hook.name))
+        })
+        .collect::<Vec<_>>();
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("failed to enable hooks: {}", errors.join("; "))),
+    }
+}
+
+/// Disable every registered hook, in reverse registration order. Keeps going past failures
+/// and reports all of them.
+pub fn disable_all() -> anyhow::Result<()> {
+    let mut hooks = hooks();
+    let errors = hooks
+        .iter_mut()
+        .rev()
+        .filter_map(|hook| {
+            unsafe { hook.disable() }
+                .err()
+                .map(|e| format!("{}: {e:#}", hook.name))
+        })
+        .collect::<Vec<_>>();
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("failed to disable hooks: {}", errors.join("; "))),
+    }
+}
+
+/// List every registered hook.
+pub fn list() -> Vec<HookInfo> {
+    snapshot(&hooks())
+}
+
+/// List every registered hook without blocking. Returns `None` if the registry is busy,
+/// which is what the crash handler wants when the crash happened mid-update.
+pub fn try_list() -> Option<Vec<HookInfo>> {
+    HOOKS.try_lock().ok().map(|hooks| snapshot(&hooks))
+}
+
+fn snapshot(hooks: &[Hook]) -> Vec<HookInfo> {
+    hooks
+        .iter()
+        .map(|h| HookInfo {
+            name: h.name,
+            kind: h.kind,
+            address: h.address,
+            enabled: h.enabled,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A leaked vtable of `len` distinct fake methods.
+    fn fake_vtable(len: usize) -> &'static mut [usize] {
+        Box::leak((0..len).map(|i| 0x1000 + i).collect())
+    }
+
+    #[test]
+    fn vtable_slot_resolves_to_the_entry() {
+        let target = Target::VtableSlot { vtable: 0x4000, index: 3 };
+        assert_eq!(target.resolve().unwrap(), 0x4000 + 3 * std::mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn vtable_hook_swaps_and_restores_the_slot() {
+        let vtable = fake_vtable(4);
+        let address = vtable.as_ptr() as usize;
+        let slot = |index: usize| unsafe { (address as *const usize).add(index).read_volatile() };
+
+        register(Hook::vtable("test/vtable", address, 2, 0xBEEF)).unwrap();
+        assert_eq!(original("test/vtable"), None);
+
+        enable("test/vtable").unwrap();
+        assert_eq!(slot(2), 0xBEEF);
+        assert_eq!(original("test/vtable"), Some(0x1002));
+        assert_eq!([slot(1), slot(3)], [0x1001, 0x1003]);
+
+        disable("test/vtable").unwrap();
+        assert_eq!(slot(2), 0x1002);
+        assert_eq!(original("test/vtable"), None);
+
+        unregister("test/vtable").unwrap();
+    }
+
+    #[test]
+    fn refuses_to_patch_unexpected_bytes() {
+        let slot = fake_vtable(1);
+        let address = slot.as_ptr() as usize;
+        let expected = 0x1000usize.to_ne_bytes();
+        let wrong = 0x2000usize.to_ne_bytes();
+
+        register(Hook::pointer("test/mismatch", Target::Address(address), 0xBEEF).with_expected_bytes(wrong.to_vec()))
+            .unwrap();
+        let error = enable("test/mismatch").unwrap_err();
+        assert!(format!("{error:#}").contains("unexpected bytes"), "{error:#}");
+        assert_eq!(unsafe { (address as *const usize).read_volatile() }, 0x1000);
+        assert!(!list().iter().any(|h| h.name == "test/mismatch" && h.enabled));
+        unregister("test/mismatch").unwrap();
+
+        register(Hook::pointer("test/match", Target::Address(address), 0xBEEF).with_expected_bytes(expected.to_vec()))
+            .unwrap();
+        enable("test/match").unwrap();
+        assert_eq!(unsafe { (address as *const usize).read_volatile() }, 0xBEEF);
+        unregister("test/match").unwrap();
+        assert_eq!(unsafe { (address as *const usize).read_volatile() }, 0x1000);
+    }
+}
@@ -0,0 +1,230 @@
+//! This module contains functionality related to the Import Address Table (IAT).
+//!
+//! Enumeration works on any native-width PE image, whether it's mapped in memory (the UDK)
+//! or read from disk, so it doesn't depend on running inside the game.
+use std::fmt;
+
+use anyhow::Context;
+
+// `pelite::pe` only exists on Windows; pick the native width explicitly so this module
+// also builds (and can be exercised against fixture images) elsewhere.
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32 as pe;
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64 as pe;
+
+use pe::imports::Import;
+use pe::{Pe, PeView, Va};
+
+use crate::dll::get_udk_ptr;
+
+/// How an import is identified in its DLL.
+#[derive(Clone, PartialEq, Eq)]
+pub enum ImportName {
+    Name(String),
+    Ordinal(u16),
+}
+
+impl fmt::Display for ImportName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportName::Name(name) => f.write_str(name),
+            ImportName::Ordinal(ord) => write!(f, "#{ord}"),
+        }
+    }
+}
+
+/// A single imported function.
+#[derive(Clone)]
+pub struct ImportEntry {
+    /// Name of the DLL the function is imported from, as written in the image.
+    pub dll: String,
+    pub name: ImportName,
+    /// RVA of the IAT slot the loader writes the function's address into.
+    pub slot_rva: u32,
+}
+
+impl ImportEntry {
+    /// Whether this entry is `function` imported from `dll`. DLL names are matched
+    /// case-insensitively, with or without the `.dll` extension.
+    pub fn matches(&self, dll: &str, function: &str) -> bool {
+        let strip = |s: &str| {
+            let s = s.to_ascii_lowercase();
+            s.strip_suffix(".dll").map(str::to_owned).unwrap_or(s)
+        };
+
+        strip(&self.dll) == strip(dll) && matches!(&self.name, ImportName::Name(name) if name == function)
+    }
+}
+
+/// Enumerate every function imported by a PE image, grouped by DLL in image order.
+pub fn enumerate<'a, P: Pe<'a>>(pe: P) -> pelite::Result<Vec<ImportEntry>> {
+    let mut entries = Vec::new();
+
+    for desc in pe.imports()? {
+        let dll = desc.dll_name()?.to_string();
+        let first_thunk = desc.image().FirstThunk;
+
+        for (i, import) in desc.int()?.enumerate() {
+            let name = match import? {
+                Import::ByName { name, .. } => ImportName::Name(name.to_string()),
+                Import::ByOrdinal { ord } => ImportName::Ordinal(ord),
+            };
+
+            entries.push(ImportEntry {
+                dll: dll.clone(),
+                name,
+                slot_rva: first_thunk + (i * std::mem::size_of::<Va>()) as u32,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Find the IAT slot for `function` imported from `dll`.
+pub fn find<'a, P: Pe<'a>>(pe: P, dll: &str, function: &str) -> pelite::Result<Option<ImportEntry>> {
+    Ok(enumerate(pe)?.into_iter().find(|e| e.matches(dll, function)))
+}
+
+/// Return a view of the running UDK.exe.
+pub fn udk_view() -> PeView<'static> {
+    // SAFETY: UDK.exe is mapped and verified by the time anyone asks about it.
+    unsafe { PeView::module(get_udk_ptr()) }
+}
+
+/// Return the absolute address of the UDK's IAT slot for `function` imported from `dll`.
+pub fn udk_slot(dll: &str, function: &str) -> anyhow::Result<usize> {
+    let entry = find(udk_view(), dll, function)
+        .context("failed to parse UDK imports")?
+        .with_context(|| format!("UDK does not import {dll}!{function}"))?;
+
+    Ok(get_udk_ptr() as usize + entry.slot_rva as usize)
+}
+
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+
+    /// Build a mapped PE32+ image that imports `imports`, with each DLL's IAT at a known RVA
+    /// (`0x800` plus `0x100` per DLL).
+    fn fixture(imports: &[(&str, &[ImportName])]) -> Vec<u64> {
+        let mut image = vec![0u8; 0x1000];
+        let put = |image: &mut Vec<u8>, at: usize, bytes: &[u8]| image[at..at + bytes.len()].copy_from_slice(bytes);
+
+        // DOS header, pointing at the NT headers.
+        put(&mut image, 0, b"MZ");
+        put(&mut image, 0x3C, &0x40u32.to_le_bytes());
+
+        // NT headers: x64, no sections, one page that's all headers.
+        put(&mut image, 0x40, b"PE\0\0");
+        put(&mut image, 0x44, &0x8664u16.to_le_bytes());
+        put(&mut image, 0x54, &240u16.to_le_bytes());
+        let optional = 0x58;
+        put(&mut image, optional, &0x20Bu16.to_le_bytes());
+        put(&mut image, optional + 56, &0x1000u32.to_le_bytes());
+        put(&mut image, optional + 60, &0x1000u32.to_le_bytes());
+        put(&mut image, optional + 108, &16u32.to_le_bytes());
+        put(&mut image, optional + 120, &0x200u32.to_le_bytes());
+        put(&mut image, optional + 124, &(20 * (imports.len() as u32 + 1)).to_le_bytes());
+
+        // Names go after the descriptors, lookup tables after the IATs.
+        let mut names = 0x300;
+        let mut lookup = 0xC00;
+        for (i, (dll, functions)) in imports.iter().enumerate() {
+            let descriptor = 0x200 + 20 * i;
+            let iat = 0x800 + 0x100 * i;
+
+            put(&mut image, names, dll.as_bytes());
+            put(&mut image, descriptor, &(lookup as u32).to_le_bytes());
+            put(&mut image, descriptor + 12, &(names as u32).to_le_bytes());
+            put(&mut image, descriptor + 16, &(iat as u32).to_le_bytes());
+            names += dll.len() + 1;
+
+            for (j, function) in functions.iter().enumerate() {
+                let thunk = match function {
+                    ImportName::Name(name) => {
+                        // Hint/name entries start with a `u16`.
+                        let hint_name = (names + 1) & !1;
+                        put(&mut image, hint_name + 2, name.as_bytes());
+                        names = hint_name + 2 + name.len() + 1;
+                        hint_name as u64
+                    }
+                    ImportName::Ordinal(ord) => 1 << 63 | *ord as u64,
+                };
+                put(&mut image, lookup + 8 * j, &thunk.to_le_bytes());
+                put(&mut image, iat + 8 * j, &thunk.to_le_bytes());
+            }
+            lookup += 8 * (functions.len() + 1);
+        }
+
+        // `PeView` wants the image aligned.
+        image.chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect()
+    }
+
+    fn name(s: &str) -> ImportName {
+        ImportName::Name(s.to_owned())
+    }
+
+    fn view(image: &[u64]) -> PeView<'_> {
+        use zerocopy::AsBytes;
+
+        PeView::from_bytes(image.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn enumerates_imports_in_image_order() {
+        let image = fixture(&[
+            ("KERNEL32.dll", &[name("GetTickCount"), name("Sleep")]),
+            ("dinput8.dll", &[name("DirectInput8Create")]),
+        ]);
+
+        let entries = enumerate(view(&image)).unwrap();
+        let listed = entries
+            .iter()
+            .map(|e| (e.dll.as_str(), e.name.to_string(), e.slot_rva))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            [
+                ("KERNEL32.dll", "GetTickCount".to_owned(), 0x800),
+                ("KERNEL32.dll", "Sleep".to_owned(), 0x808),
+                ("dinput8.dll", "DirectInput8Create".to_owned(), 0x900),
+            ]
+        );
+    }
+
+    #[test]
+    fn enumerates_ordinal_imports() {
+        let image = fixture(&[("WSOCK32.dll", &[ImportName::Ordinal(23), name("recv")])]);
+
+        let entries = enumerate(view(&image)).unwrap();
+        assert!(entries[0].name == ImportName::Ordinal(23));
+        assert_eq!(entries[0].name.to_string(), "#23");
+        assert_eq!(entries[1].slot_rva, 0x808);
+    }
+
+    #[test]
+    fn finds_imports_ignoring_dll_case_and_extension() {
+        let image = fixture(&[
+            ("USER32.dll", &[name("PeekMessageW"), name("GetMessageW")]),
+            ("KERNEL32.dll", &[name("Sleep")]),
+        ]);
+
+        for dll in ["USER32.dll", "user32.DLL", "user32"] {
+            let entry = find(view(&image), dll, "GetMessageW").unwrap().unwrap();
+            assert_eq!(entry.slot_rva, 0x808);
+        }
+
+        assert!(find(view(&image), "user32", "getmessagew").unwrap().is_none());
+        assert!(find(view(&image), "kernel32", "PeekMessageW").unwrap().is_none());
+        assert!(find(view(&image), "user", "PeekMessageW").unwrap().is_none());
+    }
+
+    #[test]
+    fn ordinals_never_match_names() {
+        let image = fixture(&[("WSOCK32.dll", &[ImportName::Ordinal(23)])]);
+
+        assert!(find(view(&image), "wsock32", "#23").unwrap().is_none());
+    }
+}
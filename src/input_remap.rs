@@ -0,0 +1,350 @@
+//! This module contains the key remapping rules applied to DirectInput keyboard data.
+//!
+//! Everything here is plain data in, plain data out; the DirectInput side lives in `dinput_hooks.rs`.
+//! Keys are identified by their DirectInput scan code (`DIK_*`), which is also what the keyboard
+//! reports in both immediate (`GetDeviceState`) and buffered (`GetDeviceData`) mode.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{bail, Context};
+
+/// Key names accepted in rules, by DirectInput scan code. Names follow the UDK's own key names
+/// where one exists, so rules read the same as `DefaultInput.ini` bindings.
+const KEY_NAMES: &[(&str, u8)] = &[
+    ("Escape", 0x01),
+    ("One", 0x02),
+    ("Two", 0x03),
+    ("Three", 0x04),
+    ("Four", 0x05),
+    ("Five", 0x06),
+    ("Six", 0x07),
+    ("Seven", 0x08),
+    ("Eight", 0x09),
+    ("Nine", 0x0A),
+    ("Zero", 0x0B),
+    ("Underscore", 0x0C),
+    ("Equals", 0x0D),
+    ("BackSpace", 0x0E),
+    ("Tab", 0x0F),
+    ("Q", 0x10),
+    ("W", 0x11),
+    ("E", 0x12),
+    ("R", 0x13),
+    ("T", 0x14),
+    ("Y", 0x15),
+    ("U", 0x16),
+    ("I", 0x17),
+    ("O", 0x18),
+    ("P", 0x19),
+    ("LeftBracket", 0x1A),
+    ("RightBracket", 0x1B),
+    ("Enter", 0x1C),
+    ("LeftControl", 0x1D),
+    ("A", 0x1E),
+    ("S", 0x1F),
+    ("D", 0x20),
+    ("F", 0x21),
+    ("G", 0x22),
+    ("H", 0x23),
+    ("J", 0x24),
+    ("K", 0x25),
+    ("L", 0x26),
+    ("Semicolon", 0x27),
+    ("Quote", 0x28),
+    ("Tilde", 0x29),
+    ("LeftShift", 0x2A),
+    ("Backslash", 0x2B),
+    ("Z", 0x2C),
+    ("X", 0x2D),
+    ("C", 0x2E),
+    ("V", 0x2F),
+    ("B", 0x30),
+    ("N", 0x31),
+    ("M", 0x32),
+    ("Comma", 0x33),
+    ("Period", 0x34),
+    ("Slash", 0x35),
+    ("RightShift", 0x36),
+    ("Multiply", 0x37),
+    ("LeftAlt", 0x38),
+    ("SpaceBar", 0x39),
+    ("CapsLock", 0x3A),
+    ("F1", 0x3B),
+    ("F2", 0x3C),
+    ("F3", 0x3D),
+    ("F4", 0x3E),
+    ("F5", 0x3F),
+    ("F6", 0x40),
+    ("F7", 0x41),
+    ("F8", 0x42),
+    ("F9", 0x43),
+    ("F10", 0x44),
+    ("NumLock", 0x45),
+    ("ScrollLock", 0x46),
+    ("NumPadSeven", 0x47),
+    ("NumPadEight", 0x48),
+    ("NumPadNine", 0x49),
+    ("Subtract", 0x4A),
+    ("NumPadFour", 0x4B),
+    ("NumPadFive", 0x4C),
+    ("NumPadSix", 0x4D),
+    ("Add", 0x4E),
+    ("NumPadOne", 0x4F),
+    ("NumPadTwo", 0x50),
+    ("NumPadThree", 0x51),
+    ("NumPadZero", 0x52),
+    ("Decimal", 0x53),
+    ("F11", 0x57),
+    ("F12", 0x58),
+    ("NumPadEnter", 0x9C),
+    ("RightControl", 0x9D),
+    ("Divide", 0xB5),
+    ("PrintScreen", 0xB7),
+    ("RightAlt", 0xB8),
+    ("Pause", 0xC5),
+    ("Home", 0xC7),
+    ("Up", 0xC8),
+    ("PageUp", 0xC9),
+    ("Left", 0xCB),
+    ("Right", 0xCD),
+    ("End", 0xCF),
+    ("Down", 0xD0),
+    ("PageDown", 0xD1),
+    ("Insert", 0xD2),
+    ("Delete", 0xD3),
+    ("LeftWindows", 0xDB),
+    ("RightWindows", 0xDC),
+    ("Apps", 0xDD),
+];
+
+/// Parse a key name (case-insensitive) or a raw scan code (`0x3A` or `58`).
+pub fn parse_key(s: &str) -> anyhow::Result<u8> {
+    let s = s.trim();
+
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+        return Ok(code);
+    }
+
+    let code = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse::<u8>(),
+    }
+    .with_context(|| format!("unknown key `{s}`"))?;
+
+    if code == 0 {
+        bail!("scan code 0 is not a key");
+    }
+    Ok(code)
+}
+
+/// Return the display name of a scan code.
+pub fn key_name(code: u8) -> String {
+    match KEY_NAMES.iter().find(|&&(_, c)| c == code) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{code:02X}"),
+    }
+}
+
+/// What a remapped key does.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Binding {
+    /// The key reports as another key.
+    Key(u8),
+    /// The key never reports at all.
+    Disabled,
+}
+
+/// A set of key remapping rules. Keys without a rule pass through untouched.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Rules {
+    keys: BTreeMap<u8, Binding>,
+}
+
+impl Rules {
+    pub const fn new() -> Self {
+        Self {
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Parse rules of the form `CapsLock=LeftControl, Insert=None`. Rules are separated by
+    /// commas or semicolons, and `None` disables a key. Each key may only appear once on the left.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut rules = Self::new();
+
+        for rule in s.split([',', ';']).map(str::trim).filter(|r| !r.is_empty()) {
+            let (from, to) = rule
+                .split_once('=')
+                .with_context(|| format!("rule `{rule}` is not of the form `Key=Key`"))?;
+
+            let from = parse_key(from).with_context(|| format!("in rule `{rule}`"))?;
+            let to = match to.trim() {
+                t if t.eq_ignore_ascii_case("None") => Binding::Disabled,
+                t => Binding::Key(parse_key(t).with_context(|| format!("in rule `{rule}`"))?),
+            };
+
+            if rules.keys.insert(from, to).is_some() {
+                bail!("key `{}` is remapped more than once", key_name(from));
+            }
+        }
+
+        Ok(rules)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Map a single key. Returns `None` if the key is disabled.
+    pub fn map_key(&self, key: u8) -> Option<u8> {
+        match self.keys.get(&key) {
+            None => Some(key),
+            Some(Binding::Key(to)) => Some(*to),
+            Some(Binding::Disabled) => None,
+        }
+    }
+
+    /// Apply the rules to a `DIKEYBOARDSTATE`-style buffer, where the high bit of each byte
+    /// marks the key as held.
+    ///
+    /// Rules are applied simultaneously: `A=B, B=A` swaps the two keys, and a key that several
+    /// keys are mapped to is held while any of them is.
+    pub fn apply_state(&self, state: &mut [u8; 256]) {
+        if self.keys.is_empty() {
+            return;
+        }
+
+        let original = *state;
+        for &from in self.keys.keys() {
+            state[from as usize] = 0;
+        }
+        for (&from, binding) in &self.keys {
+            if let Binding::Key(to) = binding {
+                state[*to as usize] |= original[from as usize];
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (&from, binding)) in self.keys.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+
+            match binding {
+                Binding::Key(to) => write!(f, "{}={}", key_name(from), key_name(*to))?,
+                Binding::Disabled => write!(f, "{}=None", key_name(from))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A keyboard state with `keys` held.
+    fn state(keys: &[u8]) -> [u8; 256] {
+        let mut state = [0; 256];
+        for &key in keys {
+            state[key as usize] = 0x80;
+        }
+        state
+    }
+
+    fn held(state: &[u8; 256]) -> Vec<u8> {
+        (0..=255).filter(|&k| state[k as usize] & 0x80 != 0).collect()
+    }
+
+    #[test]
+    fn parses_names_and_scan_codes() {
+        assert_eq!(parse_key("CapsLock").unwrap(), 0x3A);
+        assert_eq!(parse_key(" capslock ").unwrap(), 0x3A);
+        assert_eq!(parse_key("0x3a").unwrap(), 0x3A);
+        assert_eq!(parse_key("0X3A").unwrap(), 0x3A);
+        assert_eq!(parse_key("58").unwrap(), 0x3A);
+
+        assert!(parse_key("0").is_err());
+        assert!(parse_key("0x100").is_err());
+        assert!(parse_key("Hyper").is_err());
+    }
+
+    #[test]
+    fn names_unknown_codes_in_hex() {
+        assert_eq!(key_name(0x1D), "LeftControl");
+        assert_eq!(key_name(0x54), "0x54");
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = Rules::parse("CapsLock=LeftControl; Insert = none,, 0x54=F1").unwrap();
+        assert_eq!(rules.map_key(0x3A), Some(0x1D));
+        assert_eq!(rules.map_key(0xD2), None);
+        assert_eq!(rules.map_key(0x54), Some(0x3B));
+        assert_eq!(rules.map_key(0x1E), Some(0x1E));
+
+        assert!(Rules::parse("").unwrap().is_empty());
+        assert!(Rules::parse("CapsLock").is_err());
+        assert!(Rules::parse("CapsLock=Hyper").is_err());
+        assert!(Rules::parse("CapsLock=A, capslock=B").is_err());
+    }
+
+    #[test]
+    fn displays_parseable_rules() {
+        let rules = Rules::parse("Insert=None, CapsLock=LeftControl, 0x54=F1").unwrap();
+        assert_eq!(rules.to_string(), "CapsLock=LeftControl, 0x54=F1, Insert=None");
+        assert_eq!(Rules::parse(&rules.to_string()).unwrap(), rules);
+    }
+
+    #[test]
+    fn remaps_state() {
+        let rules = Rules::parse("CapsLock=LeftControl, Insert=None").unwrap();
+
+        let mut keys = state(&[0x3A, 0xD2, 0x1E]);
+        rules.apply_state(&mut keys);
+        assert_eq!(held(&keys), [0x1D, 0x1E]);
+
+        // The target key still works on its own.
+        let mut keys = state(&[0x1D]);
+        rules.apply_state(&mut keys);
+        assert_eq!(held(&keys), [0x1D]);
+    }
+
+    #[test]
+    fn swaps_keys_simultaneously() {
+        let rules = Rules::parse("A=S, S=A").unwrap();
+
+        let mut keys = state(&[0x1E]);
+        rules.apply_state(&mut keys);
+        assert_eq!(held(&keys), [0x1F]);
+
+        let mut keys = state(&[0x1E, 0x1F]);
+        rules.apply_state(&mut keys);
+        assert_eq!(held(&keys), [0x1E, 0x1F]);
+    }
+
+    #[test]
+    fn holds_shared_target_while_any_source_is_held() {
+        let rules = Rules::parse("A=LeftShift, S=LeftShift").unwrap();
+
+        let mut keys = state(&[0x1F]);
+        rules.apply_state(&mut keys);
+        assert_eq!(held(&keys), [0x2A]);
+
+        let mut keys = state(&[]);
+        rules.apply_state(&mut keys);
+        assert_eq!(held(&keys), []);
+    }
+
+    #[test]
+    fn empty_rules_leave_state_alone() {
+        let mut keys = state(&[0x3A]);
+        keys[0x10] = 0x01;
+        let before = keys;
+        Rules::new().apply_state(&mut keys);
+        assert_eq!(keys, before);
+    }
+}
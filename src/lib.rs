@@ -1,13 +1,166 @@
+// Only the parts that don't touch the live process build on other platforms, for tests.
+#![cfg_attr(not(windows), allow(dead_code, unused_imports, unused_macros))]
+
+#[cfg(all(windows, feature = "proxy-dinput8"))]
 mod dinput8;
+#[cfg(all(windows, feature = "proxy-dinput8"))]
+mod dinput_hooks;
+#[cfg(windows)]
+mod proxy;
+mod vtable;
+#[cfg(windows)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
+#[allow(clippy::too_many_arguments)]
 mod xaudio27;
 
+mod audio_stats;
+mod config;
+mod console_commands;
+#[cfg(windows)]
+mod crash;
+mod device_filter;
 mod dll;
+mod frame_stats;
+mod game_thread;
+#[cfg(windows)]
+mod hooks;
+mod iat;
+mod input_remap;
+#[cfg(windows)]
+mod plugins;
+mod sigscan;
+#[cfg(windows)]
+mod udk_call;
+#[cfg(windows)]
+mod udk_events;
+mod udk_fname;
+mod udk_ini;
 mod udk_log;
+mod udk_mem;
+mod udk_native;
+mod udk_object;
+mod udk_offsets;
+#[cfg(windows)]
+mod udk_ready;
+#[cfg(windows)]
+mod udk_tick;
+#[cfg(windows)]
 mod udk_xaudio;
+mod voice_budget;
+
+#[cfg(windows)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Push the settings that can change at runtime to the subsystems that use them. Runs at
+/// startup and again whenever the configuration is reloaded.
+#[cfg(windows)]
+fn apply_config(config: &config::Config) {
+    #[cfg(feature = "proxy-dinput8")]
+    dinput_hooks::configure(dinput_hooks::InputSettings {
+        remap: config.input.remap.clone(),
+        devices: config.input.devices.clone(),
+    });
 
+    if config.xaudio.enabled {
+        udk_xaudio::apply_config(&config.xaudio);
+    }
+}
+
+#[cfg(windows)]
 pub fn post_udk_init() -> anyhow::Result<()> {
-    udk_xaudio::init()?;
-    Ok(())
-}
\ No newline at end of file
+    let config = config::get();
+
+    apply_config(&config);
+    // The watcher reloads on its own thread.
+    config::subscribe(|_| game_thread::run("apply config", || apply_config(&config::get())));
+    config::watch();
+
+    // Anything built on reflection waits for the engine to come up.
+    if let Err(error) = udk_ready::init() {
+        udk_log::log(udk_log::LogType::Error, &format!("Failed to watch for engine startup: {:#}", error));
+    }
+    game_thread::init();
+    if let Err(error) = console_commands::init() {
+        udk_log::log(udk_log::LogType::Error, &format!("Failed to set up console commands: {:#}", error));
+    }
+    if let Err(error) = audio_stats::init() {
+        udk_log::log(udk_log::LogType::Error, &format!("Failed to set up audio statistics: {:#}", error));
+    }
+    if let Err(error) = frame_stats::init() {
+        udk_log::log(udk_log::LogType::Error, &format!("Failed to set up frame statistics: {:#}", error));
+    }
+    #[cfg(feature = "proxy-dinput8")]
+    if let Err(error) = dinput_hooks::init() {
+        udk_log::log(udk_log::LogType::Error, &format!("Failed to set up input commands: {:#}", error));
+    }
+
+    let result = match config.xaudio.enabled {
+        true => udk_xaudio::init(),
+        false => {
+            udk_log::log(udk_log::LogType::Init, "XAudio hooks are disabled by configuration");
+            Ok(())
+        }
+    };
+
+    // Plugins come last, so they see our hooks in place. They don't depend on our own
+    // subsystems coming up, but running their code has to wait until we're out of DllMain.
+    if config.plugins.enabled {
+        udk_ready::when_ready("Plugins", || {
+            plugins::load_all();
+            Ok(())
+        });
+    }
+
+    result
+}
+
+/// Set once `shutdown` has run, so detaching doesn't try to undo it again.
+#[cfg(windows)]
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Tear down everything `post_udk_init` installed, ahead of the library being unloaded from a
+/// live process. This waits on our own threads, the XAudio engines' threads and plugins, so it
+/// can't run under the loader lock; whoever is about to unload us calls it through the exported
+/// `RenX_Shutdown` first.
+#[cfg(windows)]
+pub fn shutdown() {
+    if SHUT_DOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    config::stop_watching();
+    plugins::shutdown_all();
+
+    if let Err(error) = hooks::disable_all() {
+        udk_log::log(udk_log::LogType::Error, &format!("An error occurred removing hooks: {:#}", error));
+    }
+
+    // Only now that nothing points into them.
+    plugins::unload_all();
+
+    udk_xaudio::shutdown();
+
+    crash::uninstall();
+}
+
+/// Called when the library is unloaded from a live process, under the loader lock. If
+/// `shutdown` didn't run first, only undo what doesn't wait on other threads: the patches that
+/// would have the UDK call into unmapped code.
+#[cfg(windows)]
+pub fn pre_unload() {
+    if SHUT_DOWN.load(Ordering::SeqCst) {
+        return;
+    }
+
+    udk_log::log(
+        udk_log::LogType::Error,
+        "Unloaded without RenX_Shutdown; plugins, XAudio engines and the config watcher are left running",
+    );
+
+    if let Err(error) = hooks::disable_all() {
+        udk_log::log(udk_log::LogType::Error, &format!("An error occurred removing hooks: {:#}", error));
+    }
+
+    crash::uninstall();
+}
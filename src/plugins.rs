@@ -0,0 +1,358 @@
+//! This module loads plugin DLLs from the `Plugins` folder next to UDK.exe.
+//!
+//! A plugin exports two functions with C linkage:
+//!  * `RenXPlugin_AbiVersion() -> u32`, which must return [`PLUGIN_ABI_VERSION`].
+//!  * `RenXPlugin_Init(const RenXPluginContext*) -> i32`, which returns 0 on success.
+//!
+//! It may also export `RenXPlugin_Shutdown()`, which is called before the extensions unload.
+//! The context (see `include/renx_plugin.h`) describes the matched UDK build and gives the
+//! plugin our logging and hook manager. A plugin that fails to load or initialize has its hooks
+//! removed and is unloaded; the others carry on.
+use std::ffi::{c_char, CStr, CString};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{bail, Context};
+use libloading::Library;
+
+use crate::dll::{get_udk_ptr, udk_build_hash, UDK_RANGE};
+use crate::hooks::{self, Hook, Target};
+use crate::udk_log::{log, LogType};
+
+/// Version of the plugin ABI. Bump this on any incompatible change to [`PluginContext`] or
+/// the exported functions.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// Name of the plugin folder, next to UDK.exe.
+const PLUGIN_DIR: &str = "Plugins";
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type InitFn = unsafe extern "C" fn(*const PluginContext) -> i32;
+type ShutdownFn = unsafe extern "C" fn();
+
+/// Log levels accepted by [`PluginContext::log`]. Anything else (0 by convention) is informational.
+const PLUGIN_LOG_WARNING: u32 = 1;
+const PLUGIN_LOG_ERROR: u32 = 2;
+
+/// The context handed to `RenXPlugin_Init`. Mirrors `RenXPluginContext` in `include/renx_plugin.h`.
+///
+/// The context stays valid for as long as the plugin is loaded. Every function takes the
+/// context as its first argument, and returns 0 on success; failures are logged on the
+/// plugin's behalf.
+#[repr(C)]
+pub struct PluginContext {
+    /// [`PLUGIN_ABI_VERSION`].
+    pub abi_version: u32,
+    /// `sizeof(RenXPluginContext)`, so fields can be appended without bumping the version.
+    pub size: u32,
+    /// File name of the plugin, NUL-terminated.
+    pub plugin_name: *const c_char,
+    /// SHA-256 of the UDK's `.text` section, 32 bytes.
+    pub udk_hash: *const u8,
+    /// Base address and size of UDK.exe in memory.
+    pub udk_base: *const u8,
+    pub udk_size: usize,
+
+    /// Write a NUL-terminated UTF-8 message to the UDK log.
+    pub log: unsafe extern "C" fn(ctx: *const PluginContext, level: u32, message: *const c_char),
+    /// Detour the function at `target` to `replacement`, and enable it. If `original` is not
+    /// null, it receives the address to call the original function through.
+    pub hook_detour: unsafe extern "C" fn(
+        ctx: *const PluginContext,
+        name: *const c_char,
+        target: usize,
+        replacement: usize,
+        original: *mut usize,
+    ) -> i32,
+    /// Overwrite the function pointer at `slot` with `replacement`, and enable it. If `original`
+    /// is not null, it receives the pointer that was replaced.
+    pub hook_pointer: unsafe extern "C" fn(
+        ctx: *const PluginContext,
+        name: *const c_char,
+        slot: usize,
+        replacement: usize,
+        original: *mut usize,
+    ) -> i32,
+    /// Enable or disable one of the plugin's hooks, by the name it was installed with.
+    pub hook_enable: unsafe extern "C" fn(ctx: *const PluginContext, name: *const c_char) -> i32,
+    pub hook_disable: unsafe extern "C" fn(ctx: *const PluginContext, name: *const c_char) -> i32,
+    /// Overwrite slot `index` of the vtable at `vtable` with `replacement`, and enable it. If
+    /// `original` is not null, it receives the method that was replaced.
+    pub hook_vtable: unsafe extern "C" fn(
+        ctx: *const PluginContext,
+        name: *const c_char,
+        vtable: usize,
+        index: usize,
+        replacement: usize,
+        original: *mut usize,
+    ) -> i32,
+}
+
+/// A loaded plugin.
+struct Plugin {
+    name: String,
+    /// Kept loaded until the extensions unload.
+    library: Library,
+    shutdown: Option<ShutdownFn>,
+}
+
+static PLUGINS: Mutex<Vec<Plugin>> = Mutex::new(Vec::new());
+
+/// Names of the hooks each plugin has installed, by plugin name.
+static PLUGIN_HOOKS: Mutex<Vec<(String, &'static str)>> = Mutex::new(Vec::new());
+
+/// Load every plugin in the plugin folder, in file name order. Loading runs plugin code, so this
+/// must not run under the loader lock.
+pub fn load_all() {
+    let Ok(dir) = std::env::current_exe().map(|exe| exe.with_file_name(PLUGIN_DIR)) else {
+        return;
+    };
+
+    let mut paths = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("dll")))
+            .collect::<Vec<_>>(),
+        // No plugin folder, no plugins.
+        Err(_) => return,
+    };
+    paths.sort();
+
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+        // A panic in here must only cost us this plugin.
+        let result = std::panic::catch_unwind(|| load(&name, &path))
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("panicked while loading")));
+
+        match result {
+            Ok(plugin) => {
+                log(LogType::Init, &format!("Loaded plugin {}", plugin.name));
+                PLUGINS.lock().unwrap_or_else(|e| e.into_inner()).push(plugin);
+            }
+            Err(e) => log(LogType::Error, &format!("Failed to load plugin {name}: {:#}", e)),
+        }
+    }
+}
+
+fn load(name: &str, path: &Path) -> anyhow::Result<Plugin> {
+    // SAFETY: Loading a DLL runs its initialization code; the player put it there on purpose.
+    let library = unsafe { Library::new(path) }.context("failed to load library")?;
+
+    let (abi_version, init, shutdown) = unsafe {
+        let abi_version = *library
+            .get::<AbiVersionFn>(b"RenXPlugin_AbiVersion\0")
+            .context("not a plugin (RenXPlugin_AbiVersion is missing)")?;
+        let init = *library
+            .get::<InitFn>(b"RenXPlugin_Init\0")
+            .context("not a plugin (RenXPlugin_Init is missing)")?;
+        let shutdown = library.get::<ShutdownFn>(b"RenXPlugin_Shutdown\0").ok().map(|f| *f);
+
+        (abi_version, init, shutdown)
+    };
+
+    let version = unsafe { abi_version() };
+    if version != PLUGIN_ABI_VERSION {
+        bail!("built for plugin ABI version {version}, but this is version {PLUGIN_ABI_VERSION}");
+    }
+
+    let ctx = Box::leak(Box::new(context(name)));
+    let status = unsafe { init(ctx) };
+    if status != 0 {
+        // The library is unloaded on the way out, so nothing may point into it.
+        remove_hooks_of(name);
+        bail!("RenXPlugin_Init returned {status}");
+    }
+
+    Ok(Plugin {
+        name: name.to_string(),
+        library,
+        shutdown,
+    })
+}
+
+fn context(name: &str) -> PluginContext {
+    let udk = UDK_RANGE.get().expect("plugins are loaded after the UDK is verified");
+    let plugin_name = CString::new(name).unwrap_or_default().into_raw();
+
+    PluginContext {
+        abi_version: PLUGIN_ABI_VERSION,
+        size: std::mem::size_of::<PluginContext>() as u32,
+        plugin_name,
+        udk_hash: udk_build_hash().as_ptr(),
+        udk_base: get_udk_ptr(),
+        udk_size: udk.end - udk.start,
+        log: plugin_log,
+        hook_detour: plugin_hook_detour,
+        hook_pointer: plugin_hook_pointer,
+        hook_enable: plugin_hook_enable,
+        hook_disable: plugin_hook_disable,
+        hook_vtable: plugin_hook_vtable,
+    }
+}
+
+/// Call every plugin's shutdown function, in reverse load order.
+pub fn shutdown_all() {
+    let plugins = PLUGINS.lock().unwrap_or_else(|e| e.into_inner());
+    for plugin in plugins.iter().rev() {
+        if let Some(shutdown) = plugin.shutdown {
+            unsafe { shutdown() };
+        }
+    }
+}
+
+/// Unload every plugin, removing its hooks first.
+pub fn unload_all() {
+    let plugins = std::mem::take(&mut *PLUGINS.lock().unwrap_or_else(|e| e.into_inner()));
+    for plugin in plugins.into_iter().rev() {
+        remove_hooks_of(&plugin.name);
+        drop(plugin.library);
+    }
+}
+
+/// Unregister every hook a plugin installed, so nothing points into it once it's unloaded.
+fn remove_hooks_of(plugin: &str) {
+    let names = {
+        let mut owned = PLUGIN_HOOKS.lock().unwrap_or_else(|e| e.into_inner());
+        let names = owned
+            .iter()
+            .filter(|(owner, _)| owner == plugin)
+            .map(|&(_, name)| name)
+            .collect::<Vec<_>>();
+        owned.retain(|(owner, _)| owner != plugin);
+        names
+    };
+
+    for name in names {
+        if let Err(e) = hooks::unregister(name) {
+            log(LogType::Error, &format!("{:#}", e));
+        }
+    }
+}
+
+// The functions handed to plugins. Each one reports failures through the UDK log, on
+// behalf of the plugin, and returns non-zero.
+
+/// SAFETY: `ctx` must be a context we handed out.
+unsafe fn plugin_name<'a>(ctx: *const PluginContext) -> std::borrow::Cow<'a, str> {
+    CStr::from_ptr((*ctx).plugin_name).to_string_lossy()
+}
+
+/// Run a plugin API call, logging its error if it fails.
+unsafe fn api_call(ctx: *const PluginContext, what: &str, f: impl FnOnce() -> anyhow::Result<()>) -> i32 {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            log(LogType::Error, &format!("Plugin {}: {what} failed: {:#}", plugin_name(ctx), e));
+            1
+        }
+        Err(_) => {
+            log(LogType::Error, &format!("Plugin {}: {what} panicked", plugin_name(ctx)));
+            1
+        }
+    }
+}
+
+/// Read a NUL-terminated string from a plugin.
+unsafe fn plugin_str<'a>(s: *const c_char) -> anyhow::Result<&'a str> {
+    if s.is_null() {
+        bail!("string is null");
+    }
+    CStr::from_ptr(s).to_str().context("string is not UTF-8")
+}
+
+/// The registry name of a new hook of a plugin: `<plugin>/<name>`. Hook names live forever, like the hooks.
+unsafe fn hook_name(ctx: *const PluginContext, name: *const c_char) -> anyhow::Result<&'static str> {
+    let name = format!("{}/{}", plugin_name(ctx), plugin_str(name)?);
+
+    let mut owned = PLUGIN_HOOKS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(&(_, existing)) = owned.iter().find(|(_, n)| *n == name) {
+        return Ok(existing);
+    }
+
+    let name: &'static str = Box::leak(name.into_boxed_str());
+    owned.push((plugin_name(ctx).into_owned(), name));
+    Ok(name)
+}
+
+unsafe extern "C" fn plugin_log(ctx: *const PluginContext, level: u32, message: *const c_char) {
+    let typ = match level {
+        PLUGIN_LOG_WARNING => LogType::Warning,
+        PLUGIN_LOG_ERROR => LogType::Error,
+        _ => LogType::Init,
+    };
+
+    let message = match message.is_null() {
+        true => "".into(),
+        false => CStr::from_ptr(message).to_string_lossy(),
+    };
+    log(typ, &format!("[{}] {}", plugin_name(ctx), message));
+}
+
+unsafe extern "C" fn plugin_hook_detour(
+    ctx: *const PluginContext,
+    name: *const c_char,
+    target: usize,
+    replacement: usize,
+    original: *mut usize,
+) -> i32 {
+    api_call(ctx, "hook_detour", || {
+        let name = hook_name(ctx, name)?;
+        hooks::install(Hook::raw_detour(name, Target::Address(target), replacement))?;
+
+        if !original.is_null() {
+            *original = hooks::original(name).context("detour has no trampoline")?;
+        }
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn plugin_hook_pointer(
+    ctx: *const PluginContext,
+    name: *const c_char,
+    slot: usize,
+    replacement: usize,
+    original: *mut usize,
+) -> i32 {
+    api_call(ctx, "hook_pointer", || {
+        let name = hook_name(ctx, name)?;
+        hooks::install(Hook::pointer(name, Target::Address(slot), replacement))?;
+
+        if !original.is_null() {
+            *original = hooks::original(name).context("slot has no original value")?;
+        }
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn plugin_hook_enable(ctx: *const PluginContext, name: *const c_char) -> i32 {
+    api_call(ctx, "hook_enable", || {
+        hooks::enable(&format!("{}/{}", plugin_name(ctx), plugin_str(name)?))
+    })
+}
+
+unsafe extern "C" fn plugin_hook_disable(ctx: *const PluginContext, name: *const c_char) -> i32 {
+    api_call(ctx, "hook_disable", || {
+        hooks::disable(&format!("{}/{}", plugin_name(ctx), plugin_str(name)?))
+    })
+}
+
+unsafe extern "C" fn plugin_hook_vtable(
+    ctx: *const PluginContext,
+    name: *const c_char,
+    vtable: usize,
+    index: usize,
+    replacement: usize,
+    original: *mut usize,
+) -> i32 {
+    api_call(ctx, "hook_vtable", || {
+        let name = hook_name(ctx, name)?;
+        hooks::install(Hook::vtable(name, vtable, index, replacement))?;
+
+        if !original.is_null() {
+            *original = hooks::original(name).context("slot has no original value")?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,61 @@
+//! This module forwards the exports of alternate proxy identities (`version.dll`, `winmm.dll`,
+//! ...) to the system DLL they stand in for. The export table itself is generated by `build.rs`.
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+
+use anyhow::bail;
+use libloading::Library;
+
+use crate::dll::load_system_library;
+
+include!(concat!(env!("OUT_DIR"), "/proxy_exports.rs"));
+
+/// The system DLL our forwarded exports point into. Kept loaded for the lifetime of the process.
+static SYSTEM_LIBRARY: OnceLock<Library> = OnceLock::new();
+
+/// Resolve every forwarded export against the system DLL. This must run on DLL attach,
+/// before the host gets a chance to call any of them.
+pub fn init() -> anyhow::Result<()> {
+    if PROXY_EXPORTS.is_empty() {
+        return Ok(());
+    }
+
+    let library = load_system_library(PROXY_DLL)?;
+
+    let mut missing = Vec::new();
+    for (slot, export) in PROXY_TABLE.iter().zip(PROXY_EXPORTS) {
+        let name = format!("{export}\0");
+        match unsafe { library.get::<*const ()>(name.as_bytes()) } {
+            Ok(f) => slot.store(*f as usize, Ordering::SeqCst),
+            Err(_) => missing.push(*export),
+        }
+    }
+
+    let _ = SYSTEM_LIBRARY.set(library);
+
+    if !missing.is_empty() {
+        bail!("system {PROXY_DLL} is missing exports: {}", missing.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Where the trampoline of an export the system DLL lacks ends up, in place of the export.
+///
+/// On x64 the caller cleans up the arguments, so this can return the export's failure value
+/// whatever its signature. The 32-bit exports are `stdcall`, which leaves that to the callee,
+/// and nothing here knows their argument sizes; there the process is ended instead.
+#[cfg(not(feature = "proxy-dinput8"))]
+extern "C" fn missing_export(index: usize) -> isize {
+    let export = PROXY_EXPORTS.get(index).copied().unwrap_or("?");
+    crate::udk_log::log_ratelimited!(crate::udk_log::LogType::Error, "{export} was called, but the system {PROXY_DLL} doesn't export it");
+
+    #[cfg(target_arch = "x86")]
+    std::process::abort();
+
+    #[cfg(not(target_arch = "x86"))]
+    {
+        unsafe { windows::Win32::Foundation::SetLastError(windows::Win32::Foundation::ERROR_PROC_NOT_FOUND) };
+        PROXY_FAILURES.get(index).map_or(0, |&failure| failure as isize)
+    }
+}
@@ -0,0 +1,144 @@
+//! This module finds byte signatures in code, for UDK functions and globals that aren't exported.
+//!
+//! Patterns are written the way disassemblers show them: `48 8B 05 ?? ?? ?? ?? 48 8B 0C C8`,
+//! where `??` (or `?`) matches any byte.
+use std::fmt;
+
+use anyhow::{bail, Context};
+
+/// A byte pattern with wildcards.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Pattern {
+    /// `None` is a wildcard.
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let bytes = s
+            .split_whitespace()
+            .map(|b| match b {
+                "?" | "??" => Ok(None),
+                _ if b.len() == 2 => u8::from_str_radix(b, 16)
+                    .map(Some)
+                    .with_context(|| format!("`{b}` is not a hex byte")),
+                _ => bail!("`{b}` is not a hex byte or wildcard"),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        match bytes.first() {
+            None => bail!("empty pattern"),
+            // Anchoring on a known byte keeps the scan fast and the pattern meaningful.
+            Some(None) => bail!("patterns must not start with a wildcard"),
+            Some(Some(_)) => Ok(Self { bytes }),
+        }
+    }
+
+    /// Whether the pattern matches at the start of `data`.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && self.bytes.iter().zip(data).all(|(p, b)| p.is_none_or(|p| p == *b))
+    }
+
+    /// Return the offset of every match in `data`.
+    pub fn scan<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let first = self.bytes[0].unwrap();
+        let last = data.len().saturating_sub(self.bytes.len() - 1);
+
+        data[..last]
+            .iter()
+            .enumerate()
+            .filter(move |&(i, &b)| b == first && self.matches(&data[i..]))
+            .map(|(i, _)| i)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, b) in self.bytes.iter().enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+            match b {
+                Some(b) => write!(f, "{b:02X}")?,
+                None => f.write_str("??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a 32-bit displacement at `offset` in `data`, relative to the end of the displacement.
+/// This is how x86_64 encodes RIP-relative operands and `call`/`jmp` targets, when the
+/// displacement is the last field of the instruction.
+///
+/// Returns the target as an offset from the start of `data`, which may be outside of it.
+pub fn relative_target(data: &[u8], offset: usize) -> Option<isize> {
+    let disp = data.get(offset..offset + 4)?;
+    let disp = i32::from_le_bytes(disp.try_into().unwrap());
+
+    Some(offset as isize + 4 + disp as isize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(pattern: &str, data: &[u8]) -> Vec<usize> {
+        Pattern::parse(pattern).unwrap().scan(data).collect()
+    }
+
+    #[test]
+    fn parses_wildcards() {
+        let pattern = Pattern::parse("48 8b 05 ?? ? 0C").unwrap();
+        assert_eq!(pattern.to_string(), "48 8B 05 ?? ?? 0C");
+        assert!(pattern.matches(&[0x48, 0x8B, 0x05, 0x12, 0x34, 0x0C, 0xFF]));
+        assert!(!pattern.matches(&[0x48, 0x8B, 0x05, 0x12, 0x34, 0x0D]));
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        for pattern in ["", "   ", "?? 48", "? 48", "48 8", "48 8B0", "48 XX", "48 ???"] {
+            assert!(Pattern::parse(pattern).is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn scans_every_match() {
+        let data = [0x48, 0x8B, 0x05, 0x00, 0x48, 0x8B, 0x0D, 0x48, 0x8B];
+        assert_eq!(scan("48 8B ??", &data), [0, 4]);
+        assert_eq!(scan("48 8B 0D", &data), [4]);
+    }
+
+    #[test]
+    fn scans_up_to_the_end() {
+        let data = [0x90, 0x90, 0xE8, 0x01, 0x02];
+        assert_eq!(scan("E8 ?? 02", &data), [2]);
+        assert_eq!(scan("90 90 E8 01 02", &data), [0]);
+        // One byte short at the end, and longer than the data.
+        assert!(scan("E8 ?? 02 ??", &data).is_empty());
+        assert!(scan("90 90 E8 01 02 03", &data).is_empty());
+    }
+
+    #[test]
+    fn finds_nothing() {
+        assert!(scan("CC", &[0x90; 16]).is_empty());
+        assert!(scan("90", &[]).is_empty());
+    }
+
+    #[test]
+    fn resolves_relative_targets() {
+        // call +0x10, at offset 0x20
+        let mut data = vec![0x90; 0x20];
+        data.extend([0xE8, 0x10, 0x00, 0x00, 0x00]);
+        assert_eq!(relative_target(&data, 0x21), Some(0x25 + 0x10));
+
+        // jmp -0x30, which lands before the start of `data`
+        let data = [0xE9, 0xD0, 0xFF, 0xFF, 0xFF];
+        assert_eq!(relative_target(&data, 1), Some(5 - 0x30));
+
+        // Not enough bytes for the displacement.
+        assert_eq!(relative_target(&data, 2), None);
+        assert_eq!(relative_target(&data, 8), None);
+    }
+}
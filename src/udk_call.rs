@@ -0,0 +1,219 @@
+//! This module calls UnrealScript functions and events from Rust, the way native code does:
+//! fill in a parameter frame laid out by the function's properties, and hand it to the target
+//! object's `ProcessEvent`. Out parameters and the return value are read back from the frame.
+//!
+//! Strings in the frame belong to the engine's allocator, like those of the engine's own calls:
+//! string arguments are allocated with it, and every string left in the frame after the call
+//! (arguments, out parameters and the return value alike) is freed with it when the frame goes.
+//!
+//! Calls are only allowed on the game thread.
+use anyhow::{bail, Context};
+
+use crate::game_thread::require_game_thread;
+use crate::udk_log::{log_ratelimited, LogType};
+use crate::udk_mem::{Memory, Process};
+use crate::udk_object::{
+    self, ObjectRef, Property, PropertyKind, Reflection, Value, CPF_PARM, CPF_RETURN_PARM,
+};
+use crate::{udk_events, udk_native, udk_offsets};
+
+type ProcessEventFn = unsafe extern "C" fn(usize, usize, usize, usize);
+
+/// A function's parameter frame. `u64`s keep it aligned for any parameter.
+struct Frame {
+    reflection: &'static Reflection<'static, Process>,
+    function: ObjectRef,
+    data: Vec<u64>,
+}
+
+impl Frame {
+    fn base(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    fn base_mut(&mut self) -> usize {
+        self.data.as_mut_ptr() as usize
+    }
+
+    fn param(&self, name: &str) -> anyhow::Result<Property> {
+        let property = self.reflection.property(self.function, name)?;
+        if property.flags & CPF_PARM == 0 {
+            bail!("{name} is a local, not a parameter");
+        }
+        Ok(property)
+    }
+}
+
+impl Drop for Frame {
+    /// Free the strings left in the frame.
+    fn drop(&mut self) {
+        let Ok(properties) = self.reflection.properties(self.function) else {
+            return;
+        };
+
+        let strings = properties
+            .iter()
+            .filter(|p| p.flags & CPF_PARM != 0 && p.kind == PropertyKind::Str)
+            .flat_map(|p| (0..p.array_dim.max(1)).map(|i| p.offset + i * p.element_size));
+        for offset in strings {
+            let Ok(data) = Process::CHECKED.read_ptr(self.base_mut() + offset) else {
+                continue;
+            };
+
+            // SAFETY: Strings in the frame were allocated by the engine, by us or by the callee.
+            if data != 0 {
+                if let Err(error) = unsafe { udk_native::engine_free(data) } {
+                    log_ratelimited!(LogType::Warning, "Leaked a string parameter: {:#}", error);
+                }
+            }
+        }
+    }
+}
+
+/// A call being prepared.
+pub struct Call {
+    frame: Frame,
+}
+
+impl Call {
+    /// Prepare a call of the function with full name `function`
+    /// (`Function Engine.PlayerController.ClientMessage`). Parameters start out zeroed.
+    pub fn new(function: &str) -> anyhow::Result<Self> {
+        require_game_thread()?;
+        let reflection = udk_object::reflection()?;
+        let function = reflection.find(function)?.with_context(|| format!("{function} not found"))?;
+
+        let size = reflection.properties_size(function)?;
+        Ok(Self {
+            frame: Frame {
+                reflection,
+                function,
+                data: vec![0; size.div_ceil(8)],
+            },
+        })
+    }
+
+    /// Set the parameter `name`.
+    pub fn arg(mut self, name: &str, value: Value) -> anyhow::Result<Self> {
+        let property = self.frame.param(name)?;
+        let base = self.frame.base_mut();
+        let address = base + property.offset;
+
+        match (&property.kind, value) {
+            (PropertyKind::Str, Value::Str(s)) => {
+                let mut units = s.encode_utf16().collect::<Vec<_>>();
+                units.push(0);
+                let len = i32::try_from(units.len()).context("string is too long")?;
+
+                // The frame is zeroed, unless this parameter was already set.
+                let mem = Process::CHECKED;
+                let old = mem.read_ptr(address)?;
+                mem.write(address, 0usize)?;
+                if old != 0 {
+                    // SAFETY: Allocated by us, below.
+                    unsafe { udk_native::engine_free(old)? };
+                }
+
+                // SAFETY: The buffer is sized for the string.
+                let data = unsafe { udk_native::engine_malloc(units.len() * 2)? };
+                let bytes = units.iter().flat_map(|u| u.to_le_bytes()).collect::<Vec<_>>();
+                mem.write_bytes(data, &bytes)?;
+                mem.write(address, data)?;
+                mem.write(address + std::mem::size_of::<usize>(), len)?;
+                mem.write(address + std::mem::size_of::<usize>() + 4, len)?;
+            }
+            (_, value) => self.frame.reflection.write_value(base, &property, 0, &value)?,
+        }
+
+        Ok(self)
+    }
+
+    /// Call the function on `object`.
+    pub fn invoke(mut self, object: ObjectRef) -> anyhow::Result<Outcome> {
+        require_game_thread()?;
+        let (reflection, function) = (self.frame.reflection, self.frame.function);
+
+        // Methods must be called on an instance of their class. (Functions can also belong to
+        // states, which we don't check.)
+        let owner = reflection.outer(function)?.context("function has no outer")?;
+        let owner_class = reflection.class(owner)?.context("function outer has no class")?;
+        if reflection.name_string(owner_class)? == "Class" && !reflection.is_a(object, owner)? {
+            bail!("{} is not a {}", reflection.full_name(object)?, reflection.path_name(owner)?);
+        }
+
+        // Locating it checks the vtable slot.
+        udk_events::process_event()?;
+        let index = udk_offsets::PROCESS_EVENT
+            .vtable_index()
+            .context("ProcessEvent is not known for this UDK build")?;
+        let mem = Process::CHECKED;
+        let vtable = mem.read_ptr(object.address())?;
+        let process_event = mem.read_ptr(vtable + index * std::mem::size_of::<usize>())?;
+        if !udk_offsets::is_code(process_event) {
+            bail!("{:#x} doesn't have a vtable", object.address());
+        }
+
+        // SAFETY: The object is live (we're on the game thread), and the frame is laid out
+        // and sized for the function.
+        unsafe {
+            let process_event = std::mem::transmute::<usize, ProcessEventFn>(process_event);
+            process_event(object.address(), function.address(), self.frame.base_mut(), 0);
+        }
+
+        Ok(Outcome { frame: self.frame })
+    }
+}
+
+/// The parameter frame after a call.
+pub struct Outcome {
+    frame: Frame,
+}
+
+impl Outcome {
+    /// Read the parameter `name`, usually an `out` parameter.
+    pub fn get(&self, name: &str) -> anyhow::Result<Value> {
+        let property = self.frame.param(name)?;
+        self.frame.reflection.read_value(self.frame.base(), &property, 0)
+    }
+
+    /// Read the return value, if the function has one.
+    pub fn return_value(&self) -> anyhow::Result<Option<Value>> {
+        let properties = self.frame.reflection.properties(self.frame.function)?;
+        match properties.iter().find(|p| p.flags & CPF_RETURN_PARM != 0) {
+            Some(p) => self.frame.reflection.read_value(self.frame.base(), p, 0).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Call `function` on `object` with the named arguments.
+pub fn call<'a>(
+    object: ObjectRef,
+    function: &str,
+    args: impl IntoIterator<Item = (&'a str, Value)>,
+) -> anyhow::Result<Outcome> {
+    let mut call = Call::new(function)?;
+    for (name, value) in args {
+        call = call.arg(name, value)?;
+    }
+
+    call.invoke(object)
+}
+
+/// The function `name` that `object` would run: its class's own, or the nearest inherited one.
+pub fn find_function(object: ObjectRef, name: &str) -> anyhow::Result<ObjectRef> {
+    let reflection = udk_object::reflection()?;
+    let mut class = reflection.class(object)?;
+    for _ in 0..64 {
+        let Some(c) = class else {
+            break;
+        };
+
+        if let Some(function) = reflection.find(&format!("Function {}.{name}", reflection.path_name(c)?))? {
+            return Ok(function);
+        }
+        class = reflection.super_struct(c)?;
+    }
+
+    bail!("{} has no function {name}", reflection.full_name(object)?)
+}
@@ -0,0 +1,239 @@
+//! This module hooks `UObject::ProcessEvent` and dispatches the calls going through it to Rust
+//! handlers.
+//!
+//! `ProcessEvent` is how native code calls into script: events raised by the engine (`Tick`,
+//! `Touch`, `PostBeginPlay`, ...), delegates, timers and console `exec` functions. Calls from one
+//! UnrealScript function to another, including script calling natives, run in the VM and never
+//! reach it, so a handler only sees a function when native code calls it.
+//!
+//! Handlers are registered by the function's full name, such as
+//! `Function Engine.PlayerController.ClientMessage`. They see the call's parameters through
+//! reflection, and may suppress the call entirely.
+//!
+//! The hook is only installed once the first handler is registered, and only once the engine
+//! is up (see `udk_ready.rs`).
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once, RwLock};
+
+use anyhow::{bail, Context};
+use retour::static_detour;
+
+use crate::hooks::{self, Hook, Target};
+use crate::udk_fname::FName;
+use crate::udk_log::{log, log_ratelimited, LogType};
+use crate::udk_mem::Process;
+use crate::udk_object::{self, ObjectRef, Property, Reflection, Value, CPF_PARM, OBJECT_LAYOUT};
+use crate::udk_offsets;
+use crate::udk_ready;
+
+static_detour! {
+    static ProcessEventHook: extern "C" fn(usize, usize, usize, usize);
+}
+
+type ProcessEventFn = extern "C" fn(usize, usize, usize, usize);
+
+/// What should happen to the call after a handler has seen it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Continue,
+    /// Don't run the function. Out parameters and the return value keep whatever is in the frame.
+    Suppress,
+}
+
+/// A call passing through `ProcessEvent`.
+pub struct Event<'a> {
+    /// The object the function is called on.
+    pub object: ObjectRef,
+    /// The `UFunction` being called.
+    pub function: ObjectRef,
+    /// The parameter frame, laid out as the function's properties.
+    params: usize,
+    reflection: &'a Reflection<'static, Process>,
+}
+
+impl<'a> Event<'a> {
+    pub fn reflection(&self) -> &'a Reflection<'static, Process> {
+        self.reflection
+    }
+
+    /// The function's parameters, including the return value.
+    pub fn params(&self) -> anyhow::Result<Vec<Property>> {
+        let properties = self.reflection.properties(self.function)?;
+        Ok(properties.iter().filter(|p| p.flags & CPF_PARM != 0).cloned().collect())
+    }
+
+    fn param(&self, name: &str) -> anyhow::Result<Property> {
+        let property = self.reflection.property(self.function, name)?;
+        if property.flags & CPF_PARM == 0 {
+            bail!("{name} is a local, not a parameter");
+        }
+        if self.params == 0 {
+            bail!("the call has no parameter frame");
+        }
+        Ok(property)
+    }
+
+    /// Read the parameter `name`.
+    pub fn get(&self, name: &str) -> anyhow::Result<Value> {
+        self.reflection.read_value(self.params, &self.param(name)?, 0)
+    }
+}
+
+type Handler = Arc<dyn Fn(&mut Event) -> Action + Send + Sync>;
+
+/// Identifies a registered handler, for [`remove`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HandlerId(u64);
+
+struct Registration {
+    id: HandlerId,
+    /// Lowercased full name of the function.
+    function: String,
+    handler: Handler,
+}
+
+static HANDLERS: RwLock<Vec<Registration>> = RwLock::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Whether any handlers are registered, so calls can skip the lookup entirely.
+static ANY_HANDLERS: AtomicBool = AtomicBool::new(false);
+
+/// Handlers for each function seen so far, by `UFunction` address. The function's name is kept
+/// to notice when the address has been reused by another object.
+type Resolved = HashMap<usize, (FName, Arc<[Handler]>)>;
+static RESOLVED: Mutex<Option<Resolved>> = Mutex::new(None);
+
+/// Call `handler` whenever native code calls the function with full name `function` through
+/// `ProcessEvent`.
+/// Register handlers during initialization or from the game thread.
+pub fn on(function: &str, handler: impl Fn(&mut Event) -> Action + Send + Sync + 'static) -> HandlerId {
+    let id = HandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    HANDLERS.write().unwrap_or_else(|e| e.into_inner()).push(Registration {
+        id,
+        function: function.to_lowercase(),
+        handler: Arc::new(handler),
+    });
+
+    ANY_HANDLERS.store(true, Ordering::Release);
+    *RESOLVED.lock().unwrap_or_else(|e| e.into_inner()) = None;
+
+    INSTALL.call_once(|| udk_ready::when_ready("ProcessEvent hook", install));
+    id
+}
+
+/// Unregister a handler.
+pub fn remove(id: HandlerId) {
+    let any = {
+        let mut handlers = HANDLERS.write().unwrap_or_else(|e| e.into_inner());
+        handlers.retain(|r| r.id != id);
+        !handlers.is_empty()
+    };
+
+    ANY_HANDLERS.store(any, Ordering::Release);
+    *RESOLVED.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+static INSTALL: Once = Once::new();
+
+/// Locate `UObject::ProcessEvent`. Its neighbours in the vtable are told apart by what it reads
+/// of the function it runs: `FunctionFlags`, `iNative` and `Func`. Needs reflection.
+pub fn process_event() -> anyhow::Result<usize> {
+    let layout = &OBJECT_LAYOUT;
+    let fields = [
+        layout.function_flags..layout.function_flags + 4,
+        layout.native_index..layout.native_index + 2,
+        layout.func..layout.func + std::mem::size_of::<usize>(),
+    ];
+
+    udk_offsets::locate(&udk_offsets::PROCESS_EVENT, |candidate| {
+        udk_offsets::is_code(candidate) && udk_offsets::references_fields(candidate, &fields)
+    })
+}
+
+/// Hook `ProcessEvent`. Needs reflection, so this runs through `udk_ready`.
+fn install() -> anyhow::Result<()> {
+    let target = process_event()?;
+
+    // SAFETY: The target is UObject::ProcessEvent, whose signature we know.
+    hooks::install(Hook::detour("ProcessEvent", Target::Address(target), |target| unsafe {
+        ProcessEventHook.initialize(std::mem::transmute::<usize, ProcessEventFn>(target), process_event_hook)
+    }))
+    .context("failed to hook ProcessEvent")?;
+
+    log(LogType::Init, "Hooked UObject::ProcessEvent");
+    Ok(())
+}
+
+fn process_event_hook(object: usize, function: usize, params: usize, result: usize) {
+    if ANY_HANDLERS.load(Ordering::Acquire) && dispatch(object, function, params) == Action::Suppress {
+        return;
+    }
+
+    ProcessEventHook.call(object, function, params, result)
+}
+
+/// Run the handlers for `function`. Handlers may themselves call into `ProcessEvent`, so no
+/// locks are held while they run.
+fn dispatch(object: usize, function: usize, params: usize) -> Action {
+    let Ok(reflection) = udk_object::reflection() else {
+        return Action::Continue;
+    };
+    let (Some(object), Some(function)) = (nonnull(object), nonnull(function)) else {
+        return Action::Continue;
+    };
+
+    let handlers = match handlers_for(reflection, function) {
+        Ok(handlers) => handlers,
+        Err(error) => {
+            log_ratelimited!(LogType::Warning, "ProcessEvent: failed to identify function {:#x}: {:#}", function.0, error);
+            return Action::Continue;
+        }
+    };
+
+    let mut event = Event {
+        object,
+        function,
+        params,
+        reflection,
+    };
+
+    let mut action = Action::Continue;
+    for handler in handlers.iter() {
+        match catch_unwind(AssertUnwindSafe(|| handler(&mut event))) {
+            Ok(Action::Suppress) => action = Action::Suppress,
+            Ok(Action::Continue) => {}
+            Err(_) => log_ratelimited!(LogType::Error, "ProcessEvent: a handler panicked"),
+        }
+    }
+
+    action
+}
+
+fn nonnull(address: usize) -> Option<ObjectRef> {
+    (address != 0).then_some(ObjectRef(address))
+}
+
+fn handlers_for(reflection: &Reflection<'static, Process>, function: ObjectRef) -> anyhow::Result<Arc<[Handler]>> {
+    let name = reflection.name(function)?;
+
+    let mut resolved = RESOLVED.lock().unwrap_or_else(|e| e.into_inner());
+    let resolved = resolved.get_or_insert_with(HashMap::new);
+    if let Some((cached_name, handlers)) = resolved.get(&function.address()) {
+        if *cached_name == name {
+            return Ok(handlers.clone());
+        }
+    }
+
+    let full_name = reflection.full_name(function)?.to_lowercase();
+    let handlers = HANDLERS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|r| r.function == full_name)
+        .map(|r| r.handler.clone())
+        .collect::<Arc<[Handler]>>();
+
+    resolved.insert(function.address(), (name, handlers.clone()));
+    Ok(handlers)
+}
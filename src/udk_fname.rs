@@ -0,0 +1,437 @@
+//! This module reads the UDK's global name pool, `FName::Names`.
+//!
+//! An `FName` is an index into `Names`, a `TArray<FNameEntry*>`, plus an instance number
+//! (`Foo_3` is `Foo` with number 4; 0 means no number). Each `FNameEntry` is laid out as:
+//!
+//! | Field      | Type            | Notes                                              |
+//! | ---------- | --------------- | -------------------------------------------------- |
+//! | `Flags`    | `QWORD`         |                                                    |
+//! | `Index`    | `INT`           | `index << 1`, with the low bit set for wide names  |
+//! | `HashNext` | `FNameEntry*`   | next entry in the same `NameHash` bucket           |
+//! | `Name`     | `ANSICHAR[]`/`UNICHAR[]` | NUL-terminated, allocated to fit          |
+//!
+//! Entries are never freed, so decoded names are cached for good.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{bail, Context};
+
+use crate::udk_mem::{Memory, Process};
+use crate::udk_offsets;
+
+/// Longest name the UDK allows, in characters, including the terminator.
+pub const NAME_SIZE: usize = 1024;
+
+/// `FNameEntry::Index` holds the entry's index shifted left by this, and the wide flag below it.
+const NAME_INDEX_SHIFT: u32 = 1;
+const NAME_WIDE_MASK: i32 = 1;
+
+/// Field offsets within an `FNameEntry`.
+pub struct EntryLayout {
+    pub index: usize,
+    pub hash_next: usize,
+    pub name: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+pub const ENTRY_LAYOUT: EntryLayout = EntryLayout {
+    index: 0x08,
+    hash_next: 0x10,
+    name: 0x18,
+};
+
+#[cfg(target_pointer_width = "32")]
+pub const ENTRY_LAYOUT: EntryLayout = EntryLayout {
+    index: 0x08,
+    hash_next: 0x0C,
+    name: 0x10,
+};
+
+/// UE3's `FName`, as it's laid out in engine structures.
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct FName {
+    pub index: i32,
+    pub number: i32,
+}
+
+/// A decoded `FNameEntry`.
+#[derive(Clone, Debug)]
+pub struct NameEntry {
+    /// Where the entry lives.
+    pub address: usize,
+    /// Its index in `Names`.
+    pub index: i32,
+    /// Whether the name is stored as UTF-16.
+    pub wide: bool,
+    /// The next entry in the same hash bucket, or 0.
+    pub hash_next: usize,
+    pub name: String,
+}
+
+/// Decode the `FNameEntry` at `address`.
+pub fn read_entry(mem: &impl Memory, address: usize) -> anyhow::Result<NameEntry> {
+    let raw_index = mem.read::<i32>(address + ENTRY_LAYOUT.index)?;
+    let hash_next = mem.read_ptr(address + ENTRY_LAYOUT.hash_next)?;
+    let wide = raw_index & NAME_WIDE_MASK != 0;
+
+    let start = address + ENTRY_LAYOUT.name;
+    let mut name = String::new();
+    let mut units = Vec::new();
+    for i in 0..NAME_SIZE {
+        // Entries are allocated to fit their name, so read one character at a time rather
+        // than risk running off the end of the allocation.
+        if wide {
+            match mem.read::<u16>(start + i * 2)? {
+                0 => break,
+                c => units.push(c),
+            }
+        } else {
+            match mem.read::<u8>(start + i)? {
+                0 => break,
+                // ANSICHAR names are Latin-1.
+                c => name.push(c as char),
+            }
+        }
+
+        if i == NAME_SIZE - 1 {
+            bail!("name at {address:#x} is not terminated");
+        }
+    }
+    if wide {
+        name = String::from_utf16_lossy(&units);
+    }
+
+    Ok(NameEntry {
+        address,
+        index: raw_index >> NAME_INDEX_SHIFT,
+        wide,
+        hash_next,
+        name,
+    })
+}
+
+/// Split the instance number off a name, the way the UDK does when it makes an `FName`:
+/// `Foo_3` is `("Foo", 4)`. Numbers with leading zeros are part of the name.
+pub fn split_number(name: &str) -> (&str, i32) {
+    let Some((base, digits)) = name.rsplit_once('_') else {
+        return (name, 0);
+    };
+
+    let plain = !digits.is_empty()
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && (digits == "0" || !digits.starts_with('0'));
+    match digits.parse::<i32>().ok().filter(|_| plain && !base.is_empty()) {
+        Some(n) if n < i32::MAX => (base, n + 1),
+        _ => (name, 0),
+    }
+}
+
+/// Reads names out of a `Names` array, caching what it decodes.
+pub struct NameTable<M> {
+    mem: M,
+    /// Address of the `TArray<FNameEntry*>`.
+    array: usize,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Decoded names, by index.
+    names: HashMap<i32, Arc<str>>,
+    /// Lowercased name to index, for every entry below `indexed`.
+    lookup: HashMap<String, i32>,
+    indexed: usize,
+}
+
+impl<M: Memory> NameTable<M> {
+    /// Read the `TArray<FNameEntry*>` at `array` from `mem`.
+    pub fn new(mem: M, array: usize) -> Self {
+        Self {
+            mem,
+            array,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    /// Address of the `TArray<FNameEntry*>`.
+    pub fn array(&self) -> usize {
+        self.array
+    }
+
+    /// The array's data pointer and length. The array grows as names are added, so this is
+    /// read afresh every time.
+    fn header(&self) -> anyhow::Result<(usize, usize)> {
+        let data = self.mem.read_ptr(self.array)?;
+        let num = self.mem.read::<i32>(self.array + std::mem::size_of::<usize>())?;
+        let num = usize::try_from(num).context("negative name count")?;
+
+        Ok((data, num))
+    }
+
+    /// The number of slots in the table. Some may be empty.
+    pub fn len(&self) -> anyhow::Result<usize> {
+        self.header().map(|(_, num)| num)
+    }
+
+    /// Decode the entry at `index`, or `None` if the slot is empty.
+    pub fn entry(&self, index: usize) -> anyhow::Result<Option<NameEntry>> {
+        let (data, num) = self.header()?;
+        if index >= num {
+            bail!("name index {index} is out of range ({num} names)");
+        }
+
+        let address = self.mem.read_ptr(data + index * std::mem::size_of::<usize>())?;
+        if address == 0 {
+            return Ok(None);
+        }
+
+        let entry = read_entry(&self.mem, address)?;
+        if entry.index as usize != index {
+            bail!("name entry at {address:#x} claims index {}, expected {index}", entry.index);
+        }
+        Ok(Some(entry))
+    }
+
+    /// The plain name at `index`, without an instance number.
+    pub fn name(&self, index: i32) -> anyhow::Result<Arc<str>> {
+        if let Some(name) = self.cache().names.get(&index) {
+            return Ok(name.clone());
+        }
+
+        let slot = usize::try_from(index).context("negative name index")?;
+        let entry = self.entry(slot)?.with_context(|| format!("name {index} is empty"))?;
+        let name: Arc<str> = entry.name.into();
+
+        self.cache().names.insert(index, name.clone());
+        Ok(name)
+    }
+
+    /// Format `name` the way the UDK does, with its instance number.
+    pub fn to_string(&self, name: FName) -> anyhow::Result<String> {
+        let base = self.name(name.index)?;
+        Ok(match name.number {
+            0 => base.to_string(),
+            n => format!("{base}_{}", n - 1),
+        })
+    }
+
+    /// Find the `FName` for `name`, if the UDK has ever made it. Like the UDK, this ignores case.
+    pub fn find(&self, name: &str) -> anyhow::Result<Option<FName>> {
+        let (base, number) = split_number(name);
+        if let Some(index) = self.find_plain(base)? {
+            return Ok(Some(FName { index, number }));
+        }
+
+        // Something like `Foo_3` may be a plain name of its own.
+        match number {
+            0 => Ok(None),
+            _ => Ok(self.find_plain(name)?.map(|index| FName { index, number: 0 })),
+        }
+    }
+
+    fn find_plain(&self, name: &str) -> anyhow::Result<Option<i32>> {
+        let key = name.to_lowercase();
+        if let Some(&index) = self.cache().lookup.get(&key) {
+            return Ok(Some(index));
+        }
+
+        // Index whatever has been added since we last looked.
+        let num = self.len()?;
+        let indexed = self.cache().indexed;
+        for slot in indexed..num {
+            let Some(entry) = self.entry(slot)? else {
+                continue;
+            };
+
+            let mut cache = self.cache();
+            cache.lookup.entry(entry.name.to_lowercase()).or_insert(entry.index);
+            cache.names.entry(entry.index).or_insert_with(|| entry.name.into());
+        }
+
+        let mut cache = self.cache();
+        cache.indexed = cache.indexed.max(num);
+        Ok(cache.lookup.get(&key).copied())
+    }
+
+    /// Whether this looks like the real name table: a sane size, with `None` at index 0 like
+    /// every UE3 build has.
+    pub fn is_plausible(&self) -> bool {
+        let sane = |num: usize| (1..1 << 24).contains(&num);
+        match self.header() {
+            Ok((data, num)) if data != 0 && sane(num) => {
+                matches!(self.entry(0), Ok(Some(e)) if e.name == "None" && !e.wide)
+            }
+            _ => false,
+        }
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Display for FName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match names().and_then(|n| n.to_string(*self)) {
+            Ok(name) => f.write_str(&name),
+            Err(_) => write!(f, "<name {}:{}>", self.index, self.number),
+        }
+    }
+}
+
+static NAMES: OnceLock<NameTable<Process>> = OnceLock::new();
+
+/// Return the UDK's name table, locating it on first use.
+pub fn names() -> anyhow::Result<&'static NameTable<Process>> {
+    if let Some(names) = NAMES.get() {
+        return Ok(names);
+    }
+
+    let array = udk_offsets::locate(&udk_offsets::GNAMES, |candidate| {
+        NameTable::new(Process::CHECKED, candidate).is_plausible()
+    })?;
+
+    // SAFETY: The table checked out above, and entries are never freed.
+    let table = NameTable::new(unsafe { Process::trusted() }, array);
+    Ok(NAMES.get_or_init(|| table))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::udk_mem::Dump;
+
+    const PTR: usize = std::mem::size_of::<usize>();
+    /// Where [`add_table`] puts the `Names` array.
+    pub(crate) const ARRAY: usize = 0x10000;
+    const DATA: usize = 0x20000;
+    const ENTRIES: usize = 0x30000;
+
+    fn table(names: &[Option<&str>]) -> Dump {
+        let mut dump = Dump::new();
+        add_table(&mut dump, names);
+        dump
+    }
+
+    /// Lay out a `Names` array at [`ARRAY`], with entries for `names` (`None` for empty slots)
+    /// 0x100 bytes apart. Names with non-ASCII characters are stored wide.
+    pub(crate) fn add_table(dump: &mut Dump, names: &[Option<&str>]) {
+        let mut array = vec![0; PTR + 8];
+        array[..PTR].copy_from_slice(&DATA.to_ne_bytes());
+        array[PTR..PTR + 4].copy_from_slice(&(names.len() as i32).to_ne_bytes());
+        array[PTR + 4..].copy_from_slice(&(names.len() as i32).to_ne_bytes());
+
+        let mut data = vec![0; names.len() * PTR];
+        let mut entries = vec![0; names.len() * 0x100];
+        for (i, name) in names.iter().enumerate() {
+            let Some(name) = name else {
+                continue;
+            };
+
+            let address = ENTRIES + i * 0x100;
+            data[i * PTR..(i + 1) * PTR].copy_from_slice(&address.to_ne_bytes());
+
+            let entry = &mut entries[i * 0x100..(i + 1) * 0x100];
+            let wide = !name.is_ascii();
+            let raw_index = (i as i32) << NAME_INDEX_SHIFT | wide as i32;
+            entry[ENTRY_LAYOUT.index..ENTRY_LAYOUT.index + 4].copy_from_slice(&raw_index.to_ne_bytes());
+            // Chain every entry to the previous one, as if they shared a hash bucket.
+            let hash_next = if i == 0 { 0 } else { address - 0x100 };
+            entry[ENTRY_LAYOUT.hash_next..ENTRY_LAYOUT.hash_next + PTR].copy_from_slice(&hash_next.to_ne_bytes());
+
+            let text = &mut entry[ENTRY_LAYOUT.name..];
+            match wide {
+                true => {
+                    for (j, unit) in name.encode_utf16().enumerate() {
+                        text[j * 2..j * 2 + 2].copy_from_slice(&unit.to_ne_bytes());
+                    }
+                }
+                false => text[..name.len()].copy_from_slice(name.as_bytes()),
+            }
+        }
+
+        dump.add_region(ARRAY, array).unwrap();
+        dump.add_region(DATA, data).unwrap();
+        dump.add_region(ENTRIES, entries).unwrap();
+    }
+
+    const NAMES: &[Option<&str>] = &[Some("None"), Some("Object"), None, Some("Größe"), Some("Foo_3"), Some("Foo")];
+
+    #[test]
+    fn decodes_entries() {
+        let dump = table(NAMES);
+
+        let none = read_entry(&dump, ENTRIES).unwrap();
+        assert_eq!((none.index, none.wide, none.hash_next, none.name.as_str()), (0, false, 0, "None"));
+
+        let wide = read_entry(&dump, ENTRIES + 0x300).unwrap();
+        assert_eq!(wide.address, ENTRIES + 0x300);
+        assert_eq!((wide.index, wide.wide, wide.name.as_str()), (3, true, "Größe"));
+        assert_eq!(wide.hash_next, ENTRIES + 0x200);
+    }
+
+    #[test]
+    fn reads_names_by_index() {
+        let names = NameTable::new(table(NAMES), ARRAY);
+
+        assert!(names.is_plausible());
+        assert_eq!(names.len().unwrap(), 6);
+        assert_eq!(&*names.name(1).unwrap(), "Object");
+        assert!(names.entry(2).unwrap().is_none());
+        assert!(names.name(2).is_err());
+        assert!(names.entry(6).is_err());
+
+        assert_eq!(names.to_string(FName { index: 5, number: 0 }).unwrap(), "Foo");
+        assert_eq!(names.to_string(FName { index: 5, number: 1 }).unwrap(), "Foo_0");
+        assert_eq!(names.to_string(FName { index: 5, number: 8 }).unwrap(), "Foo_7");
+    }
+
+    #[test]
+    fn finds_names_ignoring_case() {
+        let names = NameTable::new(table(NAMES), ARRAY);
+
+        assert_eq!(names.find("object").unwrap(), Some(FName { index: 1, number: 0 }));
+        assert_eq!(names.find("GRÖßE").unwrap(), Some(FName { index: 3, number: 0 }));
+        assert_eq!(names.find("Missing").unwrap(), None);
+
+        // Numbered names resolve to their base, unless only the full name exists.
+        assert_eq!(names.find("FOO_7").unwrap(), Some(FName { index: 5, number: 8 }));
+        assert_eq!(names.find("Object_01").unwrap(), None);
+    }
+
+    #[test]
+    fn finds_numbered_names_stored_whole() {
+        let names = NameTable::new(table(&[Some("None"), Some("Foo_3")]), ARRAY);
+        assert_eq!(names.find("Foo_3").unwrap(), Some(FName { index: 1, number: 0 }));
+    }
+
+    #[test]
+    fn splits_instance_numbers() {
+        assert_eq!(split_number("Foo_3"), ("Foo", 4));
+        assert_eq!(split_number("Foo_0"), ("Foo", 1));
+        assert_eq!(split_number("Foo_Bar_12"), ("Foo_Bar", 13));
+        assert_eq!(split_number("Foo_03"), ("Foo_03", 0));
+        assert_eq!(split_number("Foo_"), ("Foo_", 0));
+        assert_eq!(split_number("_3"), ("_3", 0));
+        assert_eq!(split_number("Foo"), ("Foo", 0));
+        assert_eq!(split_number("Foo_2147483647"), ("Foo_2147483647", 0));
+    }
+
+    #[test]
+    fn rejects_implausible_tables() {
+        let names = table(NAMES);
+        names.write(ENTRIES + ENTRY_LAYOUT.name, *b"Nope").unwrap();
+        assert!(!NameTable::new(names, ARRAY).is_plausible());
+
+        // An entry claiming the wrong index.
+        let names = table(NAMES);
+        names.write(ENTRIES + 0x100 + ENTRY_LAYOUT.index, 7i32 << NAME_INDEX_SHIFT).unwrap();
+        assert!(NameTable::new(names, ARRAY).entry(1).is_err());
+
+        let mut empty = Dump::new();
+        empty.add_region(ARRAY, vec![0; PTR + 8]).unwrap();
+        assert!(!NameTable::new(empty, ARRAY).is_plausible());
+    }
+}
@@ -0,0 +1,359 @@
+//! This module reads UDK's ini dialect, so our settings can live in the game's own configs.
+//!
+//! On top of plain `Key=Value` lines, the dialect has array operators:
+//!  * `+Key=Value` adds a value, unless the key already has exactly that value.
+//!  * `.Key=Value` adds a value, even if it's a duplicate.
+//!  * `-Key=Value` removes that exact value.
+//!  * `!Key=` removes every value of the key.
+//!
+//! A file may inherit from another with `BasedOn=` in its `[Configuration]` section. The base
+//! file is applied first and the file's own lines on top. Like the UDK, `BasedOn` paths are
+//! relative to the game directory (`UDKGame`), e.g. `..\Engine\Config\BaseGame.ini`.
+//!
+//! Nothing here touches the file system directly, so hierarchies can be loaded from fixtures.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+/// How deep `BasedOn` chains may go before we assume they're circular.
+const MAX_DEPTH: usize = 16;
+
+/// A merged ini hierarchy.
+#[derive(Default, Debug)]
+pub struct UdkIni {
+    /// Sections in order of first appearance, each with its values in order.
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+struct Section {
+    name: String,
+    values: Vec<(String, String)>,
+}
+
+/// A line's operation on its key.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Op {
+    Set,
+    AddUnique,
+    Add,
+    Remove,
+    Clear,
+}
+
+impl UdkIni {
+    /// Load `path` and every file it's based on. `game_dir` is what `BasedOn` paths are relative
+    /// to, and `read` reads a file's contents.
+    ///
+    /// Returns the merged hierarchy along with a warning for each malformed line.
+    pub fn load(
+        path: &Path,
+        game_dir: &Path,
+        read: impl Fn(&Path) -> io::Result<String>,
+    ) -> anyhow::Result<(UdkIni, Vec<String>)> {
+        let mut ini = UdkIni::default();
+        let mut warnings = Vec::new();
+        let mut chain = Vec::new();
+
+        ini.load_file(path, game_dir, &read, &mut chain, &mut warnings)?;
+        Ok((ini, warnings))
+    }
+
+    fn load_file(
+        &mut self,
+        path: &Path,
+        game_dir: &Path,
+        read: &impl Fn(&Path) -> io::Result<String>,
+        chain: &mut Vec<PathBuf>,
+        warnings: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        if chain.iter().any(|p| p == path) {
+            bail!("{} is based on itself", path.display());
+        }
+        if chain.len() >= MAX_DEPTH {
+            bail!("BasedOn chain is deeper than {MAX_DEPTH} files");
+        }
+
+        let text = read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let lines = parse_lines(&text, &name, warnings);
+
+        // The base is applied first, wherever BasedOn appears in the file.
+        let based_on = lines
+            .iter()
+            .rev()
+            .find(|l| l.section.eq_ignore_ascii_case("Configuration") && l.key.eq_ignore_ascii_case("BasedOn"))
+            .map(|l| l.value.clone());
+
+        chain.push(path.to_path_buf());
+        if let Some(base) = based_on.filter(|b| !b.is_empty()) {
+            let base_path = game_dir.join(base.replace('\\', "/"));
+            self.load_file(&base_path, game_dir, read, chain, warnings)
+                .with_context(|| format!("in BasedOn of {name}"))?;
+        }
+        chain.pop();
+
+        for line in lines.iter().filter(|l| !l.section.eq_ignore_ascii_case("Configuration")) {
+            self.apply(line);
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, line: &Line) {
+        let section = match self.sections.iter().position(|s| s.name.eq_ignore_ascii_case(&line.section)) {
+            Some(i) => &mut self.sections[i],
+            None => {
+                self.sections.push(Section {
+                    name: line.section.clone(),
+                    values: Vec::new(),
+                });
+                self.sections.last_mut().unwrap()
+            }
+        };
+
+        let key = line.key.as_str();
+        let same_key = |(k, _): &(String, String)| k.eq_ignore_ascii_case(key);
+        let same_entry = |(k, v): &(String, String)| k.eq_ignore_ascii_case(key) && *v == line.value;
+
+        match line.op {
+            Op::Set => {
+                section.values.retain(|e| !same_key(e));
+                section.values.push((line.key.clone(), line.value.clone()));
+            }
+            Op::AddUnique => {
+                if !section.values.iter().any(same_entry) {
+                    section.values.push((line.key.clone(), line.value.clone()));
+                }
+            }
+            Op::Add => section.values.push((line.key.clone(), line.value.clone())),
+            Op::Remove => section.values.retain(|e| !same_entry(e)),
+            Op::Clear => section.values.retain(|e| !same_key(e)),
+        }
+    }
+
+    /// Return the keys of a section with all of their values, in order of first appearance.
+    pub fn section(&self, name: &str) -> Vec<(String, Vec<String>)> {
+        let Some(section) = self.sections.iter().find(|s| s.name.eq_ignore_ascii_case(name)) else {
+            return Vec::new();
+        };
+
+        let mut keys: Vec<(String, Vec<String>)> = Vec::new();
+        for (key, value) in &section.values {
+            match keys.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                Some((_, values)) => values.push(value.clone()),
+                None => keys.push((key.clone(), vec![value.clone()])),
+            }
+        }
+        keys
+    }
+}
+
+/// A single operation from an ini file.
+struct Line {
+    section: String,
+    op: Op,
+    key: String,
+    value: String,
+}
+
+fn parse_lines(text: &str, name: &str, warnings: &mut Vec<String>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut section = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            section = Some(header.trim().to_string());
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            warnings.push(format!("{name}:{}: expected `Key=Value`, found `{line}`", i + 1));
+            continue;
+        };
+        let Some(section) = &section else {
+            warnings.push(format!("{name}:{}: `{}` is outside of any section", i + 1, key.trim()));
+            continue;
+        };
+
+        let key = key.trim();
+        let (op, key) = match key.chars().next() {
+            Some('+') => (Op::AddUnique, &key[1..]),
+            Some('.') => (Op::Add, &key[1..]),
+            Some('-') => (Op::Remove, &key[1..]),
+            Some('!') => (Op::Clear, &key[1..]),
+            _ => (Op::Set, key),
+        };
+
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+
+        lines.push(Line {
+            section: section.clone(),
+            op,
+            key: key.trim().to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `UDKGame` directory with `Config/DefaultGame.ini` based on `Engine/Config/BaseGame.ini`,
+    /// as the UDK lays it out.
+    const TREE: &[(&str, &str)] = &[
+        (
+            "/Game/UDKGame/Config/DefaultGame.ini",
+            "[Configuration]\n\
+             BasedOn=..\\Engine\\Config\\BaseGame.ini\n\
+             \n\
+             [TotemArts.Extensions]\n\
+             XAudio.TraceLevel=Errors\n\
+             +Input.DenyDevices=*RGB*\n\
+             +Input.DenyDevices=1234:bead\n\
+             -Input.DenyDevices=*Wheel*\n\
+             .Input.Remap=A=B\n\
+             .Input.Remap=A=B\n\
+             !Plugins.Enabled=\n",
+        ),
+        (
+            "/Game/Engine/Config/BaseGame.ini",
+            "; The engine's defaults.\n\
+             [TotemArts.Extensions]\n\
+             XAudio.TraceLevel=Detail\n\
+             +Input.DenyDevices=*Wheel*\n\
+             +Input.DenyDevices=*RGB*\n\
+             Plugins.Enabled=false\n\
+             [Engine.GameInfo]\n\
+             DefaultGame=\"UTGame.UTGame\"\n",
+        ),
+    ];
+
+    fn read(tree: &'static [(&'static str, &'static str)]) -> impl Fn(&Path) -> io::Result<String> {
+        move |path| {
+            let path = path.to_string_lossy().replace('\\', "/");
+            // Resolve the `..` that `BasedOn` paths start with.
+            let mut parts: Vec<&str> = Vec::new();
+            for part in path.split('/') {
+                match part {
+                    ".." => {
+                        parts.pop();
+                    }
+                    part => parts.push(part),
+                }
+            }
+            let path = parts.join("/");
+
+            tree.iter()
+                .find(|(p, _)| *p == path)
+                .map(|(_, text)| text.to_string())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path))
+        }
+    }
+
+    fn load(tree: &'static [(&'static str, &'static str)]) -> anyhow::Result<(UdkIni, Vec<String>)> {
+        UdkIni::load(Path::new("/Game/UDKGame/Config/DefaultGame.ini"), Path::new("/Game/UDKGame"), read(tree))
+    }
+
+    fn values<'a>(section: &'a [(String, Vec<String>)], key: &str) -> Option<&'a [String]> {
+        section.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_slice())
+    }
+
+    #[test]
+    fn merges_based_on_hierarchy() {
+        let (ini, warnings) = load(TREE).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+
+        let section = ini.section("totemarts.extensions");
+        assert_eq!(values(&section, "XAudio.TraceLevel").unwrap(), ["Errors"]);
+        // `+` skips the duplicate, `-` removes the base's value, `.` keeps duplicates.
+        assert_eq!(values(&section, "Input.DenyDevices").unwrap(), ["*RGB*", "1234:bead"]);
+        assert_eq!(values(&section, "Input.Remap").unwrap(), ["A=B", "A=B"]);
+        // `!` clears every value.
+        assert_eq!(values(&section, "Plugins.Enabled"), None);
+
+        let game = ini.section("Engine.GameInfo");
+        assert_eq!(values(&game, "DefaultGame").unwrap(), ["UTGame.UTGame"]);
+        assert!(ini.section("Configuration").is_empty());
+        assert!(ini.section("Missing").is_empty());
+    }
+
+    #[test]
+    fn orders_keys_by_their_earliest_value() {
+        let (ini, _) = load(TREE).unwrap();
+        let keys = ini
+            .section("TotemArts.Extensions")
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        // Setting a key replaces its values, so the child's TraceLevel comes after the base's
+        // surviving DenyDevices value.
+        assert_eq!(keys, ["Input.DenyDevices", "XAudio.TraceLevel", "Input.Remap"]);
+    }
+
+    #[test]
+    fn set_replaces_every_value() {
+        const TREE: &[(&str, &str)] = &[(
+            "/Game/UDKGame/Config/DefaultGame.ini",
+            "[S]\n.Key=1\n.key=2\nKEY=3\n",
+        )];
+
+        let (ini, _) = load(TREE).unwrap();
+        assert_eq!(ini.section("S"), [("KEY".to_string(), vec!["3".to_string()])]);
+    }
+
+    #[test]
+    fn warns_about_malformed_lines() {
+        const TREE: &[(&str, &str)] = &[(
+            "/Game/UDKGame/Config/DefaultGame.ini",
+            "Orphan=1\n[S]\nNoValue\nKey=1\n",
+        )];
+
+        let (ini, warnings) = load(TREE).unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "DefaultGame.ini:1: `Orphan` is outside of any section",
+                "DefaultGame.ini:3: expected `Key=Value`, found `NoValue`",
+            ]
+        );
+        assert_eq!(ini.section("S").len(), 1);
+    }
+
+    #[test]
+    fn rejects_circular_and_missing_bases() {
+        const CIRCULAR: &[(&str, &str)] = &[
+            (
+                "/Game/UDKGame/Config/DefaultGame.ini",
+                "[Configuration]\nBasedOn=..\\UDKGame\\Config\\Other.ini\n",
+            ),
+            (
+                "/Game/UDKGame/Config/Other.ini",
+                "[Configuration]\nBasedOn=..\\UDKGame\\Config\\DefaultGame.ini\n",
+            ),
+        ];
+        const MISSING: &[(&str, &str)] = &[(
+            "/Game/UDKGame/Config/DefaultGame.ini",
+            "[Configuration]\nBasedOn=..\\Engine\\Config\\Nowhere.ini\n",
+        )];
+
+        let error = format!("{:#}", load(CIRCULAR).unwrap_err());
+        assert!(error.contains("is based on itself"), "{error}");
+
+        let error = format!("{:#}", load(MISSING).unwrap_err());
+        assert!(error.contains("failed to read"), "{error}");
+    }
+}
@@ -1,5 +1,9 @@
 //! This module contains functionality relevant to UDK logging.
-use crate::dll::get_udk_ptr;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dll::{get_udk_ptr, UDK_RANGE};
 
 /// Offset from the beginning of UDK64.exe to the debug log object.
 #[cfg(target_arch = "x86_64")]
@@ -16,14 +20,14 @@ const DEBUG_LOG_OFFSET: usize = 0x029a_31a8;
 const DEBUG_FN_OFFSET: usize = 0x0002_1c500;
 
 /// This is the type signature of UDK's log function.
-type UDKLogFn = unsafe extern "C" fn(usize, u32, *const widestring::WideChar);
+type UDKLogFn = unsafe extern "C" fn(usize, u32, *const u16);
 
 /// This enum represents the UDK message types.
 #[repr(u32)]
 pub enum LogType {
     Init = 0x2fa,
     //Debug = 0x36c,
-    //Log = 0x2f8,
+    Log = 0x2f8,
     Warning = 0x2ff,
     Error = 0x315,
     //Critical = 0x2f9,
@@ -31,14 +35,166 @@ pub enum LogType {
 
 /// Log a message via the UDK logging framework.
 pub fn log(typ: LogType, msg: &str) {
+    // Before the UDK is verified there's no log to write to, and the message is dropped. Tests
+    // never have one, so they get theirs on stderr instead.
+    if UDK_RANGE.get().is_none() {
+        #[cfg(test)]
+        eprintln!("TotemArts Extensions: {}", msg);
+        return;
+    }
+
     let udk_ptr = get_udk_ptr();
     let log_obj = unsafe { udk_ptr.add(DEBUG_LOG_OFFSET) };
     let log_fn: UDKLogFn = unsafe { std::mem::transmute(udk_ptr.add(DEBUG_FN_OFFSET)) };
 
     // Convert the UTF-8 Rust string into an OS wide string.
-    let wmsg: widestring::U16CString = widestring::WideCString::from_str(format!("TotemArts Extensions: {}", msg)).unwrap();
+    let wmsg = widestring::U16CString::from_str(format!("TotemArts Extensions: {}", msg)).unwrap();
 
     unsafe {
         (log_fn)(log_obj as usize, typ as u32, wmsg.as_ptr());
     }
 }
+
+/// How many messages a rate limited call site logs before rate limiting kicks in.
+pub const RATE_LIMIT_BURST: u64 = 5;
+/// Once rate limited, a call site logs at most one message per this interval.
+pub const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Call-site rate limiter for log messages that may fire from hot paths.
+///
+/// The first `burst` messages are always let through. After that, at most one message
+/// is let through per `interval`, and it carries the number of messages suppressed since.
+pub struct RateLimit {
+    burst: u64,
+    interval: Duration,
+    state: Mutex<RateLimitState>,
+}
+
+struct RateLimitState {
+    count: u64,
+    suppressed: u64,
+    last: Option<Instant>,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u64, interval: Duration) -> Self {
+        Self {
+            burst,
+            interval,
+            state: Mutex::new(RateLimitState {
+                count: 0,
+                suppressed: 0,
+                last: None,
+            }),
+        }
+    }
+
+    /// Record a hit on this call site. Returns `None` if the message should be dropped,
+    /// or the number of messages suppressed since the last one that was let through.
+    pub fn check(&self) -> Option<Suppressed> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Option<Suppressed> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        state.count += 1;
+        if state.count > self.burst {
+            if let Some(last) = state.last {
+                if now.duration_since(last) < self.interval {
+                    state.suppressed += 1;
+                    return None;
+                }
+            }
+        }
+
+        state.last = Some(now);
+        Some(Suppressed(std::mem::take(&mut state.suppressed)))
+    }
+}
+
+/// Number of messages dropped by a [`RateLimit`]. Displays as a suffix for the next
+/// message that gets through, or as nothing if no messages were dropped.
+#[derive(Clone, Copy)]
+pub struct Suppressed(pub u64);
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => Ok(()),
+            n => write!(f, " ({n} similar messages suppressed)"),
+        }
+    }
+}
+
+/// Log a message via the UDK logging framework, rate limited per call site.
+///
+/// The first [`RATE_LIMIT_BURST`] messages from a call site are logged, then at most one
+/// every [`RATE_LIMIT_INTERVAL`].
+macro_rules! log_ratelimited {
+    ($typ:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::udk_log::RateLimit =
+            $crate::udk_log::RateLimit::new($crate::udk_log::RATE_LIMIT_BURST, $crate::udk_log::RATE_LIMIT_INTERVAL);
+
+        if let Some(suppressed) = LIMIT.check() {
+            $crate::udk_log::log($typ, &format!("{}{}", format_args!($($arg)+), suppressed));
+        }
+    }};
+}
+pub(crate) use log_ratelimited;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn limiter() -> RateLimit {
+        RateLimit::new(3, 10 * SECOND)
+    }
+
+    #[test]
+    fn lets_the_burst_through() {
+        let limit = limiter();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limit.check_at(start).map(|s| s.0), Some(0));
+        }
+        assert!(limit.check_at(start).is_none());
+    }
+
+    #[test]
+    fn lets_one_through_per_interval_with_the_suppressed_count() {
+        let limit = limiter();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            limit.check_at(start);
+        }
+        assert!(limit.check_at(start + SECOND).is_none());
+        assert!(limit.check_at(start + 9 * SECOND).is_none());
+        assert_eq!(limit.check_at(start + 10 * SECOND).map(|s| s.0), Some(2));
+
+        // The window restarts at the message that got through.
+        assert!(limit.check_at(start + 19 * SECOND).is_none());
+        assert_eq!(limit.check_at(start + 20 * SECOND).map(|s| s.0), Some(1));
+    }
+
+    #[test]
+    fn quiet_call_sites_pass_without_a_count() {
+        let limit = limiter();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            limit.check_at(start);
+        }
+        assert_eq!(limit.check_at(start + 60 * SECOND).map(|s| s.0), Some(0));
+    }
+
+    #[test]
+    fn suppressed_counts_display_as_a_suffix() {
+        assert_eq!(Suppressed(0).to_string(), "");
+        assert_eq!(Suppressed(4).to_string(), " (4 similar messages suppressed)");
+    }
+}
@@ -0,0 +1,193 @@
+//! This module abstracts access to UDK memory, so the code decoding engine structures can run
+//! against the live process or against bytes captured from it.
+//!
+//! [`Process`] is the memory of the process we're loaded into. In tests, `Dump` serves accesses
+//! to regions of captured (or synthetic) memory, each placed at the address it was captured from,
+//! so pointers inside it resolve like they did in the game.
+#[cfg(test)]
+use std::cell::RefCell;
+
+use anyhow::bail;
+#[cfg(test)]
+use anyhow::Context;
+use zerocopy::{AsBytes, FromBytes};
+
+/// A source of engine memory.
+pub trait Memory {
+    /// Fill `buf` with the bytes at `address`.
+    fn read_bytes(&self, address: usize, buf: &mut [u8]) -> anyhow::Result<()>;
+
+    /// Copy `buf` to `address`.
+    fn write_bytes(&self, address: usize, buf: &[u8]) -> anyhow::Result<()>;
+
+    /// Read a plain value at `address`. Values are read unaligned.
+    fn read<T: FromBytes + AsBytes>(&self, address: usize) -> anyhow::Result<T>
+    where
+        Self: Sized,
+    {
+        let mut value = T::new_zeroed();
+        self.read_bytes(address, value.as_bytes_mut())?;
+        Ok(value)
+    }
+
+    /// Write a plain value to `address`.
+    fn write<T: AsBytes>(&self, address: usize, value: T) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        self.write_bytes(address, value.as_bytes())
+    }
+
+    /// Read a pointer-sized value at `address`.
+    fn read_ptr(&self, address: usize) -> anyhow::Result<usize>
+    where
+        Self: Sized,
+    {
+        self.read::<usize>(address)
+    }
+}
+
+impl<M: Memory> Memory for &M {
+    fn read_bytes(&self, address: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        (**self).read_bytes(address, buf)
+    }
+
+    fn write_bytes(&self, address: usize, buf: &[u8]) -> anyhow::Result<()> {
+        (**self).write_bytes(address, buf)
+    }
+}
+
+/// The memory of our own process.
+#[derive(Clone, Copy, Debug)]
+pub struct Process {
+    /// Whether to ask the OS that memory is accessible before each access. This is slow, but makes
+    /// probing unverified addresses (e.g. signature scan candidates) safe.
+    checked: bool,
+}
+
+impl Process {
+    /// Accesses are checked against the OS' view of the address space.
+    pub const CHECKED: Process = Process { checked: true };
+
+    /// Accesses go straight to memory. Only use this for structures that have been verified.
+    ///
+    /// # Safety
+    /// Every address accessed through this must be valid for the duration of the access.
+    pub const unsafe fn trusted() -> Process {
+        Process { checked: false }
+    }
+
+    fn check(&self, address: usize, len: usize, write: bool) -> anyhow::Result<()> {
+        if address < 0x10000 || address.checked_add(len).is_none() {
+            bail!("invalid address {address:#x}");
+        }
+        if self.checked && !is_accessible(address, len, write) {
+            bail!("{address:#x} is not {}", if write { "writable" } else { "readable" });
+        }
+        Ok(())
+    }
+}
+
+impl Memory for Process {
+    fn read_bytes(&self, address: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.check(address, buf.len(), false)?;
+
+        // SAFETY: Either checked above, or vouched for by whoever created a trusted `Process`.
+        unsafe { std::ptr::copy_nonoverlapping(address as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write_bytes(&self, address: usize, buf: &[u8]) -> anyhow::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.check(address, buf.len(), true)?;
+
+        // SAFETY: As above.
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), address as *mut u8, buf.len()) };
+        Ok(())
+    }
+}
+
+/// Whether every page of `address..address + len` is committed, and readable (or writable).
+fn is_accessible(address: usize, len: usize, write: bool) -> bool {
+    let end = address + len;
+    let mut next = address;
+
+    while next < end {
+        let Ok(r) = region::query(next as *const u8) else {
+            return false;
+        };
+        let allowed = if write { r.is_writable() } else { r.is_readable() };
+        if !r.is_committed() || r.is_guarded() || !allowed {
+            return false;
+        }
+
+        next = r.as_range().end;
+    }
+
+    true
+}
+
+/// Captured regions of memory.
+#[cfg(test)]
+#[derive(Default, Debug)]
+pub struct Dump {
+    /// `(base address, bytes)`, unordered and non-overlapping.
+    regions: Vec<(usize, RefCell<Vec<u8>>)>,
+}
+
+#[cfg(test)]
+impl Dump {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Place `bytes` at `base`.
+    pub fn add_region(&mut self, base: usize, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let end = base.checked_add(bytes.len()).context("region overflows the address space")?;
+        let overlap = self.regions.iter().find(|(b, r)| base < b + r.borrow().len() && *b < end);
+        if let Some((b, r)) = overlap {
+            bail!("region {base:#x}..{end:#x} overlaps {b:#x}..{:#x}", b + r.borrow().len());
+        }
+
+        self.regions.push((base, RefCell::new(bytes)));
+        Ok(())
+    }
+
+    /// Find the region holding `address..address + len`, and where in it that starts.
+    fn locate(&self, address: usize, len: usize) -> anyhow::Result<(&RefCell<Vec<u8>>, usize)> {
+        let region = self
+            .regions
+            .iter()
+            .find(|(base, bytes)| (*base..base + bytes.borrow().len()).contains(&address));
+
+        let Some((base, bytes)) = region else {
+            bail!("{address:#x} is not in the dump");
+        };
+
+        let start = address - base;
+        if start + len > bytes.borrow().len() {
+            bail!("{address:#x}+{len:#x} runs past the end of its region");
+        }
+        Ok((bytes, start))
+    }
+}
+
+#[cfg(test)]
+impl Memory for Dump {
+    fn read_bytes(&self, address: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        let (bytes, start) = self.locate(address, buf.len())?;
+        buf.copy_from_slice(&bytes.borrow()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_bytes(&self, address: usize, buf: &[u8]) -> anyhow::Result<()> {
+        let (bytes, start) = self.locate(address, buf.len())?;
+        bytes.borrow_mut()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
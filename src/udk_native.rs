@@ -0,0 +1,798 @@
+//! This module implements UnrealScript `native` functions in Rust.
+//!
+//! A native function's `UFunction` has a `Func` pointer the VM calls with the caller's script
+//! stack, an `FFrame`. The native reads its own arguments off that stack one expression at a time
+//! (`P_GET_INT` and friends evaluate the next expression through `FFrame::Step`), skips the
+//! end-of-parameters token (`P_FINISH`), and writes its return value to `Result`. Functions
+//! declared with a native index (`native(1234)`) are also called through that slot of
+//! `GNatives`, so binding one patches both.
+//!
+//! Handlers are declared with [`native_function!`], which generates the thunk, and bound with
+//! [`register`] once the engine is up:
+//!
+//! ```ignore
+//! native_function! {
+//!     static ADD = "Function MyMod.MyMutator.Add";
+//!     fn(_this, a: i32, b: i32) -> i32 {
+//!         a + b
+//!     }
+//! }
+//!
+//! udk_native::register(&ADD);
+//! ```
+//!
+//! The handler's parameter and return types are checked against the function's declaration
+//! before anything is patched. `out` parameters aren't supported. The function's package must
+//! stay loaded, since the patches are only undone when we unload.
+//!
+//! A function that's already native can be bound too, to look at its calls first. Such a
+//! thunk is written by hand: it can [`FFrame::peek`] at the first argument, and either handle the
+//! call itself through [`invoke`] or hand it on with [`NativeFunction::call_original`].
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context};
+
+#[cfg(windows)]
+use crate::hooks::{self, Hook, Target};
+use crate::udk_fname::FName;
+use crate::udk_log::{log, log_ratelimited, LogType};
+use crate::udk_mem::{Memory, Process};
+use crate::udk_object::{
+    self, kind_name, ObjectRef, PropertyKind, Reflection, CPF_OUT_PARM, CPF_PARM, CPF_RETURN_PARM, OBJECT_LAYOUT,
+};
+use crate::udk_offsets;
+#[cfg(windows)]
+use crate::udk_ready;
+
+const PTR_SIZE: usize = std::mem::size_of::<usize>();
+
+/// `UFunction::FunctionFlags`: the function is `final`.
+const FUNC_FINAL: u32 = 0x1;
+/// The function is implemented in native code.
+const FUNC_NATIVE: u32 = 0x400;
+/// The function is `static`.
+const FUNC_STATIC: u32 = 0x2000;
+
+/// The token after a native function's last argument.
+const EX_END_FUNCTION_PARMS: u8 = 0x16;
+/// Tokens of expressions that can be evaluated twice, since they only read a value:
+/// `EX_LocalVariable`, `EX_InstanceVariable` and `EX_StringConst`.
+const PURE_TOKENS: [u8; 3] = [0x00, 0x01, 0x1F];
+/// Entries in `GNatives`.
+const NATIVES_LEN: usize = 0x1000;
+
+/// `Core.Object.Abs` is `native(186) static final`. It's used to check the `UFunction` layout.
+const ABS: &str = "Function Core.Object.Abs";
+const ABS_NATIVE_INDEX: u16 = 186;
+
+/// Slots in `FMalloc`'s vtable: a destructor and `FExec::Exec` come first.
+const MALLOC_SLOT: usize = 2;
+const FREE_SLOT: usize = 4;
+/// Let the allocator pick the alignment.
+const DEFAULT_ALIGNMENT: u32 = 0;
+
+/// `UObject::*Native`: `(this->*Func)(Stack, Result)`.
+pub type NativeFn = unsafe extern "C" fn(usize, *mut FFrame, usize);
+type MallocFn = unsafe extern "C" fn(usize, u32, u32) -> usize;
+type FreeFn = unsafe extern "C" fn(usize, usize);
+
+/// UE3's `FFrame`, the state of a running UnrealScript function.
+#[repr(C)]
+pub struct FFrame {
+    /// `FOutputDevice`'s vtable and flags.
+    _vtable: usize,
+    _allow_suppression: u32,
+    _suppress_event_tag: u32,
+    _auto_emit_line_terminator: u32,
+    /// The function (or state) being run.
+    _node: usize,
+    /// The object the function runs on, which expressions are evaluated against.
+    object: usize,
+    /// The next bytecode. The rest of the frame isn't needed.
+    code: *mut u8,
+}
+
+impl FFrame {
+    /// Evaluate the next expression into `result`: `FFrame::Step`.
+    ///
+    /// # Safety
+    /// The frame must be the one a native was called with, and `result` must have room for the
+    /// expression's value.
+    unsafe fn step(&mut self, result: usize) {
+        // Always set: thunks are only installed once the table is found.
+        let natives = *NATIVES.get().expect("GNatives is not located");
+
+        let token = *self.code;
+        self.code = self.code.add(1);
+        let exec: NativeFn = std::mem::transmute(*(natives as *const usize).add(token as usize));
+        exec(self.object, self, result);
+    }
+
+    /// Evaluate the next expression as a `T`. Every type we take fits in two words, an `FString`.
+    unsafe fn step_as<T: Copy>(&mut self) -> T {
+        let mut slot = [0u64; 2];
+        debug_assert!(std::mem::size_of::<T>() <= std::mem::size_of_val(&slot));
+
+        self.step(slot.as_mut_ptr() as usize);
+        std::ptr::read_unaligned(slot.as_ptr() as *const T)
+    }
+
+    /// Read the next argument: `P_GET_*`.
+    ///
+    /// # Safety
+    /// See [`FFrame::step`]; the argument must be a `T`.
+    pub unsafe fn get<T: NativeParam>(&mut self) -> T {
+        T::read(self)
+    }
+
+    /// Read the next argument, and rewind to it. Only variables and constants are read, since
+    /// anything else may have side effects; others give `None` without evaluating anything.
+    ///
+    /// # Safety
+    /// See [`FFrame::get`].
+    pub unsafe fn peek<T: NativeParam>(&mut self) -> Option<T> {
+        if !PURE_TOKENS.contains(&*self.code) {
+            return None;
+        }
+
+        let code = self.code;
+        let value = T::read(self);
+        self.code = code;
+        Some(value)
+    }
+
+    /// Evaluate the arguments that haven't been read, and skip the end of them: `P_FINISH`.
+    /// The values are dropped, so strings among them leak.
+    ///
+    /// # Safety
+    /// See [`FFrame::step`]. The next token must start an argument, or end them.
+    unsafe fn skip_args(&mut self) {
+        while *self.code != EX_END_FUNCTION_PARMS {
+            self.step_as::<[u64; 2]>();
+        }
+        self.code = self.code.add(1);
+    }
+}
+
+/// UE3's `FString`: a `TArray<TCHAR>` including the terminator.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FString {
+    data: *mut u16,
+    num: i32,
+    max: i32,
+}
+
+/// The argument and return types a handler can use.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NativeType {
+    Byte,
+    Int,
+    Float,
+    Bool,
+    Name,
+    Str,
+    Object,
+}
+
+impl NativeType {
+    /// Whether a parameter of kind `kind` is passed as this type.
+    fn accepts(self, kind: &PropertyKind) -> bool {
+        matches!(
+            (self, kind),
+            (NativeType::Byte, PropertyKind::Byte)
+                | (NativeType::Int, PropertyKind::Int)
+                | (NativeType::Float, PropertyKind::Float)
+                | (NativeType::Bool, PropertyKind::Bool { .. })
+                | (NativeType::Name, PropertyKind::Name)
+                | (NativeType::Str, PropertyKind::Str)
+                | (NativeType::Object, PropertyKind::Object { .. })
+        )
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            NativeType::Byte => "byte",
+            NativeType::Int => "int",
+            NativeType::Float => "float",
+            NativeType::Bool => "bool",
+            NativeType::Name => "name",
+            NativeType::Str => "string",
+            NativeType::Object => "object",
+        }
+    }
+}
+
+/// A type a handler can take as an argument.
+pub trait NativeParam: Sized {
+    const TYPE: NativeType;
+
+    /// # Safety
+    /// See [`FFrame::get`].
+    unsafe fn read(frame: &mut FFrame) -> Self;
+}
+
+impl NativeParam for u8 {
+    const TYPE: NativeType = NativeType::Byte;
+
+    unsafe fn read(frame: &mut FFrame) -> Self {
+        frame.step_as()
+    }
+}
+
+impl NativeParam for i32 {
+    const TYPE: NativeType = NativeType::Int;
+
+    unsafe fn read(frame: &mut FFrame) -> Self {
+        frame.step_as()
+    }
+}
+
+impl NativeParam for f32 {
+    const TYPE: NativeType = NativeType::Float;
+
+    unsafe fn read(frame: &mut FFrame) -> Self {
+        frame.step_as()
+    }
+}
+
+impl NativeParam for bool {
+    const TYPE: NativeType = NativeType::Bool;
+
+    /// Bools are passed as a whole `UBOOL`, not a bit.
+    unsafe fn read(frame: &mut FFrame) -> Self {
+        frame.step_as::<u32>() != 0
+    }
+}
+
+impl NativeParam for FName {
+    const TYPE: NativeType = NativeType::Name;
+
+    unsafe fn read(frame: &mut FFrame) -> Self {
+        frame.step_as()
+    }
+}
+
+impl NativeParam for String {
+    const TYPE: NativeType = NativeType::Str;
+
+    /// The engine copies the string into our slot with its own allocator, so it's freed with it too.
+    unsafe fn read(frame: &mut FFrame) -> Self {
+        let string = frame.step_as::<FString>();
+        if string.data.is_null() {
+            return String::new();
+        }
+
+        let len = usize::try_from(string.num - 1).unwrap_or(0);
+        let value = String::from_utf16_lossy(std::slice::from_raw_parts(string.data, len));
+        if let Err(error) = engine_free(string.data as usize) {
+            log_ratelimited!(LogType::Warning, "Leaked a string argument: {:#}", error);
+        }
+        value
+    }
+}
+
+impl NativeParam for Option<ObjectRef> {
+    const TYPE: NativeType = NativeType::Object;
+
+    unsafe fn read(frame: &mut FFrame) -> Self {
+        let object = frame.step_as::<usize>();
+        (object != 0).then_some(ObjectRef(object))
+    }
+}
+
+/// A type a handler can return.
+pub trait NativeReturn {
+    /// The return type, or `None` for functions that don't return anything.
+    const TYPE: Option<NativeType>;
+
+    /// # Safety
+    /// `result` must point at storage for the function's return value.
+    unsafe fn write(self, result: usize) -> anyhow::Result<()>;
+}
+
+impl NativeReturn for () {
+    const TYPE: Option<NativeType> = None;
+
+    unsafe fn write(self, _result: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Errors are logged, and the return value is left as the engine initialized it.
+impl<T: NativeReturn> NativeReturn for anyhow::Result<T> {
+    const TYPE: Option<NativeType> = T::TYPE;
+
+    unsafe fn write(self, result: usize) -> anyhow::Result<()> {
+        self?.write(result)
+    }
+}
+
+macro_rules! impl_native_return {
+    ($($ty:ty => $native:ident),*) => {$(
+        impl NativeReturn for $ty {
+            const TYPE: Option<NativeType> = Some(NativeType::$native);
+
+            unsafe fn write(self, result: usize) -> anyhow::Result<()> {
+                *(result as *mut $ty) = self;
+                Ok(())
+            }
+        }
+    )*};
+}
+
+impl_native_return!(u8 => Byte, i32 => Int, f32 => Float, FName => Name);
+
+impl NativeReturn for bool {
+    const TYPE: Option<NativeType> = Some(NativeType::Bool);
+
+    unsafe fn write(self, result: usize) -> anyhow::Result<()> {
+        *(result as *mut u32) = self as u32;
+        Ok(())
+    }
+}
+
+impl NativeReturn for Option<ObjectRef> {
+    const TYPE: Option<NativeType> = Some(NativeType::Object);
+
+    unsafe fn write(self, result: usize) -> anyhow::Result<()> {
+        *(result as *mut usize) = self.map_or(0, ObjectRef::address);
+        Ok(())
+    }
+}
+
+impl NativeReturn for String {
+    const TYPE: Option<NativeType> = Some(NativeType::Str);
+
+    /// Assign to the engine's `FString`, which will free the buffer with its own allocator.
+    unsafe fn write(self, result: usize) -> anyhow::Result<()> {
+        let string = &mut *(result as *mut FString);
+        if !string.data.is_null() {
+            engine_free(string.data as usize)?;
+        }
+        *string = FString {
+            data: std::ptr::null_mut(),
+            num: 0,
+            max: 0,
+        };
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let mut units = self.encode_utf16().collect::<Vec<_>>();
+        units.push(0);
+        let len = i32::try_from(units.len()).context("string is too long")?;
+
+        let data = engine_malloc(units.len() * 2)? as *mut u16;
+        data.copy_from_nonoverlapping(units.as_ptr(), units.len());
+        *string = FString { data, num: len, max: len };
+        Ok(())
+    }
+}
+
+/// A native function implemented in Rust. Declare these with [`native_function!`].
+pub struct NativeFunction {
+    /// The function's full name (`Function MyMod.MyMutator.Add`).
+    pub path: &'static str,
+    pub thunk: NativeFn,
+    pub params: &'static [NativeType],
+    pub ret: Option<NativeType>,
+    /// The function's `Func` before it was bound, or 0.
+    original: AtomicUsize,
+}
+
+impl NativeFunction {
+    pub const fn new(
+        path: &'static str,
+        thunk: NativeFn,
+        params: &'static [NativeType],
+        ret: Option<NativeType>,
+    ) -> Self {
+        Self {
+            path,
+            thunk,
+            params,
+            ret,
+            original: AtomicUsize::new(0),
+        }
+    }
+
+    /// Run the native implementation the function had before it was bound to us, if any.
+    ///
+    /// # Safety
+    /// Must be called from the thunk, with the arguments the VM passed to it, before any of
+    /// the arguments have been read.
+    pub unsafe fn call_original(&self, this: usize, stack: *mut FFrame, result: usize) {
+        match self.original.load(Ordering::Acquire) {
+            0 => log_ratelimited!(LogType::Error, "{} has no original implementation", self.path),
+            original => std::mem::transmute::<usize, NativeFn>(original)(this, stack, result),
+        }
+    }
+}
+
+/// Declare a [`NativeFunction`] named `$name` implementing the UnrealScript function `$path`.
+///
+/// The handler takes the object the function was called on (the default object for `static`
+/// functions) followed by the function's arguments, and may return an `anyhow::Result`.
+// Nothing binds a handler declared with it yet but the tests.
+#[cfg_attr(not(test), allow(unused_macros))]
+macro_rules! native_function {
+    (@ret $ret:ty) => { $ret };
+    (@ret) => { () };
+    (
+        $(#[$meta:meta])*
+        $vis:vis static $name:ident = $path:literal;
+        fn($this:pat $(, $arg:ident: $ty:ty)* $(,)?) $(-> $ret:ty)? $body:block
+    ) => {
+        $(#[$meta])*
+        $vis static $name: $crate::udk_native::NativeFunction = $crate::udk_native::NativeFunction::new(
+            $path,
+            {
+                fn handler(
+                    $this: $crate::udk_object::ObjectRef,
+                    $($arg: $ty),*
+                ) -> $crate::udk_native::native_function!(@ret $($ret)?) $body
+
+                unsafe extern "C" fn thunk(this: usize, stack: *mut $crate::udk_native::FFrame, result: usize) {
+                    $crate::udk_native::invoke(
+                        $path,
+                        this,
+                        stack,
+                        result,
+                        |frame| ($(frame.get::<$ty>(),)*),
+                        |this, ($($arg,)*)| handler(this, $($arg),*),
+                    )
+                }
+
+                thunk
+            },
+            &[$(<$ty as $crate::udk_native::NativeParam>::TYPE),*],
+            <$crate::udk_native::native_function!(@ret $($ret)?) as $crate::udk_native::NativeReturn>::TYPE,
+        );
+    };
+}
+#[cfg_attr(not(test), allow(unused_imports))]
+pub(crate) use native_function;
+
+/// Handle a native call: `read` the arguments off the stack, then run `handler` on them, writing
+/// its return value to `result`. Used by [`native_function!`]'s thunks.
+///
+/// Whatever happens, the stack is left after the arguments, where the VM carries on. If `read`
+/// stops early, the arguments it didn't read are still evaluated.
+///
+/// # Safety
+/// Must be called with the arguments the VM passed to the thunk, before any have been read.
+pub unsafe fn invoke<A, R: NativeReturn>(
+    path: &str,
+    this: usize,
+    stack: *mut FFrame,
+    result: usize,
+    read: impl FnOnce(&mut FFrame) -> A,
+    handler: impl FnOnce(ObjectRef, A) -> R,
+) {
+    let frame = &mut *stack;
+    let args = catch_unwind(AssertUnwindSafe(|| read(frame)));
+    frame.skip_args();
+    let Ok(args) = args else {
+        log_ratelimited!(LogType::Error, "{}: reading the arguments panicked", path);
+        return;
+    };
+
+    let Ok(value) = catch_unwind(AssertUnwindSafe(|| handler(ObjectRef(this), args))) else {
+        log_ratelimited!(LogType::Error, "{}: the native handler panicked", path);
+        return;
+    };
+
+    if result != 0 {
+        if let Err(error) = value.write(result) {
+            log_ratelimited!(LogType::Error, "{}: {:#}", path, error);
+        }
+    }
+}
+
+/// `GNatives`, for [`FFrame::step`].
+static NATIVES: OnceLock<usize> = OnceLock::new();
+
+/// Bind `native` once the engine is up. Failures are logged.
+#[cfg(windows)]
+pub fn register(native: &'static NativeFunction) {
+    udk_ready::when_ready(native.path, move || bind(native));
+}
+
+#[cfg(windows)]
+fn bind(native: &'static NativeFunction) -> anyhow::Result<()> {
+    let reflection = udk_object::reflection()?;
+    check_function_layout(reflection)?;
+
+    let function = reflection.find(native.path)?.with_context(|| format!("{} not found", native.path))?;
+    if reflection.function_flags(function)? & FUNC_NATIVE == 0 {
+        bail!("{} isn't declared native", native.path);
+    }
+    check_signature(reflection, native, function)?;
+
+    // Find everything the thunk needs before the VM can call it.
+    let natives = natives()?;
+    if native.params.contains(&NativeType::Str) || native.ret == Some(NativeType::Str) {
+        allocator()?;
+    }
+
+    let index = usize::from(reflection.native_index(function)?);
+    if index >= NATIVES_LEN {
+        bail!("{} has native index {index}, past the end of GNatives", native.path);
+    }
+
+    // Saved before the thunk can be called.
+    let func = Process::CHECKED.read_ptr(function.address() + OBJECT_LAYOUT.func)?;
+    native.original.store(func, Ordering::Release);
+
+    hooks::install(Hook::pointer(
+        native.path,
+        Target::Address(function.address() + OBJECT_LAYOUT.func),
+        native.thunk as usize,
+    ))?;
+    if index != 0 {
+        let name: &'static str = Box::leak(format!("{} (GNatives[{index}])", native.path).into_boxed_str());
+        hooks::install(Hook::pointer(
+            name,
+            Target::Address(natives + index * PTR_SIZE),
+            native.thunk as usize,
+        ))?;
+    }
+
+    log(LogType::Init, &format!("Bound native {}", native.path));
+    Ok(())
+}
+
+/// Check the function's parameters against the handler's.
+fn check_signature<M: Memory + Copy>(
+    reflection: &Reflection<'_, M>,
+    native: &NativeFunction,
+    function: ObjectRef,
+) -> anyhow::Result<()> {
+    let properties = reflection.properties(function)?;
+    let params = properties
+        .iter()
+        .filter(|p| p.flags & CPF_PARM != 0 && p.flags & CPF_RETURN_PARM == 0)
+        .collect::<Vec<_>>();
+    let ret = properties.iter().find(|p| p.flags & CPF_RETURN_PARM != 0);
+
+    if let Some(p) = params.iter().find(|p| p.flags & CPF_OUT_PARM != 0) {
+        bail!("{}: out parameter {} isn't supported", native.path, p.name);
+    }
+    if params.len() != native.params.len() {
+        bail!(
+            "{} takes {} arguments, but the handler takes {}",
+            native.path,
+            params.len(),
+            native.params.len()
+        );
+    }
+    for (param, &expected) in params.iter().zip(native.params) {
+        if !expected.accepts(&param.kind) || param.array_dim > 1 {
+            bail!(
+                "{}: {} is {}, but the handler takes {}",
+                native.path,
+                param.name,
+                kind_name(&param.kind),
+                expected.name()
+            );
+        }
+    }
+
+    match (ret, native.ret) {
+        (None, None) => Ok(()),
+        (Some(p), Some(expected)) if expected.accepts(&p.kind) => Ok(()),
+        (ret, expected) => bail!(
+            "{} returns {}, but the handler returns {}",
+            native.path,
+            ret.map_or("nothing", |p| kind_name(&p.kind)),
+            expected.map_or("nothing", NativeType::name)
+        ),
+    }
+}
+
+/// Check the `UFunction` fields in [`OBJECT_LAYOUT`] against a function we know.
+fn check_function_layout(reflection: &Reflection<'static, Process>) -> anyhow::Result<()> {
+    let abs = reflection.find(ABS)?.with_context(|| format!("{ABS} not found"))?;
+    let flags = reflection.function_flags(abs)?;
+    let index = reflection.native_index(abs)?;
+    let func = Process::CHECKED.read_ptr(abs.address() + OBJECT_LAYOUT.func)?;
+
+    let expected = FUNC_FINAL | FUNC_NATIVE | FUNC_STATIC;
+    if flags & expected != expected || index != ABS_NATIVE_INDEX || !udk_offsets::is_code(func) {
+        bail!("{ABS} doesn't match the UFunction layout (flags {flags:#x}, native index {index}, Func {func:#x})");
+    }
+    Ok(())
+}
+
+/// Locate `GNatives`: a table whose entries all point into code, since unused ones hold
+/// `execUndefined`.
+fn natives() -> anyhow::Result<usize> {
+    if let Some(&natives) = NATIVES.get() {
+        return Ok(natives);
+    }
+
+    let natives = udk_offsets::locate(&udk_offsets::GNATIVES, |candidate| {
+        let mem = Process::CHECKED;
+        (0..0x100).all(|i| mem.read_ptr(candidate + i * PTR_SIZE).is_ok_and(udk_offsets::is_code))
+    })?;
+    Ok(*NATIVES.get_or_init(|| natives))
+}
+
+/// Locate `GMalloc`, returning the allocator it currently points to. It's a pointer to an
+/// object whose vtable points into code.
+fn allocator() -> anyhow::Result<usize> {
+    let mem = Process::CHECKED;
+    let is_allocator = |global: usize| -> anyhow::Result<bool> {
+        let vtable = mem.read_ptr(mem.read_ptr(global)?)?;
+        for slot in 0..=FREE_SLOT {
+            if !udk_offsets::is_code(mem.read_ptr(vtable + slot * PTR_SIZE)?) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    };
+
+    let global = udk_offsets::locate(&udk_offsets::GMALLOC, |candidate| is_allocator(candidate).unwrap_or(false))?;
+    mem.read_ptr(global)
+}
+
+fn allocator_method(slot: usize) -> anyhow::Result<(usize, usize)> {
+    let allocator = allocator()?;
+    let vtable = Process::CHECKED.read_ptr(allocator)?;
+    Ok((allocator, Process::CHECKED.read_ptr(vtable + slot * PTR_SIZE)?))
+}
+
+/// Allocate `size` bytes with the engine's allocator, so the engine can free them.
+pub(crate) unsafe fn engine_malloc(size: usize) -> anyhow::Result<usize> {
+    let (allocator, malloc) = allocator_method(MALLOC_SLOT)?;
+    let size = u32::try_from(size).context("allocation is too large")?;
+
+    let malloc: MallocFn = std::mem::transmute(malloc);
+    match malloc(allocator, size, DEFAULT_ALIGNMENT) {
+        0 => bail!("the engine failed to allocate {size} bytes"),
+        data => Ok(data),
+    }
+}
+
+/// Free memory the engine allocated.
+pub(crate) unsafe fn engine_free(data: usize) -> anyhow::Result<()> {
+    let (allocator, free) = allocator_method(FREE_SLOT)?;
+
+    let free: FreeFn = std::mem::transmute(free);
+    free(allocator, data);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udk_fname::NameTable;
+    use crate::udk_fname::tests::ARRAY;
+    use crate::udk_object::tests::{reflect, Graph};
+
+    /// `EX_IntConst`, followed by the value.
+    const EX_INT_CONST: u8 = 0x1D;
+    const PTR_SIZE: usize = std::mem::size_of::<usize>();
+
+    native_function! {
+        static ADD = "Function Engine.Actor.Add";
+        fn(_this, a: i32, b: i32) -> i32 {
+            a + b
+        }
+    }
+
+    native_function! {
+        static SAY = "Function Engine.Actor.Say";
+        fn(_this, message: String, team: u8, target: Option<ObjectRef>) -> anyhow::Result<bool> {
+            Ok(!message.is_empty() && team == 0 && target.is_some())
+        }
+    }
+
+    native_function! {
+        static PANIC = "Function Engine.Actor.Add";
+        fn(_this, a: i32) -> i32 {
+            panic!("{a}")
+        }
+    }
+
+    /// `Engine.Actor` with `native function int Add(int A, int B)` and
+    /// `native function bool Say(string Message, byte Team, Actor Target, out int Count)`.
+    fn functions() -> (crate::udk_mem::Dump, usize, usize) {
+        let mut g = Graph::new();
+        let engine = g.add_object(g.class, None, "Engine");
+        let actor = g.add_class(engine, "Actor", Some(g.object), 0x100);
+        g.add_class(g.core, "Function", None, OBJECT_LAYOUT.func + PTR_SIZE);
+
+        let add = g.add_field(actor, "Add", "Function");
+        g.write(add + OBJECT_LAYOUT.function_flags, FUNC_NATIVE);
+        g.add_property(add, "A", "IntProperty", 0, 4, CPF_PARM);
+        g.add_property(add, "B", "IntProperty", 4, 4, CPF_PARM);
+        g.add_property(add, "ReturnValue", "IntProperty", 8, 4, CPF_PARM | CPF_RETURN_PARM);
+
+        let say = g.add_field(actor, "Say", "Function");
+        g.write(say + OBJECT_LAYOUT.function_flags, FUNC_NATIVE);
+        g.add_property(say, "Message", "StrProperty", 0, 2 * PTR_SIZE, CPF_PARM);
+        g.add_property(say, "Team", "ByteProperty", 2 * PTR_SIZE, 1, CPF_PARM);
+        let target = g.add_property(say, "Target", "ObjectProperty", 3 * PTR_SIZE, PTR_SIZE, CPF_PARM);
+        g.write(Graph::extra(target), actor);
+        g.add_property(say, "ReturnValue", "BoolProperty", 4 * PTR_SIZE, 4, CPF_PARM | CPF_RETURN_PARM);
+        g.add_property(say, "Count", "IntProperty", 4 * PTR_SIZE + 4, 4, CPF_PARM | CPF_OUT_PARM);
+
+        (g.finish(), add, say)
+    }
+
+    #[test]
+    fn checks_signatures() {
+        let (dump, add, say) = functions();
+        let names = NameTable::new(&dump, ARRAY);
+        let reflection = reflect(&dump, &names);
+        let (add, say) = (ObjectRef(add), ObjectRef(say));
+
+        assert_eq!(ADD.params, [NativeType::Int, NativeType::Int]);
+        assert_eq!(ADD.ret, Some(NativeType::Int));
+        assert_eq!(SAY.params, [NativeType::Str, NativeType::Byte, NativeType::Object]);
+        assert_eq!(SAY.ret, Some(NativeType::Bool));
+
+        check_signature(&reflection, &ADD, add).unwrap();
+        let error = check_signature(&reflection, &PANIC, add).unwrap_err();
+        assert_eq!(error.to_string(), "Function Engine.Actor.Add takes 2 arguments, but the handler takes 1");
+        let floats = NativeFunction::new(ADD.path, ADD.thunk, &[NativeType::Float, NativeType::Int], ADD.ret);
+        let error = check_signature(&reflection, &floats, add).unwrap_err();
+        assert_eq!(error.to_string(), "Function Engine.Actor.Add: A is int, but the handler takes float");
+        let error = check_signature(&reflection, &SAY, say).unwrap_err();
+        assert_eq!(error.to_string(), "Function Engine.Actor.Say: out parameter Count isn't supported");
+
+        let no_return = NativeFunction::new(ADD.path, ADD.thunk, ADD.params, None);
+        let error = check_signature(&reflection, &no_return, add).unwrap_err();
+        assert_eq!(error.to_string(), "Function Engine.Actor.Add returns int, but the handler returns nothing");
+    }
+
+    /// `EX_IntConst`: push the next four bytes of code.
+    unsafe extern "C" fn int_const(_object: usize, stack: *mut FFrame, result: usize) {
+        let frame = &mut *stack;
+        *(result as *mut i32) = (frame.code as *const i32).read_unaligned();
+        frame.code = frame.code.add(4);
+    }
+
+    /// Run `native`'s thunk on `code`, returning the result and how much code it used.
+    fn run(native: &NativeFunction, code: &mut [u8]) -> (i32, usize) {
+        NATIVES.get_or_init(|| {
+            let mut natives = vec![0usize; 0x100];
+            natives[usize::from(EX_INT_CONST)] = int_const as NativeFn as usize;
+            Box::leak(natives.into_boxed_slice()).as_ptr() as usize
+        });
+
+        let mut frame = FFrame {
+            _vtable: 0,
+            _allow_suppression: 0,
+            _suppress_event_tag: 0,
+            _auto_emit_line_terminator: 0,
+            _node: 0,
+            object: 0,
+            code: code.as_mut_ptr(),
+        };
+        let mut result = -1i32;
+        // SAFETY: The code is made of the tokens in `NATIVES`.
+        unsafe { (native.thunk)(0, &mut frame, &mut result as *mut i32 as usize) };
+        (result, frame.code as usize - code.as_ptr() as usize)
+    }
+
+    #[test]
+    fn reads_arguments_and_returns() {
+        let mut code = [EX_INT_CONST, 2, 0, 0, 0, EX_INT_CONST, 3, 0, 0, 0, EX_END_FUNCTION_PARMS, 0xFF];
+        assert_eq!(run(&ADD, &mut code), (5, 11));
+    }
+
+    #[test]
+    fn skips_the_arguments_after_a_panic() {
+        let mut code = [EX_INT_CONST, 2, 0, 0, 0, EX_END_FUNCTION_PARMS, 0xFF];
+        assert_eq!(run(&PANIC, &mut code), (-1, 6));
+
+        // Arguments the handler didn't read are still evaluated.
+        let mut code = [EX_INT_CONST, 2, 0, 0, 0, EX_INT_CONST, 3, 0, 0, 0, EX_END_FUNCTION_PARMS, 0xFF];
+        assert_eq!(run(&PANIC, &mut code), (-1, 11));
+    }
+}
@@ -0,0 +1,1114 @@
+//! This module walks the UDK's object table, `UObject::GObjObjects`, and the reflection data
+//! hanging off of it, so game state can be read and written by property name.
+//!
+//! The engine structures involved are:
+//!  * `UObject`: every object has an `Outer` (the object containing it), a `Name` and a `Class`.
+//!  * `UField`: `Next` links the fields of a struct together.
+//!  * `UStruct` (classes, functions, structs): `SuperField` is the parent, `Children` the first of
+//!    its own fields, and `PropertiesSize` the size of an instance.
+//!  * `UProperty`: a field with an `Offset` into instances, `ArrayDim` elements of `ElementSize`.
+//!
+//! Offsets for these come from [`OBJECT_LAYOUT`], which is checked against the reflection data
+//! itself before the live table is handed out: `Core.Object` declares its own native members
+//! (`Outer`, `Name`, `Class`) as properties. Fields of the property subclasses (a bool's bit mask,
+//! an object property's class) sit right after `UProperty`, whose size the engine tells us.
+//!
+//! Objects may only be touched on the game thread, where the garbage collector frees them.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{bail, Context};
+
+use crate::udk_fname::{self, FName, NameTable};
+use crate::udk_mem::{Memory, Process};
+use crate::udk_offsets;
+
+/// Field offsets within the reflection classes.
+pub struct ObjectLayout {
+    pub outer: usize,
+    pub name: usize,
+    pub class: usize,
+    /// `UField::Next`
+    pub next: usize,
+    pub super_field: usize,
+    pub children: usize,
+    pub properties_size: usize,
+    pub array_dim: usize,
+    pub element_size: usize,
+    pub property_flags: usize,
+    pub offset: usize,
+    /// `UFunction::FunctionFlags`
+    pub function_flags: usize,
+    /// `UFunction::iNative`, the function's slot in `GNatives`, or 0.
+    pub native_index: usize,
+    /// `UFunction::Func`, the native implementation.
+    pub func: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+pub const OBJECT_LAYOUT: ObjectLayout = ObjectLayout {
+    outer: 0x40,
+    name: 0x48,
+    class: 0x50,
+    next: 0x60,
+    super_field: 0x78,
+    children: 0x80,
+    properties_size: 0x88,
+    array_dim: 0x68,
+    element_size: 0x6C,
+    property_flags: 0x70,
+    offset: 0x90,
+    function_flags: 0xF0,
+    native_index: 0xF4,
+    func: 0x110,
+};
+
+#[cfg(target_pointer_width = "32")]
+pub const OBJECT_LAYOUT: ObjectLayout = ObjectLayout {
+    outer: 0x28,
+    name: 0x2C,
+    class: 0x34,
+    next: 0x3C,
+    super_field: 0x48,
+    children: 0x4C,
+    properties_size: 0x50,
+    array_dim: 0x40,
+    element_size: 0x44,
+    property_flags: 0x48,
+    offset: 0x60,
+    function_flags: 0x90,
+    native_index: 0x94,
+    func: 0xAC,
+};
+
+const PTR_SIZE: usize = std::mem::size_of::<usize>();
+
+/// Deepest class hierarchy we follow before deciding a `SuperField` chain loops.
+const MAX_SUPER_DEPTH: usize = 64;
+/// Most fields we follow in one struct before deciding a `Next` chain loops.
+const MAX_FIELDS: usize = 0x10000;
+
+/// `UProperty::PropertyFlags`: the property is a function parameter.
+pub const CPF_PARM: u64 = 0x80;
+/// The parameter is passed by reference (`out`).
+pub const CPF_OUT_PARM: u64 = 0x100;
+/// The parameter is the function's return value.
+pub const CPF_RETURN_PARM: u64 = 0x400;
+/// The property is a member of the native class, like `Object`'s own.
+const CPF_NATIVE: u64 = 0x1000;
+
+/// A pointer to a live `UObject`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct ObjectRef(pub usize);
+
+impl ObjectRef {
+    pub fn address(self) -> usize {
+        self.0
+    }
+
+    fn from_ptr(ptr: usize) -> Option<Self> {
+        (ptr != 0).then_some(Self(ptr))
+    }
+}
+
+/// What a property holds.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PropertyKind {
+    Byte,
+    Int,
+    Float,
+    /// A bit in a 32-bit word.
+    Bool { mask: u32 },
+    Name,
+    Str,
+    /// A reference to an object of `class`.
+    Object { class: Option<ObjectRef> },
+    Struct { strukt: ObjectRef },
+    /// A dynamic array of `inner`.
+    Array { inner: ObjectRef },
+    /// Anything else, by the name of its property class.
+    Other(String),
+}
+
+/// A property of a class, struct or function.
+#[derive(Clone, Debug)]
+pub struct Property {
+    pub name: String,
+    pub kind: PropertyKind,
+    pub offset: usize,
+    pub element_size: usize,
+    pub array_dim: usize,
+    pub flags: u64,
+}
+
+/// A property value, for the kinds that can be read and written directly.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Byte(u8),
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+    Name(FName),
+    Str(String),
+    Object(Option<ObjectRef>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Byte(v) => write!(f, "{v}"),
+            Value::Int(v) => write!(f, "{v}"),
+            Value::Float(v) => write!(f, "{v}"),
+            Value::Bool(v) => write!(f, "{v}"),
+            Value::Name(v) => write!(f, "'{v}'"),
+            Value::Str(v) => write!(f, "\"{v}\""),
+            Value::Object(Some(o)) => write!(f, "{:#x}", o.0),
+            Value::Object(None) => f.write_str("None"),
+        }
+    }
+}
+
+/// Reflection over an object table.
+pub struct Reflection<'n, M> {
+    mem: M,
+    names: &'n NameTable<M>,
+    /// Address of the `TArray<UObject*>`.
+    array: usize,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Properties of each struct, including inherited ones.
+    properties: HashMap<ObjectRef, Arc<[Property]>>,
+    /// Objects found by full name. Checked before use, since objects come and go.
+    found: HashMap<String, ObjectRef>,
+    /// `sizeof(UProperty)`, where the subclasses' own fields start.
+    property_size: Option<usize>,
+}
+
+impl<'n, M: Memory + Copy> Reflection<'n, M> {
+    /// Walk the `TArray<UObject*>` at `array` in `mem`, using `names` for names.
+    pub fn new(mem: M, names: &'n NameTable<M>, array: usize) -> Self {
+        Self {
+            mem,
+            names,
+            array,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    fn header(&self) -> anyhow::Result<(usize, usize)> {
+        let data = self.mem.read_ptr(self.array)?;
+        let num = self.mem.read::<i32>(self.array + PTR_SIZE)?;
+        let num = usize::try_from(num).context("negative object count")?;
+
+        Ok((data, num))
+    }
+
+    /// The number of slots in the table. Slots of destroyed objects are empty.
+    pub fn len(&self) -> anyhow::Result<usize> {
+        self.header().map(|(_, num)| num)
+    }
+
+    /// The object at `index`, or `None` if the slot is empty.
+    pub fn object(&self, index: usize) -> anyhow::Result<Option<ObjectRef>> {
+        let (data, num) = self.header()?;
+        if index >= num {
+            bail!("object index {index} is out of range ({num} objects)");
+        }
+
+        Ok(ObjectRef::from_ptr(self.mem.read_ptr(data + index * PTR_SIZE)?))
+    }
+
+    /// Every live object.
+    pub fn objects(&self) -> impl Iterator<Item = ObjectRef> + '_ {
+        let num = self.len().unwrap_or(0);
+        (0..num).filter_map(|i| self.object(i).ok().flatten())
+    }
+
+    pub fn name(&self, object: ObjectRef) -> anyhow::Result<FName> {
+        let address = object.0 + OBJECT_LAYOUT.name;
+        Ok(FName {
+            index: self.mem.read(address)?,
+            number: self.mem.read(address + 4)?,
+        })
+    }
+
+    /// The object's name as a string.
+    pub fn name_string(&self, object: ObjectRef) -> anyhow::Result<String> {
+        self.names.to_string(self.name(object)?)
+    }
+
+    pub fn class(&self, object: ObjectRef) -> anyhow::Result<Option<ObjectRef>> {
+        Ok(ObjectRef::from_ptr(self.mem.read_ptr(object.0 + OBJECT_LAYOUT.class)?))
+    }
+
+    pub fn outer(&self, object: ObjectRef) -> anyhow::Result<Option<ObjectRef>> {
+        Ok(ObjectRef::from_ptr(self.mem.read_ptr(object.0 + OBJECT_LAYOUT.outer)?))
+    }
+
+    /// The object's path, its outers' names and its own joined by `.`: `Engine.PlayerController.ClientMessage`.
+    pub fn path_name(&self, object: ObjectRef) -> anyhow::Result<String> {
+        let mut parts = vec![self.name_string(object)?];
+        let mut outer = self.outer(object)?;
+        while let Some(o) = outer {
+            if parts.len() > 64 {
+                bail!("outer chain of {:#x} is too deep", object.0);
+            }
+            parts.push(self.name_string(o)?);
+            outer = self.outer(o)?;
+        }
+
+        parts.reverse();
+        Ok(parts.join("."))
+    }
+
+    /// The object's class name and path, the way the UDK prints objects:
+    /// `Function Engine.PlayerController.ClientMessage`.
+    pub fn full_name(&self, object: ObjectRef) -> anyhow::Result<String> {
+        let class = self.class(object)?.context("object has no class")?;
+        Ok(format!("{} {}", self.name_string(class)?, self.path_name(object)?))
+    }
+
+    /// Find an object by full name (`Class Engine.Actor`). Like the UDK, this ignores case.
+    pub fn find(&self, full_name: &str) -> anyhow::Result<Option<ObjectRef>> {
+        let key = full_name.to_lowercase();
+        let cached = self.cache().found.get(&key).copied();
+        if let Some(object) = cached {
+            if self.full_name(object).is_ok_and(|n| n.to_lowercase() == key) {
+                return Ok(Some(object));
+            }
+        }
+
+        // Most lookups are for classes and functions, whose full names end with their own name.
+        let (_, last) = key.rsplit_once(['.', ' ']).context("expected `Class Outer.Name`")?;
+        for object in self.objects() {
+            let matches = self.name_string(object).is_ok_and(|n| n.to_lowercase() == last)
+                && self.full_name(object).is_ok_and(|n| n.to_lowercase() == key);
+            if matches {
+                self.cache().found.insert(key, object);
+                return Ok(Some(object));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Find a class by path (`Engine.Actor`).
+    pub fn find_class(&self, path: &str) -> anyhow::Result<Option<ObjectRef>> {
+        self.find(&format!("Class {path}"))
+    }
+
+    /// The struct a class, struct or function extends.
+    pub fn super_struct(&self, strukt: ObjectRef) -> anyhow::Result<Option<ObjectRef>> {
+        Ok(ObjectRef::from_ptr(self.mem.read_ptr(strukt.0 + OBJECT_LAYOUT.super_field)?))
+    }
+
+    /// Whether `object` is an instance of `class` or one of its subclasses.
+    pub fn is_a(&self, object: ObjectRef, class: ObjectRef) -> anyhow::Result<bool> {
+        let mut current = self.class(object)?;
+        for _ in 0..=MAX_SUPER_DEPTH {
+            match current {
+                Some(c) if c == class => return Ok(true),
+                Some(c) => current = self.super_struct(c)?,
+                None => return Ok(false),
+            }
+        }
+
+        bail!("super chain of {:#x} is too deep", object.0)
+    }
+
+    /// A function's `FUNC_*` flags.
+    pub fn function_flags(&self, function: ObjectRef) -> anyhow::Result<u32> {
+        self.mem.read(function.0 + OBJECT_LAYOUT.function_flags)
+    }
+
+    /// A function's slot in `GNatives`, or 0 if it was declared without a native index.
+    pub fn native_index(&self, function: ObjectRef) -> anyhow::Result<u16> {
+        self.mem.read(function.0 + OBJECT_LAYOUT.native_index)
+    }
+
+    /// The size of an instance of a class or struct, or of a function's parameters.
+    pub fn properties_size(&self, strukt: ObjectRef) -> anyhow::Result<usize> {
+        let size = self.mem.read::<i32>(strukt.0 + OBJECT_LAYOUT.properties_size)?;
+        usize::try_from(size).context("negative struct size")
+    }
+
+    /// The properties of a class, struct or function, inherited ones first.
+    pub fn properties(&self, strukt: ObjectRef) -> anyhow::Result<Arc<[Property]>> {
+        self.properties_at(strukt, 0)
+    }
+
+    /// [`Reflection::properties`] of a struct `depth` levels below the one asked about.
+    fn properties_at(&self, strukt: ObjectRef, depth: usize) -> anyhow::Result<Arc<[Property]>> {
+        if let Some(properties) = self.cache().properties.get(&strukt) {
+            return Ok(properties.clone());
+        }
+        if depth > MAX_SUPER_DEPTH {
+            bail!("super chain of {:#x} is too deep", strukt.0);
+        }
+
+        let mut properties = match self.super_struct(strukt)? {
+            Some(parent) => self.properties_at(parent, depth + 1)?.to_vec(),
+            None => Vec::new(),
+        };
+
+        let mut field = ObjectRef::from_ptr(self.mem.read_ptr(strukt.0 + OBJECT_LAYOUT.children)?);
+        let mut fields = 0;
+        while let Some(f) = field {
+            fields += 1;
+            if fields > MAX_FIELDS {
+                bail!("field chain of {:#x} doesn't end", strukt.0);
+            }
+
+            // Functions, states, enums and constants are fields too.
+            let class = self.class(f)?.context("field has no class")?;
+            let class_name = self.name_string(class)?;
+            if class_name.ends_with("Property") {
+                properties.push(self.read_property(f, &class_name)?);
+            }
+
+            field = ObjectRef::from_ptr(self.mem.read_ptr(f.0 + OBJECT_LAYOUT.next)?);
+        }
+
+        let properties: Arc<[Property]> = properties.into();
+        self.cache().properties.insert(strukt, properties.clone());
+        Ok(properties)
+    }
+
+    /// Find a property of a class, struct or function by name, ignoring case.
+    pub fn property(&self, strukt: ObjectRef, name: &str) -> anyhow::Result<Property> {
+        let properties = self.properties(strukt)?;
+        // A redeclared property shadows the inherited one.
+        let property = properties.iter().rev().find(|p| p.name.eq_ignore_ascii_case(name));
+
+        match property {
+            Some(p) => Ok(p.clone()),
+            None => bail!("{} has no property {name}", self.path_name(strukt)?),
+        }
+    }
+
+    fn read_property(&self, object: ObjectRef, class_name: &str) -> anyhow::Result<Property> {
+        let m = self.mem;
+        let extra = object.0 + self.property_size()?;
+        let ptr_at = |address| m.read_ptr(address).map(ObjectRef::from_ptr);
+
+        let kind = match class_name {
+            "ByteProperty" => PropertyKind::Byte,
+            "IntProperty" => PropertyKind::Int,
+            "FloatProperty" => PropertyKind::Float,
+            "BoolProperty" => PropertyKind::Bool { mask: m.read(extra)? },
+            "NameProperty" => PropertyKind::Name,
+            "StrProperty" => PropertyKind::Str,
+            "ObjectProperty" | "ClassProperty" | "ComponentProperty" => PropertyKind::Object {
+                class: ptr_at(extra)?,
+            },
+            "StructProperty" => PropertyKind::Struct {
+                strukt: ptr_at(extra)?.context("struct property without a struct")?,
+            },
+            "ArrayProperty" => PropertyKind::Array {
+                inner: ptr_at(extra)?.context("array property without an inner property")?,
+            },
+            other => PropertyKind::Other(other.to_string()),
+        };
+
+        let offset = m.read::<i32>(object.0 + OBJECT_LAYOUT.offset)?;
+        let element_size = m.read::<i32>(object.0 + OBJECT_LAYOUT.element_size)?;
+        let array_dim = m.read::<i32>(object.0 + OBJECT_LAYOUT.array_dim)?;
+
+        Ok(Property {
+            name: self.name_string(object)?,
+            kind,
+            offset: usize::try_from(offset).context("negative property offset")?,
+            element_size: usize::try_from(element_size).context("negative property size")?,
+            array_dim: usize::try_from(array_dim).context("negative array dimension")?,
+            flags: m.read(object.0 + OBJECT_LAYOUT.property_flags)?,
+        })
+    }
+
+    /// `sizeof(UProperty)`, from the `PropertiesSize` of `Class Core.Property`.
+    fn property_size(&self) -> anyhow::Result<usize> {
+        if let Some(size) = self.cache().property_size {
+            return Ok(size);
+        }
+
+        let class = self.find_class("Core.Property")?.context("Core.Property not found")?;
+        let size = self.properties_size(class)?;
+        self.cache().property_size = Some(size);
+        Ok(size)
+    }
+
+    /// Read element `index` of `property` in the instance (or parameter frame) at `base`.
+    pub fn read_value(&self, base: usize, property: &Property, index: usize) -> anyhow::Result<Value> {
+        let address = self.element_address(base, property, index)?;
+        let m = self.mem;
+
+        Ok(match &property.kind {
+            PropertyKind::Byte => Value::Byte(m.read(address)?),
+            PropertyKind::Int => Value::Int(m.read(address)?),
+            PropertyKind::Float => Value::Float(m.read(address)?),
+            PropertyKind::Bool { mask } => Value::Bool(m.read::<u32>(address)? & mask != 0),
+            PropertyKind::Name => Value::Name(FName {
+                index: m.read(address)?,
+                number: m.read(address + 4)?,
+            }),
+            PropertyKind::Str => Value::Str(self.read_string(address)?),
+            PropertyKind::Object { .. } => Value::Object(ObjectRef::from_ptr(m.read_ptr(address)?)),
+            kind => bail!("can't read {} properties", kind_name(kind)),
+        })
+    }
+
+    /// Write element `index` of `property` in the instance (or parameter frame) at `base`.
+    ///
+    /// Strings can't be written, since that needs the engine's allocator.
+    pub fn write_value(&self, base: usize, property: &Property, index: usize, value: &Value) -> anyhow::Result<()> {
+        let address = self.element_address(base, property, index)?;
+        let m = self.mem;
+
+        match (&property.kind, value) {
+            (PropertyKind::Byte, Value::Byte(v)) => m.write(address, *v),
+            (PropertyKind::Int, Value::Int(v)) => m.write(address, *v),
+            (PropertyKind::Float, Value::Float(v)) => m.write(address, *v),
+            (PropertyKind::Bool { mask }, Value::Bool(v)) => {
+                let bits = m.read::<u32>(address)?;
+                m.write(address, if *v { bits | mask } else { bits & !mask })
+            }
+            (PropertyKind::Name, Value::Name(v)) => {
+                m.write(address, v.index)?;
+                m.write(address + 4, v.number)
+            }
+            (PropertyKind::Object { class }, Value::Object(v)) => {
+                if let (Some(class), Some(object)) = (class, v) {
+                    if !self.is_a(*object, *class)? {
+                        bail!("{} is not a {}", self.full_name(*object)?, self.path_name(*class)?);
+                    }
+                }
+                m.write(address, v.map_or(0, ObjectRef::address))
+            }
+            (kind, value) => bail!("can't write {value:?} to {} property {}", kind_name(kind), property.name),
+        }
+    }
+
+    fn element_address(&self, base: usize, property: &Property, index: usize) -> anyhow::Result<usize> {
+        if index >= property.array_dim.max(1) {
+            bail!("index {index} is out of range for {}[{}]", property.name, property.array_dim);
+        }
+
+        Ok(base + property.offset + index * property.element_size)
+    }
+
+    /// Read an `FString`: a `TArray<TCHAR>` including the terminator.
+    fn read_string(&self, address: usize) -> anyhow::Result<String> {
+        let data = self.mem.read_ptr(address)?;
+        let num = self.mem.read::<i32>(address + PTR_SIZE)?;
+        if data == 0 || num <= 1 {
+            return Ok(String::new());
+        }
+        if num > 0x10000 {
+            bail!("string at {address:#x} is implausibly long ({num})");
+        }
+
+        let mut bytes = vec![0u8; (num as usize - 1) * 2];
+        self.mem.read_bytes(data, &mut bytes)?;
+        let units = bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>();
+        Ok(String::from_utf16_lossy(&units))
+    }
+
+    /// Parse `s` as a value of `property`, the way the UDK's `set` command takes it: objects by
+    /// full name, and `None` for no object. Strings are taken as they are.
+    pub fn parse_value(&self, property: &Property, s: &str) -> anyhow::Result<Value> {
+        let number = |e: &dyn fmt::Display| anyhow::anyhow!("`{s}` is not a valid {}: {e}", kind_name(&property.kind));
+
+        Ok(match &property.kind {
+            PropertyKind::Byte => Value::Byte(s.parse().map_err(|e| number(&e))?),
+            PropertyKind::Int => Value::Int(s.parse().map_err(|e| number(&e))?),
+            PropertyKind::Float => Value::Float(s.parse().map_err(|e| number(&e))?),
+            PropertyKind::Bool { .. } => match s.to_ascii_lowercase().as_str() {
+                "true" | "1" => Value::Bool(true),
+                "false" | "0" => Value::Bool(false),
+                _ => bail!("`{s}` is not a valid bool"),
+            },
+            PropertyKind::Str => Value::Str(s.to_string()),
+            PropertyKind::Name => Value::Name(self.names.find(s)?.with_context(|| format!("`{s}` is not a name"))?),
+            PropertyKind::Object { .. } if s.eq_ignore_ascii_case("None") => Value::Object(None),
+            PropertyKind::Object { .. } => {
+                let object = self.find(s)?.with_context(|| format!("{s} not found"))?;
+                Value::Object(Some(object))
+            }
+            kind => bail!("can't parse {} values", kind_name(kind)),
+        })
+    }
+
+    /// Format `value` for people: names as text, and objects by full name.
+    pub fn describe(&self, value: &Value) -> anyhow::Result<String> {
+        Ok(match value {
+            Value::Name(name) => self.names.to_string(*name)?,
+            Value::Object(Some(object)) => self.full_name(*object)?,
+            value => value.to_string(),
+        })
+    }
+
+    /// Read the property `name` of `object`.
+    pub fn get(&self, object: ObjectRef, name: &str) -> anyhow::Result<Value> {
+        let class = self.class(object)?.context("object has no class")?;
+        self.read_value(object.0, &self.property(class, name)?, 0)
+    }
+
+    /// Write the property `name` of `object`.
+    pub fn set(&self, object: ObjectRef, name: &str, value: &Value) -> anyhow::Result<()> {
+        let class = self.class(object)?.context("object has no class")?;
+        self.write_value(object.0, &self.property(class, name)?, 0, value)
+    }
+
+    /// Whether this looks like the real object table: a sane size, and an object whose class's
+    /// class is `Class`, whose own class is itself.
+    pub fn is_plausible(&self) -> bool {
+        let f = || -> anyhow::Result<bool> {
+            let (data, num) = self.header()?;
+            if data == 0 || !(1..1 << 24).contains(&num) {
+                return Ok(false);
+            }
+
+            let object = (0..num.min(16)).find_map(|i| self.object(i).ok().flatten());
+            let Some(object) = object else {
+                return Ok(false);
+            };
+            let class = self.class(object)?.context("no class")?;
+            let class_class = self.class(class)?.context("no class class")?;
+
+            Ok(self.class(class_class)? == Some(class_class) && &*self.names.name(self.name(class_class)?.index)? == "Class")
+        };
+
+        f().unwrap_or(false)
+    }
+
+    /// Check [`OBJECT_LAYOUT`] against the reflection data `Core.Object` has about itself.
+    ///
+    /// Each of `Object`'s own members is checked through every `UProperty` field: where it is,
+    /// its size and count, that it's flagged as native, and that it fits in an `Object`.
+    /// `Object.Class` also checks where an object property keeps its class.
+    pub fn check_layout(&self) -> anyhow::Result<()> {
+        let object = self.find_class("Core.Object")?.context("Core.Object not found")?;
+        let class = self.find_class("Core.Class")?.context("Core.Class not found")?;
+        if let Some(parent) = self.super_struct(object)? {
+            bail!("Core.Object extends {:#x}, but it should be the root class", parent.0);
+        }
+
+        let property_size = self.property_size()?;
+        if property_size <= OBJECT_LAYOUT.offset {
+            bail!("Core.Property is {property_size:#x} bytes, too small to hold Offset at {:#x}", OBJECT_LAYOUT.offset);
+        }
+
+        for (name, expected, size) in [
+            ("Outer", OBJECT_LAYOUT.outer, PTR_SIZE),
+            ("Name", OBJECT_LAYOUT.name, std::mem::size_of::<FName>()),
+            ("Class", OBJECT_LAYOUT.class, PTR_SIZE),
+        ] {
+            let property = self.property(object, name)?;
+            if property.offset != expected {
+                bail!(
+                    "Object.{name} is at {:#x}, but the layout says {expected:#x}",
+                    property.offset
+                );
+            }
+            if (property.element_size, property.array_dim) != (size, 1) {
+                bail!(
+                    "Object.{name} has {} elements of {:#x} bytes, but should have 1 of {size:#x}",
+                    property.array_dim,
+                    property.element_size
+                );
+            }
+            if property.flags & (CPF_NATIVE | CPF_PARM) != CPF_NATIVE {
+                bail!("Object.{name} has unexpected flags {:#x}", property.flags);
+            }
+        }
+
+        let kind = self.property(object, "Class")?.kind;
+        if kind != (PropertyKind::Object { class: Some(class) }) {
+            bail!("Object.Class should be a Class reference, but is {kind:?}");
+        }
+
+        let size = self.properties_size(object)?;
+        for property in self.properties(object)?.iter() {
+            let end = property
+                .element_size
+                .checked_mul(property.array_dim)
+                .and_then(|n| n.checked_add(property.offset));
+            if end.is_none_or(|end| end > size) {
+                bail!("Object.{} doesn't fit in an Object ({size:#x} bytes)", property.name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The UnrealScript name of a property kind, for messages.
+pub fn kind_name(kind: &PropertyKind) -> &str {
+    match kind {
+        PropertyKind::Byte => "byte",
+        PropertyKind::Int => "int",
+        PropertyKind::Float => "float",
+        PropertyKind::Bool { .. } => "bool",
+        PropertyKind::Name => "name",
+        PropertyKind::Str => "string",
+        PropertyKind::Object { .. } => "object",
+        PropertyKind::Struct { .. } => "struct",
+        PropertyKind::Array { .. } => "array",
+        PropertyKind::Other(class) => class,
+    }
+}
+
+static REFLECTION: OnceLock<Reflection<'static, Process>> = OnceLock::new();
+
+/// Return reflection over the UDK's live objects, locating the object table on first use.
+pub fn reflection() -> anyhow::Result<&'static Reflection<'static, Process>> {
+    if let Some(reflection) = REFLECTION.get() {
+        return Ok(reflection);
+    }
+
+    let names = udk_fname::names()?;
+    let checked_names = NameTable::new(Process::CHECKED, names.array());
+    let array = udk_offsets::locate(&udk_offsets::GOBJECTS, |candidate| {
+        Reflection::new(Process::CHECKED, &checked_names, candidate).is_plausible()
+    })?;
+
+    // SAFETY: The table checked out above. Objects are only freed on the game thread, which
+    // is where callers are required to be.
+    let reflection = Reflection::new(unsafe { Process::trusted() }, names, array);
+    reflection.check_layout().context("object layout doesn't match this UDK build")?;
+    Ok(REFLECTION.get_or_init(|| reflection))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use zerocopy::AsBytes;
+
+    use super::*;
+    use crate::udk_fname::{self, split_number};
+    use crate::udk_mem::Dump;
+
+    /// Where [`Graph`] puts the object table, and the objects themselves.
+    pub(crate) const OBJECTS: usize = 0x100000;
+    const DATA: usize = 0x110000;
+    const HEAP: usize = 0x200000;
+    /// Room for each object, and how many fit.
+    const OBJECT_SIZE: usize = 0x200;
+    const CAPACITY: usize = 0x100;
+    /// `sizeof(UProperty)`; the subclasses' fields follow it.
+    const PROPERTY_SIZE: usize = OBJECT_LAYOUT.offset + 0x20;
+    /// `sizeof(UObject)`, enough for `Object`'s own members.
+    const OBJECT_INSTANCE_SIZE: usize = OBJECT_LAYOUT.class + PTR_SIZE;
+
+    /// A synthetic object table, with just enough of `Core` for reflection to work: `Class`,
+    /// `Object` with its native members, `Property` and the property classes.
+    pub(crate) struct Graph {
+        dump: Dump,
+        names: Vec<String>,
+        objects: usize,
+        /// The last field of each struct, where the next property is linked in.
+        last_field: HashMap<usize, usize>,
+        pub core: usize,
+        pub class: usize,
+        pub object: usize,
+    }
+
+    impl Graph {
+        pub fn new() -> Self {
+            let mut dump = Dump::new();
+            let mut table = vec![0; PTR_SIZE + 8];
+            table[..PTR_SIZE].copy_from_slice(&DATA.to_ne_bytes());
+            table[PTR_SIZE + 4..].copy_from_slice(&(CAPACITY as i32).to_ne_bytes());
+            dump.add_region(OBJECTS, table).unwrap();
+            dump.add_region(DATA, vec![0; CAPACITY * PTR_SIZE]).unwrap();
+            dump.add_region(HEAP, vec![0; CAPACITY * OBJECT_SIZE]).unwrap();
+
+            let mut graph = Self {
+                dump,
+                names: vec!["None".to_string()],
+                objects: 0,
+                last_field: HashMap::new(),
+                core: 0,
+                class: 0,
+                object: 0,
+            };
+
+            graph.core = graph.add_object(0, None, "Core");
+            graph.class = graph.add_object(0, Some(graph.core), "Class");
+            graph.write(graph.class + OBJECT_LAYOUT.class, graph.class);
+            let package = graph.add_class(graph.core, "Package", None, OBJECT_INSTANCE_SIZE);
+            graph.write(graph.core + OBJECT_LAYOUT.class, package);
+
+            graph.add_class(graph.core, "Property", None, PROPERTY_SIZE);
+            for kind in ["Byte", "Int", "Float", "Bool", "Name", "Str", "Object", "Struct", "Array", "Map"] {
+                graph.add_class(graph.core, &format!("{kind}Property"), None, PROPERTY_SIZE + PTR_SIZE);
+            }
+
+            graph.object = graph.add_class(graph.core, "Object", None, OBJECT_INSTANCE_SIZE);
+            let native = CPF_NATIVE | 0x2;
+            graph.add_property(graph.object, "Outer", "ObjectProperty", OBJECT_LAYOUT.outer, PTR_SIZE, native);
+            graph.add_property(graph.object, "Name", "NameProperty", OBJECT_LAYOUT.name, 8, native);
+            let object = graph.object;
+            let class = graph.add_property(object, "Class", "ObjectProperty", OBJECT_LAYOUT.class, PTR_SIZE, native);
+            graph.write(class + PROPERTY_SIZE, graph.class);
+            graph
+        }
+
+        /// The index of `name` in the name table, adding it if needed.
+        fn name(&mut self, name: &str) -> i32 {
+            match self.names.iter().position(|n| n == name) {
+                Some(index) => index as i32,
+                None => {
+                    self.names.push(name.to_string());
+                    self.names.len() as i32 - 1
+                }
+            }
+        }
+
+        pub fn write<T: AsBytes>(&self, address: usize, value: T) {
+            self.dump.write(address, value).unwrap();
+        }
+
+        /// Add an object of `class` named `name` (`Foo_3` for an instance number) to the table.
+        pub fn add_object(&mut self, class: usize, outer: Option<usize>, name: &str) -> usize {
+            assert!(self.objects < CAPACITY, "the graph is full");
+            let address = HEAP + self.objects * OBJECT_SIZE;
+            self.write(DATA + self.objects * PTR_SIZE, address);
+            self.objects += 1;
+            self.write(OBJECTS + PTR_SIZE, self.objects as i32);
+
+            let (base, number) = split_number(name);
+            let index = self.name(base);
+            self.write(address + OBJECT_LAYOUT.outer, outer.unwrap_or(0));
+            self.write(address + OBJECT_LAYOUT.name, [index, number]);
+            self.write(address + OBJECT_LAYOUT.class, class);
+            address
+        }
+
+        /// Add a class of `size` bytes, extending `parent`.
+        pub fn add_class(&mut self, package: usize, name: &str, parent: Option<usize>, size: usize) -> usize {
+            let class = self.add_object(self.class, Some(package), name);
+            self.write(class + OBJECT_LAYOUT.super_field, parent.unwrap_or(0));
+            self.write(class + OBJECT_LAYOUT.properties_size, size as i32);
+            class
+        }
+
+        /// Add a field of class `class` (by name, in `Core`) at the end of `owner`'s fields.
+        pub fn add_field(&mut self, owner: usize, name: &str, class: &str) -> usize {
+            let path = format!("Class Core.{class}");
+            let class = self.find(&path).unwrap_or_else(|| panic!("{path} is not in the graph"));
+            let field = self.add_object(class, Some(owner), name);
+
+            let link = match self.last_field.insert(owner, field) {
+                Some(last) => last + OBJECT_LAYOUT.next,
+                None => owner + OBJECT_LAYOUT.children,
+            };
+            self.write(link, field);
+            field
+        }
+
+        /// Add a property of one element. Subclass fields (a bool's mask, an object's class) are
+        /// at [`Graph::extra`].
+        pub fn add_property(
+            &mut self,
+            owner: usize,
+            name: &str,
+            class: &str,
+            offset: usize,
+            size: usize,
+            flags: u64,
+        ) -> usize {
+            let property = self.add_field(owner, name, class);
+            self.write(property + OBJECT_LAYOUT.offset, offset as i32);
+            self.write(property + OBJECT_LAYOUT.element_size, size as i32);
+            self.write(property + OBJECT_LAYOUT.array_dim, 1i32);
+            self.write(property + OBJECT_LAYOUT.property_flags, flags);
+            property
+        }
+
+        /// Where the fields of a property's subclass start.
+        pub fn extra(property: usize) -> usize {
+            property + PROPERTY_SIZE
+        }
+
+        /// Find an object added so far by full name.
+        fn find(&self, full_name: &str) -> Option<usize> {
+            let names = self.names.iter().map(|n| Some(n.as_str())).collect::<Vec<_>>();
+            let mut dump = Dump::new();
+            udk_fname::tests::add_table(&mut dump, &names);
+            let names = NameTable::new(&dump, udk_fname::tests::ARRAY);
+
+            let reflection = Reflection::new(&self.dump, &names, OBJECTS);
+            let found = reflection.objects().find(|&o| reflection.full_name(o).is_ok_and(|n| n == full_name));
+            found.map(ObjectRef::address)
+        }
+
+        /// The memory of the objects, with the name table added.
+        pub fn finish(mut self) -> Dump {
+            let names = self.names.iter().map(|n| Some(n.as_str())).collect::<Vec<_>>();
+            udk_fname::tests::add_table(&mut self.dump, &names);
+            self.dump
+        }
+    }
+
+    /// Reflection over a finished [`Graph`].
+    pub(crate) fn reflect<'a>(dump: &'a Dump, names: &'a NameTable<&'a Dump>) -> Reflection<'a, &'a Dump> {
+        Reflection::new(dump, names, OBJECTS)
+    }
+
+    struct World {
+        dump: Dump,
+        actor: usize,
+        pawn: usize,
+        pawn_3: usize,
+        other: usize,
+        owner: usize,
+    }
+
+    /// `Engine.Actor`, `Engine.Pawn` (which shadows `Health`), a `Pawn_3` in a map and an actor
+    /// that isn't a pawn.
+    fn world() -> World {
+        let mut g = Graph::new();
+        let engine = g.add_object(g.class, None, "Engine");
+        g.write(engine + OBJECT_LAYOUT.class, g.find("Class Core.Package").unwrap());
+
+        let actor = g.add_class(engine, "Actor", Some(g.object), 0x130);
+        g.add_property(actor, "Health", "IntProperty", 0x100, 4, 0);
+        let hidden = g.add_property(actor, "bHidden", "BoolProperty", 0x104, 4, 0);
+        g.write(Graph::extra(hidden), 0x4u32);
+        let owner = g.add_property(actor, "Owner", "ObjectProperty", 0x108, PTR_SIZE, 0);
+        g.write(Graph::extra(owner), actor);
+        g.add_property(actor, "Tag", "NameProperty", 0x110, 8, 0);
+        g.add_property(actor, "Message", "StrProperty", 0x118, 2 * PTR_SIZE, 0);
+        g.add_property(actor, "Speed", "FloatProperty", 0x128, 4, 0);
+        g.add_property(actor, "Team", "ByteProperty", 0x12C, 1, 0);
+        // Functions are fields, but not properties.
+        g.add_field(actor, "Touch", "Object");
+
+        let pawn = g.add_class(engine, "Pawn", Some(actor), 0x138);
+        g.add_property(pawn, "Health", "IntProperty", 0x130, 4, 0);
+
+        let map = g.add_object(g.find("Class Core.Package").unwrap(), None, "Map");
+        let pawn_3 = g.add_object(pawn, Some(map), "Pawn_3");
+        let other = g.add_object(actor, Some(map), "Actor_0");
+        let owner = g.add_object(pawn, Some(map), "Pawn_7");
+
+        g.write(pawn_3 + 0x130, 100i32);
+        g.write(pawn_3 + 0x104, 0x3u32);
+        g.write(pawn_3 + 0x128, 1.5f32);
+        g.write(pawn_3 + 0x12C, 2u8);
+        g.write(pawn_3 + 0x108, owner);
+        g.write(pawn_3 + 0x110, [0i32, 0]);
+
+        // "Hi" as an FString.
+        let text = HEAP + (CAPACITY - 1) * OBJECT_SIZE;
+        g.write(text, [b'H', 0, b'i', 0, 0, 0]);
+        g.write(pawn_3 + 0x118, text);
+        g.write(pawn_3 + 0x118 + PTR_SIZE, 3i32);
+
+        World {
+            dump: g.finish(),
+            actor,
+            pawn,
+            pawn_3,
+            other,
+            owner,
+        }
+    }
+
+    #[test]
+    fn checks_the_table_and_layout() {
+        let w = world();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+
+        assert!(r.is_plausible());
+        r.check_layout().unwrap();
+        assert!(!Reflection::new(&w.dump, &names, DATA).is_plausible());
+    }
+
+    #[test]
+    fn names_objects() {
+        let w = world();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+        let pawn_3 = ObjectRef(w.pawn_3);
+
+        assert_eq!(r.name_string(pawn_3).unwrap(), "Pawn_3");
+        assert_eq!(r.path_name(pawn_3).unwrap(), "Map.Pawn_3");
+        assert_eq!(r.full_name(pawn_3).unwrap(), "Pawn Map.Pawn_3");
+        assert_eq!(r.full_name(ObjectRef(w.actor)).unwrap(), "Class Engine.Actor");
+
+        assert_eq!(r.find("pawn map.PAWN_3").unwrap(), Some(pawn_3));
+        assert_eq!(r.find_class("Engine.Pawn").unwrap(), Some(ObjectRef(w.pawn)));
+        assert_eq!(r.find("Pawn Map.Pawn_4").unwrap(), None);
+        assert!(r.find("Pawn").is_err());
+    }
+
+    #[test]
+    fn collects_inherited_and_shadowed_properties() {
+        let w = world();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+        let pawn = ObjectRef(w.pawn);
+
+        let properties = r.properties(pawn).unwrap();
+        let names = properties.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["Outer", "Name", "Class", "Health", "bHidden", "Owner", "Tag", "Message", "Speed", "Team", "Health"]
+        );
+
+        let health = r.property(pawn, "HEALTH").unwrap();
+        assert_eq!((health.kind, health.offset), (PropertyKind::Int, 0x130));
+        assert_eq!(r.property(pawn, "bHidden").unwrap().kind, PropertyKind::Bool { mask: 0x4 });
+        assert_eq!(
+            r.property(pawn, "Owner").unwrap().kind,
+            PropertyKind::Object { class: Some(ObjectRef(w.actor)) }
+        );
+        assert!(r.property(pawn, "Touch").is_err());
+
+        assert!(r.is_a(ObjectRef(w.pawn_3), ObjectRef(w.actor)).unwrap());
+        assert!(!r.is_a(ObjectRef(w.other), pawn).unwrap());
+    }
+
+    #[test]
+    fn reads_properties() {
+        let w = world();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+        let pawn_3 = ObjectRef(w.pawn_3);
+
+        assert_eq!(r.get(pawn_3, "Health").unwrap(), Value::Int(100));
+        assert_eq!(r.get(pawn_3, "bHidden").unwrap(), Value::Bool(false));
+        assert_eq!(r.get(pawn_3, "Speed").unwrap(), Value::Float(1.5));
+        assert_eq!(r.get(pawn_3, "Team").unwrap(), Value::Byte(2));
+        assert_eq!(r.get(pawn_3, "Message").unwrap(), Value::Str("Hi".to_string()));
+        assert_eq!(r.get(pawn_3, "Owner").unwrap(), Value::Object(Some(ObjectRef(w.owner))));
+        assert_eq!(r.describe(&r.get(pawn_3, "Owner").unwrap()).unwrap(), "Pawn Map.Pawn_7");
+        assert_eq!(r.describe(&r.get(pawn_3, "Tag").unwrap()).unwrap(), "None");
+        assert!(r.get(pawn_3, "Missing").is_err());
+    }
+
+    #[test]
+    fn writes_properties() {
+        let w = world();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+        let pawn_3 = ObjectRef(w.pawn_3);
+        let property = |name| r.property(ObjectRef(w.pawn), name).unwrap();
+
+        for (name, text, expected) in [
+            ("Health", "-5", Value::Int(-5)),
+            ("bHidden", "TRUE", Value::Bool(true)),
+            ("Speed", "0.25", Value::Float(0.25)),
+            ("Team", "7", Value::Byte(7)),
+            ("Tag", "pawn", Value::Name(names.find("Pawn").unwrap().unwrap())),
+            ("Owner", "Actor Map.Actor_0", Value::Object(Some(ObjectRef(w.other)))),
+        ] {
+            let value = r.parse_value(&property(name), text).unwrap();
+            assert_eq!(value, expected, "{name}");
+            r.set(pawn_3, name, &value).unwrap();
+            assert_eq!(r.get(pawn_3, name).unwrap(), expected, "{name}");
+        }
+
+        // The bool's neighbours in its word are untouched.
+        assert_eq!(w.dump.read::<u32>(w.pawn_3 + 0x104).unwrap(), 0x7);
+        // Inherited and shadowed properties are separate.
+        assert_eq!(w.dump.read::<i32>(w.pawn_3 + 0x100).unwrap(), 0);
+
+        r.set(pawn_3, "Owner", &r.parse_value(&property("Owner"), "none").unwrap()).unwrap();
+        assert_eq!(r.get(pawn_3, "Owner").unwrap(), Value::Object(None));
+
+        assert!(r.parse_value(&property("Team"), "300").is_err());
+        assert!(r.parse_value(&property("Tag"), "NotAName").is_err());
+        let hello = r.parse_value(&property("Message"), "Hello").unwrap();
+        assert_eq!(hello, Value::Str("Hello".to_string()));
+        assert!(r.set(pawn_3, "Message", &hello).is_err());
+        assert!(r.set(pawn_3, "Health", &Value::Float(1.0)).is_err());
+    }
+
+    #[test]
+    fn checks_object_classes_on_write() {
+        let w = world();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+
+        // Owner holds actors, and a class isn't one.
+        let error = r.set(ObjectRef(w.pawn_3), "Owner", &Value::Object(Some(ObjectRef(w.pawn)))).unwrap_err();
+        assert_eq!(error.to_string(), "Class Engine.Pawn is not a Engine.Actor");
+        assert_eq!(r.get(ObjectRef(w.pawn_3), "Owner").unwrap(), Value::Object(Some(ObjectRef(w.owner))));
+    }
+
+    #[test]
+    fn rejects_layouts_that_dont_match() {
+        let object_property = |w: &World, name: &str| {
+            let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+            let r = reflect(&w.dump, &names);
+            let object = r.find_class("Core.Object").unwrap().unwrap();
+            let mut field = w.dump.read_ptr(object.0 + OBJECT_LAYOUT.children).unwrap();
+            while r.name_string(ObjectRef(field)).unwrap() != name {
+                field = w.dump.read_ptr(field + OBJECT_LAYOUT.next).unwrap();
+            }
+            field
+        };
+        let check = |w: &World| {
+            let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+            reflect(&w.dump, &names).check_layout().unwrap_err().to_string()
+        };
+
+        let w = world();
+        let name = object_property(&w, "Name");
+        w.dump.write(name + OBJECT_LAYOUT.offset, 0x30i32).unwrap();
+        assert!(check(&w).starts_with("Object.Name is at 0x30"));
+
+        let w = world();
+        let name = object_property(&w, "Name");
+        w.dump.write(name + OBJECT_LAYOUT.element_size, 4i32).unwrap();
+        assert!(check(&w).starts_with("Object.Name has 1 elements of 0x4 bytes"));
+
+        let w = world();
+        let outer = object_property(&w, "Outer");
+        w.dump.write(outer + OBJECT_LAYOUT.property_flags, CPF_PARM).unwrap();
+        assert!(check(&w).starts_with("Object.Outer has unexpected flags"));
+
+        let w = world();
+        let class = object_property(&w, "Class");
+        w.dump.write(Graph::extra(class), 0usize).unwrap();
+        assert!(check(&w).starts_with("Object.Class should be a Class reference"));
+
+        let w = world();
+        let object = object_property(&w, "Outer") - OBJECT_SIZE;
+        w.dump.write(object + OBJECT_LAYOUT.properties_size, 0x20i32).unwrap();
+        assert_eq!(check(&w), "Object.Outer doesn't fit in an Object (0x20 bytes)");
+    }
+
+    #[test]
+    fn stops_at_cycles() {
+        let w = world();
+        // Actor extends Pawn, which extends Actor.
+        w.dump.write(w.actor + OBJECT_LAYOUT.super_field, w.pawn).unwrap();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+        assert!(r.properties(ObjectRef(w.pawn)).unwrap_err().to_string().contains("too deep"));
+        let object = r.find_class("Core.Object").unwrap().unwrap();
+        assert!(r.is_a(ObjectRef(w.pawn_3), object).unwrap_err().to_string().contains("too deep"));
+
+        // Pawn's fields loop through a function, which isn't a property.
+        let w = world();
+        let names = NameTable::new(&w.dump, udk_fname::tests::ARRAY);
+        let r = reflect(&w.dump, &names);
+        let touch = r.find("Object Engine.Actor.Touch").unwrap().unwrap();
+        w.dump.write(w.pawn + OBJECT_LAYOUT.children, touch.0).unwrap();
+        w.dump.write(touch.0 + OBJECT_LAYOUT.next, touch.0).unwrap();
+        assert!(r.properties(ObjectRef(w.pawn)).unwrap_err().to_string().contains("doesn't end"));
+    }
+}
@@ -0,0 +1,330 @@
+//! This module is the registry of locations in the UDK binary that we need, but that aren't exported.
+//!
+//! Each [`Offset`] lists ways of finding it: a code signature, or a slot in a live object's
+//! vtable. Signatures can match more than one place, so whoever asks for a location
+//! passes a check that only the real thing passes (e.g. "entry 0 of the name table is `None`").
+//! Located addresses are cached by name, and so are signature matches, so an offset that's
+//! retried until the engine is up only scans once.
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context};
+use pelite::image::IMAGE_SCN_MEM_EXECUTE;
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::Pe as _;
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::Pe as _;
+
+use crate::dll::UDK_RANGE;
+use crate::iat::udk_view;
+use crate::sigscan::{self, Pattern};
+use crate::udk_log::{log, LogType};
+use crate::udk_mem::{Memory, Process};
+use crate::udk_object;
+
+/// One way of finding a location.
+pub enum Locator {
+    /// A signature in UDK's code, with a 32-bit relative displacement `relative` bytes into the
+    /// match pointing at the location.
+    Signature { pattern: &'static str, relative: usize },
+    /// Slot `index` in the vtable of the object with full name `object`. This needs reflection,
+    /// so it only works once the engine is up.
+    VirtualMethod { object: &'static str, index: usize },
+}
+
+/// A location in the UDK binary.
+pub struct Offset {
+    pub name: &'static str,
+    pub locators: &'static [Locator],
+}
+
+/// `FName::Names`, the `TArray<FNameEntry*>` of every name. The signature is the inlined
+/// `Names(Index)` in `FName::ToString` and friends: `mov rax, [Names.Data]; mov rcx, [rax+rcx*8]`.
+#[cfg(target_arch = "x86_64")]
+pub const GNAMES: Offset = Offset {
+    name: "FName::Names",
+    locators: &[Locator::Signature {
+        pattern: "48 8B 05 ?? ?? ?? ?? 48 8B 0C C8",
+        relative: 3,
+    }],
+};
+
+#[cfg(target_arch = "x86")]
+pub const GNAMES: Offset = Offset {
+    name: "FName::Names",
+    locators: &[],
+};
+
+/// `UObject::GObjObjects`, the `TArray<UObject*>` of every object. It's indexed like `Names`,
+/// with either register pair.
+#[cfg(target_arch = "x86_64")]
+pub const GOBJECTS: Offset = Offset {
+    name: "UObject::GObjObjects",
+    locators: &[
+        Locator::Signature {
+            pattern: "48 8B 05 ?? ?? ?? ?? 48 8B 0C C8",
+            relative: 3,
+        },
+        Locator::Signature {
+            pattern: "48 8B 0D ?? ?? ?? ?? 48 8B 04 C1",
+            relative: 3,
+        },
+    ],
+};
+
+#[cfg(target_arch = "x86")]
+pub const GOBJECTS: Offset = Offset {
+    name: "UObject::GObjObjects",
+    locators: &[],
+};
+
+/// `UObject::ProcessEvent(UFunction*, void* Parms, void* Result)`. `Default__Object` is a plain
+/// `UObject`, so its vtable has `UObject`'s own implementation.
+#[cfg(target_arch = "x86_64")]
+pub const PROCESS_EVENT: Offset = Offset {
+    name: "UObject::ProcessEvent",
+    locators: &[Locator::VirtualMethod {
+        object: "Object Core.Default__Object",
+        index: 67,
+    }],
+};
+
+#[cfg(target_arch = "x86")]
+pub const PROCESS_EVENT: Offset = Offset {
+    name: "UObject::ProcessEvent",
+    locators: &[],
+};
+
+/// `UGameEngine::Tick(FLOAT DeltaSeconds)`, which the main loop calls once a frame. It overrides
+/// `USubsystem::Tick`, the first virtual after `UObject`'s own.
+#[cfg(target_arch = "x86_64")]
+pub const ENGINE_TICK: Offset = Offset {
+    name: "UGameEngine::Tick",
+    locators: &[Locator::VirtualMethod {
+        object: "GameEngine Engine.Default__GameEngine",
+        index: 79,
+    }],
+};
+
+#[cfg(target_arch = "x86")]
+pub const ENGINE_TICK: Offset = Offset {
+    name: "UGameEngine::Tick",
+    locators: &[],
+};
+
+/// `GNatives`, the table of `exec` functions the script VM calls for each bytecode, which is
+/// what `FFrame::Step` indexes: `lea rdx, [GNatives]; call [rdx+rax*8]`.
+#[cfg(target_arch = "x86_64")]
+pub const GNATIVES: Offset = Offset {
+    name: "GNatives",
+    locators: &[
+        Locator::Signature {
+            pattern: "48 8D 15 ?? ?? ?? ?? FF 14 C2",
+            relative: 3,
+        },
+        Locator::Signature {
+            pattern: "48 8D 0D ?? ?? ?? ?? FF 14 C1",
+            relative: 3,
+        },
+    ],
+};
+
+#[cfg(target_arch = "x86")]
+pub const GNATIVES: Offset = Offset {
+    name: "GNatives",
+    locators: &[],
+};
+
+/// `GMalloc`, the engine's allocator, as used by the inlined `appFree`:
+/// `mov rcx, [GMalloc]; mov rax, [rcx]; mov rdx, ...; call [rax+20h]`.
+#[cfg(target_arch = "x86_64")]
+pub const GMALLOC: Offset = Offset {
+    name: "GMalloc",
+    locators: &[Locator::Signature {
+        pattern: "48 8B 0D ?? ?? ?? ?? 48 8B 01 48 8B ?? FF 50 20",
+        relative: 3,
+    }],
+};
+
+#[cfg(target_arch = "x86")]
+pub const GMALLOC: Offset = Offset {
+    name: "GMalloc",
+    locators: &[],
+};
+
+impl Offset {
+    /// The vtable slot this is found in, if it's a virtual method. Only use it once the offset
+    /// has been located, which validates the slot.
+    pub fn vtable_index(&self) -> Option<usize> {
+        self.locators.iter().find_map(|l| match l {
+            Locator::VirtualMethod { index, .. } => Some(*index),
+            _ => None,
+        })
+    }
+}
+
+/// Addresses found so far, by name.
+static LOCATED: Mutex<BTreeMap<&'static str, usize>> = Mutex::new(BTreeMap::new());
+/// Where each offset's signatures matched, by name. Only validation is retried.
+static SCANNED: Mutex<BTreeMap<&'static str, Arc<[usize]>>> = Mutex::new(BTreeMap::new());
+
+/// Most bytes of a function [`references_fields`] looks at.
+const MAX_FUNCTION_SIZE: usize = 0x4000;
+
+/// Find `offset`, accepting only candidates for which `validate` returns true.
+///
+/// Fails if no candidate validates, or if several different ones do.
+pub fn locate(offset: &Offset, validate: impl Fn(usize) -> bool) -> anyhow::Result<usize> {
+    if let Some(&address) = LOCATED.lock().unwrap_or_else(|e| e.into_inner()).get(offset.name) {
+        return Ok(address);
+    }
+
+    if offset.locators.is_empty() {
+        bail!("{} is not known for this UDK build", offset.name);
+    }
+
+    let udk = UDK_RANGE.get().context("UDK is not loaded")?;
+    let mut candidates = scanned(offset)?.to_vec();
+    for locator in offset.locators {
+        if let Locator::VirtualMethod { object, index } = locator {
+            candidates.push(virtual_method(object, *index)?);
+        }
+    }
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let valid = candidates.iter().copied().filter(|&c| validate(c)).collect::<Vec<_>>();
+    let address = match valid[..] {
+        [address] => address,
+        [] => bail!("{} not found ({} candidates, none valid)", offset.name, candidates.len()),
+        _ => bail!("{} is ambiguous ({} valid candidates)", offset.name, valid.len()),
+    };
+
+    log(
+        LogType::Init,
+        &format!("Located {} at UDK+{:#x}", offset.name, address.wrapping_sub(udk.start)),
+    );
+    LOCATED.lock().unwrap_or_else(|e| e.into_inner()).insert(offset.name, address);
+    Ok(address)
+}
+
+/// Every address the signatures of `offset` could mean, scanning for them on first use.
+fn scanned(offset: &Offset) -> anyhow::Result<Arc<[usize]>> {
+    if let Some(candidates) = SCANNED.lock().unwrap_or_else(|e| e.into_inner()).get(offset.name) {
+        return Ok(candidates.clone());
+    }
+
+    let udk = UDK_RANGE.get().context("UDK is not loaded")?;
+    let mut candidates = Vec::new();
+    for locator in offset.locators {
+        let Locator::Signature { pattern, relative } = locator else {
+            continue;
+        };
+        let pattern = Pattern::parse(pattern)?;
+
+        for (start, code) in code_sections()? {
+            for m in pattern.scan(code) {
+                let found = sigscan::relative_target(code, m + relative).map(|t| start as isize + t);
+
+                // Anything outside of the image can't be what we're after.
+                match found {
+                    Some(address) if udk.contains(&(address as usize)) => candidates.push(address as usize),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let candidates: Arc<[usize]> = candidates.into();
+    SCANNED.lock().unwrap_or_else(|e| e.into_inner()).insert(offset.name, candidates.clone());
+    Ok(candidates)
+}
+
+/// Read slot `index` of the vtable of the object named `object`.
+fn virtual_method(object: &str, index: usize) -> anyhow::Result<usize> {
+    let reflection = udk_object::reflection()?;
+    let object = reflection.find(object)?.with_context(|| format!("{object} not found"))?;
+
+    let mem = Process::CHECKED;
+    let vtable = mem.read_ptr(object.address())?;
+    mem.read_ptr(vtable + index * std::mem::size_of::<usize>())
+}
+
+/// Whether `address` lies in UDK's code. Use it to validate function candidates.
+pub fn is_code(address: usize) -> bool {
+    code_sections().is_ok_and(|sections| {
+        sections.iter().any(|(start, code)| (*start..start + code.len()).contains(&address))
+    })
+}
+
+/// Whether the function at `address` accesses a member in each of `fields`, byte ranges within
+/// the object it works on. Use it to tell a virtual method from its neighbours in the vtable.
+pub fn references_fields(address: usize, fields: &[Range<usize>]) -> bool {
+    code_sections().is_ok_and(|sections| {
+        sections.iter().any(|(start, code)| {
+            let Some(offset) = address.checked_sub(*start).filter(|&o| o < code.len()) else {
+                return false;
+            };
+            let end = code.len().min(offset + MAX_FUNCTION_SIZE);
+            references(&code[offset..end], fields)
+        })
+    })
+}
+
+/// Whether the function starting at `code` has a 32-bit displacement into each of `fields`.
+///
+/// This is a heuristic: the bytes aren't decoded into instructions, and the function is assumed
+/// to end at the first `int3` padding. Fields near the start of an object are often reached with
+/// an 8-bit displacement, so only fields from 0x80 on can be told apart this way.
+fn references(code: &[u8], fields: &[Range<usize>]) -> bool {
+    let end = code.windows(2).position(|w| w == [0xCC, 0xCC]).unwrap_or(code.len());
+    let displacements = code[..end]
+        .windows(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]) as usize)
+        .filter(|&d| d >= 0x80)
+        .collect::<Vec<_>>();
+
+    fields.iter().all(|field| displacements.iter().any(|d| field.contains(d)))
+}
+
+/// The executable sections of the running UDK.exe, with their addresses.
+fn code_sections() -> anyhow::Result<Vec<(usize, &'static [u8])>> {
+    let view = udk_view();
+    let base = UDK_RANGE.get().context("UDK is not loaded")?.start;
+
+    view.section_headers()
+        .iter()
+        .filter(|h| h.Characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+        .map(|h| {
+            let bytes = view.get_section_bytes(h).context("failed to read UDK code section")?;
+            Ok((base + h.VirtualAddress as usize, bytes))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_field_displacements() {
+        // mov eax, [rcx+0F0h]; test ax, 400h; movzx edx, word ptr [rcx+0F4h]; ret; int3 int3
+        let code = [
+            0x8B, 0x81, 0xF0, 0x00, 0x00, 0x00, 0x66, 0xA9, 0x00, 0x04, 0x0F, 0xB7, 0x91, 0xF4, 0x00, 0x00, 0x00, 0xC3,
+            0xCC, 0xCC, 0x8B, 0x81, 0x10, 0x01, 0x00, 0x00,
+        ];
+
+        let (flags, native, func) = (0xF0..0xF4, 0xF4..0xF6, 0x110..0x118);
+        assert!(references(&code, &[flags.clone(), native.clone()]));
+        // test byte ptr [rcx+0F1h], 4 tests a bit in the middle of a field.
+        assert!(references(&[0xF6, 0x81, 0xF1, 0x00, 0x00, 0x00, 0x04], std::slice::from_ref(&flags)));
+        // The next function's accesses don't.
+        assert!(!references(&code, &[flags, func]));
+        assert!(!references(&code[..6], std::slice::from_ref(&native)));
+        // Small displacements are too common to mean anything.
+        let name = 0x40..0x48;
+        assert!(!references(&[0x8B, 0x41, 0x40, 0x00, 0x00, 0x00], std::slice::from_ref(&name)));
+        assert!(references(&code, &[]));
+    }
+}
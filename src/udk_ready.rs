@@ -0,0 +1,124 @@
+//! This module runs initialization that has to wait for the engine to come up.
+//!
+//! We're loaded long before the UDK has filled its name and object tables, so anything built on
+//! reflection can't start in `post_udk_init`. Instead we watch the game thread's message pump
+//! through the UDK's `PeekMessageW` import. Once reflection works, whatever was waiting runs
+//! there, on the game thread, and the import is restored.
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dll::is_game_thread;
+use crate::hooks::{self, Hook};
+use crate::udk_log::{log, LogType};
+use crate::udk_mem::{Memory, Process};
+use crate::{game_thread, iat, udk_object};
+
+const HOOK_NAME: &str = "PeekMessageW (engine ready)";
+
+/// How often to check whether the engine is up, and for how long.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_ATTEMPTS: u32 = 120;
+
+type PeekMessageFn = unsafe extern "system" fn(*mut c_void, usize, u32, u32, u32) -> i32;
+type Task = Box<dyn FnOnce() -> anyhow::Result<()> + Send>;
+
+enum State {
+    Waiting {
+        tasks: Vec<(&'static str, Task)>,
+        next_attempt: Option<Instant>,
+        attempts: u32,
+    },
+    Ready,
+    GaveUp,
+}
+
+static STATE: Mutex<State> = Mutex::new(State::Waiting {
+    tasks: Vec::new(),
+    next_attempt: None,
+    attempts: 0,
+});
+
+/// The real `PeekMessageW`, saved before the import is patched, so the hook never has to ask
+/// the hook registry for it.
+static ORIGINAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Start watching for the engine.
+pub fn init() -> anyhow::Result<()> {
+    let slot = iat::udk_slot("user32.dll", "PeekMessageW")?;
+    ORIGINAL.store(Process::CHECKED.read_ptr(slot)?, Ordering::Release);
+
+    let hook = peek_message_hook as PeekMessageFn as usize;
+    hooks::install(Hook::import(HOOK_NAME, "user32.dll", "PeekMessageW", hook))
+}
+
+/// Run `task` on the game thread once reflection works. If it already does, `task` runs now on
+/// the game thread, or is posted to it from other threads.
+pub fn when_ready(name: &'static str, task: impl FnOnce() -> anyhow::Result<()> + Send + 'static) {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    match &mut *state {
+        State::Waiting { tasks, .. } => tasks.push((name, Box::new(task))),
+        State::Ready => {
+            drop(state);
+            match is_game_thread() {
+                true => run(name, Box::new(task)),
+                false => game_thread::post(name, move || run(name, Box::new(task))),
+            }
+        }
+        State::GaveUp => log(LogType::Warning, &format!("{name}: skipped, the engine never became ready")),
+    }
+}
+
+fn run(name: &'static str, task: Task) {
+    if let Err(error) = task() {
+        log(LogType::Error, &format!("{name}: {error:#}"));
+    }
+}
+
+unsafe extern "system" fn peek_message_hook(msg: *mut c_void, hwnd: usize, min: u32, max: u32, remove: u32) -> i32 {
+    let original = std::mem::transmute::<usize, PeekMessageFn>(ORIGINAL.load(Ordering::Acquire));
+
+    if is_game_thread() {
+        poll();
+    }
+
+    original(msg, hwnd, min, max, remove)
+}
+
+fn poll() {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    let State::Waiting { tasks, next_attempt, attempts } = &mut *state else {
+        return;
+    };
+
+    let now = Instant::now();
+    if next_attempt.is_some_and(|t| now < t) {
+        return;
+    }
+    *next_attempt = Some(now + POLL_INTERVAL);
+    *attempts += 1;
+
+    match udk_object::reflection() {
+        Ok(_) => {
+            let tasks = std::mem::take(tasks);
+            *state = State::Ready;
+            drop(state);
+
+            for (name, task) in tasks {
+                run(name, task);
+            }
+        }
+        Err(error) if *attempts >= MAX_ATTEMPTS => {
+            log(LogType::Warning, &format!("Reflection unavailable, skipping {} tasks: {error:#}", tasks.len()));
+            *state = State::GaveUp;
+            drop(state);
+        }
+        Err(_) => return,
+    }
+
+    // Either way, there's nothing left to wait for.
+    if let Err(error) = hooks::disable(HOOK_NAME) {
+        log(LogType::Warning, &format!("{error:#}"));
+    }
+}
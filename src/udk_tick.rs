@@ -0,0 +1,92 @@
+//! This module hooks `UGameEngine::Tick`, which the UDK's main loop calls once a frame on the
+//! game thread, and runs subscribers from it before the engine ticks. It also counts frames.
+//!
+//! The hook is only installed once the first subscriber is added, and only once the engine is
+//! up (see `udk_ready.rs`).
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Once, RwLock};
+
+use anyhow::Context;
+use retour::static_detour;
+
+use crate::dll::is_game_thread;
+use crate::hooks::{self, Hook, Target};
+use crate::udk_log::{log, log_ratelimited, LogType};
+use crate::{udk_object, udk_offsets};
+use crate::udk_ready;
+
+/// `UGameEngine::Tick(FLOAT DeltaSeconds)`.
+type EngineTickFn = extern "C" fn(usize, f32);
+
+static_detour! {
+    static EngineTickHook: extern "C" fn(usize, f32);
+}
+
+/// Called with the time the engine is about to advance by, in seconds.
+pub type Subscriber = fn(f32);
+
+static SUBSCRIBERS: RwLock<Vec<Subscriber>> = RwLock::new(Vec::new());
+static INSTALL: Once = Once::new();
+
+static FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// The number of frames ticked so far, counting the current one.
+pub fn frame() -> u64 {
+    FRAME.load(Ordering::Relaxed)
+}
+
+/// Call `f` at the start of every frame. Subscribe during initialization; subscribers can't
+/// subscribe others.
+pub fn subscribe(f: Subscriber) {
+    SUBSCRIBERS.write().unwrap_or_else(|e| e.into_inner()).push(f);
+    INSTALL.call_once(|| udk_ready::when_ready("engine tick hook", install));
+}
+
+/// Locate `UGameEngine::Tick`. Its neighbours in the vtable are told apart by the members of the
+/// engine it ticks: the viewport, and the level being connected to.
+fn engine_tick() -> anyhow::Result<usize> {
+    let reflection = udk_object::reflection()?;
+    let field = |class: &str, name: &str| -> anyhow::Result<Range<usize>> {
+        let class = reflection.find_class(class)?.with_context(|| format!("{class} not found"))?;
+        let property = reflection.property(class, name)?;
+        Ok(property.offset..property.offset + property.element_size)
+    };
+    let fields = [
+        field("Engine.Engine", "GameViewport")?,
+        field("Engine.GameEngine", "GPendingLevel")?,
+    ];
+
+    udk_offsets::locate(&udk_offsets::ENGINE_TICK, |candidate| {
+        udk_offsets::is_code(candidate) && udk_offsets::references_fields(candidate, &fields)
+    })
+}
+
+/// Hook the engine tick. The method is found through reflection, so this runs through `udk_ready`.
+fn install() -> anyhow::Result<()> {
+    let target = engine_tick()?;
+
+    // SAFETY: The target is UGameEngine::Tick, whose signature we know.
+    hooks::install(Hook::detour("UGameEngine::Tick", Target::Address(target), |target| unsafe {
+        EngineTickHook.initialize(std::mem::transmute::<usize, EngineTickFn>(target), engine_tick_hook)
+    }))
+    .context("failed to hook UGameEngine::Tick")?;
+
+    log(LogType::Init, "Hooked UGameEngine::Tick");
+    Ok(())
+}
+
+fn engine_tick_hook(engine: usize, delta_seconds: f32) {
+    if is_game_thread() {
+        FRAME.fetch_add(1, Ordering::Relaxed);
+
+        for subscriber in SUBSCRIBERS.read().unwrap_or_else(|e| e.into_inner()).iter() {
+            if catch_unwind(AssertUnwindSafe(|| subscriber(delta_seconds))).is_err() {
+                log_ratelimited!(LogType::Error, "UGameEngine::Tick: a subscriber panicked");
+            }
+        }
+    }
+
+    EngineTickHook.call(engine, delta_seconds)
+}
@@ -1,10 +1,15 @@
 //! This module contains functionality related to UDK XAudio hooks.
+use std::time::Duration;
+
 use anyhow::Context;
 use retour::static_detour;
 
-use crate::dll::get_udk_ptr;
-use crate::udk_log::{log, LogType};
-use crate::xaudio27::{IXAudio27, XAudio27Wrapper};
+use crate::config::XAudioConfig;
+use crate::dll;
+use crate::hooks::{self, Hook, Target};
+use crate::udk_tick;
+use crate::udk_log::{log, log_ratelimited, LogType};
+use crate::xaudio27::{self, IXAudio27, XAudio27Wrapper};
 
 use windows::core::{GUID, HRESULT};
 use windows::Win32::Foundation::{E_FAIL, S_OK};
@@ -16,6 +21,14 @@ pub const UDK_XAUDIO2CREATE_OFFSET: usize = 0x0170_F4D0;
 #[cfg(target_arch = "x86_64")]
 pub const UDK_CREATEFX_PTR_OFFSET: usize = 0x024B_E8B0;
 
+/// How much of XAudio2Create's prologue must be untouched before we detour it; retour
+/// rewrites at most this many bytes.
+const XAUDIO2CREATE_PROLOGUE_LEN: usize = 16;
+
+type XAudio2CreateFn = extern "C" fn(*mut IXAudio27, u32, u32) -> HRESULT;
+/// `xapofx!CreateFX`, as the UDK calls it.
+type CreateFxFn = fn(*const GUID, *mut Option<windows::core::IUnknown>) -> HRESULT;
+
 static_detour! {
     static XAudio2CreateHook: extern "C" fn(*mut IXAudio27, u32, u32) -> HRESULT;
 }
@@ -47,8 +60,10 @@ fn createfx_hook(uuid: *const GUID, p_effect: *mut Option<windows::core::IUnknow
         [0x74, 0x39, 0x43, 0x55, 0x00, 0x00, 0x00, 0x03],
     );
 
+    let requested = unsafe { *uuid };
+
     // Translate GUID from XAudio 2.7 to XAudio 2.9.
-    let uuid = match unsafe { *uuid } {
+    let uuid = match requested {
         // FXEQ
         OLD_FXEQ => GUID::from_values(
             0xF5E01117,
@@ -77,14 +92,26 @@ fn createfx_hook(uuid: *const GUID, p_effect: *mut Option<windows::core::IUnknow
             0x449A,
             [0x84, 0xD3, 0xA5, 0x62, 0x02, 0x55, 0x7B, 0x87],
         ),
-        _ => return E_FAIL,
+        unknown => {
+            log_ratelimited!(LogType::Warning, "CreateFX: unknown effect CLSID {:?}", unknown);
+            return E_FAIL;
+        }
     };
 
     // Call XAudio2.9 CreateFX.
-    unsafe {
-        windows::Win32::Media::Audio::XAudio2::CreateFX(&uuid, p_effect, None, 0)
-            .map_or_else(|e| e.code(), |_| S_OK)
+    let result = unsafe { windows::Win32::Media::Audio::XAudio2::CreateFX(&uuid, p_effect, None, 0) };
+    if let Err(e) = result {
+        return e.code();
     }
+
+    // The limiter's settings can be overridden by configuration, so remember which effect it is.
+    if requested == OLD_FXMASTERINGLIMITER {
+        if let Some(effect) = unsafe { (*p_effect).as_ref() } {
+            xaudio27::track_limiter(effect);
+        }
+    }
+
+    S_OK
 }
 
 /// This function is invoked when the game calls `XAudio2Create`.
@@ -104,30 +131,53 @@ fn xaudio2create_hook(xaudio2_out: *mut IXAudio27, _flags: u32, _processor: u32)
 }
 
 pub fn init() -> anyhow::Result<()> {
-    let udk = get_udk_ptr();
+    // Refuse to detour XAudio2Create if something else already patched it in memory.
+    let prologue = dll::udk_file_bytes(UDK_XAUDIO2CREATE_OFFSET, XAUDIO2CREATE_PROLOGUE_LEN)
+        .context("Failed to read the original XAudio2Create prologue")?;
 
     // SAFETY: This is only safe if the UDK binary matches what we expect.
-    unsafe {
-        XAudio2CreateHook
-            .initialize(
-                std::mem::transmute(udk.add(UDK_XAUDIO2CREATE_OFFSET)),
-                xaudio2create_hook,
-            )
-            .context("Failed to setup InitializeHardware hook")?;
-
-        XAudio2CreateHook.enable()?;
-
-        // Enable RW access to the CreateFX pointer.
-        let _guard = region::protect_with_handle(
-            udk.add(UDK_CREATEFX_PTR_OFFSET),
-            8,
-            region::Protection::READ_WRITE,
-        )
-        .context("failed to adjust memory protection for CreateFX")?;
-
-        // Overwrite xapofx!CreateFX pointer with our hook.
-        (udk.add(UDK_CREATEFX_PTR_OFFSET) as *mut usize).write(createfx_hook as usize);
-    }
+    hooks::install(
+        Hook::detour("XAudio2Create", Target::UdkOffset(UDK_XAUDIO2CREATE_OFFSET), |target| unsafe {
+            XAudio2CreateHook.initialize(std::mem::transmute::<usize, XAudio2CreateFn>(target), xaudio2create_hook)
+        })
+        .with_expected_bytes(prologue),
+    )
+    .context("Failed to setup XAudio2Create hook")?;
+
+    // Overwrite xapofx!CreateFX pointer with our hook. The slot points into xapofx, wherever it was
+    // loaded, so there are no original bytes on disk to hold it against.
+    hooks::install(Hook::pointer(
+        "CreateFX",
+        Target::UdkOffset(UDK_CREATEFX_PTR_OFFSET),
+        createfx_hook as CreateFxFn as usize,
+    ))
+    .context("Failed to setup CreateFX hook")?;
+
+    // Voices that finish free up room in the voice budget, and virtualized one-shots run out.
+    udk_tick::subscribe(|delta_seconds| {
+        xaudio27::poll_voice_budget(Duration::try_from_secs_f32(delta_seconds).unwrap_or_default())
+    });
 
     Ok(())
 }
+
+/// Apply changed XAudio settings to the live engines.
+pub fn apply_config(config: &XAudioConfig) {
+    xaudio27::apply_live_settings(config);
+}
+
+/// Quiesce the XAudio 2.9 engines we handed out. Called after our hooks have been removed.
+pub fn shutdown() {
+    // Engines the UDK has already released are gone by now. Anything still alive is owned by the
+    // UDK, so releasing it here would leave the UDK with a dangling pointer. Stop the engines so
+    // their audio threads are idle, and leave the objects to their owner.
+    let stopped = xaudio27::stop_live_engines();
+    if stopped != 0 {
+        log(
+            LogType::Warning,
+            &format!("Stopped {stopped} XAudio engine(s) still held by the UDK at unload"),
+        );
+    }
+
+    xaudio27::log_unimplemented_summary();
+}
@@ -0,0 +1,502 @@
+//! This module decides which source voices play when the UDK wants more than a budget allows.
+//!
+//! Voices over the budget are virtualized: paused, with their queued buffers and position kept,
+//! and resumed once others stop or run out of buffers. Which voices to pause is up to a
+//! [`Policy`]. Everything here works on voice IDs and returns the [`Change`]s to make, so the
+//! XAudio layer (see `xaudio27.rs`) only has to carry them out.
+//!
+//! A paused voice doesn't advance, so a one-shot sound resumed late would play late. One-shots
+//! are timed instead, and ended once they would have finished: a voice whose only queued buffer
+//! ends its stream without looping, with a known length. Looping and streaming sounds can't be
+//! timed, and resume where they were paused.
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::bail;
+
+/// Identifies a voice. The XAudio layer uses the voice's address.
+pub type VoiceId = usize;
+
+/// Something to do to a voice to stay within the budget.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Change {
+    /// Pause the voice.
+    Virtualize(VoiceId),
+    /// Resume a voice paused by `Virtualize`.
+    Resume(VoiceId),
+    /// Flush the buffers of a virtualized one-shot, which would have finished playing by now.
+    End(VoiceId),
+}
+
+/// A playing voice, as seen by a [`Policy`].
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate {
+    pub id: VoiceId,
+    /// Volume times the loudest output level, as last set by the UDK.
+    pub loudness: f32,
+    /// Increases with each voice that starts playing, so lower is older.
+    pub started: u64,
+}
+
+/// Picks the voices to virtualize.
+pub trait Policy: Send {
+    /// Sort `voices` from the first to virtualize to the last to.
+    fn order(&self, voices: &mut [Candidate]);
+}
+
+/// Virtualize the quietest voices, and the oldest of equally loud ones.
+pub struct QuietestFirst;
+
+impl Policy for QuietestFirst {
+    fn order(&self, voices: &mut [Candidate]) {
+        voices.sort_by(|a, b| a.loudness.total_cmp(&b.loudness).then(a.started.cmp(&b.started)));
+    }
+}
+
+/// Virtualize the voices that have been playing the longest.
+pub struct OldestFirst;
+
+impl Policy for OldestFirst {
+    fn order(&self, voices: &mut [Candidate]) {
+        voices.sort_by_key(|v| v.started);
+    }
+}
+
+/// The policies that can be configured.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stealing {
+    Quietest,
+    Oldest,
+}
+
+impl Stealing {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "quietest" => Self::Quietest,
+            "oldest" => Self::Oldest,
+            _ => bail!("expected one of Quietest, Oldest"),
+        })
+    }
+
+    pub fn policy(self) -> Box<dyn Policy> {
+        match self {
+            Self::Quietest => Box::new(QuietestFirst),
+            Self::Oldest => Box::new(OldestFirst),
+        }
+    }
+}
+
+struct Voice {
+    /// The UDK has started the voice, and hasn't stopped it.
+    started: bool,
+    /// The voice ran out of buffers.
+    idle: bool,
+    virtualized: bool,
+    volume: f32,
+    level: f32,
+    /// The frequency ratio, which sets how fast the voice plays.
+    ratio: f32,
+    /// When the voice last started playing.
+    since: u64,
+    /// Buffers queued since the voice was last stopped or ran out.
+    buffers: u32,
+    /// For a one-shot, how much of it is left to play at a ratio of 1.
+    remaining: Option<Duration>,
+}
+
+impl Voice {
+    fn playing(&self) -> bool {
+        self.started && !self.idle
+    }
+}
+
+/// The voices of an engine, and how many of them may play at once.
+pub struct VoiceBudget {
+    /// `None` for no limit.
+    limit: Option<usize>,
+    policy: Box<dyn Policy>,
+    voices: BTreeMap<VoiceId, Voice>,
+    next_start: u64,
+}
+
+impl VoiceBudget {
+    pub fn new(limit: Option<usize>, policy: Box<dyn Policy>) -> Self {
+        Self {
+            limit,
+            policy,
+            voices: BTreeMap::new(),
+            next_start: 0,
+        }
+    }
+
+    /// Change the limit, returning the changes needed to meet it.
+    pub fn set_limit(&mut self, limit: Option<usize>) -> Vec<Change> {
+        self.limit = limit;
+        self.rebalance()
+    }
+
+    /// Change the policy. It applies from the next change on.
+    pub fn set_policy(&mut self, policy: Box<dyn Policy>) {
+        self.policy = policy;
+    }
+
+    /// Track a new voice. Voices start out stopped, at full volume and normal speed.
+    pub fn add(&mut self, id: VoiceId) {
+        self.voices.insert(
+            id,
+            Voice {
+                started: false,
+                idle: true,
+                virtualized: false,
+                volume: 1.0,
+                level: 1.0,
+                ratio: 1.0,
+                since: 0,
+                buffers: 0,
+                remaining: None,
+            },
+        );
+    }
+
+    /// Stop tracking a voice that's being destroyed.
+    pub fn remove(&mut self, id: VoiceId) -> Vec<Change> {
+        self.voices.remove(&id);
+        self.rebalance()
+    }
+
+    /// The UDK started the voice.
+    pub fn start(&mut self, id: VoiceId) -> Vec<Change> {
+        self.update(id, |v| v.started = true)
+    }
+
+    /// The UDK stopped the voice.
+    pub fn stop(&mut self, id: VoiceId) -> Vec<Change> {
+        self.update(id, |v| {
+            v.started = false;
+            // It's stopped either way now.
+            v.virtualized = false;
+            v.buffers = 0;
+        })
+    }
+
+    /// The UDK queued a buffer on the voice. `length` is how long the buffer plays for at a
+    /// ratio of 1, if it's known and the buffer ends the sound without looping.
+    pub fn submit(&mut self, id: VoiceId, length: Option<Duration>) -> Vec<Change> {
+        self.update(id, |v| {
+            v.idle = false;
+            v.buffers += 1;
+            // A second buffer makes it a stream.
+            v.remaining = length.filter(|_| v.buffers == 1);
+        })
+    }
+
+    /// The voice played all of its buffers.
+    pub fn idle(&mut self, id: VoiceId) -> Vec<Change> {
+        self.update(id, |v| {
+            v.idle = true;
+            v.buffers = 0;
+            v.remaining = None;
+        })
+    }
+
+    /// Advance the playing voices' positions by `elapsed`, ending the virtualized one-shots that
+    /// have run out. Called once a frame.
+    pub fn advance(&mut self, elapsed: Duration) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (&id, voice) in self.voices.iter_mut().filter(|(_, v)| v.playing()) {
+            let Some(remaining) = voice.remaining else {
+                continue;
+            };
+
+            // Scaled in f64, since f32 would round whole milliseconds down.
+            let played = Duration::from_nanos((elapsed.as_nanos() as f64 * f64::from(voice.ratio)).round() as u64);
+            let remaining = remaining.saturating_sub(played);
+            voice.remaining = Some(remaining);
+            if voice.virtualized && remaining.is_zero() {
+                // Still virtualized, in case the UDK plays something else on it.
+                voice.idle = true;
+                voice.buffers = 0;
+                voice.remaining = None;
+                changes.push(Change::End(id));
+            }
+        }
+
+        changes.extend(self.rebalance());
+        changes
+    }
+
+    pub fn set_volume(&mut self, id: VoiceId, volume: f32) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.volume = volume.abs();
+        }
+    }
+
+    /// Set the voice's frequency ratio, from 0 to XAudio's maximum of 1024.
+    pub fn set_frequency_ratio(&mut self, id: VoiceId, ratio: f32) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            // It scales durations, which can't be NaN.
+            voice.ratio = if ratio.is_nan() { 1.0 } else { ratio.clamp(0.0, 1024.0) };
+        }
+    }
+
+    /// Set the loudest level in the voice's output matrix.
+    pub fn set_level(&mut self, id: VoiceId, level: f32) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.level = level.abs();
+        }
+    }
+
+    pub fn is_virtualized(&self, id: VoiceId) -> bool {
+        self.voices.get(&id).is_some_and(|v| v.virtualized)
+    }
+
+    /// The voices that are playing, virtualized or not, which may run out of buffers.
+    pub fn playing(&self) -> Vec<VoiceId> {
+        self.voices
+            .iter()
+            .filter(|(_, v)| v.playing())
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// The number of voices playing, and how many of those are virtualized.
+    pub fn counts(&self) -> (usize, usize) {
+        let playing = self.voices.values().filter(|v| v.playing()).count();
+        let virtualized = self.voices.values().filter(|v| v.playing() && v.virtualized).count();
+        (playing, virtualized)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    fn update(&mut self, id: VoiceId, f: impl FnOnce(&mut Voice)) -> Vec<Change> {
+        let Some(voice) = self.voices.get_mut(&id) else {
+            return Vec::new();
+        };
+
+        let was_playing = voice.playing();
+        f(voice);
+        if voice.playing() && !was_playing {
+            voice.since = self.next_start;
+            self.next_start += 1;
+        }
+
+        self.rebalance()
+    }
+
+    /// Virtualize the playing voices the policy picks beyond the limit, and resume the rest.
+    fn rebalance(&mut self) -> Vec<Change> {
+        let mut playing = self
+            .voices
+            .iter()
+            .filter(|(_, v)| v.playing())
+            .map(|(&id, v)| Candidate {
+                id,
+                loudness: v.volume * v.level,
+                started: v.since,
+            })
+            .collect::<Vec<_>>();
+
+        let excess = self.limit.map_or(0, |limit| playing.len().saturating_sub(limit));
+        self.policy.order(&mut playing);
+        let virtualize = playing[..excess].iter().map(|c| c.id).collect::<Vec<_>>();
+
+        let mut changes = Vec::new();
+        for (&id, voice) in self.voices.iter_mut() {
+            // Voices that aren't playing are left alone. A virtualized one that runs out of buffers
+            // stays paused, to be resumed if it gets more; `stop` clears the flag.
+            let wanted = voice.playing() && virtualize.contains(&id);
+            match (voice.virtualized, wanted) {
+                (false, true) => changes.push(Change::Virtualize(id)),
+                (true, false) if voice.playing() => changes.push(Change::Resume(id)),
+                _ => continue,
+            }
+            voice.virtualized = wanted;
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn budget(limit: Option<usize>) -> VoiceBudget {
+        VoiceBudget::new(limit, Box::new(QuietestFirst))
+    }
+
+    /// Start a voice at `volume`, returning the changes queuing its buffer makes.
+    fn play(budget: &mut VoiceBudget, id: VoiceId, volume: f32, length: Option<Duration>) -> Vec<Change> {
+        budget.add(id);
+        budget.set_volume(id, volume);
+        assert_eq!(budget.start(id), []);
+        budget.submit(id, length)
+    }
+
+    fn candidate(id: VoiceId, loudness: f32, started: u64) -> Candidate {
+        Candidate { id, loudness, started }
+    }
+
+    fn order(policy: &dyn Policy, mut voices: Vec<Candidate>) -> Vec<VoiceId> {
+        policy.order(&mut voices);
+        voices.iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn orders_by_policy() {
+        let voices = vec![candidate(1, 0.5, 2), candidate(2, 0.2, 3), candidate(3, 0.5, 1), candidate(4, 0.2, 0)];
+        assert_eq!(order(&QuietestFirst, voices.clone()), [4, 2, 3, 1]);
+        assert_eq!(order(&OldestFirst, voices), [4, 3, 1, 2]);
+
+        // Ties keep their order.
+        let voices = vec![candidate(1, 0.5, 7), candidate(2, 0.5, 7)];
+        assert_eq!(order(&QuietestFirst, voices.clone()), [1, 2]);
+        assert_eq!(order(&OldestFirst, voices), [1, 2]);
+
+        assert_eq!(Stealing::parse("OLDEST").unwrap(), Stealing::Oldest);
+        assert!(Stealing::parse("loudest").is_err());
+    }
+
+    #[test]
+    fn plays_everything_without_a_limit() {
+        let mut budget = budget(None);
+        for id in 1..=100 {
+            assert_eq!(play(&mut budget, id, 0.01, None), []);
+        }
+        assert_eq!(budget.counts(), (100, 0));
+    }
+
+    #[test]
+    fn virtualizes_beyond_the_limit() {
+        let mut budget = budget(Some(2));
+        assert_eq!(play(&mut budget, 1, 0.5, None), []);
+        assert_eq!(play(&mut budget, 2, 1.0, None), []);
+        assert_eq!(play(&mut budget, 3, 0.2, None), [Change::Virtualize(3)]);
+        assert_eq!(play(&mut budget, 4, 0.8, None), [Change::Virtualize(1)]);
+        assert_eq!(budget.counts(), (4, 2));
+
+        // The output level counts as much as the volume.
+        budget.set_level(4, 0.1);
+        assert_eq!(budget.submit(4, None), [Change::Resume(1), Change::Virtualize(4)]);
+    }
+
+    #[test]
+    fn virtualizes_the_oldest_of_equally_loud_voices() {
+        let mut budget = budget(Some(1));
+        assert_eq!(play(&mut budget, 2, 0.5, None), []);
+        assert_eq!(play(&mut budget, 1, 0.5, None), [Change::Virtualize(2)]);
+
+        let mut budget = VoiceBudget::new(Some(1), Box::new(OldestFirst));
+        assert_eq!(play(&mut budget, 1, 0.1, None), []);
+        assert_eq!(play(&mut budget, 2, 1.0, None), [Change::Virtualize(1)]);
+    }
+
+    #[test]
+    fn follows_the_voices_state() {
+        let mut budget = budget(Some(1));
+
+        // Not playing until it's started and has a buffer.
+        budget.add(1);
+        assert_eq!(budget.submit(1, None), []);
+        assert_eq!(budget.counts(), (0, 0));
+        assert_eq!(budget.start(1), []);
+        assert_eq!(budget.counts(), (1, 0));
+
+        assert_eq!(play(&mut budget, 2, 0.5, None), [Change::Virtualize(2)]);
+        assert_eq!(play(&mut budget, 3, 0.2, None), [Change::Virtualize(3)]);
+        assert_eq!(budget.playing(), [1, 2, 3]);
+
+        // A voice running out makes room.
+        assert_eq!(budget.idle(1), [Change::Resume(2)]);
+        // A virtualized voice that runs out stays paused, and resumes once it has more to play.
+        assert_eq!(budget.idle(3), []);
+        assert!(budget.is_virtualized(3));
+        assert_eq!(budget.submit(3, None), []);
+        assert_eq!(budget.stop(2), [Change::Resume(3)]);
+
+        // Stopping a virtualized voice leaves it stopped.
+        assert_eq!(play(&mut budget, 4, 0.1, None), [Change::Virtualize(4)]);
+        assert_eq!(budget.stop(4), []);
+        assert!(!budget.is_virtualized(4));
+        assert_eq!(budget.start(4), [Change::Virtualize(4)]);
+
+        assert_eq!(budget.remove(3), [Change::Resume(4)]);
+        assert_eq!(budget.counts(), (1, 0));
+    }
+
+    #[test]
+    fn meets_a_new_limit() {
+        let mut budget = budget(None);
+        for (id, volume) in [(1, 0.3), (2, 0.2), (3, 0.1)] {
+            assert_eq!(play(&mut budget, id, volume, None), []);
+        }
+
+        assert_eq!(budget.set_limit(Some(1)), [Change::Virtualize(2), Change::Virtualize(3)]);
+        assert_eq!(budget.counts(), (3, 2));
+        assert_eq!(budget.set_limit(Some(2)), [Change::Resume(2)]);
+        assert_eq!(budget.set_limit(None), [Change::Resume(3)]);
+        assert_eq!(budget.limit(), None);
+    }
+
+    #[test]
+    fn ends_virtualized_one_shots_on_time() {
+        let mut budget = budget(Some(1));
+        assert_eq!(play(&mut budget, 1, 1.0, None), []);
+        assert_eq!(play(&mut budget, 2, 0.5, Some(100 * MS)), [Change::Virtualize(2)]);
+
+        assert_eq!(budget.advance(60 * MS), []);
+        assert_eq!(budget.advance(40 * MS), [Change::End(2)]);
+        assert_eq!(budget.counts(), (1, 0));
+        assert_eq!(budget.advance(100 * MS), []);
+
+        // It plays again once the UDK gives it another sound.
+        assert_eq!(budget.stop(2), []);
+        assert_eq!(budget.start(2), []);
+        assert_eq!(budget.submit(2, Some(100 * MS)), [Change::Virtualize(2)]);
+        assert_eq!(budget.stop(1), [Change::Resume(2)]);
+    }
+
+    #[test]
+    fn keeps_the_position_of_one_shots() {
+        let mut budget = budget(Some(1));
+        assert_eq!(play(&mut budget, 1, 0.5, Some(100 * MS)), []);
+        assert_eq!(budget.advance(80 * MS), []);
+
+        assert_eq!(play(&mut budget, 2, 1.0, None), [Change::Virtualize(1)]);
+        assert_eq!(budget.advance(20 * MS), [Change::End(1)]);
+    }
+
+    #[test]
+    fn times_one_shots_at_their_frequency_ratio() {
+        let mut budget = budget(Some(1));
+        assert_eq!(play(&mut budget, 1, 1.0, None), []);
+        assert_eq!(play(&mut budget, 2, 0.5, Some(100 * MS)), [Change::Virtualize(2)]);
+
+        budget.set_frequency_ratio(2, 2.0);
+        assert_eq!(budget.advance(50 * MS), [Change::End(2)]);
+
+        budget.set_frequency_ratio(2, f32::NAN);
+        assert_eq!(budget.submit(2, Some(100 * MS)), []);
+        assert_eq!(budget.advance(99 * MS), []);
+        assert_eq!(budget.advance(MS), [Change::End(2)]);
+    }
+
+    #[test]
+    fn leaves_looping_and_streaming_voices_paused() {
+        let mut budget = budget(Some(1));
+        assert_eq!(play(&mut budget, 1, 1.0, Some(10 * MS)), []);
+        // Looping, or of unknown length.
+        assert_eq!(play(&mut budget, 2, 0.5, None), [Change::Virtualize(2)]);
+        // Streaming: more than one buffer.
+        assert_eq!(play(&mut budget, 3, 0.5, Some(10 * MS)), [Change::Virtualize(3)]);
+        assert_eq!(budget.submit(3, Some(10 * MS)), []);
+
+        // Voices that aren't virtualized end on their own.
+        assert_eq!(budget.advance(Duration::from_secs(1)), []);
+        assert_eq!(budget.counts(), (3, 2));
+    }
+}
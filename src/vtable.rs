@@ -0,0 +1,488 @@
+//! This module contains utilities for implementing and intercepting vtable-based interfaces.
+//!
+//! `impl_iface!` builds non-COM interfaces out of Rust types, and [`intercept`] lets us
+//! replace individual methods of an existing COM object with Rust closures, while every other
+//! method keeps going to the original implementation.
+//!
+//! Interception is per-object: the object's vtable pointer is swapped for a copy of its
+//! vtable (the "shadow"), so other objects sharing the original vtable are unaffected.
+//! Methods must use the `system` calling convention, which covers COM on both architectures
+//! and every C++ virtual on x86_64 (x86 `thiscall` interfaces are not supported).
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::bail;
+
+// This macro is derived from the #[implement()] macro provided by the Windows crate, but modified
+// for non-COM interfaces. Remove this when they fix the upstream macro.
+macro_rules! impl_iface {
+    ($implementation:ident, $iface:ident) => {
+        ::paste::paste! {
+            #[repr(C)]
+            struct [<$implementation _Impl>] {
+                // This *MUST* be laid out as follows, due to reasons.
+                // vtables: (*const <$iface as ::windows::core::Vtable>::Vtable,),
+                this: $implementation,
+                container: *const ::windows::core::ScopedHeap,
+            }
+            impl [<$implementation _Impl>] {
+                /*
+                const VTABLES: (<$iface as ::windows::core::Vtable>::Vtable,) = (
+                    <$iface as ::windows::core::Vtable>::Vtable::new::<$implementation>(
+                    ),
+                );
+                */
+                fn new(this: $implementation) -> Self {
+                    Self {
+                        // vtables: (&Self::VTABLES.0,),
+                        this,
+                        container: ::core::ptr::null(),
+                    }
+                }
+            }
+            impl ::core::convert::From<$implementation> for $iface {
+                fn from(this: $implementation) -> Self {
+                    const VTABLE: <$iface as ::windows::core::Interface>::Vtable =
+                            <$iface as ::windows::core::Interface>::Vtable::new::<$implementation>();
+
+                    let this = [<$implementation _Impl>]::new(this);
+                    let this = ::std::boxed::Box::into_raw(::std::boxed::Box::new(this));
+
+                    let container = ::windows::core::ScopedHeap {
+                        vtable: &VTABLE as *const _ as *const _,
+                        this: this as *const _ as *const _,
+                    };
+
+                    let container = ::std::boxed::Box::into_raw(::std::boxed::Box::new(container));
+
+                    unsafe {
+                        // Add a reverse link from this to the container object.
+                        (*this).container = container;
+
+                        // Return the vtable pointer in our container.
+                        let vtable_ptr = &(*container).vtable;
+                        ::std::mem::transmute(vtable_ptr)
+                    }
+                }
+            }
+            impl ::windows::core::AsImpl<$implementation> for $iface {
+                unsafe fn as_impl(&self) -> &$implementation {
+                    let this = ::windows::core::Interface::as_raw(self);
+                    unsafe {
+                        let this = (this as *mut *mut ::core::ffi::c_void).sub(0 + 0)
+                            as *mut [<$implementation _Impl>];
+                        &(*this).this
+                    }
+                }
+            }
+            impl $crate::vtable::ScopedDrop for $implementation {
+                unsafe fn drop_in_place(&self) {
+                    let this = (self as *const _ as *mut [<$implementation _Impl>]);
+                    let container = (*this).container as *mut ::windows::core::ScopedHeap;
+
+                    // Convert this back into a box and drop it.
+                    let _ = ::std::boxed::Box::from_raw(this);
+                    // Ditto for the container.
+                    let _ = ::std::boxed::Box::from_raw(container);
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use impl_iface;
+
+pub trait ScopedDrop {
+    /// Drop the interface in-place. Note that this is unsafe for obvious reasons,
+    /// and you must take care that you do not access `self` whatsoever after calling
+    /// this function.
+    unsafe fn drop_in_place(&self);
+}
+
+/// Vtable index of `IUnknown::Release`.
+const RELEASE_INDEX: usize = 2;
+
+type ReleaseFn = unsafe extern "system" fn(*mut c_void) -> u32;
+
+/// Interception state for a single object.
+struct State {
+    object: usize,
+    /// The object's original vtable.
+    original: *const usize,
+    /// `[state pointer, entries...]`. The object's vtable pointer points at `slots[1]`, so a
+    /// thunk can find this state from `this` alone.
+    slots: Box<[AtomicUsize]>,
+    /// Closures for the hooked methods, by vtable index. Each is set at most once, and is only
+    /// read by the thunk published after it was set.
+    closures: Box<[OnceLock<Box<dyn Any + Send + Sync>>]>,
+}
+
+impl State {
+    /// Find the state of an intercepted object from its `this` pointer.
+    ///
+    /// SAFETY: `this` must be an object that is currently intercepted.
+    unsafe fn of<'a>(this: *mut c_void) -> &'a State {
+        let vtable = *(this as *const *const usize);
+        &*(*vtable.sub(1) as *const State)
+    }
+
+    fn len(&self) -> usize {
+        self.closures.len()
+    }
+
+    unsafe fn original_entry(&self, index: usize) -> usize {
+        *self.original.add(index)
+    }
+
+    fn closure<C: ?Sized + 'static>(&self, index: usize) -> &C {
+        self.closures[index]
+            .get()
+            .and_then(|c| c.downcast_ref::<Box<C>>())
+            .expect("vtable thunk called without a matching closure")
+    }
+}
+
+/// Intercepted objects, mapped to their state.
+static INTERCEPTED: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+fn intercepted() -> std::sync::MutexGuard<'static, BTreeMap<usize, usize>> {
+    INTERCEPTED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A method signature that can be intercepted: `unsafe extern "system" fn(this, args...) -> R`.
+///
+/// The closure for a method receives the original implementation, `this`, and the arguments.
+///
+/// # Safety
+///
+/// `thunk::<I>()` must return a function with exactly this signature, which calls the closure
+/// hooked into slot `I` of the intercepted `this`. The implementations below are the only ones.
+pub unsafe trait Method: Copy + 'static {
+    type Closure: ?Sized + Send + Sync + 'static;
+
+    /// Return the thunk that dispatches vtable slot `I` to its closure.
+    fn thunk<const I: usize>() -> usize;
+}
+
+macro_rules! impl_method {
+    ($($arg:ident: $ty:ident),*) => {
+        unsafe impl<Ret: 'static, $($ty: 'static),*> Method for unsafe extern "system" fn(*mut c_void $(, $ty)*) -> Ret {
+            type Closure = dyn Fn(Self, *mut c_void $(, $ty)*) -> Ret + Send + Sync;
+
+            fn thunk<const I: usize>() -> usize {
+                type Sig<Ret, $($ty),*> = unsafe extern "system" fn(*mut c_void $(, $ty)*) -> Ret;
+
+                unsafe extern "system" fn thunk<const I: usize, Ret: 'static, $($ty: 'static),*>(
+                    this: *mut c_void $(, $arg: $ty)*
+                ) -> Ret {
+                    let state = State::of(this);
+                    let original = std::mem::transmute::<usize, Sig<Ret, $($ty),*>>(state.original_entry(I));
+                    let closure = state.closure::<<Sig<Ret, $($ty),*> as Method>::Closure>(I);
+
+                    closure(original, this $(, $arg)*)
+                }
+
+                thunk::<I, Ret $(, $ty)*> as Sig<Ret, $($ty),*> as usize
+            }
+        }
+    };
+}
+
+impl_method!();
+impl_method!(a: A);
+impl_method!(a: A, b: B);
+impl_method!(a: A, b: B, c: C);
+impl_method!(a: A, b: B, c: C, d: D);
+impl_method!(a: A, b: B, c: C, d: D, e: E);
+impl_method!(a: A, b: B, c: C, d: D, e: E, f: F);
+impl_method!(a: A, b: B, c: C, d: D, e: E, f: F, g: G);
+impl_method!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H);
+
+/// Handle to an intercepted object. The interception is freed along with the object, when
+/// its reference count drops to zero.
+#[derive(Clone, Copy)]
+pub struct Interception {
+    state: *const State,
+}
+
+/// Start intercepting the COM object `object`, whose vtable has (at least) `len` entries. If the
+/// object is already intercepted, the existing interception is returned.
+///
+/// SAFETY: `object` must point to a live COM object, whose vtable is at least `len` entries long.
+pub unsafe fn intercept(object: *mut c_void, len: usize) -> anyhow::Result<Interception> {
+    let mut intercepted = intercepted();
+    if let Some(&state) = intercepted.get(&(object as usize)) {
+        return Ok(Interception { state: state as *const State });
+    }
+
+    if len <= RELEASE_INDEX {
+        bail!("COM vtables have at least {} entries", RELEASE_INDEX + 1);
+    }
+
+    let vptr = object as *mut *const usize;
+    let original = *vptr;
+
+    let state = Box::into_raw(Box::new(State {
+        object: object as usize,
+        original,
+        slots: (0..=len).map(|_| AtomicUsize::new(0)).collect(),
+        closures: (0..len).map(|_| OnceLock::new()).collect(),
+    }));
+
+    // Fill in the shadow: a back-pointer to our state, followed by the original entries.
+    (*state).slots[0].store(state as usize, Ordering::Relaxed);
+    for i in 0..len {
+        (*state).slots[i + 1].store(*original.add(i), Ordering::Relaxed);
+    }
+    (*state).slots[RELEASE_INDEX + 1].store(release_thunk as ReleaseFn as usize, Ordering::Relaxed);
+
+    // Publish the shadow vtable. From here on, every call on this object goes through it.
+    let shadow = (*state).slots.as_ptr().add(1) as *const usize;
+    (*(vptr as *const AtomicUsize)).store(shadow as usize, Ordering::SeqCst);
+
+    intercepted.insert(object as usize, state as usize);
+    Ok(Interception { state })
+}
+
+/// Replacement for `IUnknown::Release`, which frees our state along with the object.
+unsafe extern "system" fn release_thunk(this: *mut c_void) -> u32 {
+    let state = State::of(this) as *const State;
+    let object = (*state).object;
+    let original = std::mem::transmute::<usize, ReleaseFn>((*state).original_entry(RELEASE_INDEX));
+
+    // The registry isn't locked across the release, which may well release (and so end the
+    // interceptions of) other objects.
+    let refs = original(this);
+    if refs == 0 {
+        // The object's address may have been reused and intercepted again already.
+        let mut intercepted = intercepted();
+        if intercepted.get(&object) == Some(&(state as usize)) {
+            intercepted.remove(&object);
+        }
+        drop(intercepted);
+
+        drop(Box::from_raw(state as *mut State));
+    }
+
+    refs
+}
+
+impl Interception {
+    /// Replace vtable slot `I` with `closure`, which receives the original method, `this`,
+    /// and the method's arguments. A slot can only be hooked once.
+    ///
+    /// SAFETY: The object must still be alive, and `M` must match the method's real signature.
+    pub unsafe fn hook<const I: usize, M: Method>(&self, closure: Box<M::Closure>) -> anyhow::Result<()> {
+        let state = &*self.state;
+
+        if I >= state.len() {
+            bail!("vtable index {I} is out of range (vtable has {} entries)", state.len());
+        }
+        if I == RELEASE_INDEX {
+            bail!("Release is managed by the interception and can't be hooked");
+        }
+
+        // The closure must be in place before the slot is published.
+        if state.closures[I].set(Box::new(closure)).is_err() {
+            bail!("vtable index {I} is already hooked");
+        }
+        state.slots[I + 1].store(M::thunk::<I>(), Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU32};
+    use std::sync::Arc;
+
+    use super::*;
+
+    type AddRefFn = unsafe extern "system" fn(*mut c_void) -> u32;
+    type QueryInterfaceFn = unsafe extern "system" fn(*mut c_void, *const c_void, *mut *mut c_void) -> i32;
+    type BinaryFn = unsafe extern "system" fn(*mut c_void, u32, u32) -> u32;
+
+    const ADD: usize = 3;
+    const MUL: usize = 4;
+    const VTABLE_LEN: usize = 5;
+
+    /// A COM-shaped object: `IUnknown`, then `Add` and `Mul`.
+    #[repr(C)]
+    struct Fake {
+        vtable: *const usize,
+        refs: AtomicU32,
+        /// Released along with this object.
+        child: *mut c_void,
+        dropped: Arc<AtomicBool>,
+    }
+
+    unsafe extern "system" fn query_interface(_: *mut c_void, _: *const c_void, _: *mut *mut c_void) -> i32 {
+        // E_NOINTERFACE
+        0x8000_4002_u32 as i32
+    }
+
+    unsafe extern "system" fn add_ref(this: *mut c_void) -> u32 {
+        (*(this as *const Fake)).refs.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    unsafe extern "system" fn release(this: *mut c_void) -> u32 {
+        let refs = (*(this as *const Fake)).refs.fetch_sub(1, Ordering::SeqCst) - 1;
+        if refs == 0 {
+            let fake = Box::from_raw(this as *mut Fake);
+            if !fake.child.is_null() {
+                call::<ReleaseFn>(fake.child, RELEASE_INDEX)(fake.child);
+            }
+            fake.dropped.store(true, Ordering::SeqCst);
+        }
+        refs
+    }
+
+    unsafe extern "system" fn add(_: *mut c_void, a: u32, b: u32) -> u32 {
+        a + b
+    }
+
+    unsafe extern "system" fn mul(_: *mut c_void, a: u32, b: u32) -> u32 {
+        a * b
+    }
+
+    fn vtable() -> *const usize {
+        static VTABLE: OnceLock<[usize; VTABLE_LEN]> = OnceLock::new();
+
+        VTABLE
+            .get_or_init(|| {
+                [
+                    query_interface as QueryInterfaceFn as usize,
+                    add_ref as AddRefFn as usize,
+                    release as ReleaseFn as usize,
+                    add as BinaryFn as usize,
+                    mul as BinaryFn as usize,
+                ]
+            })
+            .as_ptr()
+    }
+
+    /// A new object with one reference, and a flag that's set once it's freed.
+    fn fake(child: *mut c_void) -> (*mut c_void, Arc<AtomicBool>) {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let object = Box::new(Fake {
+            vtable: vtable(),
+            refs: AtomicU32::new(1),
+            child,
+            dropped: dropped.clone(),
+        });
+        (Box::into_raw(object) as *mut c_void, dropped)
+    }
+
+    /// Look up a method the way a caller would, through the object's current vtable.
+    unsafe fn call<F: Copy>(object: *mut c_void, index: usize) -> F {
+        let vtable = *(object as *const *const usize);
+        std::mem::transmute_copy(&*vtable.add(index))
+    }
+
+    fn is_intercepted(object: *mut c_void) -> bool {
+        intercepted().contains_key(&(object as usize))
+    }
+
+    /// Freed objects' addresses get reused, so tests that check the registry take turns.
+    fn serial() -> std::sync::MutexGuard<'static, ()> {
+        static SERIAL: Mutex<()> = Mutex::new(());
+        SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn hooked_methods_call_the_closure_with_the_original() {
+        let _serial = serial();
+        let (object, _) = fake(std::ptr::null_mut());
+        let (other, _) = fake(std::ptr::null_mut());
+
+        unsafe {
+            let iface = intercept(object, VTABLE_LEN).unwrap();
+            iface
+                .hook::<ADD, BinaryFn>(Box::new(|original: BinaryFn, this, a, b| original(this, a, b) * 10))
+                .unwrap();
+
+            assert_eq!(call::<BinaryFn>(object, ADD)(object, 2, 3), 50);
+            assert_eq!(call::<BinaryFn>(object, MUL)(object, 2, 3), 6);
+
+            // Other objects with the same vtable are left alone.
+            assert_eq!(call::<BinaryFn>(other, ADD)(other, 2, 3), 5);
+
+            call::<ReleaseFn>(object, RELEASE_INDEX)(object);
+            call::<ReleaseFn>(other, RELEASE_INDEX)(other);
+        }
+    }
+
+    #[test]
+    fn intercepting_twice_shares_the_interception() {
+        let _serial = serial();
+        let (object, _) = fake(std::ptr::null_mut());
+
+        unsafe {
+            let first = intercept(object, VTABLE_LEN).unwrap();
+            let second = intercept(object, VTABLE_LEN).unwrap();
+
+            first.hook::<ADD, BinaryFn>(Box::new(|_, _, _, _| 0)).unwrap();
+            assert!(second.hook::<ADD, BinaryFn>(Box::new(|_, _, _, _| 1)).is_err());
+            assert_eq!(call::<BinaryFn>(object, ADD)(object, 2, 3), 0);
+
+            call::<ReleaseFn>(object, RELEASE_INDEX)(object);
+        }
+    }
+
+    #[test]
+    fn rejects_slots_it_cannot_hook() {
+        let _serial = serial();
+        let (object, _) = fake(std::ptr::null_mut());
+
+        unsafe {
+            assert!(intercept(object, RELEASE_INDEX).is_err());
+
+            let iface = intercept(object, VTABLE_LEN).unwrap();
+            assert!(iface.hook::<VTABLE_LEN, BinaryFn>(Box::new(|_, _, _, _| 0)).is_err());
+            assert!(iface.hook::<RELEASE_INDEX, ReleaseFn>(Box::new(|_, _| 0)).is_err());
+
+            call::<ReleaseFn>(object, RELEASE_INDEX)(object);
+        }
+    }
+
+    #[test]
+    fn final_release_ends_the_interception() {
+        let _serial = serial();
+        let (object, dropped) = fake(std::ptr::null_mut());
+
+        unsafe {
+            intercept(object, VTABLE_LEN).unwrap();
+            assert_eq!(call::<AddRefFn>(object, 1)(object), 2);
+
+            assert_eq!(call::<ReleaseFn>(object, RELEASE_INDEX)(object), 1);
+            assert!(is_intercepted(object));
+            assert!(!dropped.load(Ordering::SeqCst));
+
+            assert_eq!(call::<ReleaseFn>(object, RELEASE_INDEX)(object), 0);
+            assert!(!is_intercepted(object));
+            assert!(dropped.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn releases_can_release_other_intercepted_objects() {
+        let _serial = serial();
+        let (child, child_dropped) = fake(std::ptr::null_mut());
+        let (parent, parent_dropped) = fake(child);
+
+        unsafe {
+            intercept(child, VTABLE_LEN).unwrap();
+            intercept(parent, VTABLE_LEN).unwrap();
+
+            assert_eq!(call::<ReleaseFn>(parent, RELEASE_INDEX)(parent), 0);
+        }
+
+        assert!(parent_dropped.load(Ordering::SeqCst));
+        assert!(child_dropped.load(Ordering::SeqCst));
+        assert!(!is_intercepted(parent));
+        assert!(!is_intercepted(child));
+    }
+}
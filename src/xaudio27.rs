@@ -1,23 +1,32 @@
-use windows::core::{implement, IUnknown, IUnknown_Vtbl, GUID, HRESULT};
+use windows::core::{implement, IUnknown, IUnknown_Vtbl, Interface, ScopedInterface, GUID, HRESULT};
 use windows_interface::interface;
 use windows::Win32::Foundation::{BOOL, E_FAIL, S_OK};
 use windows::Win32::Media::Audio::XAudio2::{
-    IXAudio2, IXAudio2MasteringVoice, IXAudio2SourceVoice, IXAudio2SubmixVoice, IXAudio2Voice,
-    XAUDIO2_BUFFER, XAUDIO2_BUFFER_WMA, XAUDIO2_DEBUG_CONFIGURATION, XAUDIO2_DEFAULT_PROCESSOR,
-    XAUDIO2_EFFECT_CHAIN, XAUDIO2_FILTER_PARAMETERS, XAUDIO2_LOG_ERRORS, XAUDIO2_LOG_WARNINGS,
-    XAUDIO2_SEND_DESCRIPTOR, XAUDIO2_VOICE_SENDS, XAUDIO2_VOICE_STATE,
+    FXMASTERINGLIMITER_PARAMETERS, IXAudio2, IXAudio2EngineCallback, IXAudio2EngineCallback_Impl,
+    IXAudio2MasteringVoice, IXAudio2SourceVoice, IXAudio2SubmixVoice, IXAudio2Voice, IXAudio2VoiceCallback,
+    XAUDIO2_BUFFER, XAUDIO2_BUFFER_WMA, XAUDIO2_COMMIT_NOW, XAUDIO2_DEBUG_CONFIGURATION, XAUDIO2_DEFAULT_PROCESSOR,
+    XAUDIO2_EFFECT_CHAIN, XAUDIO2_END_OF_STREAM, XAUDIO2_FILTER_PARAMETERS, XAUDIO2_LOG_DETAIL, XAUDIO2_LOG_ERRORS,
+    XAUDIO2_LOG_INFO, XAUDIO2_LOG_WARNINGS, XAUDIO2_PERFORMANCE_DATA,
+    XAUDIO2_SEND_DESCRIPTOR, XAUDIO2_VOICE_NOSAMPLESPLAYED, XAUDIO2_VOICE_SENDS, XAUDIO2_VOICE_STATE,
 };
 use windows::Win32::Media::Audio::{
     AudioCategory_GameMedia, XAudio2, WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_PCM,
 };
 use windows::Win32::System::SystemInformation::NTDDI_WIN10;
 
-use paste::paste;
+use std::collections::BTreeMap;
 use std::ffi::c_void;
 use std::mem::ManuallyDrop;
+use std::sync::Mutex;
+use std::time::Duration;
 use widestring::{WideCStr, WideChar};
 
+use crate::audio_stats;
+use crate::game_thread;
+use crate::config::{self, XAudioConfig, XAudioTraceLevel};
 use crate::udk_log;
+use crate::voice_budget::{Change, VoiceBudget, VoiceId};
+use crate::vtable::{impl_iface, ScopedDrop};
 
 fn log_warning(msg: std::fmt::Arguments) {
     let msg = std::fmt::format(msg);
@@ -49,114 +58,78 @@ fn wstr_array<const N: usize>(src: &WideCStr) -> [u16; N] {
     a
 }
 
-macro_rules! todo_log {
-    () => {
-        log_warning(std::format_args!(
-            "XAudio27 HOOK: unimplemented: {}:{}",
-            file!(),
-            line!()
-        ));
-    };
-    ($fmt:expr) => {
-        log_warning(std::format_args!(
-            concat!("XAudio27 HOOK: unimplemented: {}:{}: ", $fmt),
-            file!(),
-            line!(),
-        ));
-    };
+/// Unimplemented entry points hit during this session, keyed by the function's type path.
+static UNIMPLEMENTED_HITS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
 
-    ($fmt:expr, $($args:tt),*) => {
-        log_warning(std::format_args!(
-            concat!("XAudio27 HOOK: unimplemented: {}:{}: ", $fmt),
-            file!(),
-            line!(),
-            $($args),*
-        ));
-    };
+fn record_unimplemented(path: &'static str) {
+    let mut hits = UNIMPLEMENTED_HITS.lock().unwrap_or_else(|e| e.into_inner());
+    *hits.entry(path).or_default() += 1;
 }
 
-// This macro is derived from the #[implement()] macro provided by the Windows crate, but modified
-// for non-COM interfaces. Remove this when they fix the upstream macro.
-macro_rules! impl_iface {
-    ($implementation:ident, $iface:ident) => {
-        paste! {
-            #[repr(C)]
-            struct [<$implementation _Impl>] {
-                // This *MUST* be laid out as follows, due to reasons.
-                // vtables: (*const <$iface as ::windows::core::Vtable>::Vtable,),
-                this: $implementation,
-                container: *const ::windows::core::ScopedHeap,
-            }
-            impl [<$implementation _Impl>] {
-                /*
-                const VTABLES: (<$iface as ::windows::core::Vtable>::Vtable,) = (
-                    <$iface as ::windows::core::Vtable>::Vtable::new::<$implementation>(
-                    ),
-                );
-                */
-                fn new(this: $implementation) -> Self {
-                    Self {
-                        // vtables: (&Self::VTABLES.0,),
-                        this,
-                        container: ::core::ptr::null(),
-                    }
-                }
-            }
-            impl ::core::convert::From<$implementation> for $iface {
-                fn from(this: $implementation) -> Self {
-                    const VTABLE: <$iface as ::windows::core::Interface>::Vtable =
-                            <$iface as ::windows::core::Interface>::Vtable::new::<$implementation>();
-
-                    let this = [<$implementation _Impl>]::new(this);
-                    let this = ::std::boxed::Box::into_raw(::std::boxed::Box::new(this));
+/// Turn the type path of a function-local item (as produced by `todo_log!`) into
+/// a short `Type::Method` name.
+fn entry_point_name(path: &str) -> String {
+    let path = path.strip_suffix("::f").unwrap_or(path);
+
+    match path.rsplit_once(">::") {
+        // e.g. `<dinput8::xaudio27::XAudio27Wrapper as dinput8::xaudio27::IXAudio27_Impl>::Initialize`
+        Some((imp, method)) => {
+            let ty = imp.trim_start_matches('<').split(" as ").next().unwrap_or(imp);
+            let ty = ty.rsplit("::").next().unwrap_or(ty);
+            format!("{ty}::{method}")
+        }
+        None => path.to_string(),
+    }
+}
 
-                    let container = ::windows::core::ScopedHeap {
-                        vtable: &VTABLE as *const _ as *const _,
-                        this: this as *const _ as *const _,
-                    };
+/// Log a summary of the unimplemented XAudio entry points hit this session, and reset it.
+pub fn log_unimplemented_summary() {
+    let hits = std::mem::take(&mut *UNIMPLEMENTED_HITS.lock().unwrap_or_else(|e| e.into_inner()));
+    if hits.is_empty() {
+        return;
+    }
 
-                    let container = ::std::boxed::Box::into_raw(::std::boxed::Box::new(container));
+    let summary = hits
+        .iter()
+        .map(|(path, count)| format!("{} x{}", entry_point_name(path), count))
+        .collect::<Vec<_>>()
+        .join(", ");
 
-                    unsafe {
-                        // Add a reverse link from this to the container object.
-                        (*this).container = container;
+    udk_log::log(
+        udk_log::LogType::Warning,
+        &format!("XAudio27 HOOK: unimplemented entry points hit this session: {summary}"),
+    );
+}
 
-                        // Return the vtable pointer in our container.
-                        let vtable_ptr = &(*container).vtable;
-                        ::std::mem::transmute(vtable_ptr)
-                    }
-                }
-            }
-            impl ::windows::core::AsImpl<$implementation> for $iface {
-                unsafe fn as_impl(&self) -> &$implementation {
-                    let this = ::windows::core::Interface::as_raw(self);
-                    unsafe {
-                        let this = (this as *mut *mut ::core::ffi::c_void).sub(0 + 0)
-                            as *mut [<$implementation _Impl>];
-                        &(*this).this
-                    }
-                }
-            }
-            impl ScopedDrop for $implementation {
-                unsafe fn drop_in_place(&self) {
-                    let this = (self as *const _ as *mut [<$implementation _Impl>]);
-                    let container = (*this).container as *mut ::windows::core::ScopedHeap;
-
-                    // Convert this back into a box and drop it.
-                    let _ = ::std::boxed::Box::from_raw(this);
-                    // Ditto for the container.
-                    let _ = ::std::boxed::Box::from_raw(container);
-                }
-            }
+macro_rules! todo_log {
+    (@emit $fmt:expr $(, $args:tt)*) => {{
+        static LIMIT: udk_log::RateLimit = udk_log::RateLimit::new(udk_log::RATE_LIMIT_BURST, udk_log::RATE_LIMIT_INTERVAL);
+
+        record_unimplemented({
+            fn f() {}
+            std::any::type_name_of_val(&f)
+        });
+
+        if let Some(suppressed) = LIMIT.check() {
+            log_warning(std::format_args!(
+                concat!("XAudio27 HOOK: unimplemented: {}:{}", $fmt, "{}"),
+                file!(),
+                line!(),
+                $($args,)*
+                suppressed
+            ));
         }
+    }};
+    () => {
+        todo_log!(@emit "")
+    };
+    ($fmt:expr) => {
+        todo_log!(@emit concat!(": ", $fmt))
     };
-}
 
-trait ScopedDrop {
-    /// Drop the interface in-place. Note that this is unsafe for obvious reasons,
-    /// and you must take care that you do not access `self` whatsoever after calling
-    /// this function.
-    unsafe fn drop_in_place(&self);
+    ($fmt:expr, $($args:tt),*) => {
+        todo_log!(@emit concat!(": ", $fmt), $($args),*)
+    };
 }
 
 #[repr(C, packed)]
@@ -574,8 +547,348 @@ unsafe fn translate_send_list(sends: *const XAudio27VoiceSends) -> Vec<XAUDIO2_S
     sends_out
 }
 
+/// Raw pointers to the XAudio 2.9 engines owned by live `XAudio27Wrapper`s.
+static LIVE_ENGINES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Stop every XAudio 2.9 engine that is still owned by a live wrapper, returning how many there were.
+pub fn stop_live_engines() -> usize {
+    let engines = LIVE_ENGINES.lock().unwrap_or_else(|e| e.into_inner());
+
+    for &engine in engines.iter() {
+        // SAFETY: Wrappers remove their engine from this list before releasing it, and we hold the lock.
+        unsafe {
+            if let Some(engine) = <IXAudio2 as Interface>::from_raw_borrowed(&(engine as *mut c_void)) {
+                engine.StopEngine();
+            }
+        }
+    }
+
+    engines.len()
+}
+
+/// A mastering limiter effect `CreateFX` handed to the UDK. Holding a reference keeps its address
+/// from being reused by another object while it's tracked.
+struct LimiterEffect(IUnknown);
+
+// SAFETY: XAPOs are free-threaded, and only the reference count is touched through this.
+unsafe impl Send for LimiterEffect {}
+
+impl LimiterEffect {
+    /// Whether anyone but us still holds a reference to the effect.
+    fn in_use(&self) -> bool {
+        // `Release` returns the new reference count, so after a balanced `AddRef` it's the count
+        // with our own reference included.
+        unsafe {
+            let raw = self.0.as_raw();
+            (self.0.vtable().AddRef)(raw);
+            (self.0.vtable().Release)(raw) > 1
+        }
+    }
+}
+
+/// The mastering limiter effects the UDK may still put in an effect chain.
+static LIMITER_EFFECTS: Mutex<Vec<LimiterEffect>> = Mutex::new(Vec::new());
+
+/// Lock the tracked limiters, dropping those the UDK and XAudio have released.
+fn limiter_effects() -> std::sync::MutexGuard<'static, Vec<LimiterEffect>> {
+    let mut limiters = LIMITER_EFFECTS.lock().unwrap_or_else(|e| e.into_inner());
+    limiters.retain(LimiterEffect::in_use);
+    limiters
+}
+
+/// Remember a mastering limiter effect, so it can be recognized in an effect chain later.
+pub fn track_limiter(effect: &IUnknown) {
+    limiter_effects().push(LimiterEffect(effect.clone()));
+}
+
+/// A mastering voice handed to the UDK, with the settings the UDK asked for.
+struct MasteringVoice {
+    /// Raw pointer to the XAudio 2.9 voice.
+    voice: usize,
+    /// The volume the UDK set. The voice's real volume is this scaled by the configured master volume.
+    requested_volume: f32,
+    /// Index of the mastering limiter in the voice's effect chain.
+    limiter: Option<u32>,
+}
+
+static MASTERING_VOICES: Mutex<Vec<MasteringVoice>> = Mutex::new(Vec::new());
+
+fn mastering_voices() -> std::sync::MutexGuard<'static, Vec<MasteringVoice>> {
+    MASTERING_VOICES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Find the mastering limiter in an effect chain.
+unsafe fn find_limiter(effect_chain: *const XAUDIO2_EFFECT_CHAIN) -> Option<u32> {
+    let chain = effect_chain.as_ref()?;
+    let limiters = limiter_effects();
+
+    (0..chain.EffectCount).find(|&i| {
+        // The descriptor is packed; read the effect pointer without forming a reference to it.
+        let descriptor = chain.pEffectDescriptors.add(i as usize);
+        let effect = std::ptr::addr_of!((*descriptor).pEffect).read_unaligned();
+        effect
+            .as_ref()
+            .is_some_and(|effect| limiters.iter().any(|l| l.0.as_raw() == effect.as_raw()))
+    })
+}
+
+/// Apply the configured limiter overrides to a set of limiter parameters.
+fn override_limiter(parameters: &mut FXMASTERINGLIMITER_PARAMETERS, config: &XAudioConfig) {
+    if let Some(release) = config.limiter_release {
+        parameters.Release = release;
+    }
+    if let Some(loudness) = config.limiter_loudness {
+        parameters.Loudness = loudness;
+    }
+}
+
+/// Push the configured volume and limiter settings to a mastering voice.
+unsafe fn apply_to_mastering_voice(voice: &IXAudio2MasteringVoice, state: &MasteringVoice, config: &XAudioConfig) -> windows::core::Result<()> {
+    voice.SetVolume(state.requested_volume * config.master_volume, XAUDIO2_COMMIT_NOW)?;
+
+    if let Some(index) = state.limiter.filter(|_| config.limiter_release.is_some() || config.limiter_loudness.is_some()) {
+        let mut parameters = FXMASTERINGLIMITER_PARAMETERS::default();
+        let len = std::mem::size_of_val(&parameters) as u32;
+
+        voice.GetEffectParameters(index, &mut parameters as *mut _ as *mut c_void, len)?;
+        override_limiter(&mut parameters, config);
+        voice.SetEffectParameters(index, &parameters as *const _ as *const c_void, len, XAUDIO2_COMMIT_NOW)?;
+    }
+
+    Ok(())
+}
+
+/// Apply changed XAudio settings to every live mastering voice.
+pub fn apply_live_settings(config: &XAudioConfig) {
+    let voices = mastering_voices();
+
+    for state in voices.iter() {
+        // SAFETY: Voices are removed from the list before they're destroyed, and we hold the lock.
+        unsafe {
+            let raw = state.voice as *mut c_void;
+            let Some(voice) = <IXAudio2MasteringVoice as Interface>::from_raw_borrowed(&raw) else {
+                continue;
+            };
+
+            if let Err(e) = apply_to_mastering_voice(voice, state, config) {
+                log_warning(format_args!("Failed to apply settings to a mastering voice: {}", e));
+            }
+        }
+    }
+    drop(voices);
+
+    with_voice_budgets(|budgets| {
+        for budget in budgets.values_mut() {
+            budget.set_policy(config.voice_stealing.policy());
+            let changes = budget.set_limit(config.voice_budget.map(|v| v as usize));
+            // SAFETY: We're in `with_voice_budgets`.
+            unsafe { apply_voice_changes(&changes) };
+        }
+    });
+}
+
+/// The budgets for the source voices handed to the UDK, by the address of their XAudio 2.9
+/// engine. Each budget tracks its voices by the address of the XAudio 2.9 voice.
+static VOICE_BUDGETS: Mutex<BTreeMap<usize, VoiceBudget>> = Mutex::new(BTreeMap::new());
+
+/// Run `f` on the voice budgets. Voices are removed from them before they're destroyed, so while
+/// `f` runs, every voice in them is alive.
+fn with_voice_budgets<T>(f: impl FnOnce(&mut BTreeMap<usize, VoiceBudget>) -> T) -> T {
+    f(&mut VOICE_BUDGETS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Run `f` on the voice budget of `engine`. See [`with_voice_budgets`].
+fn with_voice_budget<T>(engine: usize, f: impl FnOnce(&mut VoiceBudget) -> T) -> T {
+    with_voice_budgets(|budgets| {
+        let budget = budgets.entry(engine).or_insert_with(|| {
+            let config = &config::get().xaudio;
+            VoiceBudget::new(config.voice_budget.map(|v| v as usize), config.voice_stealing.policy())
+        });
+
+        f(budget)
+    })
+}
+
+/// Carry out the budget's changes on source voices. Only call this from `with_voice_budgets`.
+unsafe fn apply_voice_changes(changes: &[Change]) {
+    for &change in changes {
+        let (Change::Virtualize(id) | Change::Resume(id) | Change::End(id)) = change;
+        let raw = id as *mut c_void;
+        let Some(voice) = <IXAudio2SourceVoice as Interface>::from_raw_borrowed(&raw) else {
+            continue;
+        };
+
+        let (action, result) = match change {
+            Change::Virtualize(_) => ("virtualize", voice.Stop(0, XAUDIO2_COMMIT_NOW)),
+            Change::Resume(_) => ("resume", voice.Start(0, XAUDIO2_COMMIT_NOW)),
+            Change::End(_) => ("end", voice.FlushSourceBuffers()),
+        };
+        if let Err(e) = result {
+            log_warning(format_args!("Failed to {action} a source voice: {e}"));
+        }
+    }
+}
+
+/// Tell the budgets about source voices that played all of their buffers, and the time that
+/// passed, letting virtualized voices resume in their place. Called once a frame.
+pub fn poll_voice_budget(elapsed: Duration) {
+    with_voice_budgets(|budgets| {
+        for budget in budgets.values_mut() {
+            for id in budget.playing() {
+                // SAFETY: As in `with_voice_budgets`.
+                unsafe {
+                    let raw = id as *mut c_void;
+                    let Some(voice) = <IXAudio2SourceVoice as Interface>::from_raw_borrowed(&raw) else {
+                        continue;
+                    };
+
+                    let mut state = XAUDIO2_VOICE_STATE::default();
+                    voice.GetState(&mut state, XAUDIO2_VOICE_NOSAMPLESPLAYED);
+                    if state.BuffersQueued == 0 {
+                        apply_voice_changes(&budget.idle(id));
+                    }
+                }
+            }
+
+            // SAFETY: As above.
+            unsafe { apply_voice_changes(&budget.advance(elapsed)) };
+        }
+    });
+}
+
+/// Block size and sample rate of a PCM format, the only kind whose buffers' lengths we know.
+unsafe fn pcm_format(format: *const WAVEFORMATEX) -> Option<(u32, u32)> {
+    let format = format.as_ref()?;
+    // The struct is packed, so copy the fields out rather than borrow them.
+    let (tag, block_align, rate) = ({ format.wFormatTag }, { format.nBlockAlign }, { format.nSamplesPerSec });
+    (u32::from(tag) == WAVE_FORMAT_PCM && block_align != 0 && rate != 0).then_some((u32::from(block_align), rate))
+}
+
+/// How long `buffer` plays for at a frequency ratio of 1, if it ends a PCM sound without looping.
+fn one_shot_length(buffer: &XAUDIO2_BUFFER, pcm: Option<(u32, u32)>) -> Option<Duration> {
+    let (block_align, rate) = pcm?;
+    if buffer.Flags & XAUDIO2_END_OF_STREAM == 0 || buffer.LoopCount != 0 {
+        return None;
+    }
+
+    let frames = match buffer.PlayLength {
+        0 => (buffer.AudioBytes / block_align).saturating_sub(buffer.PlayBegin),
+        length => length,
+    };
+    Some(Duration::from_secs_f64(f64::from(frames) / f64::from(rate)))
+}
+
+/// Query the first live engine, which is the one the UDK plays through.
+///
+/// The cycle counts cover the time since the previous query by anyone, the UDK included.
+pub fn performance_data() -> Option<XAUDIO2_PERFORMANCE_DATA> {
+    let engines = LIVE_ENGINES.lock().unwrap_or_else(|e| e.into_inner());
+    let &engine = engines.first()?;
+
+    // SAFETY: As in `stop_live_engines`.
+    unsafe {
+        let raw = engine as *mut c_void;
+        let engine = <IXAudio2 as Interface>::from_raw_borrowed(&raw)?;
+        let mut perf = XAUDIO2_PERFORMANCE_DATA::default();
+        engine.GetPerformanceData(&mut perf);
+        Some(perf)
+    }
+}
+
+/// Describe the live engines and mastering voices, one line each.
+pub fn dump_state() -> Vec<String> {
+    let mut lines = Vec::new();
+
+    let engines = LIVE_ENGINES.lock().unwrap_or_else(|e| e.into_inner()).len();
+    lines.push(format!("{engines} live engine(s)"));
+    // Shares the frame's read, since reading resets the cycle counts the statistics are built on.
+    if let Some(perf) = audio_stats::sample() {
+        lines.push(format!(
+            "engine 0: {}/{} source voices active, {} submix voices, {} samples latency, {} glitches, {} KiB",
+            perf.active_source_voices,
+            perf.total_source_voices,
+            perf.active_submix_voices,
+            perf.latency_samples,
+            perf.glitches,
+            perf.memory_bytes / 1024,
+        ));
+    }
+
+    with_voice_budgets(|budgets| {
+        for budget in budgets.values() {
+            let (playing, virtualized) = budget.counts();
+            lines.push(format!(
+                "{playing} source voice(s) playing, {virtualized} virtualized, budget {}",
+                budget.limit().map_or("unlimited".to_string(), |l| l.to_string()),
+            ));
+        }
+    });
+
+    for (i, state) in mastering_voices().iter().enumerate() {
+        // SAFETY: As in `apply_live_settings`.
+        let volume = unsafe {
+            let raw = state.voice as *mut c_void;
+            <IXAudio2MasteringVoice as Interface>::from_raw_borrowed(&raw).map(|v| v.GetVolume())
+        };
+        lines.push(format!(
+            "mastering voice {i}: requested volume {:.2}, actual {}, limiter {}",
+            state.requested_volume,
+            volume.map_or("?".to_string(), |v| format!("{v:.2}")),
+            state.limiter.map_or("none".to_string(), |l| format!("at effect {l}")),
+        ));
+    }
+
+    let hits = UNIMPLEMENTED_HITS.lock().unwrap_or_else(|e| e.into_inner());
+    if !hits.is_empty() {
+        let summary = hits
+            .iter()
+            .map(|(path, count)| format!("{} x{}", entry_point_name(path), count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        lines.push(format!("unimplemented entry points hit: {summary}"));
+    }
+
+    lines
+}
+
+/// XAudio trace mask for a configured trace level. Each level includes the ones before it.
+fn trace_mask(level: XAudioTraceLevel) -> u32 {
+    [
+        (XAudioTraceLevel::Errors, XAUDIO2_LOG_ERRORS),
+        (XAudioTraceLevel::Warnings, XAUDIO2_LOG_WARNINGS),
+        (XAudioTraceLevel::Info, XAUDIO2_LOG_INFO),
+        (XAudioTraceLevel::Detail, XAUDIO2_LOG_DETAIL),
+    ]
+    .into_iter()
+    .filter(|&(l, _)| l <= level)
+    .fold(0, |mask, (_, bit)| mask | bit)
+}
+
+/// Reports XAudio's critical errors, which mean the engine has stopped, usually because the
+/// audio device went away.
+struct EngineCallback;
+
+static ENGINE_CALLBACK: EngineCallback = EngineCallback;
+
+impl IXAudio2EngineCallback_Impl for EngineCallback {
+    fn OnProcessingPassStart(&self) {}
+
+    fn OnProcessingPassEnd(&self) {}
+
+    /// Called on XAudio's thread, so it's reported from the game thread.
+    fn OnCriticalError(&self, error: HRESULT) {
+        game_thread::post("XAudio critical error", move || {
+            udk_log::log(
+                udk_log::LogType::Error,
+                &format!("XAudio stopped with a critical error ({:#010x}); there's no sound until a restart", error.0),
+            );
+        });
+    }
+}
+
+/// The engine, and the callback registered with it.
 #[implement(IXAudio27)]
-pub struct XAudio27Wrapper(IXAudio2);
+pub struct XAudio27Wrapper(IXAudio2, ScopedInterface<'static, IXAudio2EngineCallback>);
 
 impl XAudio27Wrapper {
     pub fn new() -> windows::core::Result<XAudio27Wrapper> {
@@ -594,7 +907,7 @@ impl XAudio27Wrapper {
         unsafe {
             xaudio2.SetDebugConfiguration(
                 Some(&XAUDIO2_DEBUG_CONFIGURATION {
-                    TraceMask: XAUDIO2_LOG_ERRORS | XAUDIO2_LOG_WARNINGS,
+                    TraceMask: trace_mask(config::get().xaudio.trace_level),
                     BreakMask: 0,
                     LogThreadID: windows::Win32::Foundation::BOOL(0),
                     LogFileline: windows::Win32::Foundation::BOOL(1),
@@ -605,7 +918,33 @@ impl XAudio27Wrapper {
             );
         }
 
-        Ok(Self(xaudio2))
+        let callback = IXAudio2EngineCallback::new(&ENGINE_CALLBACK);
+        unsafe { xaudio2.RegisterForCallbacks(&*callback)? };
+
+        LIVE_ENGINES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(xaudio2.as_raw() as usize);
+        audio_stats::start_session();
+
+        Ok(Self(xaudio2, callback))
+    }
+}
+
+impl Drop for XAudio27Wrapper {
+    fn drop(&mut self) {
+        let engine = self.0.as_raw() as usize;
+        LIVE_ENGINES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|&e| e != engine);
+        // Its voices went with it.
+        with_voice_budgets(|budgets| budgets.remove(&engine));
+        unsafe { self.0.UnregisterForCallbacks(&*self.1) };
+
+        // The UDK releases the engine when the audio device shuts down, which ends the session.
+        log_unimplemented_summary();
+        audio_stats::log_summary();
     }
 }
 
@@ -702,13 +1041,21 @@ impl IXAudio27_Impl for XAudio27Wrapper {
                 source_format,
                 flags & 0x0E,
                 max_frequency_ratio,
-                (!callback.is_null()).then_some(std::mem::transmute(&callback)), // SAFETY: The interface is compatible between 2.7 and 2.9.
+                // SAFETY: The interface is compatible between 2.7 and 2.9.
+                (!callback.is_null()).then_some(std::mem::transmute::<&*const (), &IXAudio2VoiceCallback>(&callback)),
                 sends.as_ref().map(|x| x as *const _),
                 (!effect_chain.is_null()).then_some(effect_chain), // SAFETY: The interface is compatible between 2.7 and 2.9.
             )?;
 
-            let source_voice: IXAudio27SourceVoice =
-                XAudio27SourceVoiceWrapper(voice_out.unwrap()).into();
+            let voice = voice_out.unwrap();
+            let engine = self.0.as_raw() as usize;
+            with_voice_budget(engine, |budget| budget.add(voice.as_raw() as VoiceId));
+
+            let source = Source {
+                engine,
+                pcm: pcm_format(source_format),
+            };
+            let source_voice: IXAudio27SourceVoice = XAudio27SourceVoiceWrapper(voice, source).into();
 
             source_voice_out.write(source_voice);
             Ok(())
@@ -801,8 +1148,20 @@ impl IXAudio27_Impl for XAudio27Wrapper {
                 AudioCategory_GameMedia,
             )?;
 
+            let voice = voice_out.unwrap();
+            let state = MasteringVoice {
+                voice: voice.as_raw() as usize,
+                requested_volume: 1.0,
+                limiter: find_limiter(effect_chain),
+            };
+            // The voice is usable with the UDK's own settings, so don't fail its creation over ours.
+            if let Err(e) = apply_to_mastering_voice(&voice, &state, &config::get().xaudio) {
+                log_warning(format_args!("Failed to apply settings to a new mastering voice: {}", e));
+            }
+            mastering_voices().push(state);
+
             let mastering_voice: IXAudio27MasteringVoice =
-                XAudio27MasteringVoiceWrapper(voice_out.unwrap()).into();
+                XAudio27MasteringVoiceWrapper(voice).into();
 
             mastering_voice_out.write(mastering_voice);
             Ok(())
@@ -847,6 +1206,14 @@ struct XAudio27MasteringVoiceWrapper(IXAudio2MasteringVoice);
 
 impl_iface!(XAudio27MasteringVoiceWrapper, IXAudio27MasteringVoice);
 
+impl XAudio27MasteringVoiceWrapper {
+    /// Run `f` on this voice's entry in the mastering voice list.
+    fn with_state<R>(&self, f: impl FnOnce(&mut MasteringVoice) -> R) -> Option<R> {
+        let voice = self.0.as_raw() as usize;
+        mastering_voices().iter_mut().find(|v| v.voice == voice).map(f)
+    }
+}
+
 impl IXAudio27MasteringVoice_Impl for XAudio27MasteringVoiceWrapper {
     // impl IXAudio27Voice_Impl for XAudio27MasteringVoiceWrapper {
     unsafe fn GetVoiceDetails(&self, _details_out: *mut XAudio27VoiceDetails) {
@@ -859,6 +1226,9 @@ impl IXAudio27MasteringVoice_Impl for XAudio27MasteringVoiceWrapper {
     }
 
     unsafe fn SetEffectChain(&self, effect_chain: *const XAUDIO2_EFFECT_CHAIN) -> HRESULT {
+        let limiter = find_limiter(effect_chain);
+        self.with_state(|state| state.limiter = limiter);
+
         // SAFETY: The interface is compatible between 2.7 and 2.9.
         self.0
             .SetEffectChain((!effect_chain.is_null()).then_some(effect_chain))
@@ -874,7 +1244,7 @@ impl IXAudio27MasteringVoice_Impl for XAudio27MasteringVoiceWrapper {
     }
 
     unsafe fn GetEffectState(&self, effect_index: u32, enabled_out: *mut BOOL) {
-        *enabled_out = self.0.GetEffectState(effect_index).into()
+        *enabled_out = self.0.GetEffectState(effect_index)
     }
 
     unsafe fn SetEffectParameters(
@@ -884,6 +1254,21 @@ impl IXAudio27MasteringVoice_Impl for XAudio27MasteringVoiceWrapper {
         parameters_len: u32,
         operation_set: u32,
     ) -> HRESULT {
+        // Configured limiter settings take precedence over the UDK's.
+        let is_limiter = self.with_state(|state| state.limiter == Some(effect_index)) == Some(true);
+        if is_limiter
+            && !parameters.is_null()
+            && parameters_len as usize == std::mem::size_of::<FXMASTERINGLIMITER_PARAMETERS>()
+        {
+            let mut overridden = *(parameters as *const FXMASTERINGLIMITER_PARAMETERS);
+            override_limiter(&mut overridden, &config::get().xaudio);
+
+            return self
+                .0
+                .SetEffectParameters(effect_index, &overridden as *const _ as *const c_void, parameters_len, operation_set)
+                .into();
+        }
+
         // NOTE: The parameters are identical between XAudio 2.7 and 2.9, so we can simply forward the call.
         self.0
             .SetEffectParameters(effect_index, parameters, parameters_len, operation_set)
@@ -933,11 +1318,18 @@ impl IXAudio27MasteringVoice_Impl for XAudio27MasteringVoiceWrapper {
     }
 
     unsafe fn SetVolume(&self, volume: f32, operation_set: u32) -> HRESULT {
-        self.0.SetVolume(volume, operation_set).into()
+        // The UDK's volume is scaled by the configured master volume, but reads back unscaled.
+        self.with_state(|state| state.requested_volume = volume);
+        self.0
+            .SetVolume(volume * config::get().xaudio.master_volume, operation_set)
+            .into()
     }
 
     unsafe fn GetVolume(&self, volume: *mut f32) {
-        *volume = self.0.GetVolume()
+        *volume = match self.with_state(|state| state.requested_volume) {
+            Some(requested) => requested,
+            None => self.0.GetVolume(),
+        }
     }
 
     unsafe fn SetChannelVolumes(
@@ -982,6 +1374,9 @@ impl IXAudio27MasteringVoice_Impl for XAudio27MasteringVoiceWrapper {
     }
 
     unsafe fn DestroyVoice(&self) {
+        let voice = self.0.as_raw() as usize;
+        mastering_voices().retain(|v| v.voice != voice);
+
         self.0.DestroyVoice();
         self.drop_in_place();
     }
@@ -1019,7 +1414,7 @@ impl IXAudio27SubmixVoice_Impl for XAudio27SubmixVoiceWrapper {
     }
 
     unsafe fn GetEffectState(&self, effect_index: u32, enabled_out: *mut BOOL) {
-        *enabled_out = self.0.GetEffectState(effect_index).into()
+        *enabled_out = self.0.GetEffectState(effect_index)
     }
 
     unsafe fn SetEffectParameters(
@@ -1147,10 +1542,30 @@ impl IXAudio27SubmixVoice_Impl for XAudio27SubmixVoiceWrapper {
     // } IXAudio27Voice
 }
 
-struct XAudio27SourceVoiceWrapper(IXAudio2SourceVoice);
+/// A source voice handed to the UDK. Its playback is subject to the voice budget: while the
+/// budget has it virtualized, the UDK's `Start` isn't passed on.
+struct XAudio27SourceVoiceWrapper(IXAudio2SourceVoice, Source);
+
+/// What the voice budget needs to know about a source voice.
+struct Source {
+    /// The engine that created the voice, whose budget it counts against.
+    engine: usize,
+    /// See [`pcm_format`].
+    pcm: Option<(u32, u32)>,
+}
 
 impl_iface!(XAudio27SourceVoiceWrapper, IXAudio27SourceVoice);
 
+impl XAudio27SourceVoiceWrapper {
+    fn id(&self) -> VoiceId {
+        self.0.as_raw() as VoiceId
+    }
+
+    fn with_budget<T>(&self, f: impl FnOnce(&mut VoiceBudget) -> T) -> T {
+        with_voice_budget(self.1.engine, f)
+    }
+}
+
 impl IXAudio27SourceVoice_Impl for XAudio27SourceVoiceWrapper {
     // impl IXAudio27Voice_Impl for XAudio27SourceVoiceWrapper {
     unsafe fn GetVoiceDetails(&self, _details_out: *mut XAudio27VoiceDetails) {
@@ -1178,7 +1593,7 @@ impl IXAudio27SourceVoice_Impl for XAudio27SourceVoiceWrapper {
     }
 
     unsafe fn GetEffectState(&self, effect_index: u32, enabled_out: *mut BOOL) {
-        *enabled_out = self.0.GetEffectState(effect_index).into()
+        *enabled_out = self.0.GetEffectState(effect_index)
     }
 
     unsafe fn SetEffectParameters(
@@ -1237,7 +1652,11 @@ impl IXAudio27SourceVoice_Impl for XAudio27SourceVoiceWrapper {
     }
 
     unsafe fn SetVolume(&self, volume: f32, operation_set: u32) -> HRESULT {
-        self.0.SetVolume(volume, operation_set).into()
+        let result = self.0.SetVolume(volume, operation_set);
+        if result.is_ok() {
+            self.with_budget(|budget| budget.set_volume(self.id(), volume));
+        }
+        result.into()
     }
 
     unsafe fn GetVolume(&self, volume: *mut f32) {
@@ -1273,15 +1692,19 @@ impl IXAudio27SourceVoice_Impl for XAudio27SourceVoiceWrapper {
     ) -> HRESULT {
         let dest_voice = translate_voice(dest_voice);
 
-        self.0
-            .SetOutputMatrix(
-                dest_voice.as_ref(),
-                source_channels,
-                dest_channels,
-                level_matrix,
-                operation_set,
-            )
-            .into()
+        let result = self.0.SetOutputMatrix(
+            dest_voice.as_ref(),
+            source_channels,
+            dest_channels,
+            level_matrix,
+            operation_set,
+        );
+        if result.is_ok() && !level_matrix.is_null() {
+            let levels = std::slice::from_raw_parts(level_matrix, (source_channels * dest_channels) as usize);
+            let level = levels.iter().fold(0.0f32, |max, l| max.max(l.abs()));
+            self.with_budget(|budget| budget.set_level(self.id(), level));
+        }
+        result.into()
     }
 
     unsafe fn GetOutputMatrix(
@@ -1295,17 +1718,28 @@ impl IXAudio27SourceVoice_Impl for XAudio27SourceVoiceWrapper {
     }
 
     unsafe fn DestroyVoice(&self) {
+        self.with_budget(|budget| apply_voice_changes(&budget.remove(self.id())));
         self.0.DestroyVoice();
         self.drop_in_place();
     }
     // } (IXAudio27Voice)
 
     unsafe fn Start(&self, flags: u32, operation_set: u32) -> HRESULT {
-        self.0.Start(flags, operation_set).into()
+        self.with_budget(|budget| {
+            apply_voice_changes(&budget.start(self.id()));
+
+            // The voice resumes when the budget allows.
+            match budget.is_virtualized(self.id()) {
+                true => S_OK,
+                false => self.0.Start(flags, operation_set).into(),
+            }
+        })
     }
 
     unsafe fn Stop(&self, flags: u32, operation_set: u32) -> HRESULT {
-        self.0.Stop(flags, operation_set).into()
+        let result = self.0.Stop(flags, operation_set);
+        self.with_budget(|budget| apply_voice_changes(&budget.stop(self.id())));
+        result.into()
     }
 
     unsafe fn SubmitSourceBuffer(
@@ -1313,9 +1747,14 @@ impl IXAudio27SourceVoice_Impl for XAudio27SourceVoiceWrapper {
         buffer: *const XAUDIO2_BUFFER,
         buffer_wma: *const XAUDIO2_BUFFER_WMA,
     ) -> HRESULT {
-        self.0
-            .SubmitSourceBuffer(buffer, (!buffer_wma.is_null()).then_some(buffer_wma))
-            .into()
+        let result = self
+            .0
+            .SubmitSourceBuffer(buffer, (!buffer_wma.is_null()).then_some(buffer_wma));
+        if result.is_ok() {
+            let length = buffer.as_ref().and_then(|b| one_shot_length(b, self.1.pcm));
+            self.with_budget(|budget| apply_voice_changes(&budget.submit(self.id(), length)));
+        }
+        result.into()
     }
 
     unsafe fn FlushSourceBuffers(&self) -> HRESULT {
@@ -1335,7 +1774,11 @@ impl IXAudio27SourceVoice_Impl for XAudio27SourceVoiceWrapper {
     }
 
     unsafe fn SetFrequencyRatio(&self, ratio: f32, operation_set: u32) -> HRESULT {
-        self.0.SetFrequencyRatio(ratio, operation_set).into()
+        let result = self.0.SetFrequencyRatio(ratio, operation_set);
+        if result.is_ok() {
+            self.with_budget(|budget| budget.set_frequency_ratio(self.id(), ratio));
+        }
+        result.into()
     }
 
     unsafe fn GetFrequencyRatio(&self, ratio: *mut f32) {
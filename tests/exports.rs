@@ -0,0 +1,94 @@
+//! Checks the export table of the built DLL: the host only ever sees what's listed there, so a
+//! forwarded export that goes missing would only show up as a game that refuses to start.
+#![cfg(windows)]
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use pelite::pe::{Pe, PeFile};
+
+/// Exports of the `dinput8` identity, implemented in `src/dinput8.rs`.
+const DINPUT8_EXPORTS: &[&str] = &[
+    "DirectInput8Create",
+    "DllCanUnloadNow",
+    "DllGetClassObject",
+    "DllRegisterServer",
+    "DllUnregisterServer",
+    "GetdfDIJoystick",
+];
+
+/// Exported whichever identity is selected.
+const COMMON_EXPORTS: &[&str] = &["RenX_Shutdown"];
+
+/// The DLL built alongside this test. Cargo puts it next to the test binary, and copies it one
+/// directory up.
+fn built_dll() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    let deps = exe.parent().unwrap();
+
+    [deps, deps.parent().unwrap()]
+        .iter()
+        .map(|dir| dir.join("dinput8.dll"))
+        .find(|path| path.exists())
+        .expect("dinput8.dll was not built")
+}
+
+/// The exports the selected identity has to provide.
+fn expected_exports() -> BTreeSet<String> {
+    let identity = if cfg!(feature = "proxy-version") {
+        Some("version")
+    } else if cfg!(feature = "proxy-winmm") {
+        Some("winmm")
+    } else if cfg!(feature = "proxy-dsound") {
+        Some("dsound")
+    } else {
+        None
+    };
+
+    let mut expected = COMMON_EXPORTS.iter().map(|&e| e.to_owned()).collect::<BTreeSet<_>>();
+    match identity {
+        Some(identity) => {
+            let list = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("proxy/{identity}.exports"));
+            let list = std::fs::read_to_string(list).unwrap();
+            expected.extend(
+                list.lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .filter_map(|l| l.split_whitespace().next())
+                    .map(str::to_owned),
+            );
+        }
+        None => expected.extend(DINPUT8_EXPORTS.iter().map(|&e| e.to_owned())),
+    }
+    expected
+}
+
+#[test]
+fn built_dll_exports_everything_its_identity_needs() {
+    let bytes = std::fs::read(built_dll()).unwrap();
+    let file = PeFile::from_bytes(&bytes).unwrap();
+
+    let exports = file.exports().unwrap().by().unwrap();
+    let names = exports
+        .iter_names()
+        .map(|(name, _)| name.unwrap().to_str().unwrap().to_owned())
+        .collect::<BTreeSet<_>>();
+
+    let missing = expected_exports().difference(&names).cloned().collect::<Vec<_>>();
+    assert!(missing.is_empty(), "missing exports: {}", missing.join(", "));
+}
+
+#[test]
+fn exports_resolve_into_the_dll() {
+    let bytes = std::fs::read(built_dll()).unwrap();
+    let file = PeFile::from_bytes(&bytes).unwrap();
+    let exports = file.exports().unwrap().by().unwrap();
+
+    for name in expected_exports() {
+        let export = exports.name(&name).unwrap();
+        assert!(
+            export.symbol().is_some(),
+            "{name} is forwarded by the loader instead of through our own code"
+        );
+    }
+}